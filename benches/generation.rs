@@ -0,0 +1,152 @@
+//! Generation throughput benchmark.
+//!
+//! Runs a fixed prompt through the same inference worker the server uses and prints one JSON
+//! line with the prefill and decode speed in tokens per second, so the numbers can be tracked
+//! across changes:
+//!
+//! ```bash
+//! cargo bench --bench generation
+//! ```
+//!
+//! Like the inference tests, it needs a GGUF model and a GPU. Without a model file at
+//! `MODEL_FILE_NAME` it prints `{"skipped": ...}` and exits successfully, so a smoke run can be
+//! part of CI:
+//!
+//! ```bash
+//! BENCH_ITERATIONS=1 BENCH_MAX_TOKENS=8 cargo bench --bench generation
+//! ```
+//!
+//! - `BENCH_ITERATIONS`: measured generations, after one warm-up generation. Defaults to 5.
+//! - `BENCH_MAX_TOKENS`: tokens generated per iteration. Defaults to 128.
+//!
+//! Prefill speed is the prompt length over the time to the first token, and decode speed the
+//! remaining tokens over the time it took to generate them.
+
+use serde::Serialize;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio_local_llm_api::core::assistant::{
+    ChatMessage, InferenceTask, Role, background_task, model_file_name,
+};
+use tokio_local_llm_api::core::task_queue::{self, TaskSender};
+
+const PROMPT: &str = "Explain in a few paragraphs how a transformer language model turns a \
+prompt into text, from tokenization to sampling the next token.";
+
+#[derive(Serialize, Debug)]
+struct Iteration {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    prefill_tokens_per_second: f64,
+    decode_tokens_per_second: f64,
+}
+
+#[derive(Serialize, Debug)]
+struct Report {
+    model: String,
+    iterations: Vec<Iteration>,
+    median_prefill_tokens_per_second: f64,
+    median_decode_tokens_per_second: f64,
+}
+
+fn env_usize(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|s| usize::from_str(&s).ok())
+        .unwrap_or(default)
+}
+
+fn tokens_per_second(tokens: usize, elapsed: Duration) -> f64 {
+    tokens as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_by(f64::total_cmp);
+    values[values.len() / 2]
+}
+
+/// Generates a reply to [`PROMPT`] on the worker behind `sender` and times it.
+async fn run_iteration(sender: &TaskSender, max_tokens: usize) -> Iteration {
+    let (mut task, mut receiver) =
+        InferenceTask::new(vec![ChatMessage::new(Role::User, PROMPT.to_owned())]);
+    task.set_max_tokens(max_tokens);
+    let prompt_tokens = task.track_prompt_tokens();
+
+    let start = Instant::now();
+    sender.send(task).await.expect("inference worker stopped");
+
+    let mut first_token_at = None;
+    let mut completion_tokens = 0;
+    while receiver.recv().await.is_some() {
+        first_token_at.get_or_insert_with(Instant::now);
+        completion_tokens += 1;
+    }
+    let end = Instant::now();
+    let prompt_tokens = prompt_tokens.await.unwrap_or(0);
+    let first_token_at = first_token_at.unwrap_or(end);
+
+    Iteration {
+        prompt_tokens,
+        completion_tokens,
+        prefill_tokens_per_second: tokens_per_second(prompt_tokens, first_token_at - start),
+        decode_tokens_per_second: tokens_per_second(
+            completion_tokens.saturating_sub(1),
+            end - first_token_at,
+        ),
+    }
+}
+
+fn main() {
+    let model = model_file_name();
+    if !Path::new(&model).exists() {
+        println!(
+            "{}",
+            serde_json::json!({ "skipped": format!("model file not found at '{model}'") })
+        );
+        return;
+    }
+
+    let iterations = env_usize("BENCH_ITERATIONS", 5);
+    let max_tokens = env_usize("BENCH_MAX_TOKENS", 128);
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build the runtime");
+
+    let report = runtime.block_on(async {
+        let (sender, receiver) = task_queue::channel(1);
+        let worker = tokio::spawn(background_task(receiver));
+
+        // The first task also waits for the model to load
+        run_iteration(&sender, max_tokens).await;
+
+        let mut results = Vec::with_capacity(iterations);
+        for _ in 0..iterations {
+            results.push(run_iteration(&sender, max_tokens).await);
+        }
+
+        drop(sender);
+        worker.await.expect("inference worker panicked");
+
+        Report {
+            model,
+            median_prefill_tokens_per_second: median(
+                results
+                    .iter()
+                    .map(|i| i.prefill_tokens_per_second)
+                    .collect(),
+            ),
+            median_decode_tokens_per_second: median(
+                results.iter().map(|i| i.decode_tokens_per_second).collect(),
+            ),
+            iterations: results,
+        }
+    });
+
+    println!("{}", serde_json::to_string(&report).unwrap());
+}
@@ -0,0 +1,11 @@
+-- Add up migration script here
+CREATE TABLE message_feedback
+(
+    message_id TEXT    NOT NULL,
+    user_id    TEXT    NOT NULL,
+    rating     INTEGER NOT NULL,
+    comment    TEXT,
+    created_at TEXT    NOT NULL,
+    PRIMARY KEY (message_id, user_id),
+    FOREIGN KEY (message_id) REFERENCES messages (id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE messages ADD COLUMN token_count INTEGER NOT NULL DEFAULT 0;
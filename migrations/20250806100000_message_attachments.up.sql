@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE messages ADD COLUMN attachments TEXT NOT NULL DEFAULT '[]';
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE conversations DROP COLUMN model_fingerprint;
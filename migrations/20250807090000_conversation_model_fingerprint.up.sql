@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE conversations ADD COLUMN model_fingerprint TEXT;
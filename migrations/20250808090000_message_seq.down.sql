@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TRIGGER messages_seq;
+ALTER TABLE messages DROP COLUMN seq;
@@ -0,0 +1,12 @@
+-- Add up migration script here
+ALTER TABLE messages ADD COLUMN seq INTEGER;
+
+-- Existing messages keep their insertion order
+UPDATE messages SET seq = rowid;
+
+-- Numbers every new message after all earlier ones, so messages with equal timestamps stay in
+-- the order they were created in
+CREATE TRIGGER messages_seq AFTER INSERT ON messages WHEN NEW.seq IS NULL
+BEGIN
+    UPDATE messages SET seq = (SELECT COALESCE(MAX(seq), 0) + 1 FROM messages) WHERE id = NEW.id;
+END;
@@ -0,0 +1,8 @@
+-- Add down migration script here
+DROP TRIGGER messages_deleted;
+DROP TRIGGER messages_updated;
+DROP TRIGGER messages_inserted;
+DROP TRIGGER conversations_updated;
+DROP TRIGGER conversations_inserted;
+ALTER TABLE messages DROP COLUMN updated_at;
+ALTER TABLE conversations DROP COLUMN updated_at;
@@ -0,0 +1,41 @@
+-- Add up migration script here
+ALTER TABLE conversations ADD COLUMN updated_at TEXT;
+ALTER TABLE messages ADD COLUMN updated_at TEXT;
+
+-- Existing rows were last changed when they were created, as far as anyone knows
+UPDATE conversations SET updated_at = created_at;
+UPDATE messages SET updated_at = created_at;
+
+-- The columns are kept by the database, so no query can forget them. A conversation counts as
+-- changed whenever a message in it is created, changed or deleted.
+CREATE TRIGGER conversations_inserted AFTER INSERT ON conversations
+BEGIN
+    UPDATE conversations SET updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now') WHERE id = NEW.id;
+END;
+
+CREATE TRIGGER conversations_updated AFTER UPDATE ON conversations
+    WHEN NEW.updated_at IS OLD.updated_at
+BEGIN
+    UPDATE conversations SET updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now') WHERE id = NEW.id;
+END;
+
+CREATE TRIGGER messages_inserted AFTER INSERT ON messages
+BEGIN
+    UPDATE messages SET updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now') WHERE id = NEW.id;
+    UPDATE conversations SET updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now')
+        WHERE id = NEW.conversation_id;
+END;
+
+CREATE TRIGGER messages_updated AFTER UPDATE ON messages
+    WHEN NEW.updated_at IS OLD.updated_at
+BEGIN
+    UPDATE messages SET updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now') WHERE id = NEW.id;
+    UPDATE conversations SET updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now')
+        WHERE id = NEW.conversation_id;
+END;
+
+CREATE TRIGGER messages_deleted AFTER DELETE ON messages
+BEGIN
+    UPDATE conversations SET updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now')
+        WHERE id = OLD.conversation_id;
+END;
@@ -0,0 +1,10 @@
+-- Add up migration script here
+CREATE TABLE conversation_tags
+(
+    conversation_id TEXT NOT NULL,
+    tag             TEXT NOT NULL,
+    PRIMARY KEY (conversation_id, tag),
+    FOREIGN KEY (conversation_id) REFERENCES conversations (id) ON DELETE CASCADE
+);
+
+CREATE INDEX conversation_tags_tag ON conversation_tags (tag);
@@ -0,0 +1,17 @@
+-- Add up migration script here
+CREATE TABLE request_log
+(
+    id                TEXT    NOT NULL PRIMARY KEY,
+    user              TEXT    NOT NULL,
+    conversation_id   TEXT    NOT NULL,
+    message_id        TEXT    NOT NULL,
+    messages          TEXT    NOT NULL,
+    sampling          TEXT    NOT NULL,
+    response          TEXT    NOT NULL,
+    outcome           TEXT    NOT NULL,
+    completion_tokens INTEGER NOT NULL,
+    started_at        TEXT    NOT NULL,
+    finished_at       TEXT    NOT NULL
+);
+
+CREATE INDEX request_log_started_at ON request_log (started_at);
@@ -0,0 +1,160 @@
+//! Admin endpoints
+//!
+//! Every endpoint requires `Authorization: Bearer <ADMIN_TOKEN>`. Without an `ADMIN_TOKEN` the
+//! endpoints are disabled and respond with 403.
+
+use crate::CHAT_TEMPLATE;
+use crate::api::ErrorBody;
+use crate::core::config::AppConfig;
+use crate::infrastructure::database::DatabaseConnection;
+use async_trait::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use di_axum::Inject;
+use log::error;
+use serde::Serialize;
+use std::convert::Infallible;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/config", get(get_config))
+        .route("/chat_template", get(get_chat_template))
+        .route("/db/optimize", post(optimize_database))
+}
+
+/// The configuration the server runs with, secrets redacted.
+async fn get_config(
+    Inject(config): Inject<AppConfig>,
+    token: BearerToken,
+) -> Result<Json<AppConfig>, AdminRejection> {
+    authorize(&config, &token)?;
+    Ok(Json(config.as_ref().clone()))
+}
+
+/// The chat template prompts are rendered with, as plain text. Responds with 503 until the model
+/// is loaded.
+async fn get_chat_template(
+    Inject(config): Inject<AppConfig>,
+    token: BearerToken,
+) -> Result<Response, AdminRejection> {
+    authorize(&config, &token)?;
+    Ok(match CHAT_TEMPLATE.get() {
+        Some(template) => template.clone().into_response(),
+        None => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ErrorBody {
+                error: "the model is not loaded",
+                code: "model_not_loaded",
+            }),
+        )
+            .into_response(),
+    })
+}
+
+/// What `POST /admin/db/optimize` did.
+#[derive(Serialize, Debug)]
+pub struct OptimizeResult {
+    /// Whether `VACUUM` ran, see [`crate::core::config::vacuum_on_optimize`]
+    pub vacuumed: bool,
+    pub duration_ms: u64,
+    pub size_before_bytes: u64,
+    pub size_after_bytes: u64,
+}
+
+/// Runs `PRAGMA optimize` on the database, and `VACUUM` if configured. With `VACUUM` every other
+/// query waits until it is done, so this is best run when the server is quiet.
+async fn optimize_database(
+    Inject(config): Inject<AppConfig>,
+    Inject(database): Inject<DatabaseConnection>,
+    token: BearerToken,
+) -> Result<Response, AdminRejection> {
+    authorize(&config, &token)?;
+    Ok(match database.optimize(config.vacuum_on_optimize).await {
+        Ok(report) => Json(OptimizeResult {
+            vacuumed: report.vacuumed,
+            duration_ms: report.duration.as_millis() as u64,
+            size_before_bytes: report.size_before,
+            size_after_bytes: report.size_after,
+        })
+        .into_response(),
+        Err(e) => {
+            error!("failed to optimize the database: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorBody {
+                    error: "failed to optimize the database",
+                    code: "optimize_failed",
+                }),
+            )
+                .into_response()
+        }
+    })
+}
+
+/// The token from an `Authorization: Bearer <token>` header, if there is one.
+#[derive(Debug)]
+pub struct BearerToken(pub Option<String>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for BearerToken
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Infallible> {
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_owned());
+        Ok(BearerToken(token))
+    }
+}
+
+/// Checks that `token` is the configured admin token.
+pub fn authorize(config: &AppConfig, token: &BearerToken) -> Result<(), AdminRejection> {
+    let Some(admin_token) = &config.admin_token else {
+        return Err(AdminRejection::Disabled);
+    };
+    match &token.0 {
+        Some(token) if token == admin_token => Ok(()),
+        _ => Err(AdminRejection::Unauthorized),
+    }
+}
+
+/// Why an admin request was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminRejection {
+    /// `ADMIN_TOKEN` isn't set
+    Disabled,
+    /// The token is missing or wrong
+    Unauthorized,
+}
+
+impl IntoResponse for AdminRejection {
+    fn into_response(self) -> Response {
+        let (status, body) = match self {
+            AdminRejection::Disabled => (
+                StatusCode::FORBIDDEN,
+                ErrorBody {
+                    error: "admin endpoints are disabled",
+                    code: "admin_disabled",
+                },
+            ),
+            AdminRejection::Unauthorized => (
+                StatusCode::UNAUTHORIZED,
+                ErrorBody {
+                    error: "a valid admin token is required",
+                    code: "unauthorized",
+                },
+            ),
+        };
+        (status, Json(body)).into_response()
+    }
+}
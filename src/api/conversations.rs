@@ -1,75 +1,346 @@
 //! Conversations endpoints
 
 use crate::TASK_SENDER;
-use crate::api::ExtractUser;
-use crate::api::conversations::schemas::{ConversationList, CreateConversation, CreateMessage};
-use crate::core::assistant::{ChatMessage, InferenceTask};
-use crate::core::traits::ConversationService;
+use crate::api::conversations::schemas::{
+    ConversationList, CreateConversation, CreateMessage, StreamFormat,
+};
+use crate::api::openai::completion_reason;
+use crate::api::{ErrorBody, ExtractUser, JsonBody, compression, json_event, reject_during_reload};
+use crate::core::assistant::InferenceTask;
+use crate::core::compaction::{self, DEFAULT_KEEP_TURNS};
+use crate::core::config;
+use crate::core::conversation_events::{self, ConversationEvent};
+use crate::core::conversation_locks::{self, ConversationLock};
+use crate::core::generation_webhooks;
+use crate::core::inference_events::CompletionReason;
+use crate::core::latency::{self, generation_span};
+use crate::core::leak_guard::LeakGuard;
+use crate::core::prompt_history::{EstimatedTokens, build_prompt_messages};
+use crate::core::queue::QueuePosition;
+use crate::core::sampling::{FieldError, SamplingParams, SamplingPreset};
+use crate::core::task_queue::{Priority, TaskSender};
+use crate::core::thinking::{Segment, ThinkingSplitter};
+use crate::core::traits::{ConversationService, CreateConversationError, CreateMessageError};
+use crate::core::user_generations::{self, UserGeneration};
+use crate::infrastructure::entities::{self, ConversationOrder, MessageKind, MessageOrder};
+use crate::infrastructure::repositories::{CONVERSATION_SORT_KEYS, MESSAGE_SORT_KEYS};
+use crate::infrastructure::webhooks::FinishReason;
 use anyhow::anyhow;
 use async_stream::stream;
-use axum::extract::Path;
-use axum::http::StatusCode;
-use axum::response::Sse;
-use axum::response::sse::{Event, KeepAlive};
-use axum::routing::{get, post};
+use axum::extract::{Path, Query};
+use axum::handler::Handler;
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::middleware::from_fn;
+use axum::response::sse::Event;
+use axum::response::{IntoResponse, Response, Sse};
+use axum::routing::{get, post, put};
 use axum::{Json, Router};
+use chrono::{DateTime, Utc};
 use di::Ref;
 use di_axum::Inject;
-use futures_util::Stream;
+use futures_util::{Stream, StreamExt};
+use log::{error, warn};
+use std::collections::{HashMap, VecDeque};
 use std::convert::Infallible;
 use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::MissedTickBehavior;
+use tracing::Instrument;
 use uuid::Uuid;
 
 pub fn router() -> Router {
     Router::new()
-        .route("/", get(list_conversations).post(new_conversation))
+        .route(
+            "/",
+            get(list_conversations.layer(compression()))
+                .post(new_conversation.layer(from_fn(reject_during_reload))),
+        )
+        .route("/sync", get(sync_conversations.layer(compression())))
         .route(
             "/:id/messages",
-            get(conversation_messages).post(post_message),
+            get(conversation_messages.layer(compression()))
+                .post(post_message.layer(from_fn(reject_during_reload))),
+        )
+        .route(
+            "/:id/messages/:message_id/feedback",
+            get(message_feedback).post(post_message_feedback),
+        )
+        .route(
+            "/:id/messages/:message_id/continue",
+            post(continue_message.layer(from_fn(reject_during_reload))),
+        )
+        .route("/:id/messages/:message_id/stream", get(replay_message))
+        .route("/:id/usage", get(conversation_usage))
+        .route(
+            "/:id/compact",
+            post(compact_conversation.layer(from_fn(reject_during_reload))),
         )
+        .route("/:id/duplicate", post(duplicate_conversation))
+        .route("/:id/system", put(update_system_message))
+        .route(
+            "/:id/tags",
+            post(add_conversation_tag).delete(remove_conversation_tag),
+        )
+        .route("/:id/events", get(conversation_events))
 }
 
+/// Lists the user's conversations, by default oldest first. `sort` takes a key of
+/// [`CONVERSATION_SORT_KEYS`], anything else is rejected. With `tag`, only the conversations
+/// tagged with it are listed.
+///
+/// The list comes with a weak `ETag`, see [`conversation_list_etag`]. A request whose
+/// `If-None-Match` has it gets 304 without a body while the list is unchanged.
+#[utoipa::path(
+    get,
+    path = "/conversations",
+    tag = "conversations",
+    params(schemas::ConversationsQuery),
+    responses(
+        (status = 200, body = ConversationList),
+        (status = 304, description = "The list hasn't changed since the `If-None-Match` ETag"),
+        (status = 400, description = "Unknown sort key"),
+    )
+)]
 async fn list_conversations(
     Inject(conversation_service): Inject<dyn ConversationService>,
     ExtractUser(current_user): ExtractUser,
-) -> (StatusCode, Json<ConversationList>) {
-    let conversations = conversation_service.list_conversations(current_user).await;
+    Query(query): Query<schemas::ConversationsQuery>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let mut order = ConversationOrder::default();
+    if let Some(sort) = query.sort {
+        order.sort = CONVERSATION_SORT_KEYS
+            .key(&sort)
+            .ok_or((StatusCode::BAD_REQUEST, "unknown sort key"))?;
+    }
+    order.descending = matches!(query.order, Some(schemas::Order::Desc));
+
+    let conversations = match query.tag {
+        Some(tag) => {
+            conversation_service
+                .list_conversations_by_tag(current_user, tag.trim().to_owned(), order)
+                .await
+        }
+        None => {
+            conversation_service
+                .list_conversations(current_user, order)
+                .await
+        }
+    };
+
+    let etag = conversation_list_etag(&conversations);
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|if_none_match| etag_matches(if_none_match, &etag))
+    {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
 
-    (
+    Ok((
         StatusCode::OK,
-        ConversationList {
+        [(header::ETAG, etag)],
+        Json(ConversationList {
             conversations: conversations
                 .into_iter()
                 .map(schemas::Conversation::from)
                 .collect(),
-        }
-        .into(),
+        }),
     )
+        .into_response())
+}
+
+/// A weak ETag of a list of conversations: a hash of their ids and when they last changed, in
+/// order. It changes when a conversation is created, deleted or gets a new message.
+///
+/// The hash is FNV-1a, so it is stable across builds and restarts.
+fn conversation_list_etag(conversations: &[entities::Conversation]) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for conversation in conversations {
+        let updated_at = conversation
+            .updated_at
+            .map(|updated_at| updated_at.timestamp_micros())
+            .unwrap_or_default();
+        let bytes = conversation
+            .id
+            .as_bytes()
+            .iter()
+            .copied()
+            .chain(updated_at.to_le_bytes());
+        for byte in bytes {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    format!("W/\"{hash:016x}\"")
+}
+
+/// Whether an `If-None-Match` header names `etag`, or any ETag with `*`. ETags are compared
+/// weakly, ignoring the `W/` prefix.
+fn etag_matches(if_none_match: &HeaderValue, etag: &str) -> bool {
+    let Ok(if_none_match) = if_none_match.to_str() else {
+        return false;
+    };
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    let etag = opaque(etag);
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+/// The user's conversations and messages changed since the cursor `since`, for clients that keep
+/// a copy of them. Without `since` everything is listed. The response holds the cursor of the
+/// next sync.
+#[utoipa::path(
+    get,
+    path = "/conversations/sync",
+    tag = "conversations",
+    params(schemas::SyncQuery),
+    responses((status = 200, body = schemas::Sync))
+)]
+async fn sync_conversations(
+    Inject(conversation_service): Inject<dyn ConversationService>,
+    ExtractUser(current_user): ExtractUser,
+    Query(query): Query<schemas::SyncQuery>,
+) -> Result<(StatusCode, Json<schemas::Sync>), StatusCode> {
+    conversation_service
+        .changes_since(current_user, query.since)
+        .await
+        .map(|changes| (StatusCode::OK, Json(changes.into())))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
+#[utoipa::path(
+    post,
+    path = "/conversations",
+    tag = "conversations",
+    params(schemas::StreamQuery),
+    request_body = CreateConversation,
+    responses(
+        (status = 200, description = "The reply as server-sent events: `new_message`, `queued`, `thinking`, `message_part`, `done`, `detached` and `ping`", content_type = "text/event-stream"),
+        (status = 400, description = "Unknown persona, or sampling parameters out of range or over the limits"),
+        (status = 403, body = ErrorBody, description = "`conversation_id` names a conversation of another user"),
+        (status = 409, description = "Conversation limit reached, or a reply is being generated in the existing conversation"),
+        (status = 415, body = ErrorBody, description = "The body is not sent as `application/json`"),
+        (status = 429, body = ErrorBody, description = "The user has too many replies being generated already"),
+        (status = 503, body = ErrorBody, description = "The model is not ready or is reloading"),
+    )
+)]
 async fn new_conversation(
     Inject(conversation_service): Inject<dyn ConversationService>,
     ExtractUser(current_user): ExtractUser,
-    Json(create_conversation): Json<CreateConversation>,
-) -> Sse<impl Stream<Item = Result<Event, &'static str>>> {
-    let conversation = conversation_service.create_conversation(current_user).await;
+    Query(stream): Query<schemas::StreamQuery>,
+    JsonBody(create_conversation): JsonBody<CreateConversation>,
+) -> Result<Sse<impl Stream<Item = Result<Event, &'static str>>>, Response> {
+    let task_sender = task_sender().map_err(IntoResponse::into_response)?;
+    let sampling = request_sampling(create_conversation.preset, create_conversation.sampling)
+        .map_err(IntoResponse::into_response)?;
+    let generation = start_user_generation(current_user).map_err(IntoResponse::into_response)?;
+
+    // A conversation the user created before with the same id gets the message appended instead
+    if let Some(conversation_id) = create_conversation.conversation_id {
+        let owner = conversation_service
+            .conversation_owner(conversation_id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+        match owner {
+            Some(owner) if owner == current_user => {
+                let lock = lock_conversation(conversation_id, BusyConversationPolicy::from_env())
+                    .await
+                    .map_err(IntoResponse::into_response)?;
+                return save_message_and_generate_response(
+                    conversation_service,
+                    task_sender,
+                    current_user,
+                    conversation_id,
+                    create_conversation.message,
+                    Vec::new(),
+                    None,
+                    sampling,
+                    lock,
+                    generation,
+                    stream.format,
+                )
+                .await;
+            }
+            Some(_) => return Err(ConversationForbidden.into_response()),
+            None => {}
+        }
+    }
 
+    let conversation = conversation_service
+        .create_conversation(
+            current_user,
+            create_conversation.conversation_id,
+            create_conversation.persona,
+        )
+        .await
+        .map_err(|e| {
+            match e {
+                CreateConversationError::UnknownPersona => {
+                    (StatusCode::BAD_REQUEST, "unknown persona")
+                }
+                CreateConversationError::TooManyConversations => {
+                    (StatusCode::CONFLICT, "conversation limit reached")
+                }
+                CreateConversationError::Internal => (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "failed to create conversation",
+                ),
+            }
+            .into_response()
+        })?;
+
+    // Only a request with the same client-provided id can know the conversation yet
+    let lock = conversation_locks::lock(conversation.id).await;
     save_message_and_generate_response(
         conversation_service,
+        task_sender,
         current_user,
         conversation.id,
         create_conversation.message,
+        Vec::new(),
+        None,
+        sampling,
+        lock,
+        generation,
+        stream.format,
     )
     .await
 }
 
+#[utoipa::path(
+    get,
+    path = "/conversations/{id}/messages",
+    tag = "conversations",
+    params(("id" = Uuid, Path, description = "Id of the conversation"), schemas::MessagesQuery),
+    responses(
+        (status = 200, body = schemas::MessagesList),
+        (status = 400, description = "Unknown sort key or conversation"),
+    )
+)]
 async fn conversation_messages(
     Inject(conversation_service): Inject<dyn ConversationService>,
     Path(conversation_id): Path<Uuid>,
+    Query(query): Query<schemas::MessagesQuery>,
     ExtractUser(current_user): ExtractUser,
 ) -> (StatusCode, Json<schemas::MessagesList>) {
+    // Messages are only sorted by their creation time, but other keys are rejected all the same
+    if let Some(sort) = query.sort
+        && MESSAGE_SORT_KEYS.key(&sort).is_none()
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(schemas::MessagesList::default()),
+        );
+    }
+
     let messages = conversation_service
-        .list_messages(current_user, conversation_id)
+        .list_messages(
+            current_user,
+            conversation_id,
+            query.order.unwrap_or_default().into(),
+        )
         .await;
 
     if let Ok(messages) = messages {
@@ -87,93 +358,1598 @@ async fn conversation_messages(
     }
 }
 
+/// Posts a message and streams the reply. While another reply is generated in the conversation,
+/// waits for it to finish or responds with 409, see [`BusyConversationPolicy`].
+#[utoipa::path(
+    post,
+    path = "/conversations/{id}/messages",
+    tag = "conversations",
+    params(("id" = Uuid, Path, description = "Id of the conversation"), schemas::StreamQuery),
+    request_body = CreateMessage,
+    responses(
+        (status = 200, description = "The reply as server-sent events: `new_message`, `queued`, `thinking`, `message_part`, `done`, `detached` and `ping`", content_type = "text/event-stream"),
+        (status = 400, body = schemas::InvalidSamplingBody, description = "Sampling parameters out of range or over the limits"),
+        (status = 409, body = ErrorBody, description = "A reply is being generated in the conversation, or it has reached its message limit"),
+        (status = 415, body = ErrorBody, description = "The body is not sent as `application/json`"),
+        (status = 429, body = ErrorBody, description = "The user has too many replies being generated already"),
+        (status = 503, body = ErrorBody, description = "The model is not ready or is reloading"),
+    )
+)]
 async fn post_message(
     Inject(conversation_service): Inject<dyn ConversationService>,
     ExtractUser(current_user): ExtractUser,
     Path(conversation_id): Path<Uuid>,
-    Json(message): Json<schemas::CreateMessage>,
-) -> Sse<impl Stream<Item = Result<Event, &'static str>>> {
+    Query(stream): Query<schemas::StreamQuery>,
+    JsonBody(message): JsonBody<schemas::CreateMessage>,
+) -> Result<Sse<impl Stream<Item = Result<Event, &'static str>>>, Response> {
+    let task_sender = task_sender().map_err(IntoResponse::into_response)?;
+    let sampling =
+        request_sampling(message.preset, message.sampling).map_err(IntoResponse::into_response)?;
+    let generation = start_user_generation(current_user).map_err(IntoResponse::into_response)?;
+    let lock = lock_conversation(conversation_id, BusyConversationPolicy::from_env())
+        .await
+        .map_err(IntoResponse::into_response)?;
     save_message_and_generate_response(
         conversation_service,
+        task_sender,
         current_user,
         conversation_id,
         message.text,
+        message.attachments.into_iter().map(Into::into).collect(),
+        message.context,
+        sampling,
+        lock,
+        generation,
+        stream.format,
     )
     .await
 }
 
+/// Continues a bot message that was cut short, e.g. by `max_tokens`. The continuation is streamed
+/// like a new message, but appended to the existing message instead of saved as a new one.
+#[utoipa::path(
+    post,
+    path = "/conversations/{id}/messages/{message_id}/continue",
+    tag = "conversations",
+    params(("id" = Uuid, Path, description = "Id of the conversation"), ("message_id" = Uuid, Path, description = "Id of the message"), schemas::StreamQuery),
+    responses(
+        (status = 200, description = "The reply as server-sent events: `new_message`, `queued`, `thinking`, `message_part`, `done`, `detached` and `ping`", content_type = "text/event-stream"),
+        (status = 404, description = "No such bot message"),
+        (status = 409, body = ErrorBody, description = "A reply is being generated in the conversation"),
+        (status = 429, body = ErrorBody, description = "The user has too many replies being generated already"),
+        (status = 503, body = ErrorBody, description = "The model is not ready or is reloading"),
+    )
+)]
+async fn continue_message(
+    Inject(conversation_service): Inject<dyn ConversationService>,
+    ExtractUser(current_user): ExtractUser,
+    Path((conversation_id, message_id)): Path<(Uuid, Uuid)>,
+    Query(stream): Query<schemas::StreamQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, &'static str>>>, Response> {
+    let generation = start_user_generation(current_user).map_err(IntoResponse::into_response)?;
+    let lock = lock_conversation(conversation_id, BusyConversationPolicy::from_env())
+        .await
+        .map_err(IntoResponse::into_response)?;
+
+    let mut messages = conversation_service
+        .list_messages(current_user, conversation_id, MessageOrder::OldestFirst)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND.into_response())?;
+
+    let position = messages
+        .iter()
+        .position(|message| message.id == message_id && matches!(message.kind, MessageKind::Bot))
+        .ok_or(StatusCode::NOT_FOUND.into_response())?;
+    // The model sees the conversation up to the message, and continues the message itself
+    messages.truncate(position + 1);
+    let message = messages
+        .pop()
+        .ok_or(StatusCode::NOT_FOUND.into_response())?;
+
+    let (mut task, receiver) = InferenceTask::new(build_prompt_messages(
+        messages,
+        config::prompt_token_budget(),
+        &EstimatedTokens,
+    ));
+    task.continue_from(message.text.clone());
+    task.set_user_generation(generation);
+    let queue_position = task.queue_position();
+    let prompt_tokens = task.track_prompt_tokens();
+    let failure = task.track_failure();
+    let completion = task.track_completion();
+    let completion_tokens = task.track_completion_tokens();
+    let request_log = PendingRequestLog::start(&task);
+    let span = generation_span(conversation_id, message_id);
+    latency::record_on(task.id(), span.clone());
+    generation_webhooks::watch(task.id(), conversation_id, message_id);
+
+    task_sender()
+        .map_err(IntoResponse::into_response)?
+        .send(task)
+        .await
+        .map_err(|_| ModelNotReady.into_response())?;
+
+    let (client_sender, client_receiver) = mpsc::channel(CLIENT_BUFFER_SIZE);
+    let client_receiver = pace_client_events(client_receiver);
+    tokio::spawn(
+        relay_generation(
+            conversation_service,
+            current_user,
+            conversation_id,
+            message_id,
+            Reply::Continuation(message),
+            receiver,
+            prompt_tokens,
+            failure,
+            completion,
+            completion_tokens,
+            request_log,
+            client_sender,
+            SlowClientPolicy::from_env(),
+            lock,
+        )
+        .instrument(span),
+    );
+
+    Ok(Sse::new(with_pings(stream_message_parts(
+        conversation_id,
+        message_id,
+        Some(queue_position),
+        client_receiver,
+        Some(config::sse_retry()),
+        stream.format,
+    ))))
+}
+
+/// Streams a saved bot message again, in parts of [`config::replay_chars_per_event`] characters,
+/// so a client can render historical content the way it renders a generation. The model is not
+/// involved.
+#[utoipa::path(
+    get,
+    path = "/conversations/{id}/messages/{message_id}/stream",
+    tag = "conversations",
+    params(("id" = Uuid, Path, description = "Id of the conversation"), ("message_id" = Uuid, Path, description = "Id of the message"), schemas::StreamQuery),
+    responses(
+        (status = 200, description = "The message as server-sent events: `message_part`, `done` and `ping`", content_type = "text/event-stream"),
+        (status = 404, description = "No such bot message"),
+    )
+)]
+async fn replay_message(
+    Inject(conversation_service): Inject<dyn ConversationService>,
+    ExtractUser(current_user): ExtractUser,
+    Path((conversation_id, message_id)): Path<(Uuid, Uuid)>,
+    Query(stream): Query<schemas::StreamQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, &'static str>>>, StatusCode> {
+    let messages = conversation_service
+        .list_messages(current_user, conversation_id, MessageOrder::OldestFirst)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    let message = messages
+        .into_iter()
+        .find(|message| message.id == message_id && matches!(message.kind, MessageKind::Bot))
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let chars: Vec<char> = message.text.chars().collect();
+    let parts = chars.chunks(config::replay_chars_per_event());
+    // Everything is sent at once, so the channel has room for all of it
+    let (client_sender, client_receiver) = mpsc::channel(parts.len() + 1);
+    for part in parts {
+        let _ = client_sender.try_send(ClientEvent::Part(part.iter().collect()));
+    }
+    let _ = client_sender.try_send(ClientEvent::Done);
+
+    Ok(Sse::new(with_pings(stream_message_parts(
+        conversation_id,
+        message_id,
+        None,
+        client_receiver,
+        Some(config::sse_retry()),
+        stream.format,
+    ))))
+}
+
+#[utoipa::path(
+    get,
+    path = "/conversations/{id}/messages/{message_id}/feedback",
+    tag = "conversations",
+    params(("id" = Uuid, Path, description = "Id of the conversation"), ("message_id" = Uuid, Path, description = "Id of the message")),
+    responses(
+        (status = 200, body = schemas::Feedback),
+        (status = 404, description = "No feedback on the message"),
+    )
+)]
+async fn message_feedback(
+    Inject(conversation_service): Inject<dyn ConversationService>,
+    ExtractUser(current_user): ExtractUser,
+    Path((conversation_id, message_id)): Path<(Uuid, Uuid)>,
+) -> Result<(StatusCode, Json<schemas::Feedback>), StatusCode> {
+    match conversation_service
+        .get_message_feedback(current_user, conversation_id, message_id)
+        .await
+    {
+        Ok(Some(feedback)) => Ok((StatusCode::OK, Json(feedback.into()))),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/conversations/{id}/messages/{message_id}/feedback",
+    tag = "conversations",
+    params(("id" = Uuid, Path, description = "Id of the conversation"), ("message_id" = Uuid, Path, description = "Id of the message")),
+    request_body = schemas::CreateFeedback,
+    responses(
+        (status = 200, body = schemas::Feedback),
+        (status = 404, description = "No such bot message"),
+        (status = 415, body = ErrorBody, description = "The body is not sent as `application/json`"),
+    )
+)]
+async fn post_message_feedback(
+    Inject(conversation_service): Inject<dyn ConversationService>,
+    ExtractUser(current_user): ExtractUser,
+    Path((conversation_id, message_id)): Path<(Uuid, Uuid)>,
+    JsonBody(feedback): JsonBody<schemas::CreateFeedback>,
+) -> Result<(StatusCode, Json<schemas::Feedback>), StatusCode> {
+    conversation_service
+        .set_message_feedback(
+            current_user,
+            conversation_id,
+            message_id,
+            feedback.rating.into(),
+            feedback.comment,
+        )
+        .await
+        .map(|feedback| (StatusCode::OK, Json(feedback.into())))
+        .map_err(|_| StatusCode::NOT_FOUND)
+}
+
+#[utoipa::path(
+    get,
+    path = "/conversations/{id}/usage",
+    tag = "conversations",
+    params(("id" = Uuid, Path, description = "Id of the conversation")),
+    responses(
+        (status = 200, body = schemas::Usage),
+        (status = 404, description = "No such conversation"),
+    )
+)]
+async fn conversation_usage(
+    Inject(conversation_service): Inject<dyn ConversationService>,
+    ExtractUser(current_user): ExtractUser,
+    Path(conversation_id): Path<Uuid>,
+) -> Result<(StatusCode, Json<schemas::Usage>), StatusCode> {
+    match conversation_service
+        .conversation_usage(current_user, conversation_id)
+        .await
+    {
+        Ok(Some(usage)) => Ok((StatusCode::OK, Json(usage.into()))),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+/// Forks a conversation, so its copy can be continued differently. Responds with the new
+/// conversation.
+#[utoipa::path(
+    post,
+    path = "/conversations/{id}/duplicate",
+    tag = "conversations",
+    params(("id" = Uuid, Path, description = "Id of the conversation")),
+    responses(
+        (status = 201, body = schemas::Conversation),
+        (status = 404, description = "No such conversation"),
+    )
+)]
+async fn duplicate_conversation(
+    Inject(conversation_service): Inject<dyn ConversationService>,
+    ExtractUser(current_user): ExtractUser,
+    Path(conversation_id): Path<Uuid>,
+) -> Result<(StatusCode, Json<schemas::Conversation>), StatusCode> {
+    match conversation_service
+        .duplicate_conversation(current_user, conversation_id)
+        .await
+    {
+        Ok(Some(conversation)) => Ok((StatusCode::CREATED, Json(conversation.into()))),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Replaces the system message of a conversation, changing how the model behaves from the next
+/// generation on. Responds with the updated message.
+#[utoipa::path(
+    put,
+    path = "/conversations/{id}/system",
+    tag = "conversations",
+    params(("id" = Uuid, Path, description = "Id of the conversation")),
+    request_body = schemas::SystemMessage,
+    responses(
+        (status = 200, body = schemas::Message),
+        (status = 400, description = "Empty system message"),
+        (status = 403, body = ErrorBody, description = "The conversation belongs to another user"),
+        (status = 404, description = "No such conversation, or it has no system message"),
+        (status = 415, body = ErrorBody, description = "The body is not sent as `application/json`"),
+    )
+)]
+async fn update_system_message(
+    Inject(conversation_service): Inject<dyn ConversationService>,
+    ExtractUser(current_user): ExtractUser,
+    Path(conversation_id): Path<Uuid>,
+    JsonBody(system): JsonBody<schemas::SystemMessage>,
+) -> Result<Json<schemas::Message>, Response> {
+    if system.text.trim().is_empty() {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    let owner = conversation_service
+        .conversation_owner(conversation_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    match owner {
+        Some(owner) if owner == current_user => {}
+        Some(_) => return Err(ConversationForbidden.into_response()),
+        None => return Err(StatusCode::NOT_FOUND.into_response()),
+    }
+
+    match conversation_service
+        .update_system_message(current_user, conversation_id, system.text)
+        .await
+    {
+        Ok(Some(message)) => Ok(Json(message.into())),
+        Ok(None) => Err(StatusCode::NOT_FOUND.into_response()),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+    }
+}
+
+/// Maximum length of a tag in characters.
+const MAX_TAG_LENGTH: usize = 64;
+
+/// A tag without surrounding whitespace, or `None` if that leaves it empty or longer than
+/// [`MAX_TAG_LENGTH`].
+fn normalize_tag(tag: &str) -> Option<String> {
+    let tag = tag.trim();
+    (!tag.is_empty() && tag.chars().count() <= MAX_TAG_LENGTH).then(|| tag.to_owned())
+}
+
+/// Tags a conversation, so it can be listed with `GET /conversations?tag=`.
+#[utoipa::path(
+    post,
+    path = "/conversations/{id}/tags",
+    tag = "conversations",
+    params(("id" = Uuid, Path, description = "Id of the conversation")),
+    request_body = schemas::Tag,
+    responses(
+        (status = 204, description = "The conversation has the tag"),
+        (status = 400, description = "Empty or too long tag"),
+        (status = 404, description = "No such conversation"),
+        (status = 415, body = ErrorBody, description = "The body is not sent as `application/json`"),
+    )
+)]
+async fn add_conversation_tag(
+    Inject(conversation_service): Inject<dyn ConversationService>,
+    ExtractUser(current_user): ExtractUser,
+    Path(conversation_id): Path<Uuid>,
+    JsonBody(tag): JsonBody<schemas::Tag>,
+) -> StatusCode {
+    let Some(tag) = normalize_tag(&tag.tag) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    match conversation_service
+        .add_tag(current_user, conversation_id, tag)
+        .await
+    {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::NOT_FOUND,
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/conversations/{id}/tags",
+    tag = "conversations",
+    params(("id" = Uuid, Path, description = "Id of the conversation")),
+    request_body = schemas::Tag,
+    responses(
+        (status = 204, description = "The tag was removed"),
+        (status = 400, description = "Empty or too long tag"),
+        (status = 404, description = "No such conversation, or it doesn't have the tag"),
+        (status = 415, body = ErrorBody, description = "The body is not sent as `application/json`"),
+    )
+)]
+async fn remove_conversation_tag(
+    Inject(conversation_service): Inject<dyn ConversationService>,
+    ExtractUser(current_user): ExtractUser,
+    Path(conversation_id): Path<Uuid>,
+    JsonBody(tag): JsonBody<schemas::Tag>,
+) -> StatusCode {
+    let Some(tag) = normalize_tag(&tag.tag) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    match conversation_service
+        .remove_tag(current_user, conversation_id, tag)
+        .await
+    {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Summarizes all but the most recent turns of a conversation and replaces them with the
+/// summary. Responds with the messages of the compacted conversation.
+#[utoipa::path(
+    post,
+    path = "/conversations/{id}/compact",
+    tag = "conversations",
+    params(("id" = Uuid, Path, description = "Id of the conversation")),
+    request_body = schemas::CompactConversation,
+    responses(
+        (status = 200, body = schemas::MessagesList),
+        (status = 404, description = "No such conversation"),
+        (status = 409, body = ErrorBody, description = "A reply is being generated in the conversation"),
+        (status = 415, body = ErrorBody, description = "The body is not sent as `application/json`"),
+        (status = 429, body = ErrorBody, description = "The user has too many replies being generated already"),
+        (status = 500, description = "The summary could not be generated, the conversation is left as it was"),
+        (status = 503, body = ErrorBody, description = "The model is not ready or is reloading"),
+    )
+)]
+async fn compact_conversation(
+    Inject(conversation_service): Inject<dyn ConversationService>,
+    ExtractUser(current_user): ExtractUser,
+    Path(conversation_id): Path<Uuid>,
+    JsonBody(compact): JsonBody<schemas::CompactConversation>,
+) -> Result<(StatusCode, Json<schemas::MessagesList>), Response> {
+    let task_sender = task_sender().map_err(IntoResponse::into_response)?;
+    // The summary is a generation of the user like a reply
+    let _generation = start_user_generation(current_user).map_err(IntoResponse::into_response)?;
+    // No message may be added while the turns are summarized and replaced
+    let _lock = lock_conversation(conversation_id, BusyConversationPolicy::from_env())
+        .await
+        .map_err(IntoResponse::into_response)?;
+
+    compact_turns(
+        &*conversation_service,
+        task_sender,
+        current_user,
+        conversation_id,
+        compact.keep_turns.unwrap_or(DEFAULT_KEEP_TURNS),
+        Priority::Low,
+    )
+    .await
+    .map_err(IntoResponse::into_response)?;
+
+    let messages = conversation_service
+        .list_messages(current_user, conversation_id, MessageOrder::OldestFirst)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+
+    Ok((
+        StatusCode::OK,
+        Json(schemas::MessagesList {
+            messages: messages.into_iter().map(schemas::Message::from).collect(),
+        }),
+    ))
+}
+
+/// Has the model summarize all but the last `keep_turns` turns of a conversation, and replaces
+/// them with the summary. Does nothing if the conversation has no more turns than that. The turns
+/// are kept unless the summary runs to its end or its token limit.
+async fn compact_turns(
+    conversation_service: &dyn ConversationService,
+    task_sender: &TaskSender,
+    current_user: Uuid,
+    conversation_id: Uuid,
+    keep_turns: usize,
+    priority: Priority,
+) -> Result<(), StatusCode> {
+    let messages = conversation_service
+        .list_messages(current_user, conversation_id, MessageOrder::OldestFirst)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let replaced = compaction::messages_to_compact(&messages, keep_turns);
+    if replaced.is_empty() {
+        return Ok(());
+    }
+
+    let (mut task, mut receiver) = InferenceTask::new(compaction::summary_request(replaced));
+    task.set_priority(priority);
+    let failure = task.track_failure();
+    let completion = task.track_completion();
+    task_sender
+        .send(task)
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let mut summary = String::new();
+    while let Some(part) = receiver.recv().await {
+        summary.push_str(&part);
+    }
+    let finished = matches!(
+        completion_reason(failure, completion).await,
+        Some(CompletionReason::Stop | CompletionReason::Length)
+    );
+    if !finished || summary.trim().is_empty() {
+        warn!("summary of conversation {conversation_id} failed, its turns are kept");
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    conversation_service
+        .compact_conversation(current_user, conversation_id, replaced.to_vec(), summary)
+        .await
+        .map_err(|_| StatusCode::CONFLICT)?;
+    Ok(())
+}
+
+/// Streams the message parts of every generation in the conversation, whoever posted the message
+/// it answers, until the client disconnects. The length and CRC32 of each message cover the parts
+/// sent on this stream, so they do not match the message if the client subscribed halfway.
+#[utoipa::path(
+    get,
+    path = "/conversations/{id}/events",
+    tag = "conversations",
+    params(("id" = Uuid, Path, description = "Id of the conversation")),
+    responses(
+        (status = 200, description = "Every generation in the conversation as server-sent events: `message_part`, `done` and `ping`", content_type = "text/event-stream"),
+        (status = 404, description = "No such conversation"),
+    )
+)]
+async fn conversation_events(
+    Inject(conversation_service): Inject<dyn ConversationService>,
+    ExtractUser(current_user): ExtractUser,
+    Path(conversation_id): Path<Uuid>,
+) -> Result<Sse<impl Stream<Item = Result<Event, &'static str>>>, StatusCode> {
+    let owner = conversation_service
+        .conversation_owner(conversation_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if owner != Some(current_user) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    // Subscribe before responding, so no generation started after the response is missed
+    let mut events = conversation_events::subscribe(conversation_id);
+    let mut retry = Some(config::sse_retry());
+    let stream = stream! {
+        let mut checksums: HashMap<Uuid, StreamChecksum> = HashMap::new();
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("subscriber of conversation {conversation_id} missed {skipped} events");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            let result = match event {
+                ConversationEvent::Part { message_id, text } => {
+                    let checksum = checksums.entry(message_id).or_default();
+                    checksum.update(&text);
+                    let part = schemas::MessagePart {
+                        conversation_id,
+                        message_id,
+                        message_part: text,
+                        length: checksum.length,
+                        crc32: checksum.crc32(),
+                    };
+                    json_event(with_retry(Event::default().event("message_part"), &mut retry), part)
+                }
+                ConversationEvent::Done { message_id } => {
+                    let checksum = checksums.remove(&message_id).unwrap_or_default();
+                    let done = schemas::Done {
+                        conversation_id,
+                        message_id,
+                        length: checksum.length,
+                        crc32: checksum.crc32(),
+                    };
+                    json_event(with_retry(Event::default().event("done"), &mut retry), done)
+                }
+            };
+            match result {
+                Ok(event) => yield Ok(event),
+                Err(error) => {
+                    yield Ok(error);
+                    return;
+                }
+            }
+        }
+    };
+
+    Ok(Sse::new(with_pings(stream)))
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn save_message_and_generate_response(
     conversation_service: Ref<dyn ConversationService>,
+    task_sender: &TaskSender,
     current_user: Uuid,
     conversation_id: Uuid,
     message: String,
-) -> Sse<impl Stream<Item = Result<Event, &'static str>> + Sized> {
-    match conversation_service
-        .create_user_message(current_user, conversation_id, message)
+    attachments: Vec<entities::Attachment>,
+    context: Option<String>,
+    sampling: SamplingParams,
+    lock: ConversationLock,
+    generation: UserGeneration,
+    format: StreamFormat,
+) -> Result<Sse<impl Stream<Item = Result<Event, &'static str>> + Sized>, Response> {
+    let created = conversation_service
+        .create_user_message(
+            current_user,
+            conversation_id,
+            message.clone(),
+            attachments.clone(),
+        )
+        .await;
+    // Compacting makes room for the message, unless even the compacted conversation is too long.
+    // The summary holds up the message with the conversation locked, so it is generated like a
+    // reply, and the message is rejected if the summary fails or takes too long.
+    let created = match created {
+        Err(CreateMessageError::TooManyMessages)
+            if MessageLimitPolicy::from_env() == MessageLimitPolicy::Compact =>
+        {
+            let compacted = tokio::time::timeout(
+                AUTO_COMPACTION_TIMEOUT,
+                compact_turns(
+                    &*conversation_service,
+                    task_sender,
+                    current_user,
+                    conversation_id,
+                    DEFAULT_KEEP_TURNS,
+                    Priority::Normal,
+                ),
+            )
+            .await;
+            if !matches!(compacted, Ok(Ok(()))) {
+                return Err(MessageLimitReached.into_response());
+            }
+            conversation_service
+                .create_user_message(current_user, conversation_id, message, attachments)
+                .await
+        }
+        created => created,
+    };
+    let message = created.map_err(|e| match e {
+        CreateMessageError::TooManyMessages => MessageLimitReached.into_response(),
+        CreateMessageError::Failed => StatusCode::NOT_FOUND.into_response(),
+    })?;
+
+    let message_id = Uuid::new_v4();
+    let conversation_id = message.conversation_id.clone();
+
+    let conversation_messages = conversation_service
+        .list_messages(current_user, conversation_id, MessageOrder::OldestFirst)
         .await
-    {
-        Ok(message) => {
-            let message_id = Uuid::new_v4();
-            let conversation_id = message.conversation_id.clone();
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
 
-            let conversation_messages = conversation_service
-                .list_messages(current_user, conversation_id)
-                .await
-                .expect("failed to list user messages");
+    let chat_messages = build_prompt_messages(
+        conversation_messages,
+        config::prompt_token_budget(),
+        &EstimatedTokens,
+    );
 
-            let chat_messages = conversation_messages
-                .into_iter()
-                .map(ChatMessage::from)
-                .collect();
+    let (mut task, receiver) = InferenceTask::new(chat_messages);
+    if let Some(context) = context {
+        task.set_context(context);
+    }
+    task.set_sampling(sampling);
+    task.set_user_generation(generation);
+    let queue_position = task.queue_position();
+    let prompt_tokens = task.track_prompt_tokens();
+    let failure = task.track_failure();
+    let completion = task.track_completion();
+    let completion_tokens = task.track_completion_tokens();
+    let request_log = PendingRequestLog::start(&task);
+    let span = generation_span(conversation_id, message_id);
+    latency::record_on(task.id(), span.clone());
+    generation_webhooks::watch(task.id(), conversation_id, message_id);
 
-            let (task, mut receiver) = InferenceTask::new(chat_messages);
+    // The worker has stopped, e.g. because the model failed to load
+    task_sender
+        .send(task)
+        .await
+        .map_err(|_| ModelNotReady.into_response())?;
 
-            let task_sender = TASK_SENDER.get().expect("TASK_SENDER should be set");
+    let (client_sender, client_receiver) = mpsc::channel(CLIENT_BUFFER_SIZE);
+    let client_receiver = pace_client_events(client_receiver);
+    tokio::spawn(
+        relay_generation(
+            conversation_service,
+            current_user,
+            conversation_id,
+            message_id,
+            Reply::NewMessage {
+                user_message_id: message.id,
+            },
+            receiver,
+            prompt_tokens,
+            failure,
+            completion,
+            completion_tokens,
+            request_log,
+            client_sender,
+            SlowClientPolicy::from_env(),
+            lock,
+        )
+        .instrument(span),
+    );
 
-            task_sender.send(task).await.unwrap();
+    let parts = stream_message_parts(
+        conversation_id,
+        message_id,
+        Some(queue_position),
+        client_receiver,
+        None,
+        format,
+    );
+    let new_message = Event::default()
+        .event("new_message")
+        .retry(config::sse_retry());
+    let stream = stream! {
+        match json_event(new_message, schemas::Message::from(message)) {
+            Ok(event) => yield Ok(event),
+            Err(error) => {
+                yield Ok(error);
+                return;
+            }
+        }
 
-            let stream = stream! {
-                yield Ok(Event::default().event("new_message").json_data(schemas::Message::from(message)).unwrap());
+        for await event in parts {
+            yield event;
+        }
+    };
+
+    Ok(Sse::new(with_pings(stream)))
+}
 
-                let mut assistant_message = String::new();
+/// Streams the queue position while the generation waits in the inference queue, if it is
+/// queued, and then the parts of the message as they arrive. The first event carries `retry`, if
+/// any.
+fn stream_message_parts(
+    conversation_id: Uuid,
+    message_id: Uuid,
+    queue_position: Option<QueuePosition>,
+    mut client_receiver: mpsc::Receiver<ClientEvent>,
+    mut retry: Option<Duration>,
+    format: StreamFormat,
+) -> impl Stream<Item = Result<Event, &'static str>> {
+    stream! {
+        // While other generations are ahead in the queue, keep the client posted on its position
+        if let Some(queue_position) = queue_position {
+            let mut position = queue_position.get();
+            while position > 0 {
+                match json_event(with_retry(Event::default().event("queued"), &mut retry), schemas::Queued { position }) {
+                    Ok(event) => yield Ok(event),
+                    Err(error) => {
+                        yield Ok(error);
+                        return;
+                    }
+                }
+                position = queue_position.changed_from(position).await;
+            }
+        }
 
-                while let Some(message_part) = receiver.recv().await {
-                    assistant_message.push_str(&message_part);
-                    yield Ok(Event::default().event("message_part").retry(Duration::from_millis(100)).json_data(schemas::MessagePart {
+        let mut checksum = StreamChecksum::default();
+        while let Some(event) = client_receiver.recv().await {
+            match event {
+                ClientEvent::Part(message_part) => {
+                    checksum.update(&message_part);
+                    let event = with_retry(Event::default().event("message_part"), &mut retry);
+                    let result = match format {
+                        StreamFormat::Json => json_event(event, schemas::MessagePart {
+                            conversation_id,
+                            message_id,
+                            message_part,
+                            length: checksum.length,
+                            crc32: checksum.crc32(),
+                        }),
+                        // SSE can't carry carriage returns
+                        StreamFormat::Text => Ok(event.data(message_part.replace('\r', ""))),
+                    };
+                    match result {
+                        Ok(event) => yield Ok(event),
+                        Err(error) => {
+                            yield Ok(error);
+                            return;
+                        }
+                    }
+                }
+                ClientEvent::Thinking(thinking_part) => {
+                    let event = with_retry(Event::default().event("thinking"), &mut retry);
+                    let result = match format {
+                        StreamFormat::Json => json_event(event, schemas::ThinkingPart {
+                            conversation_id,
+                            message_id,
+                            thinking_part,
+                        }),
+                        StreamFormat::Text => Ok(event.data(thinking_part.replace('\r', ""))),
+                    };
+                    match result {
+                        Ok(event) => yield Ok(event),
+                        Err(error) => {
+                            yield Ok(error);
+                            return;
+                        }
+                    }
+                }
+                ClientEvent::Done => {
+                    let done = schemas::Done {
                         conversation_id,
                         message_id,
-                        message_part
-                    }).expect("REASON"));
+                        length: checksum.length,
+                        crc32: checksum.crc32(),
+                    };
+                    match json_event(with_retry(Event::default().event("done"), &mut retry), done) {
+                        Ok(event) => yield Ok(event),
+                        Err(error) => {
+                            yield Ok(error);
+                            return;
+                        }
+                    }
+                }
+                ClientEvent::Detached => {
+                    let detached = schemas::Detached { conversation_id, message_id };
+                    match json_event(with_retry(Event::default().event("detached"), &mut retry), detached) {
+                        Ok(event) => yield Ok(event),
+                        Err(error) => {
+                            yield Ok(error);
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Interleaves a `ping` event carrying the current time into `events` every
+/// [`config::sse_ping_interval`], to keep idle connections open.
+///
+/// Unlike the comment lines of axum's `KeepAlive`, which some client libraries surface as empty
+/// messages, a named event is easy for a client to tell apart from the parts and ignore.
+fn with_pings(
+    events: impl Stream<Item = Result<Event, &'static str>>,
+) -> impl Stream<Item = Result<Event, &'static str>> {
+    let interval = config::sse_ping_interval();
+    stream! {
+        let mut events = std::pin::pin!(events);
+        let mut pings = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                event = events.next() => match event {
+                    Some(event) => yield event,
+                    None => return,
+                },
+                _ = pings.tick() => {
+                    let ping = schemas::Ping { timestamp: Utc::now() };
+                    match json_event(Event::default().event("ping"), ping) {
+                        Ok(event) => yield Ok(event),
+                        Err(error) => {
+                            yield Ok(error);
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sets the reconnection delay on the event if it is still to be sent, so it goes out once per
+/// stream rather than on every event.
+fn with_retry(event: Event, retry: &mut Option<Duration>) -> Event {
+    match retry.take() {
+        Some(retry) => event.retry(retry),
+        None => event,
+    }
+}
+
+/// Running length and CRC32 of the text streamed so far, for clients to check that they
+/// reassembled the message without gaps.
+#[derive(Default)]
+struct StreamChecksum {
+    /// Length of the text in UTF-8 bytes
+    length: usize,
+    hasher: crc32fast::Hasher,
+}
+
+impl StreamChecksum {
+    fn update(&mut self, text: &str) {
+        self.length += text.len();
+        self.hasher.update(text.as_bytes());
+    }
+
+    fn crc32(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+}
+
+/// What the generation relays to the client's SSE stream.
+enum ClientEvent {
+    /// The next part of the message
+    Part(String),
+    /// The next part of the thinking before the message, see [`crate::core::thinking`]
+    Thinking(String),
+    /// The client has received the whole message. A stream that ends without it is incomplete.
+    Done,
+    /// The client reads too slowly and gets no more of the message, see
+    /// [`SlowClientPolicy::Detach`].
+    Detached,
+}
+
+/// Number of message parts buffered for a client before the slow client policy applies. With
+/// [`SlowClientPolicy::Detach`], the last slot is kept for the event that ends the stream.
+const CLIENT_BUFFER_SIZE: usize = 64;
+
+/// Delivers the message parts to the client at most [`config::max_tokens_per_sec_per_stream`]
+/// parts a second, if that is set. The parts are taken from `receiver` as fast as they come and
+/// buffered until their turn, so a paced client doesn't hold up the inference worker. `done` or
+/// `detached` follows the last part without waiting.
+fn pace_client_events(receiver: mpsc::Receiver<ClientEvent>) -> mpsc::Receiver<ClientEvent> {
+    let Some(tokens_per_sec) = config::max_tokens_per_sec_per_stream() else {
+        return receiver;
+    };
+    let (sender, paced) = mpsc::channel(CLIENT_BUFFER_SIZE);
+    let period = Duration::from_secs_f64(1.0 / tokens_per_sec as f64).max(Duration::from_nanos(1));
+    tokio::spawn(pace(receiver, sender, period));
+    paced
+}
+
+async fn pace(
+    mut receiver: mpsc::Receiver<ClientEvent>,
+    sender: mpsc::Sender<ClientEvent>,
+    period: Duration,
+) {
+    let mut buffered = VecDeque::new();
+    let mut received_all = false;
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            event = receiver.recv(), if !received_all => match event {
+                Some(event) => buffered.push_back(event),
+                None => received_all = true,
+            },
+            _ = interval.tick(), if matches!(buffered.front(), Some(ClientEvent::Part(_) | ClientEvent::Thinking(_))) => {
+                let Some(part) = buffered.pop_front() else {
+                    continue;
+                };
+                if sender.send(part).await.is_err() {
+                    return;
                 }
+            }
+            // Dropping the receiver tells the relay that the client went away
+            _ = sender.closed() => return,
+        }
+
+        if matches!(
+            buffered.front(),
+            Some(ClientEvent::Done | ClientEvent::Detached)
+        ) && let Some(end) = buffered.pop_front()
+        {
+            let _ = sender.send(end).await;
+        }
+        if received_all && buffered.is_empty() {
+            return;
+        }
+    }
+}
+
+/// What to do when a client reads the stream slower than the model generates it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowClientPolicy {
+    /// Wait for the client, which also holds up the inference worker.
+    Block,
+    /// Stop streaming to the client and keep generating. The client gets a `detached` event
+    /// with the id of the message, which is still saved in full.
+    Detach,
+}
+
+impl SlowClientPolicy {
+    /// Reads the policy from `SLOW_CLIENT_POLICY`, defaulting to `block`.
+    pub fn from_env() -> Self {
+        match std::env::var("SLOW_CLIENT_POLICY").as_deref() {
+            Ok("detach") => SlowClientPolicy::Detach,
+            _ => SlowClientPolicy::Block,
+        }
+    }
+}
+
+/// What to do with a message posted while a reply is generated in the same conversation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusyConversationPolicy {
+    /// Wait for the other reply to be saved, then answer the message.
+    Queue,
+    /// Respond with 409 `conversation_busy`.
+    Reject,
+}
+
+impl BusyConversationPolicy {
+    /// Reads the policy from `BUSY_CONVERSATION_POLICY`, defaulting to `queue`.
+    pub fn from_env() -> Self {
+        match std::env::var("BUSY_CONVERSATION_POLICY").as_deref() {
+            Ok("reject") => BusyConversationPolicy::Reject,
+            _ => BusyConversationPolicy::Queue,
+        }
+    }
+}
+
+/// What to do with a message posted to a conversation that has `MAX_MESSAGES_PER_CONVERSATION`
+/// messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageLimitPolicy {
+    /// Respond with 409 `message_limit_reached`.
+    Reject,
+    /// Compact the conversation like `POST /conversations/:id/compact` does, keeping the default
+    /// number of turns, then answer the message. Falls back to `Reject` if the compaction fails
+    /// or takes longer than a minute.
+    Compact,
+}
+
+/// How long a message over the limit waits for the compaction that makes room for it, see
+/// [`MessageLimitPolicy::Compact`].
+const AUTO_COMPACTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl MessageLimitPolicy {
+    /// Reads the policy from `MESSAGE_LIMIT_POLICY`, defaulting to `reject`.
+    pub fn from_env() -> Self {
+        match std::env::var("MESSAGE_LIMIT_POLICY").as_deref() {
+            Ok("compact") => MessageLimitPolicy::Compact,
+            _ => MessageLimitPolicy::Reject,
+        }
+    }
+}
+
+/// The sampling parameters of a request are out of range, for the reasons it holds. Responds
+/// with 400 and a JSON body with the code `invalid_sampling` and every reason under `errors`.
+#[derive(Debug)]
+pub struct InvalidSampling(Vec<FieldError>);
+
+impl IntoResponse for InvalidSampling {
+    fn into_response(self) -> Response {
+        let body = schemas::InvalidSamplingBody {
+            error: "invalid sampling parameters",
+            code: "invalid_sampling",
+            errors: self.0,
+        };
+        (StatusCode::BAD_REQUEST, Json(body)).into_response()
+    }
+}
+
+/// A conversation id given by the client belongs to another user. Responds with 403 and a JSON
+/// body with the code `conversation_forbidden`.
+#[derive(Debug)]
+pub struct ConversationForbidden;
+
+impl IntoResponse for ConversationForbidden {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: "the conversation belongs to another user",
+            code: "conversation_forbidden",
+        };
+        (StatusCode::FORBIDDEN, Json(body)).into_response()
+    }
+}
+
+/// The conversation has reached `MAX_MESSAGES_PER_CONVERSATION`. Responds with 409 and a JSON
+/// body with the code `message_limit_reached`.
+#[derive(Debug)]
+pub struct MessageLimitReached;
+
+impl IntoResponse for MessageLimitReached {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: "the conversation has reached its message limit",
+            code: "message_limit_reached",
+        };
+        (StatusCode::CONFLICT, Json(body)).into_response()
+    }
+}
+
+/// Another reply is being generated in the conversation. Responds with 409 and a JSON body with
+/// the code `conversation_busy`.
+#[derive(Debug)]
+pub struct ConversationBusy;
+
+impl IntoResponse for ConversationBusy {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: "a reply is being generated in this conversation",
+            code: "conversation_busy",
+        };
+        (StatusCode::CONFLICT, Json(body)).into_response()
+    }
+}
+
+/// The user has [`config::max_concurrent_generations_per_user`] generations queued or running
+/// already. Responds with 429 and a JSON body with the code `too_many_generations`.
+#[derive(Debug)]
+pub struct TooManyGenerations;
+
+impl IntoResponse for TooManyGenerations {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: "too many replies are being generated for this user",
+            code: "too_many_generations",
+        };
+        (StatusCode::TOO_MANY_REQUESTS, Json(body)).into_response()
+    }
+}
+
+/// The inference worker isn't running, because it hasn't started yet or has stopped. Responds
+/// with 503 and a JSON body with the code `model_not_ready`.
+#[derive(Debug)]
+pub struct ModelNotReady;
+
+impl IntoResponse for ModelNotReady {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: "the model is not ready",
+            code: "model_not_ready",
+        };
+        (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response()
+    }
+}
+
+/// The sampling parameters of a request, see [`config::resolve_sampling`]. The explicit ones
+/// must be in range and within [`config::sampling_limits`].
+fn request_sampling(
+    preset: Option<SamplingPreset>,
+    explicit: SamplingParams,
+) -> Result<SamplingParams, InvalidSampling> {
+    explicit
+        .validate(&config::sampling_limits())
+        .map_err(InvalidSampling)?;
+    Ok(config::resolve_sampling(preset, explicit))
+}
+
+/// The queue of the inference worker, once it has started.
+fn task_sender() -> Result<&'static TaskSender, ModelNotReady> {
+    TASK_SENDER.get().ok_or(ModelNotReady)
+}
+
+/// Counts a generation of the user, unless the user is at
+/// [`config::max_concurrent_generations_per_user`] already.
+fn start_user_generation(user_id: Uuid) -> Result<UserGeneration, TooManyGenerations> {
+    user_generations::try_start(user_id, config::max_concurrent_generations_per_user())
+        .ok_or(TooManyGenerations)
+}
+
+/// Takes the conversation's lock for a generation, according to `policy`.
+async fn lock_conversation(
+    conversation_id: Uuid,
+    policy: BusyConversationPolicy,
+) -> Result<ConversationLock, ConversationBusy> {
+    match policy {
+        BusyConversationPolicy::Queue => Ok(conversation_locks::lock(conversation_id).await),
+        BusyConversationPolicy::Reject => {
+            conversation_locks::try_lock(conversation_id).ok_or(ConversationBusy)
+        }
+    }
+}
+
+/// Where a generated text goes.
+enum Reply {
+    /// A new bot message answering the user message.
+    NewMessage { user_message_id: Uuid },
+    /// The end of an existing bot message.
+    Continuation(entities::Message),
+}
+
+/// Drains the inference output independently of the SSE stream, forwards it to the client
+/// according to `policy` and saves the full message once generation finishes. If the client
+/// disconnects, the generation stops and the text generated so far is saved as incomplete. So is
+/// the text of a generation the worker fails. A generation stopped for repeating a token is saved
+/// as complete, continuing it would likely repeat the token again.
+///
+/// For a new message, the prompt tokens of the generation are recorded on the user message it
+/// answers, and the generated tokens on the saved bot message. A continuation adds its generated
+/// tokens to the continued message.
+///
+/// The webhooks and latency of the generation come from its inference events, see
+/// [`generation_webhooks`] and [`latency`].
+///
+/// Holds the conversation's `lock` until the message is saved.
+#[allow(clippy::too_many_arguments)]
+async fn relay_generation(
+    conversation_service: Ref<dyn ConversationService>,
+    current_user: Uuid,
+    conversation_id: Uuid,
+    message_id: Uuid,
+    reply: Reply,
+    mut receiver: mpsc::Receiver<String>,
+    prompt_tokens: oneshot::Receiver<usize>,
+    mut failure: oneshot::Receiver<String>,
+    mut completion: oneshot::Receiver<CompletionReason>,
+    completion_tokens: oneshot::Receiver<usize>,
+    request_log: Option<PendingRequestLog>,
+    client_sender: mpsc::Sender<ClientEvent>,
+    policy: SlowClientPolicy,
+    lock: ConversationLock,
+) {
+    let mut assistant_message = String::new();
+    let mut thinking = String::new();
+    let mut client = Some(client_sender);
+    let mut incomplete = false;
+
+    // Continuations extend a reply that was wrapped already, and has done its thinking
+    let (prefix, suffix, mut splitter) = match reply {
+        Reply::NewMessage { .. } => (
+            config::response_prefix(),
+            config::response_suffix(),
+            ThinkingSplitter::from_env(),
+        ),
+        Reply::Continuation(_) => (None, None, None),
+    };
+
+    // The prefix and suffix are sent like generated parts, but aren't model tokens
+    if let Some(prefix) = prefix {
+        assistant_message.push_str(&prefix);
+        incomplete = !relay_part(&mut client, policy, conversation_id, message_id, prefix).await;
+    }
+
+    while !incomplete && let Some(message_part) = receiver.recv().await {
+        let segments = match splitter.as_mut() {
+            Some(splitter) => splitter.push(&message_part),
+            None => vec![Segment::Answer(message_part)],
+        };
+        incomplete = !relay_segments(
+            &mut client,
+            policy,
+            conversation_id,
+            message_id,
+            segments,
+            &mut assistant_message,
+            &mut thinking,
+        )
+        .await;
+    }
+    if !incomplete && let Some(splitter) = splitter.as_mut() {
+        incomplete = !relay_segments(
+            &mut client,
+            policy,
+            conversation_id,
+            message_id,
+            splitter.finish(),
+            &mut assistant_message,
+            &mut thinking,
+        )
+        .await;
+    }
+    if incomplete {
+        // The client went away, dropping the receiver stops the generation
+        drop(receiver);
+        client = None;
+    }
+    // The worker reports a failure before it drops the task, which ends the parts. What was
+    // generated is saved as an incomplete message, and the client's stream ends without `done`.
+    let failed = !incomplete && failure.try_recv().is_ok();
+    if failed {
+        incomplete = true;
+        client = None;
+    }
+    let completion = completion.try_recv().ok();
+    // Parts can merge several tokens, so the worker counts them. It reports the count when it
+    // drops the task, which a disconnected client's generation does once it notices.
+    let completion_tokens = completion_tokens.await.unwrap_or(0);
+
+    if let Some(suffix) = suffix {
+        assistant_message.push_str(&suffix);
+        if !incomplete {
+            relay_part(&mut client, policy, conversation_id, message_id, suffix).await;
+        }
+    }
+
+    // Only the text generated now is checked, a continuation keeps the text it continues
+    if let Some(guard) = LeakGuard::from_env()
+        && let Some(refusal) = guard.check(&assistant_message)
+    {
+        warn!("message {message_id} of conversation {conversation_id} leaked the system prompt");
+        assistant_message = refusal.to_owned();
+    }
 
+    // The worker drops the task once it is done, so the prompt size is known by now if the
+    // worker got as far as tokenizing it
+    if let Reply::NewMessage { user_message_id } = reply
+        && let Ok(prompt_tokens) = prompt_tokens.await
+    {
+        let _ = conversation_service
+            .set_message_token_count(
+                current_user,
+                conversation_id,
+                user_message_id,
+                prompt_tokens as u32,
+            )
+            .await;
+    }
 
+    // Saves the message as it should be read once `done` is sent, the incomplete flag and the
+    // thinking included
+    let save = || async {
+        let saved = match &reply {
+            Reply::NewMessage { .. } => {
                 conversation_service
-                    .create_bot_message_with_id(current_user, conversation_id, assistant_message, message_id)
-                    .await.expect("failed to save assistant message! this is bad");
-            };
+                    .create_bot_message_with_id(
+                        current_user,
+                        conversation_id,
+                        assistant_message.clone(),
+                        message_id,
+                        completion_tokens as u32,
+                        incomplete,
+                    )
+                    .await
+            }
+            Reply::Continuation(message) => {
+                let extended = conversation_service
+                    .extend_bot_message(
+                        current_user,
+                        conversation_id,
+                        message.clone(),
+                        assistant_message.clone(),
+                        completion_tokens as u32,
+                    )
+                    .await?;
+                // A continuation that runs to the end completes the message it continues
+                if message.incomplete != incomplete {
+                    conversation_service
+                        .set_message_incomplete(
+                            current_user,
+                            conversation_id,
+                            message_id,
+                            incomplete,
+                        )
+                        .await?;
+                }
+                Ok(entities::Message {
+                    incomplete,
+                    ..extended
+                })
+            }
+        };
+        if saved.is_ok()
+            && config::store_thinking()
+            && !thinking.is_empty()
+            && conversation_service
+                .set_message_thinking(current_user, conversation_id, message_id, thinking.clone())
+                .await
+                .is_err()
+        {
+            warn!("failed to save the thinking of message {message_id}");
+        }
+        saved
+    };
+    let mut saved = save().await;
+    conversation_events::publish(conversation_id, ConversationEvent::Done { message_id });
 
-            Sse::new(stream).keep_alive(KeepAlive::default())
+    // The client has every part already, so it only waits for the first attempt. Retries happen
+    // in the background.
+    if let Some(sender) = client {
+        match policy {
+            SlowClientPolicy::Block => {
+                let _ = sender.send(ClientEvent::Done).await;
+            }
+            SlowClientPolicy::Detach => {
+                let _ = sender.try_send(ClientEvent::Done);
+            }
+        }
+    }
+
+    let mut delays = SAVE_RETRY_DELAYS.iter();
+    while saved.is_err() {
+        let Some(delay) = delays.next() else {
+            break;
+        };
+        warn!("failed to save message {message_id}, retrying in {delay:?}");
+        tokio::time::sleep(*delay).await;
+        saved = save().await;
+    }
+    // The next generation in the conversation can read the saved message now
+    drop(lock);
+
+    let finish_reason = if saved.is_err() {
+        error!(
+            "failed to save message {message_id} of conversation {conversation_id}, its text was: {assistant_message}"
+        );
+        FinishReason::Error
+    } else if failed {
+        FinishReason::Error
+    } else if incomplete {
+        FinishReason::Cancelled
+    } else {
+        // `None` if the worker dropped the task without reporting how it ended
+        completion.map_or(FinishReason::Error, generation_webhooks::finish_reason)
+    };
+
+    // The client's stream has ended already, so logging doesn't hold it up
+    if let Some(request_log) = request_log {
+        let log = request_log.finish(
+            current_user,
+            conversation_id,
+            message_id,
+            assistant_message,
+            finish_reason,
+            completion_tokens,
+        );
+        if conversation_service.log_request(log).await.is_err() {
+            warn!("failed to log the request of message {message_id}");
+        }
+    }
+}
+
+/// The request of a generation, captured when it is queued for the request log. See
+/// [`config::log_requests_to_db`].
+struct PendingRequestLog {
+    messages: serde_json::Value,
+    sampling: serde_json::Value,
+    started_at: DateTime<Utc>,
+}
+
+impl PendingRequestLog {
+    /// Captures the request of `task`, if requests are logged.
+    fn start(task: &InferenceTask) -> Option<PendingRequestLog> {
+        if !config::log_requests_to_db() {
+            return None;
+        }
+        let messages = serde_json::to_value(task.messages())
+            .map_err(|e| error!("failed to serialize the messages of the request log: {e}"))
+            .ok()?;
+        let sampling = serde_json::to_value(task.sampling())
+            .map_err(|e| error!("failed to serialize the sampling of the request log: {e}"))
+            .ok()?;
+        Some(PendingRequestLog {
+            messages,
+            sampling,
+            started_at: Utc::now(),
+        })
+    }
+
+    fn finish(
+        self,
+        user: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        response: String,
+        finish_reason: FinishReason,
+        completion_tokens: usize,
+    ) -> entities::RequestLog {
+        entities::RequestLog {
+            id: Uuid::new_v4(),
+            user,
+            conversation_id,
+            message_id,
+            messages: sqlx::types::Json(self.messages),
+            sampling: sqlx::types::Json(self.sampling),
+            response,
+            outcome: finish_reason.as_str().to_owned(),
+            completion_tokens: completion_tokens as u32,
+            started_at: self.started_at,
+            finished_at: Utc::now(),
+        }
+    }
+}
+
+/// Relays the segments of generated text in order: the answer like [`relay_part`] and the
+/// thinking to the client alone. Appends them to the message and the thinking. Returns `false` if
+/// the client went away.
+async fn relay_segments(
+    client: &mut Option<mpsc::Sender<ClientEvent>>,
+    policy: SlowClientPolicy,
+    conversation_id: Uuid,
+    message_id: Uuid,
+    segments: Vec<Segment>,
+    assistant_message: &mut String,
+    thinking: &mut String,
+) -> bool {
+    for segment in segments {
+        let connected = match segment {
+            Segment::Answer(text) => {
+                assistant_message.push_str(&text);
+                relay_part(client, policy, conversation_id, message_id, text).await
+            }
+            Segment::Thinking(text) => {
+                thinking.push_str(&text);
+                send_to_client(client, policy, message_id, ClientEvent::Thinking(text)).await
+            }
+        };
+        if !connected {
+            return false;
+        }
+    }
+    true
+}
+
+/// Publishes a part of the message to the conversation's subscribers and sends it to the client
+/// according to `policy`. Returns `false` if the client went away.
+async fn relay_part(
+    client: &mut Option<mpsc::Sender<ClientEvent>>,
+    policy: SlowClientPolicy,
+    conversation_id: Uuid,
+    message_id: Uuid,
+    message_part: String,
+) -> bool {
+    conversation_events::publish(
+        conversation_id,
+        ConversationEvent::Part {
+            message_id,
+            text: message_part.clone(),
+        },
+    );
+
+    send_to_client(client, policy, message_id, ClientEvent::Part(message_part)).await
+}
+
+/// Sends an event to the client according to `policy`, if it is still streaming. Returns `false`
+/// if the client went away.
+async fn send_to_client(
+    client: &mut Option<mpsc::Sender<ClientEvent>>,
+    policy: SlowClientPolicy,
+    message_id: Uuid,
+    event: ClientEvent,
+) -> bool {
+    let Some(sender) = client.as_ref() else {
+        return true;
+    };
+
+    match policy {
+        SlowClientPolicy::Block => sender.send(event).await.is_ok(),
+        SlowClientPolicy::Detach => {
+            if sender.is_closed() {
+                return false;
+            }
+            // The last free slot is kept for `detached`, or for `done` once the message is saved
+            if sender.capacity() > 1 {
+                return sender.try_send(event).is_ok();
+            }
+            warn!("client is too slow, detaching it from the stream of message {message_id}");
+            let _ = sender.try_send(ClientEvent::Detached);
+            *client = None;
+            true
         }
-        Err(_) => panic!(),
     }
 }
 
+/// Delays between the attempts to save a generated message.
+const SAVE_RETRY_DELAYS: [Duration; 4] = [
+    Duration::from_millis(100),
+    Duration::from_millis(400),
+    Duration::from_millis(1_600),
+    Duration::from_millis(6_400),
+];
+
 pub mod schemas {
+    use crate::core::assistant::Role;
+    use crate::core::sampling::{FieldError, SamplingParams, SamplingPreset};
     use crate::infrastructure::entities;
     use chrono::{DateTime, Utc};
     use serde::{Deserialize, Serialize};
+    use utoipa::{IntoParams, ToSchema};
     use uuid::Uuid;
 
-    #[derive(Deserialize, Debug)]
+    #[derive(Deserialize, Debug, ToSchema)]
     pub struct CreateConversation {
         pub message: String,
+        /// Id for the new conversation, e.g. one synced across the client's devices. If the user
+        /// already has a conversation with this id, the message is posted to it instead.
+        pub conversation_id: Option<Uuid>,
+        /// Name of a configured persona whose system prompt the conversation starts with.
+        pub persona: Option<String>,
+        /// Sampling of the reply, see [`CreateMessage::preset`].
+        pub preset: Option<SamplingPreset>,
+        #[serde(flatten)]
+        pub sampling: SamplingParams,
     }
 
-    #[derive(Serialize, Debug)]
+    #[derive(Serialize, Debug, ToSchema)]
     pub struct Conversation {
         pub id: Uuid,
         pub created_at: DateTime<Utc>,
         pub title: Option<String>,
+        /// Fingerprint of the model the conversation was started with. Clients can compare it
+        /// to the current one to warn that earlier replies came from a different model.
+        pub model_fingerprint: Option<String>,
     }
 
     impl From<entities::Conversation> for Conversation {
@@ -182,44 +1958,110 @@ pub mod schemas {
                 id: conversation.id,
                 created_at: conversation.created_at,
                 title: None,
+                model_fingerprint: conversation.model_fingerprint,
             }
         }
     }
 
-    #[derive(Serialize, Debug)]
+    #[derive(Serialize, Debug, ToSchema)]
     pub struct ConversationList {
         pub conversations: Vec<Conversation>,
     }
 
-    #[derive(Serialize, Debug, Default)]
-    pub struct MessagesList {
+    #[derive(Deserialize, Debug, IntoParams)]
+    #[into_params(parameter_in = Query)]
+    pub struct ConversationsQuery {
+        pub sort: Option<String>,
+        pub order: Option<Order>,
+        /// Only list the conversations with this tag.
+        pub tag: Option<String>,
+    }
+
+    #[derive(Deserialize, Debug, IntoParams)]
+    #[into_params(parameter_in = Query)]
+    pub struct MessagesQuery {
+        pub sort: Option<String>,
+        pub order: Option<Order>,
+    }
+
+    /// Query of the endpoints streaming a reply.
+    #[derive(Deserialize, Debug, IntoParams)]
+    #[into_params(parameter_in = Query)]
+    pub struct StreamQuery {
+        #[serde(default)]
+        pub format: StreamFormat,
+    }
+
+    /// How `message_part` events carry the text. Other events are JSON either way.
+    #[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+    #[serde(rename_all = "lowercase")]
+    pub enum StreamFormat {
+        /// A [`MessagePart`] object
+        #[default]
+        Json,
+        /// Just the text, without carriage returns. The `done` event's length and CRC32 still
+        /// cover the whole text, carriage returns included.
+        Text,
+    }
+
+    #[derive(Deserialize, Debug, IntoParams)]
+    #[into_params(parameter_in = Query)]
+    pub struct SyncQuery {
+        /// The cursor of the previous sync, an RFC 3339 timestamp
+        pub since: Option<DateTime<Utc>>,
+    }
+
+    #[derive(Serialize, Debug, ToSchema)]
+    pub struct Sync {
+        pub conversations: Vec<Conversation>,
         pub messages: Vec<Message>,
+        /// Pass as `since` to the next sync
+        pub cursor: DateTime<Utc>,
     }
 
-    #[derive(Serialize, Debug)]
-    pub enum MessageKind {
-        System,
-        Bot,
-        User,
+    impl From<entities::Changes> for Sync {
+        fn from(changes: entities::Changes) -> Self {
+            Sync {
+                conversations: changes.conversations.into_iter().map(Into::into).collect(),
+                messages: changes.messages.into_iter().map(Into::into).collect(),
+                cursor: changes.cursor,
+            }
+        }
     }
 
-    impl From<entities::MessageKind> for MessageKind {
-        fn from(kind: entities::MessageKind) -> Self {
-            match kind {
-                entities::MessageKind::System => MessageKind::System,
-                entities::MessageKind::Bot => MessageKind::Bot,
-                entities::MessageKind::User => MessageKind::User,
+    #[derive(Deserialize, Debug, Clone, Copy, Default, ToSchema)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Order {
+        #[default]
+        Asc,
+        Desc,
+    }
+
+    impl From<Order> for entities::MessageOrder {
+        fn from(order: Order) -> Self {
+            match order {
+                Order::Asc => entities::MessageOrder::OldestFirst,
+                Order::Desc => entities::MessageOrder::NewestFirst,
             }
         }
     }
 
-    #[derive(Serialize, Debug)]
+    #[derive(Serialize, Debug, Default, ToSchema)]
+    pub struct MessagesList {
+        pub messages: Vec<Message>,
+    }
+
+    #[derive(Serialize, Debug, ToSchema)]
     pub struct Message {
         pub conversation_id: Uuid,
         pub id: Uuid,
-        pub kind: MessageKind,
+        /// `system`, `user` or `assistant`, as in the OpenAI compatible endpoints.
+        pub kind: Role,
         pub text: String,
         pub created_at: DateTime<Utc>,
+        pub attachments: Vec<Attachment>,
+        /// The generation of the message stopped before it was finished.
+        pub incomplete: bool,
     }
 
     impl From<entities::Message> for Message {
@@ -230,19 +2072,198 @@ pub mod schemas {
                 kind: message.kind.into(),
                 text: message.text,
                 created_at: message.created_at,
+                attachments: message.attachments.0.into_iter().map(Into::into).collect(),
+                incomplete: message.incomplete,
             }
         }
     }
 
-    #[derive(Deserialize, Debug)]
+    #[derive(Deserialize, Debug, ToSchema)]
+    pub struct SystemMessage {
+        pub text: String,
+    }
+
+    #[derive(Deserialize, Debug, ToSchema)]
+    pub struct Tag {
+        pub tag: String,
+    }
+
+    #[derive(Deserialize, Debug, ToSchema)]
     pub struct CreateMessage {
         pub text: String,
+        #[serde(default)]
+        pub attachments: Vec<Attachment>,
+        /// Context documents for the model to answer with, e.g. retrieved ones. Only part of the
+        /// prompt of this reply, and not stored in the conversation.
+        pub context: Option<String>,
+        /// Sampling parameters of the reply by name. The parameters the request sets explicitly
+        /// take precedence over the preset's.
+        pub preset: Option<SamplingPreset>,
+        #[serde(flatten)]
+        pub sampling: SamplingParams,
+    }
+
+    /// A file referenced by a message. Only this metadata is stored, not the file.
+    #[derive(Serialize, Deserialize, Debug, ToSchema)]
+    pub struct Attachment {
+        pub name: String,
+        pub mime: String,
+        pub uri: String,
+    }
+
+    impl From<Attachment> for entities::Attachment {
+        fn from(attachment: Attachment) -> Self {
+            entities::Attachment {
+                name: attachment.name,
+                mime: attachment.mime,
+                uri: attachment.uri,
+            }
+        }
+    }
+
+    impl From<entities::Attachment> for Attachment {
+        fn from(attachment: entities::Attachment) -> Self {
+            Attachment {
+                name: attachment.name,
+                mime: attachment.mime,
+                uri: attachment.uri,
+            }
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Rating {
+        Up,
+        Down,
     }
 
-    #[derive(Serialize, Debug)]
+    impl From<Rating> for entities::Rating {
+        fn from(rating: Rating) -> Self {
+            match rating {
+                Rating::Up => entities::Rating::Up,
+                Rating::Down => entities::Rating::Down,
+            }
+        }
+    }
+
+    impl From<entities::Rating> for Rating {
+        fn from(rating: entities::Rating) -> Self {
+            match rating {
+                entities::Rating::Up => Rating::Up,
+                entities::Rating::Down => Rating::Down,
+            }
+        }
+    }
+
+    #[derive(Deserialize, Debug, ToSchema)]
+    pub struct CreateFeedback {
+        pub rating: Rating,
+        pub comment: Option<String>,
+    }
+
+    #[derive(Serialize, Debug, ToSchema)]
+    pub struct Feedback {
+        pub message_id: Uuid,
+        pub rating: Rating,
+        pub comment: Option<String>,
+        pub created_at: DateTime<Utc>,
+    }
+
+    impl From<entities::MessageFeedback> for Feedback {
+        fn from(feedback: entities::MessageFeedback) -> Self {
+            Feedback {
+                message_id: feedback.message_id,
+                rating: feedback.rating.into(),
+                comment: feedback.comment,
+                created_at: feedback.created_at,
+            }
+        }
+    }
+
+    #[derive(Deserialize, Debug, ToSchema)]
+    pub struct CompactConversation {
+        /// Number of recent turns kept verbatim.
+        pub keep_turns: Option<usize>,
+    }
+
+    #[derive(Serialize, Debug, ToSchema)]
+    pub struct Usage {
+        pub prompt_tokens: i64,
+        pub completion_tokens: i64,
+        pub total_tokens: i64,
+    }
+
+    impl From<entities::TokenUsage> for Usage {
+        fn from(usage: entities::TokenUsage) -> Self {
+            Usage {
+                prompt_tokens: usage.prompt_tokens,
+                completion_tokens: usage.completion_tokens,
+                total_tokens: usage.prompt_tokens + usage.completion_tokens,
+            }
+        }
+    }
+
+    /// The error body of requests with out of range sampling parameters.
+    #[derive(Serialize, Debug, ToSchema)]
+    pub struct InvalidSamplingBody {
+        /// What went wrong, for humans
+        pub error: &'static str,
+        /// `invalid_sampling`
+        pub code: &'static str,
+        /// Every parameter that is out of range
+        pub errors: Vec<FieldError>,
+    }
+
+    /// Sent every `SSE_PING_INTERVAL_MS` to keep the connection open. Carries no message data.
+    #[derive(Serialize, Debug, ToSchema)]
+    pub struct Ping {
+        /// When the ping was sent.
+        pub timestamp: DateTime<Utc>,
+    }
+
+    #[derive(Serialize, Debug, ToSchema)]
+    pub struct Queued {
+        /// Number of generations that run before this one.
+        pub position: u64,
+    }
+
+    #[derive(Serialize, Debug, ToSchema)]
     pub struct MessagePart {
         pub conversation_id: Uuid,
         pub message_id: Uuid,
         pub message_part: String,
+        /// Length in UTF-8 bytes of the message streamed so far, this part included.
+        pub length: usize,
+        /// CRC32 of the message streamed so far, this part included.
+        pub crc32: u32,
+    }
+
+    /// Part of the thinking the model does before it answers, when the thinking markers are
+    /// configured. It isn't part of the message, nor of its length and CRC32.
+    #[derive(Serialize, Debug, ToSchema)]
+    pub struct ThinkingPart {
+        pub conversation_id: Uuid,
+        pub message_id: Uuid,
+        pub thinking_part: String,
+    }
+
+    /// Sent once the whole message is streamed. A stream without it ended early.
+    #[derive(Serialize, Debug, ToSchema)]
+    pub struct Done {
+        pub conversation_id: Uuid,
+        pub message_id: Uuid,
+        /// Length in UTF-8 bytes of the whole message.
+        pub length: usize,
+        /// CRC32 of the whole message.
+        pub crc32: u32,
+    }
+
+    /// Ends the stream of a client that read it too slowly. The message is still generated and
+    /// saved, and can be fetched by its id once it is.
+    #[derive(Serialize, Debug, ToSchema)]
+    pub struct Detached {
+        pub conversation_id: Uuid,
+        pub message_id: Uuid,
     }
 }
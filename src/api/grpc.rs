@@ -0,0 +1,363 @@
+//! gRPC interface, with the `grpc` feature
+//!
+//! A bidirectional `Generate` RPC for internal clients that want the tokens with less overhead
+//! than SSE, served on its own address (`GRPC_BIND_ADDRESS`). The service is defined in
+//! `proto/generation.proto`. Its messages and the service are written out by hand in this module
+//! instead of being generated, so the build doesn't need `protoc`.
+//!
+//! Generations are queued on the same inference worker as the HTTP API's, as the user of the
+//! `x-user-id` metadata, and count towards the user's `MAX_CONCURRENT_GENERATIONS_PER_USER`.
+
+use crate::TASK_SENDER;
+use crate::api::openai::{Generation, completion_reason, finish_reason};
+use crate::api::{DEFAULT_USER_ID, UserRejection};
+use crate::core::assistant::InferenceTask;
+use crate::core::config::{self, single_user_mode};
+use crate::core::model_reload;
+use crate::core::user_generations;
+use async_stream::stream;
+use futures_util::Stream;
+use log::error;
+use proto::generate_response::Event;
+use proto::{Finished, GenerateRequest, GenerateResponse};
+use std::convert::Infallible;
+use std::str::FromStr;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::error::TrySendError;
+use tonic::body::BoxBody;
+use tonic::codec::{ProstCodec, Streaming};
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::codegen::{Body, BoxFuture, BoxStream, Context, Poll, Service, StdError, http};
+use tonic::metadata::MetadataMap;
+use tonic::server::{NamedService, StreamingService};
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{Channel, Endpoint, Server};
+use tonic::{IntoStreamingRequest, Status};
+use uuid::Uuid;
+
+/// The metadata the user of a call is read from.
+const X_USER_ID: &str = "x-user-id";
+
+const GENERATE_PATH: &str = "/generation.Generation/Generate";
+
+/// Serves the `Generation` service on `listener`, until the server fails.
+pub async fn serve(listener: TcpListener) {
+    let incoming = match TcpIncoming::from_listener(listener, true, None) {
+        Ok(incoming) => incoming,
+        Err(e) => {
+            error!("failed to accept gRPC connections: {e}");
+            return;
+        }
+    };
+    if let Err(e) = Server::builder()
+        .add_service(GenerationServer)
+        .serve_with_incoming(incoming)
+        .await
+    {
+        error!("the gRPC server failed: {e}");
+    }
+}
+
+/// The `Generation` service, as a tonic server would route to it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GenerationServer;
+
+impl NamedService for GenerationServer {
+    const NAME: &'static str = "generation.Generation";
+}
+
+impl<B> Service<http::Request<B>> for GenerationServer
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Infallible>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        if request.uri().path() != GENERATE_PATH {
+            return Box::pin(async { Ok(Status::unimplemented("no such method").into_http()) });
+        }
+        Box::pin(async move {
+            let mut grpc = tonic::server::Grpc::new(ProstCodec::default());
+            Ok(grpc.streaming(Generate, request).await)
+        })
+    }
+}
+
+/// The `Generate` RPC.
+struct Generate;
+
+impl StreamingService<GenerateRequest> for Generate {
+    type Response = GenerateResponse;
+    type ResponseStream = BoxStream<GenerateResponse>;
+    type Future = BoxFuture<tonic::Response<Self::ResponseStream>, Status>;
+
+    fn call(&mut self, request: tonic::Request<Streaming<GenerateRequest>>) -> Self::Future {
+        Box::pin(async move {
+            let user = request_user(request.metadata())?;
+            let responses: BoxStream<GenerateResponse> =
+                Box::pin(generate(user, request.into_inner()));
+            Ok(tonic::Response::new(responses))
+        })
+    }
+}
+
+/// The user of a call, from its `x-user-id` metadata. Falls back to [`DEFAULT_USER_ID`] in
+/// single user mode, like [`crate::api::ExtractUser`].
+#[allow(clippy::result_large_err)]
+fn request_user(metadata: &MetadataMap) -> Result<Uuid, Status> {
+    let rejection = match metadata.get(X_USER_ID) {
+        Some(user_id) => match user_id.to_str().ok().map(Uuid::from_str) {
+            Some(Ok(user_id)) => return Ok(user_id),
+            _ => UserRejection::Invalid,
+        },
+        None if single_user_mode() => return Ok(DEFAULT_USER_ID),
+        None => UserRejection::Missing,
+    };
+    Err(Status::invalid_argument(rejection.message()))
+}
+
+/// Answers `requests` one at a time. A request that can't be generated ends the call with its
+/// status. Ending the call drops the generation in progress, which cancels it.
+fn generate(
+    user: Uuid,
+    mut requests: Streaming<GenerateRequest>,
+) -> impl Stream<Item = Result<GenerateResponse, Status>> {
+    stream! {
+        loop {
+            let request = match requests.message().await {
+                Ok(Some(request)) => request,
+                Ok(None) => return,
+                Err(status) => {
+                    yield Err(status);
+                    return;
+                }
+            };
+            let request_id = request.request_id.clone();
+            let mut generation = match start_generation(user, request) {
+                Ok(generation) => generation,
+                Err(status) => {
+                    yield Err(status);
+                    return;
+                }
+            };
+
+            while let Some(chunk) = generation.parts.recv().await {
+                yield Ok(GenerateResponse {
+                    request_id: request_id.clone(),
+                    event: Some(Event::Chunk(chunk)),
+                });
+            }
+            let completion_tokens = generation.completion_tokens.await.unwrap_or(0);
+            let prompt_tokens = generation.prompt_tokens.await.unwrap_or(0);
+            let reason = completion_reason(generation.failure, generation.completion).await;
+            yield Ok(GenerateResponse {
+                request_id,
+                event: Some(Event::Finished(Finished {
+                    finish_reason: finish_reason(reason).to_owned(),
+                    prompt_tokens: prompt_tokens as u32,
+                    completion_tokens: completion_tokens as u32,
+                })),
+            });
+        }
+    }
+}
+
+/// Queues the generation of `request` as `user`.
+#[allow(clippy::result_large_err)]
+fn start_generation(user: Uuid, request: GenerateRequest) -> Result<Generation, Status> {
+    if model_reload::is_reloading() {
+        return Err(Status::unavailable("the model is being reloaded"));
+    }
+    if request.messages.is_empty() {
+        return Err(Status::invalid_argument(
+            "`messages` must contain at least one message",
+        ));
+    }
+    let messages = request
+        .messages
+        .into_iter()
+        .map(proto::Message::into_chat_message)
+        .collect::<Result<Vec<_>, _>>()?;
+    let sampling = request
+        .sampling
+        .map(proto::Sampling::into_sampling_params)
+        .unwrap_or_default();
+    sampling
+        .validate(&config::sampling_limits())
+        .map_err(|errors| {
+            let messages: Vec<&str> = errors.iter().map(|error| error.message).collect();
+            Status::invalid_argument(messages.join(", "))
+        })?;
+
+    let task_sender = TASK_SENDER
+        .get()
+        .ok_or_else(|| Status::unavailable("the model is not loaded"))?;
+    let user_generation =
+        user_generations::try_start(user, config::max_concurrent_generations_per_user())
+            .ok_or_else(|| Status::resource_exhausted("too many generations of the user"))?;
+
+    let (mut task, parts) = InferenceTask::new(messages);
+    task.set_user_generation(user_generation);
+    task.set_sampling(config::resolve_sampling(None, sampling));
+    if let Some(max_tokens) = request.max_tokens {
+        task.set_max_tokens(max_tokens as usize);
+    }
+    let generation = Generation::track(&mut task, parts);
+
+    task_sender.try_send(task).map_err(|e| match e {
+        TrySendError::Full(_) => Status::resource_exhausted("the inference queue is full"),
+        TrySendError::Closed(_) => Status::unavailable("the inference worker has stopped"),
+    })?;
+    Ok(generation)
+}
+
+/// A client of the `Generation` service.
+#[derive(Debug, Clone)]
+pub struct GenerationClient {
+    inner: tonic::client::Grpc<Channel>,
+}
+
+impl GenerationClient {
+    /// Connects to the service at `address`, e.g. `http://localhost:50051`.
+    pub async fn connect(address: String) -> Result<Self, tonic::transport::Error> {
+        let channel = Endpoint::new(address)?.connect().await?;
+        Ok(GenerationClient {
+            inner: tonic::client::Grpc::new(channel),
+        })
+    }
+
+    /// Calls `Generate` with `requests`. Set the user of the call in the `x-user-id` metadata of
+    /// a [`tonic::Request`] around them.
+    pub async fn generate(
+        &mut self,
+        requests: impl IntoStreamingRequest<Message = GenerateRequest>,
+    ) -> Result<tonic::Response<Streaming<GenerateResponse>>, Status> {
+        self.inner
+            .ready()
+            .await
+            .map_err(|e| Status::unavailable(format!("the service is not ready: {e}")))?;
+        self.inner
+            .streaming(
+                requests.into_streaming_request(),
+                PathAndQuery::from_static(GENERATE_PATH),
+                ProstCodec::default(),
+            )
+            .await
+    }
+}
+
+/// The messages of `proto/generation.proto`.
+pub mod proto {
+    use crate::core::assistant::{self, ChatMessage};
+    use crate::core::sampling::SamplingParams;
+    use std::collections::BTreeMap;
+    use tonic::Status;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GenerateRequest {
+        #[prost(string, tag = "1")]
+        pub request_id: String,
+        #[prost(message, repeated, tag = "2")]
+        pub messages: Vec<Message>,
+        #[prost(message, optional, tag = "3")]
+        pub sampling: Option<Sampling>,
+        #[prost(uint32, optional, tag = "4")]
+        pub max_tokens: Option<u32>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Message {
+        #[prost(enumeration = "Role", tag = "1")]
+        pub role: i32,
+        #[prost(string, tag = "2")]
+        pub content: String,
+    }
+
+    impl Message {
+        #[allow(clippy::result_large_err)]
+        pub fn into_chat_message(self) -> Result<ChatMessage, Status> {
+            let role = match Role::try_from(self.role) {
+                Ok(Role::User) => assistant::Role::User,
+                Ok(Role::Assistant) => assistant::Role::Assistant,
+                Ok(Role::System) => assistant::Role::System,
+                Ok(Role::Unspecified) | Err(_) => {
+                    return Err(Status::invalid_argument(format!(
+                        "unsupported message role {}",
+                        self.role
+                    )));
+                }
+            };
+            Ok(ChatMessage::new(role, self.content))
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Role {
+        Unspecified = 0,
+        User = 1,
+        Assistant = 2,
+        System = 3,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Sampling {
+        #[prost(float, optional, tag = "1")]
+        pub temperature: Option<f32>,
+        #[prost(float, optional, tag = "2")]
+        pub top_p: Option<f32>,
+        #[prost(float, optional, tag = "3")]
+        pub repetition_penalty: Option<f32>,
+        #[prost(string, repeated, tag = "4")]
+        pub stop: Vec<String>,
+        #[prost(btree_map = "uint32, float", tag = "5")]
+        pub logit_bias: BTreeMap<u32, f32>,
+    }
+
+    impl Sampling {
+        pub fn into_sampling_params(self) -> SamplingParams {
+            SamplingParams {
+                temperature: self.temperature,
+                top_p: self.top_p,
+                repetition_penalty: self.repetition_penalty,
+                stop: self.stop,
+                logit_bias: self.logit_bias,
+            }
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GenerateResponse {
+        #[prost(string, tag = "1")]
+        pub request_id: String,
+        #[prost(oneof = "generate_response::Event", tags = "2, 3")]
+        pub event: Option<generate_response::Event>,
+    }
+
+    pub mod generate_response {
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Event {
+            #[prost(string, tag = "2")]
+            Chunk(String),
+            #[prost(message, tag = "3")]
+            Finished(super::Finished),
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Finished {
+        #[prost(string, tag = "1")]
+        pub finish_reason: String,
+        #[prost(uint32, tag = "2")]
+        pub prompt_tokens: u32,
+        #[prost(uint32, tag = "3")]
+        pub completion_tokens: u32,
+    }
+}
@@ -0,0 +1,42 @@
+//! Health endpoints
+
+use crate::MODEL_LOADED;
+use crate::infrastructure::database::DatabaseConnection;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use di_axum::Inject;
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// How long the database gets to answer the readiness probe.
+const DATABASE_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn router() -> Router {
+    Router::new().route("/ready", get(ready))
+}
+
+/// Whether the server can serve requests: the model is loaded and the database answers.
+/// Responds with 503 and the components that aren't ready otherwise.
+async fn ready(Inject(database): Inject<DatabaseConnection>) -> (StatusCode, Json<Readiness>) {
+    let readiness = Readiness {
+        model: MODEL_LOADED.load(Ordering::Acquire),
+        database: database.ping(DATABASE_PROBE_TIMEOUT).await,
+    };
+
+    let status = if readiness.model && readiness.database {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    /// The inference worker has loaded the model.
+    pub model: bool,
+    /// The database answers queries.
+    pub database: bool,
+}
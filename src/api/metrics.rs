@@ -0,0 +1,19 @@
+//! Metrics endpoint
+
+use crate::core::latency::METRICS;
+use axum::Router;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+
+pub fn router() -> Router {
+    Router::new().route("/metrics", get(metrics))
+}
+
+/// The streaming latency histograms in the Prometheus text format, for scraping.
+async fn metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(),
+    )
+}
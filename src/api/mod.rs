@@ -1,14 +1,70 @@
+use crate::core::config::single_user_mode;
+use crate::core::model_reload;
+use crate::core::stats::STATS;
+use crate::infrastructure::user_context::UserContext;
 use async_trait::async_trait;
-use axum::extract::FromRequestParts;
-use axum::http::StatusCode;
+use axum::Json;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{FromRequest, FromRequestParts, Request};
 use axum::http::request::Parts;
+use axum::http::{StatusCode, header};
+use axum::middleware::Next;
+use axum::response::sse::Event;
+use axum::response::{IntoResponse, Response};
+use log::error;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use std::str::FromStr;
+use tower_http::compression::CompressionLayer;
+use utoipa::ToSchema;
 use uuid::Uuid;
 
+pub mod admin;
 pub mod conversations;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod health;
+pub mod metrics;
+pub mod openai;
+pub mod openapi;
+pub mod personas;
+pub mod static_files;
+pub mod stats;
+pub mod usage;
 
 const X_USER_ID: &str = "X-User-ID";
 
+/// The user of requests without an `X-User-ID` header in single user mode.
+pub const DEFAULT_USER_ID: Uuid = Uuid::from_u128(1);
+
+/// Sets `data` as the JSON data of `event`. If `data` can't be serialized, the `error` event to
+/// end the stream with is returned instead. Ending the stream drops the generation.
+pub(crate) fn json_event(event: Event, data: impl Serialize) -> Result<Event, Event> {
+    event.json_data(data).map_err(|e| {
+        error!("failed to serialize an SSE event: {e}");
+        Event::default()
+            .event("error")
+            .data(r#"{"error": "failed to serialize the event", "code": "serialization_failed"}"#)
+    })
+}
+
+/// Compresses a response with gzip or deflate when the client accepts it.
+///
+/// Only layered on the JSON endpoints. SSE responses must reach the client event by event, which
+/// a compressor buffering its output would prevent.
+pub fn compression() -> CompressionLayer {
+    CompressionLayer::new().gzip(true).deflate(true)
+}
+
+/// The user from the `X-User-ID` header.
+///
+/// Any textual form of a UUID is accepted (hyphenated, simple, braced or URN). They all parse to
+/// the same `Uuid`, which is what gets bound to queries, so every form refers to the same user.
+///
+/// In single user mode (`SINGLE_USER_MODE=true`) a request without the header is made as
+/// [`DEFAULT_USER_ID`]. The header still applies when present, so anyone who can reach the server
+/// can read and write the default user's conversations without knowing any id. Only turn it on
+/// for a server that isn't reachable by others.
 #[derive(Debug)]
 pub struct ExtractUser(pub Uuid);
 
@@ -17,21 +73,242 @@ impl<S> FromRequestParts<S> for ExtractUser
 where
     S: Send + Sync,
 {
-    type Rejection = (StatusCode, &'static str);
+    type Rejection = UserRejection;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, UserRejection> {
+        #[cfg(all(feature = "test-auth-bypass", debug_assertions))]
+        if let Some(TestUser(user_id)) = parts.extensions.get::<TestUser>() {
+            return Ok(ExtractUser(*user_id));
+        }
 
-    async fn from_request_parts(
-        parts: &mut Parts,
-        _state: &S,
-    ) -> Result<Self, (StatusCode, &'static str)> {
         if let Some(user_id) = parts.headers.get(X_USER_ID) {
-            let user_id = user_id
-                .to_str()
-                .map_err(|_| (StatusCode::BAD_REQUEST, "invalid user id"))?;
-            let user_id = Uuid::from_str(user_id)
-                .map_err(|_| (StatusCode::BAD_REQUEST, "invalid user id"))?;
+            let user_id = user_id.to_str().map_err(|_| UserRejection::Invalid)?;
+            let user_id = Uuid::from_str(user_id).map_err(|_| UserRejection::Invalid)?;
             Ok(ExtractUser(user_id))
+        } else if single_user_mode() {
+            Ok(ExtractUser(DEFAULT_USER_ID))
         } else {
-            Err((StatusCode::BAD_REQUEST, "`X-User-ID` header is missing"))
+            Err(UserRejection::Missing)
+        }
+    }
+}
+
+/// Makes the request as this user, whatever its headers say, when set as a request extension.
+/// Lets tests of the generation path pick their user without relying on the `X-User-ID` header.
+///
+/// Only exists in debug builds with the `test-auth-bypass` feature, so release builds can't
+/// bypass the header even with the feature enabled.
+#[cfg(all(feature = "test-auth-bypass", debug_assertions))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TestUser(pub Uuid);
+
+/// Middleware making the user of the request the [`UserContext`] of the services resolved while
+/// handling it. Requests without a valid user are passed on as they are, for the handler's
+/// [`ExtractUser`] to reject.
+pub async fn scope_user_context(request: Request, next: Next) -> Response {
+    let (mut parts, body) = request.into_parts();
+    let user = ExtractUser::from_request_parts(&mut parts, &()).await;
+    let request = Request::from_parts(parts, body);
+
+    match user {
+        Ok(ExtractUser(user_id)) => UserContext::scope(user_id, next.run(request)).await,
+        Err(_) => next.run(request).await,
+    }
+}
+
+/// Middleware counting the requests served for the `/stats` endpoint, see [`STATS`].
+pub async fn count_requests(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    STATS.request_served();
+    response
+}
+
+/// How long clients are asked to wait before retrying a request turned away during a reload.
+pub(crate) const RELOAD_RETRY_AFTER_SECS: u64 = 5;
+
+/// Middleware responding with 503 and `Retry-After` while the model is reloaded, for requests
+/// that start a generation. They fail fast instead of queueing for a worker that is swapping its
+/// weights.
+pub async fn reject_during_reload(request: Request, next: Next) -> Response {
+    if !model_reload::is_reloading() {
+        return next.run(request).await;
+    }
+
+    let body = ErrorBody {
+        error: "the model is being reloaded",
+        code: "model_reloading",
+    };
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, RELOAD_RETRY_AFTER_SECS.to_string())],
+        Json(body),
+    )
+        .into_response()
+}
+
+/// Why [`ExtractUser`] rejected a request. Responds with 400 and a JSON body like
+/// `{"error": "`X-User-ID` header is missing", "code": "missing_user_id"}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserRejection {
+    Missing,
+    Invalid,
+}
+
+impl UserRejection {
+    pub fn code(&self) -> &'static str {
+        match self {
+            UserRejection::Missing => "missing_user_id",
+            UserRejection::Invalid => "invalid_user_id",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            UserRejection::Missing => "`X-User-ID` header is missing",
+            UserRejection::Invalid => "`X-User-ID` header is not a valid user id",
+        }
+    }
+}
+
+impl IntoResponse for UserRejection {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: self.message(),
+            code: self.code(),
+        };
+        (StatusCode::BAD_REQUEST, Json(body)).into_response()
+    }
+}
+
+/// A JSON request body. Like [`Json`], but rejects a bad body with a JSON error body, see
+/// [`JsonBodyRejection`].
+#[derive(Debug)]
+pub struct JsonBody<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = JsonBodyRejection;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, JsonBodyRejection> {
+        let Json(body) = Json::<T>::from_request(request, state).await?;
+        Ok(JsonBody(body))
+    }
+}
+
+/// Why [`JsonBody`] rejected a request. A missing or wrong `Content-Type` responds with 415 and a
+/// JSON body like `{"error": "...", "code": "unsupported_media_type"}`, a body that isn't valid
+/// JSON with 400 and one that doesn't match the expected fields with 422. A body that can't be
+/// read at all responds with 400 too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonBodyRejection {
+    UnsupportedMediaType,
+    InvalidJson,
+    InvalidBody,
+    UnreadableBody,
+}
+
+impl JsonBodyRejection {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            JsonBodyRejection::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            JsonBodyRejection::InvalidJson => StatusCode::BAD_REQUEST,
+            JsonBodyRejection::InvalidBody => StatusCode::UNPROCESSABLE_ENTITY,
+            JsonBodyRejection::UnreadableBody => StatusCode::BAD_REQUEST,
         }
     }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            JsonBodyRejection::UnsupportedMediaType => "unsupported_media_type",
+            JsonBodyRejection::InvalidJson => "invalid_json",
+            JsonBodyRejection::InvalidBody => "invalid_body",
+            JsonBodyRejection::UnreadableBody => "unreadable_body",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            JsonBodyRejection::UnsupportedMediaType => {
+                "expected a request with `Content-Type: application/json`"
+            }
+            JsonBodyRejection::InvalidJson => "the request body is not valid JSON",
+            JsonBodyRejection::InvalidBody => "the request body doesn't have the expected fields",
+            JsonBodyRejection::UnreadableBody => "the request body could not be read",
+        }
+    }
+}
+
+impl From<JsonRejection> for JsonBodyRejection {
+    fn from(rejection: JsonRejection) -> Self {
+        match rejection {
+            JsonRejection::MissingJsonContentType(_) => JsonBodyRejection::UnsupportedMediaType,
+            JsonRejection::JsonSyntaxError(_) => JsonBodyRejection::InvalidJson,
+            JsonRejection::JsonDataError(_) => JsonBodyRejection::InvalidBody,
+            _ => JsonBodyRejection::UnreadableBody,
+        }
+    }
+}
+
+impl IntoResponse for JsonBodyRejection {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: self.message(),
+            code: self.code(),
+        };
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub(crate) struct ErrorBody {
+    /// What went wrong, for humans
+    pub(crate) error: &'static str,
+    /// What went wrong, for clients to match on
+    pub(crate) code: &'static str,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::conversations::schemas::Queued;
+    use axum::response::Sse;
+    use serde::Serializer;
+    use std::convert::Infallible;
+
+    /// Fails to serialize, like a map with non-string keys would.
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("deliberately unserializable"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unserializable_payload_becomes_error_event() {
+        let event = match json_event(Event::default().event("message_part"), Unserializable) {
+            Ok(_) => panic!("the payload should fail to serialize"),
+            Err(error) => error,
+        };
+
+        let events = futures_util::stream::iter([Ok::<_, Infallible>(event)]);
+        let response = Sse::new(events).into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+
+        assert!(body.starts_with("event: error\n"), "{body}");
+        assert!(body.contains("serialization_failed"), "{body}");
+    }
+
+    #[test]
+    fn test_serializable_payload_is_event_data() {
+        let queued = Queued { position: 2 };
+        assert!(json_event(Event::default().event("queued"), queued).is_ok());
+    }
 }
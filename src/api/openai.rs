@@ -0,0 +1,636 @@
+//! OpenAI compatible endpoints
+//!
+//! Served under `/v1` so existing OpenAI clients can talk to the local model. Every error is
+//! returned in OpenAI's `{"error": {"message", "type", "code"}}` envelope.
+//!
+//! Completions are made as the user of the `X-User-ID` header like the rest of the API, which
+//! OpenAI clients can send as a default header. Their generations count towards the user's
+//! `MAX_CONCURRENT_GENERATIONS_PER_USER`.
+
+use crate::api::{ExtractUser, RELOAD_RETRY_AFTER_SECS, UserRejection, json_event};
+use crate::core::assistant::{InferenceTask, Role, model_id};
+use crate::core::config;
+use crate::core::inference_events::CompletionReason;
+use crate::core::model_reload;
+use crate::core::user_generations;
+use crate::{MODEL_QUANTIZATION, TASK_SENDER};
+use async_stream::stream;
+use axum::extract::Request;
+use axum::extract::rejection::JsonRejection;
+use axum::handler::Handler;
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::middleware::{Next, from_fn};
+use axum::response::sse::Event;
+use axum::response::{IntoResponse, Response, Sse};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::Utc;
+use futures_util::Stream;
+use log::warn;
+use std::convert::Infallible;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+
+pub fn router() -> Router {
+    Router::new()
+        .route(
+            "/chat/completions",
+            post(chat_completions.layer(from_fn(reject_during_reload))),
+        )
+        .route("/models", get(list_models))
+}
+
+/// Errors of the `/v1` endpoints.
+#[derive(Debug)]
+pub enum OpenAiError {
+    /// The request is malformed or has invalid values.
+    InvalidRequest(String),
+    /// The request names a model this server doesn't serve.
+    ModelNotFound(String),
+    /// The inference queue is full.
+    RateLimited,
+    /// The user's generations would exceed `MAX_CONCURRENT_GENERATIONS_PER_USER`.
+    TooManyGenerations,
+    /// The model is being reloaded.
+    ModelReloading,
+    /// The server can't handle the request right now.
+    ServerError(String),
+}
+
+impl IntoResponse for OpenAiError {
+    fn into_response(self) -> Response {
+        let (status, message, error_type, code) = match self {
+            OpenAiError::InvalidRequest(message) => (
+                StatusCode::BAD_REQUEST,
+                message,
+                "invalid_request_error",
+                None,
+            ),
+            OpenAiError::ModelNotFound(model) => (
+                StatusCode::NOT_FOUND,
+                format!("The model `{model}` does not exist"),
+                "invalid_request_error",
+                Some("model_not_found"),
+            ),
+            OpenAiError::RateLimited => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "The inference queue is full, please retry later".to_owned(),
+                "requests",
+                Some("rate_limit_exceeded"),
+            ),
+            OpenAiError::TooManyGenerations => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many generations of the user are running, please retry later".to_owned(),
+                "requests",
+                Some("rate_limit_exceeded"),
+            ),
+            OpenAiError::ModelReloading => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "The model is being reloaded, please retry later".to_owned(),
+                "server_error",
+                Some("model_reloading"),
+            ),
+            OpenAiError::ServerError(message) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                message,
+                "server_error",
+                None,
+            ),
+        };
+
+        let body = schemas::ErrorEnvelope {
+            error: schemas::Error {
+                message,
+                error_type,
+                code,
+            },
+        };
+
+        let mut response = (status, Json(body)).into_response();
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(RELOAD_RETRY_AFTER_SECS),
+            );
+        }
+        response
+    }
+}
+
+/// [`crate::api::reject_during_reload`] with the error in OpenAI's envelope.
+async fn reject_during_reload(request: Request, next: Next) -> Response {
+    if model_reload::is_reloading() {
+        return OpenAiError::ModelReloading.into_response();
+    }
+    next.run(request).await
+}
+
+impl From<UserRejection> for OpenAiError {
+    fn from(rejection: UserRejection) -> Self {
+        OpenAiError::InvalidRequest(rejection.message().to_owned())
+    }
+}
+
+impl From<JsonRejection> for OpenAiError {
+    fn from(rejection: JsonRejection) -> Self {
+        OpenAiError::InvalidRequest(rejection.body_text())
+    }
+}
+
+/// Maps the role of an OpenAI message to the role of a chat message.
+///
+/// `developer` is OpenAI's newer name for `system`. `tool` messages are answers to tool calls,
+/// which the model can't make yet, so they are rejected rather than passed off as another role.
+pub fn parse_role(role: &str) -> Result<Role, OpenAiError> {
+    match role.trim().to_lowercase().as_str() {
+        "system" | "developer" => Ok(Role::System),
+        "user" => Ok(Role::User),
+        "assistant" => Ok(Role::Assistant),
+        "tool" | "function" => Err(OpenAiError::InvalidRequest(format!(
+            "messages with the role `{role}` are not supported, tool calling is not available"
+        ))),
+        _ => Err(OpenAiError::InvalidRequest(format!(
+            "unknown message role `{role}`, expected `system`, `developer`, `user` or `assistant`"
+        ))),
+    }
+}
+
+/// Lists the served model, which is the only one.
+async fn list_models() -> Json<schemas::ModelList> {
+    Json(schemas::ModelList {
+        object: "list",
+        data: vec![schemas::Model {
+            id: model_id(),
+            object: "model",
+            created: 0,
+            owned_by: "local",
+            quantization: MODEL_QUANTIZATION.get().cloned(),
+        }],
+    })
+}
+
+/// Answers with the whole completion, or with `stream` a server-sent `chat.completion.chunk` per
+/// generated part followed by `[DONE]`.
+///
+/// With `n`, the whole answer has `n` independently sampled choices. They are queued together and
+/// generated one after another, and each counts as one of the user's generations. Only a single
+/// choice can be streamed.
+async fn chat_completions(
+    user: Result<ExtractUser, UserRejection>,
+    request: Result<Json<schemas::ChatCompletionRequest>, JsonRejection>,
+) -> Result<Response, OpenAiError> {
+    let ExtractUser(current_user) = user?;
+    let Json(request) = request?;
+
+    let model = model_id();
+    if request.model != model {
+        return Err(OpenAiError::ModelNotFound(request.model));
+    }
+    if request.messages.is_empty() {
+        return Err(OpenAiError::InvalidRequest(
+            "`messages` must contain at least one message".to_owned(),
+        ));
+    }
+    let messages = request
+        .messages
+        .into_iter()
+        .map(schemas::ChatMessage::into_chat_message)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let max_choices = config::max_completion_choices();
+    if !(1..=max_choices).contains(&request.n) {
+        return Err(OpenAiError::InvalidRequest(format!(
+            "`n` must be between 1 and {max_choices}"
+        )));
+    }
+    if request.stream && request.n > 1 {
+        return Err(OpenAiError::InvalidRequest(
+            "`n` greater than 1 is not supported with `stream`".to_owned(),
+        ));
+    }
+
+    let task_sender = TASK_SENDER.get().ok_or(OpenAiError::ServerError(
+        "the model is not loaded".to_owned(),
+    ))?;
+    // All the choices are queued or none, so a full queue doesn't leave a partial answer running
+    if task_sender.capacity() < request.n {
+        return Err(OpenAiError::RateLimited);
+    }
+    // Same for the user's generations, which are given back if any of them is over the limit
+    let limit = config::max_concurrent_generations_per_user();
+    let user_generations = (0..request.n)
+        .map(|_| user_generations::try_start(current_user, limit))
+        .collect::<Option<Vec<_>>>()
+        .ok_or(OpenAiError::TooManyGenerations)?;
+
+    // Each choice is its own task, sampled with its own random draws
+    let mut generations = Vec::with_capacity(request.n);
+    for user_generation in user_generations {
+        let (mut task, receiver) = InferenceTask::new(messages.clone());
+        task.set_user_generation(user_generation);
+        if let Some(max_tokens) = request.max_tokens {
+            task.set_max_tokens(max_tokens);
+        }
+        task.set_stop_token_ids(request.stop_token_ids.iter().copied());
+        if let Some(min_tokens) = request.min_tokens {
+            task.set_min_tokens(min_tokens);
+        }
+        let generation = Generation::track(&mut task, receiver);
+
+        task_sender.try_send(task).map_err(|e| match e {
+            TrySendError::Full(_) => OpenAiError::RateLimited,
+            TrySendError::Closed(_) => {
+                OpenAiError::ServerError("the inference worker has stopped".to_owned())
+            }
+        })?;
+        generations.push(generation);
+    }
+
+    let id = format!("chatcmpl-{}", Uuid::new_v4().simple());
+    let created = Utc::now().timestamp();
+
+    if request.stream {
+        let include_usage = request
+            .stream_options
+            .is_some_and(|options| options.include_usage);
+        let chunks = ChunkStream { id, created, model };
+        let generation = generations.remove(0);
+        return Ok(Sse::new(chunks.stream(generation, include_usage)).into_response());
+    }
+
+    let mut choices = Vec::with_capacity(generations.len());
+    let mut usage = schemas::Usage {
+        prompt_tokens: 0,
+        completion_tokens: 0,
+        total_tokens: 0,
+    };
+    for (index, mut generation) in generations.into_iter().enumerate() {
+        let mut content = String::new();
+        while let Some(part) = generation.parts.recv().await {
+            content.push_str(&part);
+        }
+        usage.completion_tokens += generation.completion_tokens.await.unwrap_or(0);
+        // Every choice answers the same prompt, which is counted once like in OpenAI's API
+        usage.prompt_tokens = generation.prompt_tokens.await.unwrap_or(0);
+
+        choices.push(schemas::Choice {
+            index,
+            message: schemas::ResponseMessage {
+                role: Role::Assistant,
+                content,
+            },
+            finish_reason: finish_reason(
+                completion_reason(generation.failure, generation.completion).await,
+            ),
+        });
+    }
+    usage.total_tokens = usage.prompt_tokens + usage.completion_tokens;
+
+    Ok(Json(schemas::ChatCompletion {
+        id,
+        object: "chat.completion",
+        created,
+        model,
+        choices,
+        usage,
+    })
+    .into_response())
+}
+
+/// What the worker reports back about the generation of one choice.
+pub(crate) struct Generation {
+    pub(crate) parts: mpsc::Receiver<String>,
+    pub(crate) prompt_tokens: oneshot::Receiver<usize>,
+    /// Parts can merge several tokens, so the tokens are counted by the worker
+    pub(crate) completion_tokens: oneshot::Receiver<usize>,
+    pub(crate) failure: oneshot::Receiver<String>,
+    pub(crate) completion: oneshot::Receiver<CompletionReason>,
+}
+
+impl Generation {
+    pub(crate) fn track(task: &mut InferenceTask, parts: mpsc::Receiver<String>) -> Generation {
+        Generation {
+            parts,
+            prompt_tokens: task.track_prompt_tokens(),
+            completion_tokens: task.track_completion_tokens(),
+            failure: task.track_failure(),
+            completion: task.track_completion(),
+        }
+    }
+}
+
+/// How the generation of a choice ended, once its parts have ended. `None` if the worker failed
+/// the task, which it reports before dropping the task, or dropped it without reporting an end.
+pub(crate) async fn completion_reason(
+    mut failure: oneshot::Receiver<String>,
+    completion: oneshot::Receiver<CompletionReason>,
+) -> Option<CompletionReason> {
+    if let Ok(error) = failure.try_recv() {
+        warn!("completion failed: {error}");
+        return None;
+    }
+    completion.await.ok()
+}
+
+/// The `finish_reason` of a generation that ended for `reason`, `error` if it failed. `length`
+/// and `stop` are OpenAI's, the others are this server's own.
+pub(crate) fn finish_reason(reason: Option<CompletionReason>) -> &'static str {
+    match reason {
+        Some(CompletionReason::Stop) => "stop",
+        Some(CompletionReason::Length) => "length",
+        Some(CompletionReason::Cancelled) => "cancelled",
+        Some(CompletionReason::RepetitionCollapse) => "repetition_collapse",
+        None => "error",
+    }
+}
+
+/// The chunks of one streamed completion, which all share the id, creation time and model.
+struct ChunkStream {
+    id: String,
+    created: i64,
+    model: String,
+}
+
+impl ChunkStream {
+    fn chunk(
+        &self,
+        delta: schemas::Delta,
+        finish_reason: Option<&'static str>,
+    ) -> schemas::ChatCompletionChunk {
+        schemas::ChatCompletionChunk {
+            id: self.id.clone(),
+            object: "chat.completion.chunk",
+            created: self.created,
+            model: self.model.clone(),
+            choices: vec![schemas::ChunkChoice {
+                index: 0,
+                delta,
+                finish_reason,
+            }],
+            usage: None,
+        }
+    }
+
+    /// Streams the role, then a chunk per generated part and the finish reason. With
+    /// `include_usage`, a chunk without choices carries the token usage last, as in OpenAI's API.
+    fn stream(
+        self,
+        mut generation: Generation,
+        include_usage: bool,
+    ) -> impl Stream<Item = Result<Event, Infallible>> {
+        stream! {
+            let role = schemas::Delta {
+                role: Some(Role::Assistant),
+                content: None,
+            };
+            match json_event(Event::default(), self.chunk(role, None)) {
+                Ok(event) => yield Ok(event),
+                Err(error) => {
+                    yield Ok(error);
+                    return;
+                }
+            }
+
+            while let Some(part) = generation.parts.recv().await {
+                let content = schemas::Delta {
+                    role: None,
+                    content: Some(part),
+                };
+                match json_event(Event::default(), self.chunk(content, None)) {
+                    Ok(event) => yield Ok(event),
+                    Err(error) => {
+                        yield Ok(error);
+                        return;
+                    }
+                }
+            }
+
+            let finish_reason =
+                finish_reason(completion_reason(generation.failure, generation.completion).await);
+            match json_event(Event::default(), self.chunk(schemas::Delta::default(), Some(finish_reason))) {
+                Ok(event) => yield Ok(event),
+                Err(error) => {
+                    yield Ok(error);
+                    return;
+                }
+            }
+
+            if include_usage {
+                let prompt_tokens = generation.prompt_tokens.await.unwrap_or(0);
+                let completion_tokens = generation.completion_tokens.await.unwrap_or(0);
+                let mut usage = self.chunk(schemas::Delta::default(), None);
+                usage.choices.clear();
+                usage.usage = Some(schemas::Usage {
+                    prompt_tokens,
+                    completion_tokens,
+                    total_tokens: prompt_tokens + completion_tokens,
+                });
+                match json_event(Event::default(), usage) {
+                    Ok(event) => yield Ok(event),
+                    Err(error) => {
+                        yield Ok(error);
+                        return;
+                    }
+                }
+            }
+
+            yield Ok(Event::default().data("[DONE]"));
+        }
+    }
+}
+
+pub mod schemas {
+    use crate::api::openai::{OpenAiError, parse_role};
+    use crate::core::assistant;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Debug)]
+    pub struct ErrorEnvelope {
+        pub error: Error,
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct Error {
+        pub message: String,
+        #[serde(rename = "type")]
+        pub error_type: &'static str,
+        pub code: Option<&'static str>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct ChatCompletionRequest {
+        pub model: String,
+        pub messages: Vec<ChatMessage>,
+        /// Defaults to `DEFAULT_MAX_TOKENS`. Either is capped by the space left in the context.
+        pub max_tokens: Option<usize>,
+        /// Tokens generated before the end of sequence and stop tokens can end the completion.
+        pub min_tokens: Option<usize>,
+        /// Token ids that end the completion like the end of sequence token, e.g. `<|eot_id|>`.
+        #[serde(default)]
+        pub stop_token_ids: Vec<u32>,
+        /// Number of choices to generate, at most `MAX_COMPLETION_CHOICES`.
+        #[serde(default = "one")]
+        pub n: usize,
+        /// Streams the completion as server-sent chunks.
+        #[serde(default)]
+        pub stream: bool,
+        pub stream_options: Option<StreamOptions>,
+    }
+
+    fn one() -> usize {
+        1
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct StreamOptions {
+        /// Adds a last chunk with the token usage of the completion.
+        #[serde(default)]
+        pub include_usage: bool,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct ChatMessage {
+        /// Parsed with [`parse_role`], so an unsupported role gets an OpenAI error.
+        pub role: String,
+        pub content: String,
+    }
+
+    impl ChatMessage {
+        pub fn into_chat_message(self) -> Result<assistant::ChatMessage, OpenAiError> {
+            Ok(assistant::ChatMessage::new(
+                parse_role(&self.role)?,
+                self.content,
+            ))
+        }
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct ChatCompletion {
+        pub id: String,
+        pub object: &'static str,
+        pub created: i64,
+        pub model: String,
+        pub choices: Vec<Choice>,
+        pub usage: Usage,
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct Choice {
+        pub index: usize,
+        pub message: ResponseMessage,
+        pub finish_reason: &'static str,
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct ResponseMessage {
+        pub role: assistant::Role,
+        pub content: String,
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct ChatCompletionChunk {
+        pub id: String,
+        pub object: &'static str,
+        pub created: i64,
+        pub model: String,
+        pub choices: Vec<ChunkChoice>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub usage: Option<Usage>,
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct ChunkChoice {
+        pub index: usize,
+        pub delta: Delta,
+        pub finish_reason: Option<&'static str>,
+    }
+
+    /// What a chunk adds to the message.
+    #[derive(Serialize, Debug, Default)]
+    pub struct Delta {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub role: Option<assistant::Role>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub content: Option<String>,
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct ModelList {
+        pub object: &'static str,
+        pub data: Vec<Model>,
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct Model {
+        pub id: String,
+        pub object: &'static str,
+        pub created: i64,
+        pub owned_by: &'static str,
+        /// Quantization type of most of the weights, e.g. `Q4K`. `None` until the model is
+        /// loaded.
+        pub quantization: Option<String>,
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct Usage {
+        pub prompt_tokens: usize,
+        pub completion_tokens: usize,
+        pub total_tokens: usize,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roles_map_to_chat_roles() {
+        for (role, expected) in [
+            ("system", Role::System),
+            ("developer", Role::System),
+            ("user", Role::User),
+            ("assistant", Role::Assistant),
+            ("Assistant", Role::Assistant),
+        ] {
+            assert_eq!(parse_role(role).unwrap(), expected, "{role}");
+        }
+    }
+
+    #[test]
+    fn test_tool_role_is_rejected() {
+        let Err(OpenAiError::InvalidRequest(message)) = parse_role("tool") else {
+            panic!("the tool role was accepted");
+        };
+        assert!(message.contains("`tool`"), "{message}");
+    }
+
+    #[test]
+    fn test_unknown_role_is_rejected() {
+        assert!(matches!(
+            parse_role("narrator"),
+            Err(OpenAiError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_only_a_normal_end_is_a_stop() {
+        assert_eq!(finish_reason(Some(CompletionReason::Stop)), "stop");
+        assert_eq!(finish_reason(Some(CompletionReason::Length)), "length");
+        assert_eq!(
+            finish_reason(Some(CompletionReason::Cancelled)),
+            "cancelled"
+        );
+        assert_eq!(finish_reason(None), "error");
+    }
+
+    #[tokio::test]
+    async fn test_failed_choice_has_no_completion_reason() {
+        let (failure_sender, failure) = oneshot::channel();
+        let (completion_sender, completion) = oneshot::channel();
+        failure_sender.send("out of memory".to_owned()).unwrap();
+        drop(completion_sender);
+
+        assert_eq!(completion_reason(failure, completion).await, None);
+    }
+}
@@ -0,0 +1,55 @@
+//! OpenAPI document of the conversation endpoints, served at `GET /openapi.json`.
+//!
+//! Every endpoint identifies the user by the `X-User-ID` header, see
+//! [`ExtractUser`](crate::api::ExtractUser).
+
+use crate::api::ErrorBody;
+use crate::api::conversations::{self, schemas};
+use crate::core::assistant::Role;
+use axum::routing::get;
+use axum::{Json, Router};
+use utoipa::OpenApi;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Local LLM API"),
+    paths(
+        conversations::list_conversations,
+        conversations::sync_conversations,
+        conversations::new_conversation,
+        conversations::conversation_messages,
+        conversations::post_message,
+        conversations::continue_message,
+        conversations::replay_message,
+        conversations::message_feedback,
+        conversations::post_message_feedback,
+        conversations::conversation_usage,
+        conversations::compact_conversation,
+        conversations::duplicate_conversation,
+        conversations::update_system_message,
+        conversations::add_conversation_tag,
+        conversations::remove_conversation_tag,
+        conversations::conversation_events,
+    ),
+    components(schemas(
+        ErrorBody,
+        Role,
+        schemas::Ping,
+        schemas::Queued,
+        schemas::MessagePart,
+        schemas::ThinkingPart,
+        schemas::Done,
+        schemas::Detached,
+        schemas::StreamFormat,
+        schemas::Order,
+    ))
+)]
+pub struct ApiDoc;
+
+pub fn router() -> Router {
+    Router::new().route("/openapi.json", get(openapi))
+}
+
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
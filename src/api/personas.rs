@@ -0,0 +1,41 @@
+//! Personas endpoint
+
+use crate::core::personas::Personas;
+use axum::routing::get;
+use axum::{Json, Router};
+use di_axum::Inject;
+
+pub fn router() -> Router {
+    Router::new().route("/personas", get(list_personas))
+}
+
+/// Lists the configured personas, so clients can offer them when creating a conversation. Their
+/// system prompts stay on the server.
+async fn list_personas(Inject(personas): Inject<Personas>) -> Json<schemas::PersonaList> {
+    Json(schemas::PersonaList {
+        personas: personas
+            .iter()
+            .map(|(name, persona)| schemas::Persona {
+                name: name.to_owned(),
+                description: persona.description.clone(),
+            })
+            .collect(),
+    })
+}
+
+pub mod schemas {
+    use serde::Serialize;
+
+    #[derive(Serialize, Debug)]
+    pub struct PersonaList {
+        /// Sorted by name
+        pub personas: Vec<Persona>,
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct Persona {
+        /// What to pass as `persona` when creating a conversation
+        pub name: String,
+        pub description: String,
+    }
+}
@@ -0,0 +1,32 @@
+//! Frontend files
+//!
+//! Serves the built frontend from `STATIC_DIR`, defaulting to `static` relative to the working
+//! directory. Everything is read at runtime, so deployments can mount the frontend wherever they
+//! like.
+
+use axum::Router;
+use axum::http::StatusCode;
+use axum::response::Html;
+use axum::routing::get;
+use log::warn;
+use std::path::PathBuf;
+use tower_http::services::ServeDir;
+
+pub fn router() -> Router {
+    let static_dir = PathBuf::from(std::env::var("STATIC_DIR").unwrap_or("static".to_owned()));
+    let index_path = static_dir.join("index.html");
+
+    Router::new()
+        .route("/", get(move || index(index_path.clone())))
+        .nest_service("/static", ServeDir::new(static_dir))
+}
+
+async fn index(index_path: PathBuf) -> Result<Html<String>, StatusCode> {
+    tokio::fs::read_to_string(&index_path)
+        .await
+        .map(Html)
+        .map_err(|e| {
+            warn!("failed to read {}: {e}", index_path.display());
+            StatusCode::NOT_FOUND
+        })
+}
@@ -0,0 +1,39 @@
+//! Stats endpoint
+
+use crate::core::queue;
+use crate::core::stats::STATS;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Serialize;
+
+pub fn router() -> Router {
+    Router::new().route("/stats", get(stats))
+}
+
+/// A few counters as JSON, a lightweight alternative to scraping `/metrics` with Prometheus.
+async fn stats() -> Json<Stats> {
+    let snapshot = STATS.snapshot();
+    Json(Stats {
+        queue_depth: queue::depth(),
+        requests_served: snapshot.requests_served,
+        generations: snapshot.generations,
+        tokens_generated: snapshot.tokens_generated,
+        average_tokens_per_sec: snapshot.average_tokens_per_sec,
+        uptime_secs: snapshot.uptime.as_secs(),
+    })
+}
+
+#[derive(Serialize, Debug)]
+pub struct Stats {
+    /// Generations that are queued or running.
+    pub queue_depth: u64,
+    /// HTTP requests answered since the server started.
+    pub requests_served: u64,
+    /// Generations completed since the server started.
+    pub generations: u64,
+    /// Completion tokens generated since the server started.
+    pub tokens_generated: u64,
+    /// Completion tokens generated per second of generation time.
+    pub average_tokens_per_sec: f64,
+    pub uptime_secs: u64,
+}
@@ -0,0 +1,24 @@
+//! Usage endpoints
+
+use crate::api::ExtractUser;
+use crate::api::conversations::schemas::Usage;
+use crate::core::traits::ConversationService;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use di_axum::Inject;
+
+pub fn router() -> Router {
+    Router::new().route("/", get(user_usage))
+}
+
+async fn user_usage(
+    Inject(conversation_service): Inject<dyn ConversationService>,
+    ExtractUser(current_user): ExtractUser,
+) -> Result<(StatusCode, Json<Usage>), StatusCode> {
+    conversation_service
+        .user_usage(current_user)
+        .await
+        .map(|usage| (StatusCode::OK, Json(usage.into())))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
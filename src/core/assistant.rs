@@ -1,46 +1,315 @@
 //! LLM Assistant service.
 //!
 
+use crate::core::config::AppConfig;
+use crate::core::generation::{LanguageModel, generate};
+use crate::core::gpu::{GpuInstance, create_device};
+use crate::core::inference_events::{
+    CompletionReason, GenerationStats, INFERENCE_EVENTS, InferenceEvent, TOKEN_BATCH_SIZE,
+};
+use crate::core::load_progress;
+use crate::core::logits_readback::{HalfReadback, LogitsPrecision};
+use crate::core::model_overrides::ModelOverrides;
+use crate::core::model_source::ModelSource;
+use crate::core::prompt_cache::PromptCache;
+use crate::core::queue::{QueuePosition, QueueTicket};
+use crate::core::response_prefix::response_prefixes_from_env;
+use crate::core::sampling::{SamplingParams, TokenSampler};
+use crate::core::task_queue::{Priority, TaskReceiver};
+use crate::core::user_generations::UserGeneration;
 use crate::infrastructure::entities;
-use log::{debug, info};
+use crate::{CHAT_TEMPLATE, MODEL_FINGERPRINT, MODEL_LOADED, MODEL_QUANTIZATION};
+use anyhow::anyhow;
+use log::{Log, debug, info, warn};
 use minijinja::context;
-use nalgebra::DVector;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
-use std::str::FromStr;
-use tokio::fs::File;
+use std::time::Duration;
 use tokio::io::AsyncReadExt;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio::time::Instant;
+use utoipa::ToSchema;
+use uuid::Uuid;
 use uuid::timestamp::context;
-use wgcore::gpu::GpuInstance;
 use wgcore::kernel::CommandEncoderExt;
 use wgcore::shapes::ViewShapeBuffers;
 use wgml::gguf::Gguf;
 use wgml::models::gpt2::Gpt2Tokenizer;
 use wgml::models::llama2::cpu::Llama2Config;
-use wgml::models::llama2::{Llama2, Llama2State, Llama2Weights, LlamaModelType, LlamaTokenizer};
+use wgml::models::llama2::{Llama2, Llama2State, Llama2Weights, LlamaTokenizer};
 
 pub struct InferenceTask {
+    id: Uuid,
     messages: Vec<ChatMessage>,
     return_channel: mpsc::Sender<String>,
+    queue_ticket: QueueTicket,
+    prompt_tokens: Option<oneshot::Sender<usize>>,
+    failure: Option<oneshot::Sender<String>>,
+    completion: Option<oneshot::Sender<CompletionReason>>,
+    completion_tokens: Option<oneshot::Sender<usize>>,
+    max_tokens: Option<usize>,
+    min_tokens: usize,
+    continuation: Option<String>,
+    context: Option<String>,
+    sampling: SamplingParams,
+    priority: Priority,
+    stop_token_ids: HashSet<u32>,
+    stats: GenerationStats,
+    /// Generated tokens not yet published in a [`InferenceEvent::TokenBatch`]
+    unpublished_tokens: usize,
+    /// Counts the task as a generation of its user until the task is dropped
+    user_generation: Option<UserGeneration>,
 }
 
 impl InferenceTask {
     pub fn new(messages: Vec<ChatMessage>) -> (InferenceTask, mpsc::Receiver<String>) {
         let (sender, receiver) = mpsc::channel::<String>(1000);
 
-        (
-            InferenceTask {
-                messages,
-                return_channel: sender,
-            },
-            receiver,
-        )
+        let task = InferenceTask {
+            id: Uuid::new_v4(),
+            messages,
+            return_channel: sender,
+            queue_ticket: QueueTicket::take(),
+            prompt_tokens: None,
+            failure: None,
+            completion: None,
+            completion_tokens: None,
+            max_tokens: None,
+            min_tokens: 0,
+            continuation: None,
+            context: None,
+            sampling: SamplingParams::default(),
+            priority: Priority::default(),
+            stop_token_ids: HashSet::new(),
+            stats: GenerationStats::default(),
+            unpublished_tokens: 0,
+            user_generation: None,
+        };
+        INFERENCE_EVENTS.publish(InferenceEvent::Queued {
+            task_id: task.id,
+            position: task.queue_position().get(),
+        });
+        (task, receiver)
+    }
+
+    /// Identifies the task in its [`InferenceEvent`]s.
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// The conversation this task should generate a reply to.
+    pub fn messages(&self) -> &[ChatMessage] {
+        &self.messages
+    }
+
+    /// Channel the generated tokens are streamed back through.
+    pub fn return_channel(&self) -> &mpsc::Sender<String> {
+        &self.return_channel
+    }
+
+    /// Position of this task in the inference queue, which stays valid after the task is sent.
+    pub fn queue_position(&self) -> QueuePosition {
+        self.queue_ticket.position()
+    }
+
+    /// Called by the queue when the tasks ahead of this one change.
+    pub fn set_queue_position(&self, position: u64) {
+        self.queue_ticket.set_position(position);
+    }
+
+    /// Called by the queue when it hands the task to the worker. Ends the wait of the clients
+    /// following its [`QueuePosition`].
+    pub fn dequeued(&self) {
+        self.queue_ticket.dequeue();
+    }
+
+    /// Limits the number of generated tokens. Without a limit, [`default_max_tokens`] applies.
+    /// Either way the limit is capped by the space left in the context.
+    pub fn set_max_tokens(&mut self, max_tokens: usize) {
+        self.max_tokens = Some(max_tokens);
+    }
+
+    /// Keeps the generation going until at least `min_tokens` tokens are generated, by
+    /// suppressing the tokens that would end it. `max_tokens` still ends it first if it is lower.
+    pub fn set_min_tokens(&mut self, min_tokens: usize) {
+        self.min_tokens = min_tokens;
+    }
+
+    /// The tokens the sampler must not pick after `generated` tokens: the model's `terminators`
+    /// and the stop tokens while the generation is shorter than its `min_tokens`, otherwise none.
+    pub fn suppressed_tokens(&self, generated: usize, terminators: &[usize]) -> Vec<usize> {
+        if generated >= self.min_tokens {
+            return Vec::new();
+        }
+        terminators
+            .iter()
+            .copied()
+            .chain(self.stop_token_ids.iter().map(|&token| token as usize))
+            .collect()
+    }
+
+    /// Ends the generation when the model samples one of `ids`, as it does on the end of sequence
+    /// token. The stop token itself isn't streamed back.
+    pub fn set_stop_token_ids(&mut self, ids: impl IntoIterator<Item = u32>) {
+        self.stop_token_ids = ids.into_iter().collect();
+    }
+
+    /// Whether the generation should end on `token`, besides the model's terminators.
+    pub fn is_stop_token(&self, token: usize) -> bool {
+        u32::try_from(token).is_ok_and(|token| self.stop_token_ids.contains(&token))
+    }
+
+    /// Makes the task continue `partial`, an assistant reply to the task's messages that was cut
+    /// short, instead of starting a new reply. Only the continuation is streamed back.
+    pub fn continue_from(&mut self, partial: String) {
+        self.continuation = Some(partial);
+    }
+
+    /// Adds context documents to the prompt, as a system message before the last message. The
+    /// context is only part of this generation and isn't in [`Self::messages`].
+    pub fn set_context(&mut self, context: String) {
+        self.context = Some(context);
+    }
+
+    /// Overrides the server's sampling for this generation, with the parameters that are set.
+    pub fn set_sampling(&mut self, sampling: SamplingParams) {
+        self.sampling = sampling;
+    }
+
+    pub fn sampling(&self) -> &SamplingParams {
+        &self.sampling
+    }
+
+    /// Lets the task jump ahead of queued tasks of lower priority, see
+    /// [`task_queue`](crate::core::task_queue). Tasks are [`Priority::Normal`] by default.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Counts the task as a generation of a user for as long as it is queued or running, see
+    /// [`user_generations`](crate::core::user_generations).
+    pub fn set_user_generation(&mut self, user_generation: UserGeneration) {
+        self.user_generation = Some(user_generation);
+    }
+
+    /// Returns a channel the prompt length is sent through once the worker has tokenized it.
+    pub fn track_prompt_tokens(&mut self) -> oneshot::Receiver<usize> {
+        let (sender, receiver) = oneshot::channel();
+        self.prompt_tokens = Some(sender);
+        receiver
+    }
+
+    /// Reports the number of prompt tokens to whoever called [`Self::track_prompt_tokens`].
+    pub fn report_prompt_tokens(&mut self, count: usize) {
+        if let Some(sender) = self.prompt_tokens.take() {
+            let _ = sender.send(count);
+        }
+    }
+
+    /// Returns a channel the error is sent through if the worker can't generate the task.
+    pub fn track_failure(&mut self) -> oneshot::Receiver<String> {
+        let (sender, receiver) = oneshot::channel();
+        self.failure = Some(sender);
+        receiver
+    }
+
+    /// Returns a channel the reason the generation ended is sent through, if it completes.
+    pub fn track_completion(&mut self) -> oneshot::Receiver<CompletionReason> {
+        let (sender, receiver) = oneshot::channel();
+        self.completion = Some(sender);
+        receiver
+    }
+
+    /// Returns a channel the number of generated tokens is sent through once the task is dropped,
+    /// however its generation ended. Parts can merge several tokens, so counting the parts
+    /// received undercounts.
+    pub fn track_completion_tokens(&mut self) -> oneshot::Receiver<usize> {
+        let (sender, receiver) = oneshot::channel();
+        self.completion_tokens = Some(sender);
+        receiver
+    }
+
+    /// Called by the worker once the prompt is tokenized. Reports the prompt length and publishes
+    /// [`InferenceEvent::Started`].
+    pub fn started(&mut self, prompt_tokens: usize) {
+        self.report_prompt_tokens(prompt_tokens);
+        self.stats.prompt_tokens = prompt_tokens;
+        INFERENCE_EVENTS.publish(InferenceEvent::Started {
+            task_id: self.id,
+            prompt_tokens,
+        });
+    }
+
+    /// Called by the worker for every generated token. Publishes the first one on its own, so
+    /// the time to first token can be told from the bus, and the rest in batches of
+    /// [`TOKEN_BATCH_SIZE`].
+    pub fn generated_token(&mut self) {
+        self.stats.completion_tokens += 1;
+        self.unpublished_tokens += 1;
+        if self.stats.completion_tokens == 1 || self.unpublished_tokens == TOKEN_BATCH_SIZE {
+            self.publish_token_batch();
+        }
+    }
+
+    /// Called by the worker when the generation has ended. Publishes the last token batch and
+    /// [`InferenceEvent::Completed`], and reports the reason to whoever called
+    /// [`Self::track_completion`].
+    pub fn completed(&mut self, reason: CompletionReason, prefill: Duration, generation: Duration) {
+        if let Some(sender) = self.completion.take() {
+            let _ = sender.send(reason);
+        }
+        self.publish_token_batch();
+        self.stats.prefill = prefill;
+        self.stats.generation = generation;
+        INFERENCE_EVENTS.publish(InferenceEvent::Completed {
+            task_id: self.id,
+            reason,
+            stats: self.stats,
+        });
+    }
+
+    /// Called by the worker when it can't generate the task. Publishes [`InferenceEvent::Failed`]
+    /// and reports the error to whoever called [`Self::track_failure`].
+    pub fn failed(&mut self, error: String) {
+        if let Some(sender) = self.failure.take() {
+            let _ = sender.send(error.clone());
+        }
+        INFERENCE_EVENTS.publish(InferenceEvent::Failed {
+            task_id: self.id,
+            error,
+        });
+    }
+
+    fn publish_token_batch(&mut self) {
+        if self.unpublished_tokens > 0 {
+            INFERENCE_EVENTS.publish(InferenceEvent::TokenBatch {
+                task_id: self.id,
+                tokens: self.unpublished_tokens,
+            });
+            self.unpublished_tokens = 0;
+        }
+    }
+
+    /// The system prompt the conversation starts with, if it has one.
+    pub fn system_prompt(&self) -> Option<&str> {
+        self.messages
+            .first()
+            .filter(|message| message.role == Role::System)
+            .map(|message| message.content.as_str())
     }
 
     pub fn as_jinja_input(&self) -> minijinja::Value {
-        let messages: Vec<minijinja::Value> =
+        let mut messages: Vec<minijinja::Value> =
             self.messages.iter().map(|m| m.as_jinja_value()).collect();
+        if let Some(context) = &self.context {
+            let context = ChatMessage::new(Role::System, context.clone()).as_jinja_value();
+            messages.insert(messages.len().saturating_sub(1), context);
+        }
 
         minijinja::context! {
             messages => messages
@@ -48,69 +317,351 @@ impl InferenceTask {
     }
 }
 
-#[derive(Debug, Clone)]
+impl Drop for InferenceTask {
+    /// Reports the generated tokens to whoever called
+    /// [`InferenceTask::track_completion_tokens`]. The return channel closes after this, so they
+    /// are known by the time the parts end.
+    fn drop(&mut self) {
+        if let Some(sender) = self.completion_tokens.take() {
+            let _ = sender.send(self.stats.completion_tokens);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct ChatMessage {
     role: Role,
     content: String,
 }
 
 impl ChatMessage {
+    pub fn new(role: Role, content: String) -> Self {
+        ChatMessage { role, content }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
     pub fn as_jinja_value(&self) -> minijinja::Value {
         minijinja::context! {
-            role => match self.role {
-                Role::User => "user",
-                Role::Assistant => "assistant",
-                Role::System => "system",
-            },
+            role => self.role.as_str(),
             content => self.content
         }
     }
 }
 
-#[derive(Debug, Clone)]
+/// Author of a message. This is the one mapping of message kinds to role names, used by the chat
+/// templates and every API that exposes messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
 pub enum Role {
     User,
     Assistant,
     System,
 }
 
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Assistant => "assistant",
+            Role::System => "system",
+        }
+    }
+}
+
+impl From<entities::MessageKind> for Role {
+    fn from(kind: entities::MessageKind) -> Self {
+        match kind {
+            entities::MessageKind::System => Role::System,
+            entities::MessageKind::User => Role::User,
+            entities::MessageKind::Bot => Role::Assistant,
+        }
+    }
+}
+
 impl From<entities::Message> for ChatMessage {
     fn from(m: entities::Message) -> Self {
         Self {
             content: m.text,
-            role: match m.kind {
-                entities::MessageKind::System => Role::System,
-                entities::MessageKind::User => Role::User,
-                entities::MessageKind::Bot => Role::Assistant,
-            },
+            role: m.kind.into(),
         }
     }
 }
 
-pub async fn background_task(mut task_queue: mpsc::Receiver<InferenceTask>) -> () {
-    let model_file_name = std::env::var("MODEL_FILE_NAME")
-        .unwrap_or("models/Llama-3.2-3B-Instruct-Q4_K_M.gguf".to_owned());
-    let context_size = std::env::var("CONTEXT_SIZE")
-        .ok()
-        .and_then(|s| usize::from_str(&s).ok())
-        .unwrap_or(32_768);
+const BOS_TOKEN_ID_KEY: &str = "tokenizer.ggml.bos_token_id";
+const EOS_TOKEN_ID_KEY: &str = "tokenizer.ggml.eos_token_id";
 
-    println!("Loading model: {}", model_file_name);
+/// GGUF metadata keys of the tokens that end a turn besides the end of sequence token, such as
+/// Llama 3's `<|eot_id|>` and `<|eom_id|>`.
+const END_OF_TURN_TOKEN_ID_KEYS: [&str; 2] =
+    ["tokenizer.ggml.eot_token_id", "tokenizer.ggml.eom_token_id"];
+
+/// The tokens that end a reply: the tokenizer's end of sequence token `eos`, and the end of
+/// sequence and end of turn tokens the metadata lists. `token_id` reads a token id from its
+/// metadata value. Sorted, without duplicates.
+pub fn terminator_token_ids<V>(
+    metadata: &HashMap<String, V>,
+    token_id: impl Fn(&V) -> u32,
+    eos: usize,
+) -> Vec<usize> {
+    let mut terminators: Vec<usize> = std::iter::once(EOS_TOKEN_ID_KEY)
+        .chain(END_OF_TURN_TOKEN_ID_KEYS)
+        .filter_map(|key| metadata.get(key))
+        .map(|value| token_id(value) as usize)
+        .chain(std::iter::once(eos))
+        .collect();
+    terminators.sort_unstable();
+    terminators.dedup();
+    terminators
+}
+
+fn special_token_str<V>(
+    metadata: &HashMap<String, V>,
+    key: &str,
+    token_str: impl FnOnce() -> String,
+) -> String {
+    if metadata.contains_key(key) {
+        token_str()
+    } else {
+        warn!("GGUF metadata has no `{key}`, the chat template will get an empty string.");
+        String::new()
+    }
+}
+
+/// Builds the Jinja environment the chat template is rendered with.
+///
+/// Not every GGUF tokenizer defines BOS/EOS tokens. When the metadata lacks one, the tokenizer
+/// is not asked for it and the template sees an empty string instead.
+pub fn build_chat_template_env<'a, V>(
+    chat_template: &'a str,
+    metadata: &HashMap<String, V>,
+    bos_str: impl FnOnce() -> String,
+    eos_str: impl FnOnce() -> String,
+) -> Result<minijinja::Environment<'a>, minijinja::Error> {
+    let mut env = minijinja::Environment::new();
+    env.set_trim_blocks(true);
+    env.add_global(
+        "bos_token",
+        special_token_str(metadata, BOS_TOKEN_ID_KEY, bos_str),
+    );
+    env.add_global(
+        "eos_token",
+        special_token_str(metadata, EOS_TOKEN_ID_KEY, eos_str),
+    );
+    env.add_global("add_generation_prompt", true);
+    env.add_template("main", chat_template)?;
+
+    Ok(env)
+}
+
+/// Maximum number of tokens generated for a prompt of `prompt_tokens` tokens when the task
+/// doesn't set a limit: `default_max_tokens`, but never more than what fits in the context.
+pub fn default_max_tokens(
+    default_max_tokens: usize,
+    context_size: usize,
+    prompt_tokens: usize,
+) -> usize {
+    default_max_tokens.min(context_size.saturating_sub(prompt_tokens))
+}
+
+/// Maximum number of tokens generated for a prompt of `prompt_tokens` tokens: the task's
+/// `max_tokens`, or [`default_max_tokens`] without one. Neither can run past the context, which
+/// a `min_tokens` suppressing the end of sequence would otherwise do.
+pub fn max_tokens(
+    max_tokens: Option<usize>,
+    default_max_tokens_config: usize,
+    context_size: usize,
+    prompt_tokens: usize,
+) -> usize {
+    default_max_tokens(
+        max_tokens.unwrap_or(default_max_tokens_config),
+        context_size,
+        prompt_tokens,
+    )
+}
+
+/// A system prompt taking more of the context than `MAX_SYSTEM_PROMPT_FRACTION` allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemPromptOverBudget {
+    pub tokens: usize,
+    pub budget: usize,
+}
+
+impl Display for SystemPromptOverBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the system prompt takes {} tokens, over its budget of {} tokens",
+            self.tokens, self.budget
+        )
+    }
+}
+
+/// Checks that a system prompt of `system_prompt_tokens` tokens takes at most `max_fraction` of
+/// the context.
+pub fn check_system_prompt_budget(
+    system_prompt_tokens: usize,
+    context_size: usize,
+    max_fraction: f32,
+) -> Result<(), SystemPromptOverBudget> {
+    let budget = (context_size as f32 * max_fraction) as usize;
+    if system_prompt_tokens > budget {
+        return Err(SystemPromptOverBudget {
+            tokens: system_prompt_tokens,
+            budget,
+        });
+    }
+    Ok(())
+}
+
+/// Path of the GGUF model, from `MODEL_FILE_NAME`.
+pub fn model_file_name() -> String {
+    std::env::var("MODEL_FILE_NAME")
+        .unwrap_or("models/Llama-3.2-3B-Instruct-Q4_K_M.gguf".to_owned())
+}
+
+/// Name clients refer to the served model by: the model file name without its extension.
+pub fn model_id() -> String {
+    let file_name = model_file_name();
+    std::path::Path::new(&file_name)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or(file_name)
+}
+
+/// GGUF metadata keys that change what the model generates, other than the `general.*` ones.
+const FINGERPRINT_KEY_SUFFIXES: [&str; 6] = [
+    ".context_length",
+    ".block_count",
+    ".embedding_length",
+    ".attention.head_count",
+    ".vocab_size",
+    "tokenizer.chat_template",
+];
+
+/// Identifies the loaded model: the model file name and a hash of its key GGUF metadata, e.g.
+/// `Llama-3.2-3B-Instruct-Q4_K_M.gguf:5f0b6e3c1d2a4b97`.
+///
+/// The hash is FNV-1a over the sorted keys and values, so it is stable across builds.
+pub fn model_fingerprint<V: std::fmt::Debug>(
+    file_name: &str,
+    metadata: &HashMap<String, V>,
+) -> String {
+    let mut keys: Vec<&String> = metadata
+        .keys()
+        .filter(|key| {
+            key.starts_with("general.")
+                || FINGERPRINT_KEY_SUFFIXES
+                    .iter()
+                    .any(|suffix| key.ends_with(suffix))
+        })
+        .collect();
+    keys.sort();
+
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for key in keys {
+        let entry = format!("{key}={:?};", metadata[key]);
+        for byte in entry.bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    let file_name = std::path::Path::new(file_name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or(file_name.to_owned());
+    format!("{file_name}:{hash:016x}")
+}
+
+/// The quantization type holding most of the weights, as named by the GGUF reader, e.g. `Q4K`.
+/// Mixed quantizations like Q4_K_M report their main type.
+///
+/// Takes the type and number of elements of each weight tensor. `None` without any tensors.
+pub fn dominant_quantization<T: std::fmt::Debug>(
+    tensors: impl IntoIterator<Item = (T, u64)>,
+) -> Option<String> {
+    let mut elements_by_type: HashMap<String, u64> = HashMap::new();
+    for (tensor_type, elements) in tensors {
+        *elements_by_type
+            .entry(format!("{tensor_type:?}"))
+            .or_default() += elements;
+    }
+
+    // Ties go to the first name, so the result doesn't depend on the map order
+    elements_by_type
+        .into_iter()
+        .max_by(|(a_type, a), (b_type, b)| a.cmp(b).then_with(|| b_type.cmp(a_type)))
+        .map(|(tensor_type, _)| tensor_type)
+}
+
+/// Log target of rendered prompts. They contain user data, so they are kept apart from the other
+/// logs, for log sinks to drop.
+pub const PROMPT_LOG_TARGET: &str = "prompts";
+
+/// Logs the rendered prompt of a task at debug level, if `enabled`. See [`config::log_prompts`].
+///
+/// [`config::log_prompts`]: crate::core::config::log_prompts
+pub fn log_prompt(logger: &dyn Log, enabled: bool, task_id: Uuid, prompt: &str) {
+    if enabled {
+        debug!(logger: logger, target: PROMPT_LOG_TARGET, "prompt of task {task_id}:\n{prompt}");
+    }
+}
+
+pub async fn background_task(mut task_queue: TaskReceiver) -> () {
+    let AppConfig {
+        model_file_name,
+        context_size,
+        default_max_tokens: default_max_tokens_config,
+        decoding_mode,
+        logits_readback_precision,
+        log_prompts,
+        max_system_prompt_fraction,
+        reject_oversized_system_prompt,
+        gpu_retries,
+        max_repeated_tokens,
+        inference_backend,
+        prompt_cache,
+        coalesce_whitespace,
+        ..
+    } = AppConfig::from_env();
+
+    let model_source = ModelSource::from_env();
+    println!("Loading model: {model_source}");
 
-    let gguf_file = File::open(model_file_name)
-        .await
-        .expect("failed to open model file");
     let gguf_start_time = Instant::now();
-    let gguf_mmap = unsafe { memmap2::Mmap::map(&gguf_file) }.expect("failed to map file");
-    let gguf = Gguf::from_bytes(&gguf_mmap[..]).expect("bad gguf");
+    let gguf_bytes = model_source.load().expect("failed to read model file");
+    let gguf = Gguf::from_bytes(&gguf_bytes[..]).expect("bad gguf");
+    let fingerprint = model_fingerprint(&model_file_name, &gguf.metadata);
+    info!("Model fingerprint: {fingerprint}");
+    let _ = MODEL_FINGERPRINT.set(fingerprint);
+    // Norms and biases are vectors, usually kept in F32 even in quantized models
+    let quantization = dominant_quantization(
+        gguf.tensors
+            .iter()
+            .filter(|(name, tensor)| name.ends_with(".weight") && tensor.dimensions.len() > 1)
+            .map(|(_, tensor)| (&tensor.tensor_type, tensor.dimensions.iter().product())),
+    );
+    if let Some(quantization) = quantization {
+        info!("Model quantization: {quantization}");
+        let _ = MODEL_QUANTIZATION.set(quantization);
+    }
     info!(
         "GGUF model loaded in {:.2} seconds.",
         gguf_start_time.elapsed().as_secs_f32()
     );
+    load_progress::page_in(&gguf_bytes[..]);
 
-    let gpu = GpuInstance::new().await.expect("failed to create GPU");
+    let gpu = create_device(inference_backend)
+        .await
+        .expect("failed to create inference device");
     let device = gpu.device();
-    info!("GPU device created.");
     info!("GPU device features: {:?}", device.features());
 
     let chat_template_str = gguf
@@ -118,163 +669,255 @@ pub async fn background_task(mut task_queue: mpsc::Receiver<InferenceTask>) -> (
         .get("tokenizer.chat_template")
         .map(|v| v.as_string().to_owned())
         .unwrap_or("chat template missing".into());
+    let _ = CHAT_TEMPLATE.set(chat_template_str.clone());
 
+    let overrides = ModelOverrides::from_env(context_size);
     let transformer =
-        Llama2::new(device, LlamaModelType::Llama).expect("failed to create LlamaModel");
+        Llama2::new(device, overrides.model_type).expect("failed to create LlamaModel");
 
     let mut config = Llama2Config::from_gguf(&gguf);
-    config.seq_len = config.seq_len.min(context_size);
+    overrides.apply(&mut config);
+    info!("Uploading {} tensors to the GPU", gguf.tensors.len());
+    let upload_start_time = Instant::now();
     let weights = Llama2Weights::from_gguf(device, &config, &gguf);
+    info!(
+        "Weights uploaded in {:.2} seconds.",
+        upload_start_time.elapsed().as_secs_f32()
+    );
     let tokenizer = Gpt2Tokenizer::from_gguf(&gguf);
     let state = Llama2State::new(device, &config);
+    let terminators = terminator_token_ids(&gguf.metadata, |value| value.as_u32(), tokenizer.eos());
+    info!("Generation ends on tokens {terminators:?}");
 
-    let mut chat_template_env = minijinja::Environment::new();
-    chat_template_env.set_trim_blocks(true);
-    chat_template_env.add_global("bos_token", tokenizer.bos_str());
-    chat_template_env.add_global("eos_token", tokenizer.eos_str());
-    chat_template_env.add_global("add_generation_prompt", true);
-    chat_template_env
-        .add_template("main", &chat_template_str)
-        .unwrap();
+    let chat_template_env = build_chat_template_env(
+        &chat_template_str,
+        &gguf.metadata,
+        || tokenizer.bos_str().to_owned(),
+        || tokenizer.eos_str().to_owned(),
+    )
+    .unwrap();
     let chat_template = chat_template_env.get_template("main").unwrap();
 
+    let half_readback = (logits_readback_precision == LogitsPrecision::F16)
+        .then(|| HalfReadback::new(device, state.logits().buffer(), config.vocab_size));
+
     let view_shapes = ViewShapeBuffers::new();
+    let response_prefixes = response_prefixes_from_env();
+    let mut model = GpuModel {
+        gpu: &gpu,
+        transformer: &transformer,
+        state: &state,
+        weights: &weights,
+        config: &config,
+        view_shapes: &view_shapes,
+        half_readback: half_readback.as_ref(),
+        tokenizer: &tokenizer,
+        terminators: &terminators,
+        prompt_cache: prompt_cache.then(PromptCache::new),
+    };
+    MODEL_LOADED.store(true, std::sync::atomic::Ordering::Release);
 
     loop {
         match task_queue.recv().await {
             None => {
                 return;
             }
-            Some(task) => {
+            Some(mut task) => {
                 // Run the transformer.
-                let prompt_str = chat_template.render(task.as_jinja_input()).unwrap();
+                let mut prompt_str = match chat_template.render(task.as_jinja_input()) {
+                    Ok(prompt_str) => prompt_str,
+                    Err(e) => {
+                        task.failed(format!("failed to render the chat template: {e}"));
+                        continue;
+                    }
+                };
+                // The template ends the prompt with the header of a new assistant message, which
+                // the partial reply goes after so the model picks up where it stopped
+                if let Some(partial) = &task.continuation {
+                    prompt_str.push_str(partial);
+                }
+                log_prompt(log::logger(), log_prompts, task.id(), &prompt_str);
+
+                let system_prompt_tokens = task
+                    .system_prompt()
+                    .map_or(0, |system_prompt| tokenizer.encode(system_prompt).len());
+                if let Err(over_budget) = check_system_prompt_budget(
+                    system_prompt_tokens,
+                    config.seq_len,
+                    max_system_prompt_fraction,
+                ) {
+                    if reject_oversized_system_prompt {
+                        task.failed(over_budget.to_string());
+                        continue;
+                    }
+                    warn!("task {}: {over_budget}", task.id());
+                }
 
                 let prompt_tokens = tokenizer.encode(&prompt_str);
-                let mut token = prompt_tokens[0];
-                let mut logits = DVector::zeros(config.vocab_size);
+                task.started(prompt_tokens.len());
+                let max_tokens = max_tokens(
+                    task.max_tokens,
+                    default_max_tokens_config,
+                    config.seq_len,
+                    prompt_tokens.len(),
+                );
                 view_shapes.clear_tmp();
 
-                let inference_start = Instant::now();
-                let mut prefill_time = Instant::now();
-                let mut total_generated = 0;
-
-                for pos in 0.. {
-                    let is_prefill = pos < prompt_tokens.len() - 1;
-
-                    let (rope_config, rms_norm_config, attn_params) =
-                        config.derived_configs(pos as u32);
-
-                    let mut encoder = gpu.device().create_command_encoder(&Default::default());
-                    gpu.queue().write_buffer(
-                        state.rope_config().buffer(),
-                        0,
-                        bytemuck::cast_slice(&[rope_config]),
-                    );
-                    gpu.queue().write_buffer(
-                        state.rms_norm_config().buffer(),
-                        0,
-                        bytemuck::cast_slice(&[rms_norm_config]),
-                    );
-                    gpu.queue().write_buffer(
-                        state.attn_params().buffer(),
-                        0,
-                        bytemuck::cast_slice(&[attn_params]),
-                    );
-
-                    if token < (config.vocab_size / 2) {
-                        state
-                            .x
-                            .copy_from_view(&mut encoder, weights.token_embd.column(token as u32));
-                    } else {
-                        state.x.copy_from_view(
-                            &mut encoder,
-                            weights
-                                .token_embd
-                                .column((token - config.vocab_size / 2) as u32),
-                        );
-                    }
+                let mut sampler = TokenSampler::new(
+                    task.sampling.decoding_mode(decoding_mode),
+                    config.vocab_size,
+                );
+                // A continuation goes after text that already had any prefix stripped
+                let response_prefixes: &[String] = match task.continuation {
+                    Some(_) => &[],
+                    None => &response_prefixes,
+                };
+                if let Err(e) = generate(
+                    &mut task,
+                    &mut model,
+                    &prompt_tokens,
+                    max_tokens,
+                    &mut sampler,
+                    response_prefixes,
+                    gpu_retries,
+                    max_repeated_tokens,
+                    coalesce_whitespace,
+                )
+                .await
+                {
+                    task.failed(format!("{e:#}"));
+                }
+            }
+        }
+    }
+}
 
-                    if pos % 50 == 0 {
-                        if is_prefill {
-                            println!("Prefilling token {pos}");
-                        } else {
-                            println!("Generating token {pos}");
-                        }
-                    }
+/// The model on the GPU.
+struct GpuModel<'a> {
+    gpu: &'a GpuInstance,
+    transformer: &'a Llama2,
+    state: &'a Llama2State,
+    weights: &'a Llama2Weights,
+    config: &'a Llama2Config,
+    view_shapes: &'a ViewShapeBuffers,
+    half_readback: Option<&'a HalfReadback>,
+    tokenizer: &'a Gpt2Tokenizer,
+    terminators: &'a [usize],
+    /// The positions of `state`, with `PROMPT_CACHE`
+    prompt_cache: Option<PromptCache>,
+}
 
-                    let mut compute_pass = encoder.compute_pass("transformer", None);
-                    transformer.dispatch(
-                        gpu.device(),
-                        &view_shapes,
-                        gpu.queue(),
-                        &mut compute_pass,
-                        &state,
-                        &weights,
-                        &config,
-                        &attn_params,
-                        pos as u32,
-                    );
-                    drop(compute_pass);
-
-                    if !is_prefill {
-                        state
-                            .logits_readback()
-                            .copy_from(&mut encoder, state.logits());
-
-                        gpu.queue().submit(Some(encoder.finish()));
-
-                        state
-                            .logits_readback()
-                            .read_to(gpu.device(), logits.as_mut_slice())
-                            .await
-                            .unwrap();
-                    } else {
-                        gpu.queue().submit(Some(encoder.finish()));
-                    }
+impl LanguageModel for GpuModel<'_> {
+    fn vocab_size(&self) -> usize {
+        self.config.vocab_size
+    }
+
+    fn terminators(&self) -> &[usize] {
+        self.terminators
+    }
 
-                    let mut sampler = wgml::models::sampler::Sampler::new(logits.len(), 0.9, 0.95);
+    fn decode(&self, token: usize) -> String {
+        self.tokenizer.decode(&[token as u32])
+    }
 
-                    if pos + 1 >= prompt_tokens.len() {
-                        let next_token = sampler.sample(&mut logits);
+    fn cached_prefix(&self, prompt: &[usize]) -> usize {
+        self.prompt_cache
+            .as_ref()
+            .map_or(0, |cache| cache.reusable_prefix(prompt))
+    }
 
-                        if next_token == tokenizer.eos() {
-                            break;
-                        } else {
-                            let token_str = tokenizer.decode(&[next_token as u32]);
+    async fn forward(
+        &mut self,
+        token: usize,
+        pos: usize,
+        logits: Option<&mut [f32]>,
+    ) -> anyhow::Result<()> {
+        let (gpu, state, config) = (self.gpu, self.state, self.config);
+        if let Some(cache) = &mut self.prompt_cache {
+            cache.truncate(pos);
+        }
+        // Errors of the device would otherwise go to its uncaptured error handler, which panics
+        gpu.device()
+            .push_error_scope(wgpu::ErrorFilter::OutOfMemory);
+        gpu.device().push_error_scope(wgpu::ErrorFilter::Internal);
 
-                            match task.return_channel.send(token_str).await {
-                                Ok(_) => {}
-                                Err(_) => break,
-                            }
-                        }
+        let (rope_config, rms_norm_config, attn_params) = config.derived_configs(pos as u32);
 
-                        token = next_token;
-                        total_generated += 1;
-                    } else {
-                        token = prompt_tokens[pos + 1];
+        let mut encoder = gpu.device().create_command_encoder(&Default::default());
+        gpu.queue().write_buffer(
+            state.rope_config().buffer(),
+            0,
+            bytemuck::cast_slice(&[rope_config]),
+        );
+        gpu.queue().write_buffer(
+            state.rms_norm_config().buffer(),
+            0,
+            bytemuck::cast_slice(&[rms_norm_config]),
+        );
+        gpu.queue().write_buffer(
+            state.attn_params().buffer(),
+            0,
+            bytemuck::cast_slice(&[attn_params]),
+        );
 
-                        prefill_time = Instant::now();
-                    }
-                }
+        if token < (config.vocab_size / 2) {
+            state
+                .x
+                .copy_from_view(&mut encoder, self.weights.token_embd.column(token as u32));
+        } else {
+            state.x.copy_from_view(
+                &mut encoder,
+                self.weights
+                    .token_embd
+                    .column((token - config.vocab_size / 2) as u32),
+            );
+        }
 
-                let inference_end = Instant::now();
-                let total_duration = inference_end - inference_start;
-                let prefill_duration = prefill_time - inference_start;
-                let generation_duration = total_duration - prefill_duration;
+        let mut compute_pass = encoder.compute_pass("transformer", None);
+        self.transformer.dispatch(
+            gpu.device(),
+            self.view_shapes,
+            gpu.queue(),
+            &mut compute_pass,
+            state,
+            self.weights,
+            config,
+            &attn_params,
+            pos as u32,
+        );
+        drop(compute_pass);
 
-                println!(
-                    "Inference done, total time: {total_duration:?} for {total_generated} tokens."
-                );
-                println!(
-                    "Prefill time: {prefill_duration:?}, or {:.2} tokens/s",
-                    (prompt_tokens.len() as f32) / prefill_duration.as_secs_f32()
-                );
-                println!(
-                    "Generation time: {generation_duration:?} or {:.2} tokens/s",
-                    (total_generated as f32) / generation_duration.as_secs_f32()
-                );
+        let readback = match (logits, self.half_readback) {
+            (Some(logits), Some(half_readback)) => {
+                half_readback.encode(&mut encoder);
+                gpu.queue().submit(Some(encoder.finish()));
+                half_readback.read_to(gpu.device(), logits).await
+            }
+            (Some(logits), None) => {
+                state
+                    .logits_readback()
+                    .copy_from(&mut encoder, state.logits());
+                gpu.queue().submit(Some(encoder.finish()));
+                state.logits_readback().read_to(gpu.device(), logits).await
+            }
+            (None, _) => {
+                gpu.queue().submit(Some(encoder.finish()));
+                Ok(())
             }
+        };
+
+        // The scopes are popped even if the readback failed, so they don't pile up
+        let internal = gpu.device().pop_error_scope().await;
+        let out_of_memory = gpu.device().pop_error_scope().await;
+        if let Some(error) = internal.or(out_of_memory) {
+            return Err(anyhow!("GPU error: {error}"));
         }
+        if readback.is_ok()
+            && let Some(cache) = &mut self.prompt_cache
+        {
+            cache.ran(token, pos);
+        }
+        readback
     }
 }
 
@@ -283,8 +926,10 @@ pub async fn forward(transformer: &Llama2) {}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::sampling::suppress_tokens;
     use crate::infrastructure::entities;
     use chrono::Utc;
+    use sqlx::types::Json;
     use uuid::Uuid;
 
     #[test]
@@ -295,6 +940,9 @@ mod tests {
             kind: entities::MessageKind::User,
             created_at: Utc::now(),
             text: "Hello".to_string(),
+            token_count: 0,
+            attachments: Json(Vec::new()),
+            incomplete: false,
         };
 
         let chat_message: ChatMessage = user_message.into();
@@ -310,6 +958,9 @@ mod tests {
             kind: entities::MessageKind::Bot,
             created_at: Utc::now(),
             text: "Hi there!".to_string(),
+            token_count: 0,
+            attachments: Json(Vec::new()),
+            incomplete: false,
         };
 
         let chat_message: ChatMessage = bot_message.into();
@@ -325,6 +976,9 @@ mod tests {
             kind: entities::MessageKind::System,
             created_at: Utc::now(),
             text: "You are an assistant".to_string(),
+            token_count: 0,
+            attachments: Json(Vec::new()),
+            incomplete: false,
         };
 
         let chat_message: ChatMessage = system_message.into();
@@ -344,6 +998,208 @@ mod tests {
         assert!(jinja_val.as_object().is_some());
     }
 
+    #[test]
+    fn test_chat_template_env_without_bos_eos() {
+        let metadata: HashMap<String, ()> = HashMap::new();
+        let env = build_chat_template_env(
+            "{{ bos_token }}{% for m in messages %}{{ m.content }}{% endfor %}{{ eos_token }}",
+            &metadata,
+            || unreachable!("BOS is not defined"),
+            || unreachable!("EOS is not defined"),
+        )
+        .unwrap();
+
+        let (task, _) = InferenceTask::new(vec![ChatMessage {
+            role: Role::User,
+            content: "Hello".to_string(),
+        }]);
+        let prompt = env
+            .get_template("main")
+            .unwrap()
+            .render(task.as_jinja_input())
+            .unwrap();
+        assert_eq!(prompt, "Hello");
+    }
+
+    #[test]
+    fn test_chat_template_env_with_bos_eos() {
+        let metadata: HashMap<String, ()> = HashMap::from([
+            (BOS_TOKEN_ID_KEY.to_string(), ()),
+            (EOS_TOKEN_ID_KEY.to_string(), ()),
+        ]);
+        let env = build_chat_template_env(
+            "{{ bos_token }}{{ eos_token }}",
+            &metadata,
+            || "<s>".to_string(),
+            || "</s>".to_string(),
+        )
+        .unwrap();
+
+        let prompt = env.get_template("main").unwrap().render(()).unwrap();
+        assert_eq!(prompt, "<s></s>");
+    }
+
+    #[test]
+    fn test_end_tokens_are_suppressed_below_min_tokens() {
+        let (mut task, _receiver) = InferenceTask::new(Vec::new());
+        task.set_min_tokens(3);
+        task.set_stop_token_ids([4]);
+        let terminators = [2, 5];
+
+        for generated in 0..3 {
+            let mut logits = [1.0; 8];
+            suppress_tokens(&mut logits, task.suppressed_tokens(generated, &terminators));
+            assert_eq!(logits[2], f32::NEG_INFINITY);
+            assert_eq!(logits[5], f32::NEG_INFINITY);
+            assert_eq!(logits[4], f32::NEG_INFINITY);
+            assert_eq!(logits[0], 1.0);
+        }
+
+        let mut logits = [1.0; 8];
+        suppress_tokens(&mut logits, task.suppressed_tokens(3, &terminators));
+        assert_eq!(logits, [1.0; 8]);
+    }
+
+    #[test]
+    fn test_terminators_include_every_end_of_turn_id() {
+        let metadata: HashMap<String, u32> = HashMap::from([
+            ("tokenizer.ggml.bos_token_id".to_owned(), 128_000),
+            ("tokenizer.ggml.eos_token_id".to_owned(), 128_009),
+            ("tokenizer.ggml.eot_token_id".to_owned(), 128_009),
+            ("tokenizer.ggml.eom_token_id".to_owned(), 128_008),
+        ]);
+        let terminators = terminator_token_ids(&metadata, |id| *id, 128_001);
+        assert_eq!(terminators, vec![128_001, 128_008, 128_009]);
+    }
+
+    #[test]
+    fn test_terminators_without_metadata_are_the_tokenizer_eos() {
+        let metadata: HashMap<String, u32> = HashMap::new();
+        assert_eq!(terminator_token_ids(&metadata, |id| *id, 2), vec![2]);
+    }
+
+    #[test]
+    fn test_stop_token_ids() {
+        let (mut task, _) = InferenceTask::new(Vec::new());
+        assert!(!task.is_stop_token(128_009));
+
+        task.set_stop_token_ids([128_001, 128_009]);
+        assert!(task.is_stop_token(128_009));
+        assert!(!task.is_stop_token(42));
+        assert!(!task.is_stop_token(usize::MAX));
+    }
+
+    #[tokio::test]
+    async fn test_completion_tokens_are_reported_before_the_parts_end() {
+        let (mut task, mut receiver) = InferenceTask::new(Vec::new());
+        let mut completion_tokens = task.track_completion_tokens();
+
+        // Three whitespace tokens sent as one part
+        task.return_channel().send("   ".to_owned()).await.unwrap();
+        for _ in 0..3 {
+            task.generated_token();
+        }
+        drop(task);
+
+        assert_eq!(receiver.recv().await.as_deref(), Some("   "));
+        assert_eq!(receiver.recv().await, None);
+        assert_eq!(completion_tokens.try_recv(), Ok(3));
+    }
+
+    #[test]
+    fn test_default_max_tokens_far_from_context_limit() {
+        assert_eq!(default_max_tokens(1024, 32_768, 100), 1024);
+    }
+
+    #[test]
+    fn test_requested_max_tokens_is_capped_by_the_context() {
+        assert_eq!(max_tokens(Some(50), 1024, 4096, 100), 50);
+        assert_eq!(max_tokens(Some(8192), 1024, 4096, 3900), 196);
+        assert_eq!(max_tokens(Some(8192), 1024, 4096, 5000), 0);
+        assert_eq!(max_tokens(None, 1024, 4096, 3900), 196);
+    }
+
+    #[test]
+    fn test_default_max_tokens_near_context_limit() {
+        assert_eq!(default_max_tokens(1024, 4096, 3900), 196);
+        assert_eq!(default_max_tokens(1024, 4096, 4096), 0);
+        assert_eq!(default_max_tokens(1024, 4096, 5000), 0);
+    }
+
+    #[test]
+    fn test_system_prompt_over_its_fraction_of_the_context() {
+        assert_eq!(check_system_prompt_budget(2_048, 4_096, 0.5), Ok(()));
+        assert_eq!(
+            check_system_prompt_budget(3_000, 4_096, 0.5),
+            Err(SystemPromptOverBudget {
+                tokens: 3_000,
+                budget: 2_048
+            })
+        );
+    }
+
+    #[test]
+    fn test_system_prompt_is_the_first_system_message() {
+        let (task, _receiver) = InferenceTask::new(vec![
+            ChatMessage::new(Role::System, "Be brief.".to_owned()),
+            ChatMessage::new(Role::User, "Hi!".to_owned()),
+        ]);
+        assert_eq!(task.system_prompt(), Some("Be brief."));
+
+        let (task, _receiver) =
+            InferenceTask::new(vec![ChatMessage::new(Role::User, "Hi!".to_owned())]);
+        assert_eq!(task.system_prompt(), None);
+    }
+
+    #[test]
+    fn test_model_fingerprint_hashes_key_metadata_only() {
+        let metadata = HashMap::from([
+            ("general.name".to_string(), "Llama"),
+            ("llama.context_length".to_string(), "131072"),
+            ("tokenizer.ggml.tokens".to_string(), "a b c"),
+        ]);
+        let fingerprint = model_fingerprint("models/llama.gguf", &metadata);
+        assert!(fingerprint.starts_with("llama.gguf:"));
+
+        let mut other_tokens = metadata.clone();
+        other_tokens.insert("tokenizer.ggml.tokens".to_string(), "x y z");
+        assert_eq!(
+            model_fingerprint("models/llama.gguf", &other_tokens),
+            fingerprint
+        );
+
+        let mut other_context = metadata.clone();
+        other_context.insert("llama.context_length".to_string(), "8192");
+        assert_ne!(
+            model_fingerprint("models/llama.gguf", &other_context),
+            fingerprint
+        );
+    }
+
+    #[test]
+    fn test_dominant_quantization_weighs_by_elements() {
+        #[derive(Debug)]
+        enum TensorType {
+            F32,
+            Q4K,
+            Q6K,
+        }
+
+        // Like a Q4_K_M model: mostly Q4_K, with some Q6_K matrices and F32 norms
+        let tensors = HashMap::from([
+            ("blk.0.attn_q.weight", (TensorType::Q4K, 3072 * 3072)),
+            ("blk.0.ffn_up.weight", (TensorType::Q4K, 3072 * 8192)),
+            ("blk.0.ffn_down.weight", (TensorType::Q6K, 8192 * 3072)),
+            ("blk.0.attn_norm.weight", (TensorType::F32, 3072)),
+        ]);
+
+        assert_eq!(
+            dominant_quantization(tensors.into_values()),
+            Some("Q4K".to_string())
+        );
+        assert_eq!(dominant_quantization(Vec::<(TensorType, u64)>::new()), None);
+    }
+
     #[tokio::test]
     async fn test_inference_task_new_creates_channel() {
         let messages = vec![ChatMessage {
@@ -380,4 +1236,67 @@ mod tests {
         // Verify it's structured properly
         assert!(jinja_input.as_object().is_some());
     }
+
+    #[test]
+    fn test_context_goes_before_the_last_message() {
+        let (mut task, _) = InferenceTask::new(vec![
+            ChatMessage::new(Role::System, "System prompt".to_string()),
+            ChatMessage::new(Role::User, "Question".to_string()),
+        ]);
+        task.set_context("Documents".to_string());
+
+        let env = minijinja::Environment::new();
+        let prompt = env
+            .render_str(
+                "{% for m in messages %}{{ m.role }}: {{ m.content }};{% endfor %}",
+                task.as_jinja_input(),
+            )
+            .unwrap();
+        assert_eq!(
+            prompt,
+            "system: System prompt;system: Documents;user: Question;"
+        );
+        assert_eq!(task.messages().len(), 2);
+    }
+
+    /// Keeps the messages of the records it is given.
+    #[derive(Default)]
+    struct RecordingLogger {
+        records: std::sync::Mutex<Vec<(log::Level, String, String)>>,
+    }
+
+    impl Log for RecordingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.records.lock().unwrap().push((
+                record.level(),
+                record.target().to_owned(),
+                record.args().to_string(),
+            ));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_prompt_is_logged_only_when_enabled() {
+        log::set_max_level(log::LevelFilter::Debug);
+        let task_id = Uuid::new_v4();
+
+        let logger = RecordingLogger::default();
+        log_prompt(&logger, false, task_id, "user: secret");
+        assert!(logger.records.lock().unwrap().is_empty());
+
+        log_prompt(&logger, true, task_id, "user: secret");
+        let records = logger.records.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        let (level, target, message) = &records[0];
+        assert_eq!(*level, log::Level::Debug);
+        assert_eq!(target, PROMPT_LOG_TARGET);
+        assert!(message.contains(&task_id.to_string()));
+        assert!(message.ends_with("user: secret"));
+    }
 }
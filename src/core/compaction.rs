@@ -0,0 +1,133 @@
+//! Conversation compaction.
+//!
+//! Long conversations make every prompt longer. Compaction replaces the oldest turns of a
+//! conversation with a single system message holding a model-written summary of them, while the
+//! leading system prompt and the most recent turns are kept verbatim.
+
+use crate::core::assistant::{ChatMessage, Role};
+use crate::infrastructure::entities::{Message, MessageKind};
+
+/// Number of recent turns kept verbatim when the client doesn't say otherwise.
+pub const DEFAULT_KEEP_TURNS: usize = 4;
+
+/// Prefix of the stored summary message, so the model can tell it apart from the system prompt.
+pub const SUMMARY_PREFIX: &str = "Summary of the earlier conversation:\n";
+
+const SUMMARY_PROMPT: &str = r#"You summarize conversations between a user and an AI Assistant.
+Write a short summary of the conversation you are given, keeping every fact, name, decision and open question the Assistant needs to continue it.
+You MUST ONLY produce the summary as plain text.
+"#;
+
+/// Returns the messages that compaction replaces: everything between the leading system
+/// messages and the last `keep_turns` turns, where a turn starts at a user message.
+///
+/// Empty if the conversation has no more than `keep_turns` turns.
+pub fn messages_to_compact(messages: &[Message], keep_turns: usize) -> &[Message] {
+    let start = messages
+        .iter()
+        .position(|message| !matches!(message.kind, MessageKind::System))
+        .unwrap_or(messages.len());
+
+    let turn_starts: Vec<usize> = messages
+        .iter()
+        .enumerate()
+        .skip(start)
+        .filter(|(_, message)| matches!(message.kind, MessageKind::User))
+        .map(|(index, _)| index)
+        .collect();
+
+    if turn_starts.len() <= keep_turns {
+        return &[];
+    }
+
+    let end = match keep_turns {
+        0 => messages.len(),
+        keep_turns => turn_starts[turn_starts.len() - keep_turns],
+    };
+
+    &messages[start..end]
+}
+
+/// Builds the conversation the inference engine is asked to summarize `messages` with.
+pub fn summary_request(messages: &[Message]) -> Vec<ChatMessage> {
+    let transcript = messages
+        .iter()
+        .map(|message| {
+            let speaker = match message.kind {
+                MessageKind::System => "System",
+                MessageKind::User => "User",
+                MessageKind::Bot => "Assistant",
+            };
+            format!("{speaker}: {}", message.text)
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+
+    vec![
+        ChatMessage::new(Role::System, SUMMARY_PROMPT.to_owned()),
+        ChatMessage::new(Role::User, transcript),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use sqlx::types::Json;
+    use uuid::Uuid;
+
+    fn message(kind: MessageKind, text: &str) -> Message {
+        Message {
+            id: Uuid::new_v4(),
+            conversation_id: Uuid::nil(),
+            kind,
+            created_at: Utc::now(),
+            text: text.to_owned(),
+            token_count: 0,
+            attachments: Json(Vec::new()),
+            incomplete: false,
+        }
+    }
+
+    fn texts(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|m| m.text.as_str()).collect()
+    }
+
+    fn conversation() -> Vec<Message> {
+        vec![
+            message(MessageKind::System, "prompt"),
+            message(MessageKind::User, "u1"),
+            message(MessageKind::Bot, "b1"),
+            message(MessageKind::User, "u2"),
+            message(MessageKind::Bot, "b2"),
+            message(MessageKind::User, "u3"),
+            message(MessageKind::Bot, "b3"),
+        ]
+    }
+
+    #[test]
+    fn test_compacts_all_but_recent_turns() {
+        let messages = conversation();
+        assert_eq!(
+            texts(messages_to_compact(&messages, 1)),
+            ["u1", "b1", "u2", "b2"]
+        );
+        assert_eq!(texts(messages_to_compact(&messages, 2)), ["u1", "b1"]);
+    }
+
+    #[test]
+    fn test_compacts_nothing_in_short_conversations() {
+        let messages = conversation();
+        assert!(messages_to_compact(&messages, 3).is_empty());
+        assert!(messages_to_compact(&messages, 10).is_empty());
+    }
+
+    #[test]
+    fn test_keeping_no_turns_compacts_everything_but_the_system_prompt() {
+        let messages = conversation();
+        assert_eq!(
+            texts(messages_to_compact(&messages, 0)),
+            ["u1", "b1", "u2", "b2", "u3", "b3"]
+        );
+    }
+}
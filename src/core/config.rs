@@ -0,0 +1,475 @@
+//! Runtime configuration read from the environment.
+
+use crate::core::assistant::model_file_name;
+use crate::core::gpu::InferenceBackend;
+use crate::core::logits_readback::LogitsPrecision;
+use crate::core::sampling::{DecodingMode, SamplingLimits, SamplingParams, SamplingPreset};
+use di::{inject, injectable};
+use log::warn;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The configuration the server runs with.
+#[derive(Serialize, Debug, Clone)]
+pub struct AppConfig {
+    /// Path of the GGUF model, `MODEL_FILE_NAME`.
+    pub model_file_name: String,
+    /// Maximum context size in tokens, `CONTEXT_SIZE`. The model's own limit applies if lower.
+    pub context_size: usize,
+    /// Tokens generated when a request sets no limit, `DEFAULT_MAX_TOKENS`.
+    pub default_max_tokens: usize,
+    /// Number of generations that can wait for the inference worker, `QUEUE_SIZE`.
+    pub queue_size: usize,
+    /// See [`DecodingMode::from_env`].
+    pub decoding_mode: DecodingMode,
+    /// See [`LogitsPrecision::from_env`].
+    pub logits_readback_precision: LogitsPrecision,
+    /// See [`InferenceBackend::from_env`].
+    pub inference_backend: InferenceBackend,
+    /// Address the web server listens on, `BIND_ADDRESS`.
+    pub bind_address: String,
+    /// Address the gRPC server listens on, `GRPC_BIND_ADDRESS`. See [`crate::api::grpc`].
+    #[cfg(feature = "grpc")]
+    pub grpc_bind_address: String,
+    /// Token the admin endpoints require, `ADMIN_TOKEN`. The admin endpoints are disabled
+    /// without one.
+    #[serde(serialize_with = "redacted")]
+    pub admin_token: Option<String>,
+    /// See [`single_user_mode`].
+    pub single_user_mode: bool,
+    /// See [`max_conversations_per_user`].
+    pub max_conversations_per_user: Option<usize>,
+    /// See [`max_messages_per_conversation`].
+    pub max_messages_per_conversation: Option<usize>,
+    /// See [`max_concurrent_generations_per_user`].
+    pub max_concurrent_generations_per_user: Option<usize>,
+    /// See [`sse_retry`].
+    pub sse_retry_ms: u64,
+    /// See [`sse_ping_interval`].
+    pub sse_ping_interval_ms: u64,
+    /// See [`default_persona`].
+    pub default_persona: Option<String>,
+    /// See [`response_prefix`].
+    pub response_prefix: Option<String>,
+    /// See [`response_suffix`].
+    pub response_suffix: Option<String>,
+    /// See [`replay_chars_per_event`].
+    pub replay_chars_per_event: usize,
+    /// See [`max_completion_choices`].
+    pub max_completion_choices: usize,
+    /// See [`log_prompts`].
+    pub log_prompts: bool,
+    /// See [`log_requests_to_db`].
+    pub log_requests_to_db: bool,
+    /// See [`vacuum_on_optimize`].
+    pub vacuum_on_optimize: bool,
+    /// See [`auto_migrate`].
+    pub auto_migrate: bool,
+    /// See [`store_thinking`].
+    pub store_thinking: bool,
+    /// See [`prompt_cache`].
+    pub prompt_cache: bool,
+    /// See [`max_system_prompt_fraction`].
+    pub max_system_prompt_fraction: f32,
+    /// See [`reject_oversized_system_prompt`].
+    pub reject_oversized_system_prompt: bool,
+    /// See [`gpu_retries`].
+    pub gpu_retries: usize,
+    /// See [`disable_inference`].
+    pub disable_inference: bool,
+    /// See [`max_repeated_tokens`].
+    pub max_repeated_tokens: Option<usize>,
+    /// See [`coalesce_whitespace`].
+    pub coalesce_whitespace: bool,
+    /// See [`max_tokens_per_sec_per_stream`].
+    pub max_tokens_per_sec_per_stream: Option<usize>,
+    /// See [`sampling_preset`].
+    pub sampling_presets: BTreeMap<SamplingPreset, SamplingParams>,
+    /// See [`sampling_limits`].
+    pub sampling_limits: SamplingLimits,
+}
+
+#[injectable]
+impl AppConfig {
+    #[inject]
+    pub fn create() -> AppConfig {
+        dotenvy::dotenv().ok();
+        AppConfig::from_env()
+    }
+}
+
+impl AppConfig {
+    pub fn from_env() -> AppConfig {
+        AppConfig {
+            model_file_name: model_file_name(),
+            context_size: context_size(),
+            default_max_tokens: default_max_tokens(),
+            queue_size: env_usize("QUEUE_SIZE", 10),
+            decoding_mode: DecodingMode::from_env(),
+            logits_readback_precision: LogitsPrecision::from_env(),
+            inference_backend: InferenceBackend::from_env(),
+            bind_address: std::env::var("BIND_ADDRESS").unwrap_or("0.0.0.0:3000".to_owned()),
+            #[cfg(feature = "grpc")]
+            grpc_bind_address: std::env::var("GRPC_BIND_ADDRESS")
+                .unwrap_or("0.0.0.0:50051".to_owned()),
+            admin_token: non_empty_env("ADMIN_TOKEN"),
+            single_user_mode: single_user_mode(),
+            max_conversations_per_user: max_conversations_per_user(),
+            max_messages_per_conversation: max_messages_per_conversation(),
+            max_concurrent_generations_per_user: max_concurrent_generations_per_user(),
+            sse_retry_ms: sse_retry().as_millis() as u64,
+            sse_ping_interval_ms: sse_ping_interval().as_millis() as u64,
+            default_persona: default_persona(),
+            response_prefix: response_prefix(),
+            response_suffix: response_suffix(),
+            replay_chars_per_event: replay_chars_per_event(),
+            max_completion_choices: max_completion_choices(),
+            log_prompts: log_prompts(),
+            log_requests_to_db: log_requests_to_db(),
+            vacuum_on_optimize: vacuum_on_optimize(),
+            auto_migrate: auto_migrate(),
+            store_thinking: store_thinking(),
+            prompt_cache: prompt_cache(),
+            max_system_prompt_fraction: max_system_prompt_fraction(),
+            reject_oversized_system_prompt: reject_oversized_system_prompt(),
+            gpu_retries: gpu_retries(),
+            disable_inference: disable_inference(),
+            max_repeated_tokens: max_repeated_tokens(),
+            coalesce_whitespace: coalesce_whitespace(),
+            max_tokens_per_sec_per_stream: max_tokens_per_sec_per_stream(),
+            sampling_presets: SamplingPreset::ALL
+                .into_iter()
+                .map(|preset| (preset, sampling_preset(preset)))
+                .collect(),
+            sampling_limits: sampling_limits(),
+        }
+    }
+}
+
+/// Maximum context size in tokens, `CONTEXT_SIZE`. Defaults to 32768.
+pub fn context_size() -> usize {
+    env_usize("CONTEXT_SIZE", 32_768)
+}
+
+/// Tokens generated when a request sets no limit, `DEFAULT_MAX_TOKENS`. Defaults to 1024.
+pub fn default_max_tokens() -> usize {
+    env_usize("DEFAULT_MAX_TOKENS", 1_024)
+}
+
+/// Tokens of conversation history a prompt can take: the context minus the room
+/// [`default_max_tokens`] leaves for the reply.
+pub fn prompt_token_budget() -> usize {
+    context_size().saturating_sub(default_max_tokens())
+}
+
+/// How many conversations a user can have, `MAX_CONVERSATIONS_PER_USER`. No limit when it is
+/// unset or `0`.
+pub fn max_conversations_per_user() -> Option<usize> {
+    Some(env_usize("MAX_CONVERSATIONS_PER_USER", 0)).filter(|limit| *limit > 0)
+}
+
+/// How many messages a conversation can have before it takes no more user messages,
+/// `MAX_MESSAGES_PER_CONVERSATION`. The system prompt counts too. No limit when it is unset or
+/// `0`.
+pub fn max_messages_per_conversation() -> Option<usize> {
+    Some(env_usize("MAX_MESSAGES_PER_CONVERSATION", 0)).filter(|limit| *limit > 0)
+}
+
+/// How many generations a user can have queued or running at once,
+/// `MAX_CONCURRENT_GENERATIONS_PER_USER`. Requests for more are answered with 429. No limit when
+/// it is unset or `0`.
+pub fn max_concurrent_generations_per_user() -> Option<usize> {
+    Some(env_usize("MAX_CONCURRENT_GENERATIONS_PER_USER", 0)).filter(|limit| *limit > 0)
+}
+
+/// How long a browser waits before reconnecting a dropped SSE stream, `SSE_RETRY_MS`. Sent once
+/// at the start of each stream. Defaults to 3 seconds.
+pub fn sse_retry() -> Duration {
+    Duration::from_millis(env_usize("SSE_RETRY_MS", 3_000) as u64)
+}
+
+/// How often SSE streams send a `ping` event to keep idle connections open,
+/// `SSE_PING_INTERVAL_MS`. Defaults to 15 seconds.
+pub fn sse_ping_interval() -> Duration {
+    Duration::from_millis(env_usize("SSE_PING_INTERVAL_MS", 15_000).max(1) as u64)
+}
+
+/// Name of the persona whose system prompt conversations created without a persona get,
+/// `DEFAULT_PERSONA`. It must be one of the personas in `PERSONAS_FILE`, see
+/// [`crate::core::personas`]. Without it they get a built-in prompt for a professional assistant.
+pub fn default_persona() -> Option<String> {
+    non_empty_env("DEFAULT_PERSONA")
+}
+
+/// Text every new assistant reply starts with, `RESPONSE_PREFIX`. It is streamed and saved with
+/// the reply, but isn't generated by the model, so it doesn't count as completion tokens. Not to be
+/// confused with `RESPONSE_PREFIXES`, which are stripped from what the model generates.
+pub fn response_prefix() -> Option<String> {
+    non_empty_env("RESPONSE_PREFIX")
+}
+
+/// Text every new assistant reply ends with, e.g. a disclaimer, `RESPONSE_SUFFIX`. Like
+/// [`response_prefix`], it doesn't count as completion tokens.
+pub fn response_suffix() -> Option<String> {
+    non_empty_env("RESPONSE_SUFFIX")
+}
+
+/// Number of characters per `message_part` event when a stored message is replayed,
+/// `REPLAY_CHARS_PER_EVENT`. Defaults to 16.
+pub fn replay_chars_per_event() -> usize {
+    env_usize("REPLAY_CHARS_PER_EVENT", 16).max(1)
+}
+
+/// Most completions one `/v1/chat/completions` request can ask for with `n`,
+/// `MAX_COMPLETION_CHOICES`. They are generated one after another, so each keeps the worker busy
+/// for a whole generation. Defaults to 4.
+pub fn max_completion_choices() -> usize {
+    env_usize("MAX_COMPLETION_CHOICES", 4)
+}
+
+/// Whether requests without an `X-User-ID` are made as the default user, `SINGLE_USER_MODE`.
+/// Off by default.
+pub fn single_user_mode() -> bool {
+    matches!(
+        std::env::var("SINGLE_USER_MODE").as_deref(),
+        Ok("true") | Ok("1")
+    )
+}
+
+/// Whether the inference worker logs every rendered prompt, `LOG_PROMPTS`. For debugging prompt
+/// construction only: prompts contain user data. Off by default.
+pub fn log_prompts() -> bool {
+    matches!(
+        std::env::var("LOG_PROMPTS").as_deref(),
+        Ok("true") | Ok("1")
+    )
+}
+
+/// Whether every generation of the conversation endpoints is stored in the `request_log` table,
+/// `LOG_REQUESTS_TO_DB`: its prompt messages, sampling parameters, reply, outcome and timestamps,
+/// so it can be replayed against another model later. The log contains user data, so it is off
+/// by default.
+pub fn log_requests_to_db() -> bool {
+    matches!(
+        std::env::var("LOG_REQUESTS_TO_DB").as_deref(),
+        Ok("true") | Ok("1")
+    )
+}
+
+/// Whether `POST /admin/db/optimize` also runs `VACUUM`, `VACUUM_ON_OPTIMIZE`. It shrinks a
+/// fragmented database file, but locks the whole database while it copies it. Off by default.
+pub fn vacuum_on_optimize() -> bool {
+    matches!(
+        std::env::var("VACUUM_ON_OPTIMIZE").as_deref(),
+        Ok("true") | Ok("1")
+    )
+}
+
+/// Whether the server applies pending database migrations at startup, `AUTO_MIGRATE`. On by
+/// default; when turned off, a database that is missing migrations stops the server with the
+/// list of them, so the schema can be upgraded deliberately.
+pub fn auto_migrate() -> bool {
+    !matches!(
+        std::env::var("AUTO_MIGRATE").as_deref(),
+        Ok("false") | Ok("0")
+    )
+}
+
+/// Whether the thinking section of a reply is saved with the message, in its `thinking` column,
+/// `STORE_THINKING`. Only applies when the thinking is split from the answer, see
+/// [`crate::core::thinking`]. Off by default, only the answer is saved.
+pub fn store_thinking() -> bool {
+    matches!(
+        std::env::var("STORE_THINKING").as_deref(),
+        Ok("true") | Ok("1")
+    )
+}
+
+/// Whether a prompt that starts like the previous one skips the forward passes of the shared
+/// prefix, reusing the keys and values the previous generation left in the model state,
+/// `PROMPT_CACHE`. See [`crate::core::prompt_cache`]. Off by default.
+pub fn prompt_cache() -> bool {
+    matches!(
+        std::env::var("PROMPT_CACHE").as_deref(),
+        Ok("true") | Ok("1")
+    )
+}
+
+/// Largest share of the context the system prompt of a generation should take,
+/// `MAX_SYSTEM_PROMPT_FRACTION`, between 0 and 1. The system prompt is always kept whole, so a
+/// larger one leaves less room for the conversation and the reply. Defaults to 0.5.
+pub fn max_system_prompt_fraction() -> f32 {
+    std::env::var("MAX_SYSTEM_PROMPT_FRACTION")
+        .ok()
+        .and_then(|s| f32::from_str(&s).ok())
+        .filter(|fraction| (0.0..=1.0).contains(fraction))
+        .unwrap_or(0.5)
+}
+
+/// Whether a generation whose system prompt takes more than [`max_system_prompt_fraction`] of
+/// the context fails, `REJECT_OVERSIZED_SYSTEM_PROMPT`. Otherwise it is only logged as a warning.
+/// Off by default.
+pub fn reject_oversized_system_prompt() -> bool {
+    matches!(
+        std::env::var("REJECT_OVERSIZED_SYSTEM_PROMPT").as_deref(),
+        Ok("true") | Ok("1")
+    )
+}
+
+/// Times the forward pass of a position is run again after a GPU error before the generation
+/// fails, `GPU_RETRIES`. Defaults to 2.
+pub fn gpu_retries() -> usize {
+    env_usize("GPU_RETRIES", 2)
+}
+
+/// Most message parts a second streamed to a client, `MAX_TOKENS_PER_SEC_PER_STREAM`. Each part
+/// is a token. A faster generation is buffered and delivered at this pace, which smooths the
+/// rendering in clients and keeps the worker free for other users. Not limited when it is unset
+/// or `0`.
+pub fn max_tokens_per_sec_per_stream() -> Option<usize> {
+    Some(env_usize("MAX_TOKENS_PER_SEC_PER_STREAM", 0)).filter(|limit| *limit > 0)
+}
+
+/// How many times in a row the same token can be generated before the generation is aborted as
+/// collapsed into repeating it, `MAX_REPEATED_TOKENS`. Not checked when it is unset or `0`.
+pub fn max_repeated_tokens() -> Option<usize> {
+    Some(env_usize("MAX_REPEATED_TOKENS", 0)).filter(|limit| *limit > 0)
+}
+
+/// Whether the parts of a generation hold back the whitespace they end with until the next part,
+/// so a run of spaces and newlines is never split across events, `COALESCE_WHITESPACE`. Helps
+/// clients that render Markdown or code incrementally. Off by default, every token is sent as
+/// soon as it is generated.
+pub fn coalesce_whitespace() -> bool {
+    matches!(
+        std::env::var("COALESCE_WHITESPACE").as_deref(),
+        Ok("true") | Ok("1")
+    )
+}
+
+/// Whether the server runs without a model, `DISABLE_INFERENCE`. Every generation is answered
+/// with a canned reply instead, see [`crate::core::stub_inference`]. For working on the HTTP and
+/// database layers without a GPU. Off by default.
+pub fn disable_inference() -> bool {
+    matches!(
+        std::env::var("DISABLE_INFERENCE").as_deref(),
+        Ok("true") | Ok("1")
+    )
+}
+
+/// The sampling parameters of `preset`, `SAMPLING_PRESET_<NAME>` like
+/// `SAMPLING_PRESET_CREATIVE=temperature=1.2,top_p=0.98`, see [`SamplingParams::parse`]. The
+/// parameters it leaves out are taken from the preset's defaults, which are used whole if the
+/// variable is unset or invalid.
+pub fn sampling_preset(preset: SamplingPreset) -> SamplingParams {
+    let key = format!("SAMPLING_PRESET_{}", preset.to_string().to_uppercase());
+    let Some(params) = non_empty_env(&key) else {
+        return preset.default_params();
+    };
+    match SamplingParams::parse(&params) {
+        Ok(params) => params.or(preset.default_params()),
+        Err(e) => {
+            warn!("invalid {key}, using the defaults of the preset: {e}");
+            preset.default_params()
+        }
+    }
+}
+
+/// How many stop sequences and logit biases a request can set: `MAX_STOP_SEQUENCES` sequences,
+/// defaulting to 4, of `MAX_STOP_SEQUENCES_LENGTH` bytes together, defaulting to 256, and
+/// `MAX_LOGIT_BIAS` tokens, defaulting to 300. Every generated token is matched against the stop
+/// sequences and every logit bias is added before sampling it, so these bound the cost a request
+/// adds to each token.
+pub fn sampling_limits() -> SamplingLimits {
+    let defaults = SamplingLimits::default();
+    SamplingLimits {
+        max_stop_sequences: env_usize("MAX_STOP_SEQUENCES", defaults.max_stop_sequences),
+        max_stop_sequences_length: env_usize(
+            "MAX_STOP_SEQUENCES_LENGTH",
+            defaults.max_stop_sequences_length,
+        ),
+        max_logit_bias: env_usize("MAX_LOGIT_BIAS", defaults.max_logit_bias),
+    }
+}
+
+/// The sampling parameters of a request: the ones it sets explicitly, and the rest from its
+/// preset, if any.
+pub fn resolve_sampling(
+    preset: Option<SamplingPreset>,
+    explicit: SamplingParams,
+) -> SamplingParams {
+    match preset {
+        Some(preset) => explicit.or(sampling_preset(preset)),
+        None => explicit,
+    }
+}
+
+fn non_empty_env(key: &str) -> Option<String> {
+    std::env::var(key).ok().filter(|value| !value.is_empty())
+}
+
+fn env_usize(key: &str, default: usize) -> usize {
+    std::env::var(key)
+        .ok()
+        .and_then(|s| usize::from_str(&s).ok())
+        .unwrap_or(default)
+}
+
+/// Serializes a secret as `"[redacted]"`, or `null` when it isn't set.
+fn redacted<S: Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match secret {
+        Some(_) => serializer.serialize_str("[redacted]"),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preset_resolves_to_the_configured_params() {
+        unsafe { std::env::set_var("SAMPLING_PRESET_PRECISE", "temperature=0.2, top_p=0.5") };
+        let params = resolve_sampling(Some(SamplingPreset::Precise), SamplingParams::default());
+        unsafe { std::env::remove_var("SAMPLING_PRESET_PRECISE") };
+
+        // What the configuration leaves out comes from the preset's defaults
+        assert_eq!(
+            params,
+            SamplingParams {
+                temperature: Some(0.2),
+                top_p: Some(0.5),
+                ..SamplingPreset::Precise.default_params()
+            }
+        );
+    }
+
+    #[test]
+    fn test_explicit_params_override_the_preset() {
+        let explicit = SamplingParams {
+            temperature: Some(0.7),
+            ..SamplingParams::default()
+        };
+        let params = resolve_sampling(Some(SamplingPreset::Creative), explicit.clone());
+
+        // `SAMPLING_PRESET_CREATIVE` is unset
+        let creative = SamplingPreset::Creative.default_params();
+        assert_eq!(params.temperature, Some(0.7));
+        assert_eq!(params.top_p, creative.top_p);
+        assert_eq!(params.repetition_penalty, creative.repetition_penalty);
+
+        // Without a preset only the explicit params are set
+        assert_eq!(resolve_sampling(None, explicit.clone()), explicit);
+    }
+
+    #[test]
+    fn test_invalid_preset_configuration_uses_the_defaults() {
+        unsafe { std::env::set_var("SAMPLING_PRESET_BALANCED", "temperature=hot") };
+        let params = sampling_preset(SamplingPreset::Balanced);
+        unsafe { std::env::remove_var("SAMPLING_PRESET_BALANCED") };
+
+        assert_eq!(params, SamplingPreset::Balanced.default_params());
+    }
+}
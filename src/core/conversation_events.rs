@@ -0,0 +1,82 @@
+//! Per-conversation broadcast of generation events.
+//!
+//! Every generation publishes its message parts to its conversation's channel, in addition to
+//! streaming them to the client that asked for it, so other clients of the same conversation can
+//! follow along. A channel exists only while it has subscribers.
+
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// Number of events buffered for a subscriber before it starts missing them.
+const CHANNEL_CAPACITY: usize = 256;
+
+static CHANNELS: LazyLock<Mutex<HashMap<Uuid, broadcast::Sender<ConversationEvent>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConversationEvent {
+    /// The next part of a message being generated
+    Part { message_id: Uuid, text: String },
+    /// The message has been generated in full
+    Done { message_id: Uuid },
+}
+
+/// Subscribes to the events of the conversation from now on.
+pub fn subscribe(conversation_id: Uuid) -> broadcast::Receiver<ConversationEvent> {
+    CHANNELS
+        .lock()
+        .unwrap()
+        .entry(conversation_id)
+        .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+        .subscribe()
+}
+
+/// Sends the event to the current subscribers of the conversation, if any.
+pub fn publish(conversation_id: Uuid, event: ConversationEvent) {
+    let mut channels = CHANNELS.lock().unwrap();
+    if let Some(sender) = channels.get(&conversation_id)
+        && sender.send(event).is_err()
+    {
+        // Every subscriber has gone away
+        channels.remove(&conversation_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_subscribers_receive_published_events() {
+        let conversation_id = Uuid::new_v4();
+        let message_id = Uuid::new_v4();
+        let mut first = subscribe(conversation_id);
+        let mut second = subscribe(conversation_id);
+
+        let part = ConversationEvent::Part {
+            message_id,
+            text: "Hello".to_owned(),
+        };
+        publish(conversation_id, part.clone());
+
+        assert_eq!(first.recv().await.unwrap(), part);
+        assert_eq!(second.recv().await.unwrap(), part);
+    }
+
+    #[test]
+    fn test_channel_is_removed_without_subscribers() {
+        let conversation_id = Uuid::new_v4();
+        drop(subscribe(conversation_id));
+
+        publish(
+            conversation_id,
+            ConversationEvent::Done {
+                message_id: Uuid::new_v4(),
+            },
+        );
+
+        assert!(!CHANNELS.lock().unwrap().contains_key(&conversation_id));
+    }
+}
@@ -0,0 +1,88 @@
+//! Per-conversation locks held for the duration of a generation.
+//!
+//! A generation reads the conversation's messages when it starts and saves its reply when it
+//! ends. Two generations in the same conversation at once would each answer a history without
+//! the other's reply, so a generation holds its conversation's lock until its reply is saved.
+
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use tokio::sync::OwnedMutexGuard;
+use uuid::Uuid;
+
+/// Locks of the conversations that have a generation running or waiting.
+static LOCKS: LazyLock<Mutex<HashMap<Uuid, Arc<tokio::sync::Mutex<()>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Holds a conversation's lock until dropped.
+pub struct ConversationLock {
+    conversation_id: Uuid,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for ConversationLock {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+
+        // The map only keeps locks somebody holds or waits for
+        let mut locks = LOCKS.lock().unwrap();
+        if let Some(lock) = locks.get(&self.conversation_id)
+            && Arc::strong_count(lock) == 1
+        {
+            locks.remove(&self.conversation_id);
+        }
+    }
+}
+
+fn conversation_mutex(conversation_id: Uuid) -> Arc<tokio::sync::Mutex<()>> {
+    LOCKS
+        .lock()
+        .unwrap()
+        .entry(conversation_id)
+        .or_default()
+        .clone()
+}
+
+/// Waits until no other generation holds the conversation's lock, and takes it.
+pub async fn lock(conversation_id: Uuid) -> ConversationLock {
+    let guard = conversation_mutex(conversation_id).lock_owned().await;
+    ConversationLock {
+        conversation_id,
+        guard: Some(guard),
+    }
+}
+
+/// Takes the conversation's lock, or returns `None` if another generation holds it.
+pub fn try_lock(conversation_id: Uuid) -> Option<ConversationLock> {
+    let guard = conversation_mutex(conversation_id).try_lock_owned().ok();
+    // Dropping the lock also forgets the mutex if nobody else uses it
+    let lock = ConversationLock {
+        conversation_id,
+        guard,
+    };
+    lock.guard.is_some().then_some(lock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lock_is_exclusive_per_conversation() {
+        let conversation_id = Uuid::new_v4();
+        let lock = try_lock(conversation_id).unwrap();
+
+        assert!(try_lock(conversation_id).is_none());
+        assert!(try_lock(Uuid::new_v4()).is_some());
+
+        drop(lock);
+        assert!(try_lock(conversation_id).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_released_locks_are_forgotten() {
+        let conversation_id = Uuid::new_v4();
+        drop(lock(conversation_id).await);
+
+        assert!(!LOCKS.lock().unwrap().contains_key(&conversation_id));
+    }
+}
@@ -0,0 +1,582 @@
+//! The token loop of a generation, independent of how the model runs.
+//!
+//! A forward pass can fail transiently, e.g. when the GPU device is lost for a moment. The pass of
+//! the failed position is run again from the same token, up to `GPU_RETRIES` times, which
+//! recomputes its attention keys and values too. A generation that still fails ends with an error
+//! and the worker goes on with the next task.
+//!
+//! A model can also collapse into generating the same token over and over, especially with bad
+//! sampling parameters. With `MAX_REPEATED_TOKENS`, such a generation is stopped, see
+//! [`RepetitionDetector`].
+//!
+//! The stop sequences of a request end the generation once generated, see
+//! [`StopSequenceMatcher`].
+//!
+//! With `COALESCE_WHITESPACE`, a run of whitespace is never split across parts, see
+//! [`WhitespaceCoalescer`].
+//!
+//! A model that still holds the keys and values of the start of the prompt from an earlier
+//! generation skips the passes of those positions, see [`LanguageModel::cached_prefix`].
+
+use crate::core::assistant::InferenceTask;
+use crate::core::inference_events::CompletionReason;
+use crate::core::response_prefix::PrefixStripper;
+use crate::core::sampling::{
+    TokenSampler, apply_logit_bias, apply_repetition_penalty, suppress_tokens,
+};
+use log::{debug, warn};
+use nalgebra::DVector;
+use tokio::time::Instant;
+
+/// A model that generates one token position at a time.
+pub trait LanguageModel {
+    fn vocab_size(&self) -> usize;
+
+    /// The tokens that end the reply: the end of sequence token and any end of turn tokens.
+    fn terminators(&self) -> &[usize];
+
+    fn decode(&self, token: usize) -> String;
+
+    /// Number of leading positions of `prompt` the model still holds the keys and values of from
+    /// earlier passes, which the generation doesn't run again. Never the whole prompt.
+    fn cached_prefix(&self, prompt: &[usize]) -> usize {
+        let _ = prompt;
+        0
+    }
+
+    /// Runs the model on `token` at position `pos`. With `logits` it also reads back the logits
+    /// of the next token, prompt tokens before the last one don't need them.
+    fn forward(
+        &mut self,
+        token: usize,
+        pos: usize,
+        logits: Option<&mut [f32]>,
+    ) -> impl Future<Output = anyhow::Result<()>>;
+}
+
+/// Runs the forward pass of a position, running it again up to `retries` times if it fails.
+pub async fn forward_with_retries(
+    model: &mut impl LanguageModel,
+    token: usize,
+    pos: usize,
+    mut logits: Option<&mut [f32]>,
+    retries: usize,
+) -> anyhow::Result<()> {
+    let mut attempt = 0;
+    loop {
+        match model.forward(token, pos, logits.as_deref_mut()).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn!("forward pass of position {pos} failed, retrying ({attempt}/{retries}): {e}");
+            }
+            Err(e) => {
+                return Err(e.context(format!(
+                    "forward pass of position {pos} failed {} times",
+                    attempt + 1
+                )));
+            }
+        }
+    }
+}
+
+/// Tells when the same token has been generated more than `max_repeats` times in a row.
+#[derive(Debug, Clone)]
+pub struct RepetitionDetector {
+    max_repeats: usize,
+    last: Option<usize>,
+    repeats: usize,
+}
+
+impl RepetitionDetector {
+    pub fn new(max_repeats: usize) -> Self {
+        RepetitionDetector {
+            max_repeats,
+            last: None,
+            repeats: 0,
+        }
+    }
+
+    /// Counts the generated `token`. `true` once it has been generated more than `max_repeats`
+    /// times in a row.
+    pub fn push(&mut self, token: usize) -> bool {
+        if self.last == Some(token) {
+            self.repeats += 1;
+        } else {
+            self.last = Some(token);
+            self.repeats = 1;
+        }
+        self.repeats > self.max_repeats
+    }
+}
+
+/// Finds the stop sequences in the generated text. Holds back the text that a stop sequence
+/// starts with until it is clear whether the sequence follows, so no part of one is sent.
+#[derive(Debug, Clone)]
+pub struct StopSequenceMatcher {
+    stop: Vec<String>,
+    pending: String,
+    stopped: bool,
+}
+
+impl StopSequenceMatcher {
+    pub fn new(stop: Vec<String>) -> Self {
+        StopSequenceMatcher {
+            stop,
+            pending: String::new(),
+            stopped: false,
+        }
+    }
+
+    /// Adds generated `text`. Returns the text that can be sent, and `true` once a stop sequence
+    /// has been generated, in which case the text is what came before it and the rest is dropped.
+    pub fn push(&mut self, text: &str) -> (String, bool) {
+        if self.stopped {
+            return (String::new(), true);
+        }
+        self.pending.push_str(text);
+
+        if let Some(start) = self
+            .stop
+            .iter()
+            .filter_map(|stop| self.pending.find(stop.as_str()))
+            .min()
+        {
+            self.stopped = true;
+            let before = self.pending[..start].to_owned();
+            self.pending.clear();
+            return (before, true);
+        }
+
+        let held = self
+            .pending
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| {
+                self.stop
+                    .iter()
+                    .any(|stop| stop.starts_with(&self.pending[i..]))
+            })
+            .unwrap_or(self.pending.len());
+        let sendable = self.pending.drain(..held).collect();
+        (sendable, false)
+    }
+
+    /// The held back text, once the generation has ended without a stop sequence.
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Holds back the whitespace a part ends with until the next part shows where the run ends, so a
+/// run of spaces and newlines, e.g. the indentation of a line of code, goes out in one part.
+/// Clients that render Markdown or code as it arrives otherwise see it in pieces.
+#[derive(Debug, Clone, Default)]
+pub struct WhitespaceCoalescer {
+    pending: String,
+}
+
+impl WhitespaceCoalescer {
+    /// Adds generated `text`. Returns the text that can be sent: everything up to the whitespace
+    /// at the end, which is held back.
+    pub fn push(&mut self, text: &str) -> String {
+        self.pending.push_str(text);
+        let held = self.pending.trim_end().len();
+        let mut sendable = self.pending.split_off(held);
+        std::mem::swap(&mut sendable, &mut self.pending);
+        sendable
+    }
+
+    /// The held back whitespace, once the generation has ended.
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Generates the reply to `prompt_tokens` and sends it through the task's return channel. Reports
+/// the completion to the task, or returns the error the generation stopped on.
+///
+/// With `max_repeated_tokens`, a token generated more times than that in a row ends the
+/// generation with [`CompletionReason::RepetitionCollapse`]. The repeats up to the limit are sent.
+///
+/// With `coalesce_whitespace`, runs of whitespace are sent whole, see [`WhitespaceCoalescer`].
+#[allow(clippy::too_many_arguments)]
+pub async fn generate(
+    task: &mut InferenceTask,
+    model: &mut impl LanguageModel,
+    prompt_tokens: &[usize],
+    max_tokens: usize,
+    sampler: &mut TokenSampler,
+    response_prefixes: &[String],
+    retries: usize,
+    max_repeated_tokens: Option<usize>,
+    coalesce_whitespace: bool,
+) -> anyhow::Result<()> {
+    let cached = model.cached_prefix(prompt_tokens);
+    if cached > 0 {
+        debug!("skipping the passes of {cached} cached prompt tokens");
+    }
+    let mut token = prompt_tokens[cached];
+    let mut logits = DVector::zeros(model.vocab_size());
+
+    let inference_start = Instant::now();
+    let mut prefill_time = Instant::now();
+    let mut total_generated = 0;
+    let mut reason = CompletionReason::Stop;
+    let mut prefix_stripper = PrefixStripper::new(response_prefixes);
+    let repetition_penalty = task.sampling().repetition_penalty;
+    let logit_bias = task.sampling().logit_bias.clone();
+    let mut stop_sequences = StopSequenceMatcher::new(task.sampling().stop.clone());
+    let mut generated = Vec::new();
+    let mut repetition = max_repeated_tokens.map(RepetitionDetector::new);
+    let mut whitespace = coalesce_whitespace.then(WhitespaceCoalescer::default);
+
+    for pos in cached.. {
+        let is_prefill = pos < prompt_tokens.len() - 1;
+
+        if pos % 50 == 0 {
+            if is_prefill {
+                println!("Prefilling token {pos}");
+            } else {
+                println!("Generating token {pos}");
+            }
+        }
+
+        let logits_out = (!is_prefill).then_some(logits.as_mut_slice());
+        forward_with_retries(model, token, pos, logits_out, retries).await?;
+
+        if pos + 1 >= prompt_tokens.len() {
+            if let Some(penalty) = repetition_penalty {
+                apply_repetition_penalty(logits.as_mut_slice(), generated.iter().copied(), penalty);
+            }
+            apply_logit_bias(logits.as_mut_slice(), &logit_bias);
+            suppress_tokens(
+                logits.as_mut_slice(),
+                task.suppressed_tokens(total_generated, model.terminators()),
+            );
+            let next_token = sampler.sample(&mut logits);
+
+            if model.terminators().contains(&next_token) || task.is_stop_token(next_token) {
+                break;
+            } else if total_generated >= max_tokens {
+                reason = CompletionReason::Length;
+                break;
+            } else if let Some(repetition) = &mut repetition
+                && repetition.push(next_token)
+            {
+                warn!(
+                    "token {next_token} repeated more than {} times, stopping",
+                    repetition.max_repeats
+                );
+                reason = CompletionReason::RepetitionCollapse;
+                break;
+            } else {
+                let token_str = model.decode(next_token);
+                let (mut text, stopped) = match prefix_stripper.push(token_str) {
+                    Some(text) => stop_sequences.push(&text),
+                    None => (String::new(), false),
+                };
+                if let Some(whitespace) = &mut whitespace {
+                    text = whitespace.push(&text);
+                }
+
+                if !text.is_empty() && task.return_channel().send(text).await.is_err() {
+                    reason = CompletionReason::Cancelled;
+                    break;
+                }
+                if stopped {
+                    break;
+                }
+            }
+
+            token = next_token;
+            generated.push(next_token);
+            total_generated += 1;
+            task.generated_token();
+        } else {
+            token = prompt_tokens[pos + 1];
+
+            prefill_time = Instant::now();
+        }
+    }
+
+    let mut rest = match prefix_stripper.finish() {
+        Some(text) => stop_sequences.push(&text).0,
+        None => String::new(),
+    };
+    rest.push_str(&stop_sequences.finish());
+    if let Some(whitespace) = &mut whitespace {
+        rest = whitespace.push(&rest) + &whitespace.finish();
+    }
+    if !rest.is_empty() {
+        let _ = task.return_channel().send(rest).await;
+    }
+
+    let inference_end = Instant::now();
+    let total_duration = inference_end - inference_start;
+    let prefill_duration = prefill_time - inference_start;
+    let generation_duration = total_duration - prefill_duration;
+    task.completed(reason, prefill_duration, generation_duration);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::sampling::{DecodingMode, SamplingParams};
+    use anyhow::anyhow;
+    use std::collections::HashMap;
+
+    const EOS: usize = 0;
+
+    /// Generates the token after the position from a script, and fails the passes of some
+    /// positions a number of times first.
+    struct MockModel {
+        script: Vec<usize>,
+        failures: HashMap<usize, usize>,
+        passes: Vec<usize>,
+        cached: usize,
+    }
+
+    impl MockModel {
+        fn new(script: &[usize], failures: &[(usize, usize)]) -> Self {
+            MockModel {
+                script: script.to_vec(),
+                failures: failures.iter().copied().collect(),
+                passes: Vec::new(),
+                cached: 0,
+            }
+        }
+    }
+
+    impl LanguageModel for MockModel {
+        fn vocab_size(&self) -> usize {
+            8
+        }
+
+        fn terminators(&self) -> &[usize] {
+            &[EOS]
+        }
+
+        fn decode(&self, token: usize) -> String {
+            format!("<{token}>")
+        }
+
+        fn cached_prefix(&self, prompt: &[usize]) -> usize {
+            self.cached.min(prompt.len() - 1)
+        }
+
+        async fn forward(
+            &mut self,
+            _token: usize,
+            pos: usize,
+            logits: Option<&mut [f32]>,
+        ) -> anyhow::Result<()> {
+            self.passes.push(pos);
+            if let Some(failures) = self.failures.get_mut(&pos)
+                && *failures > 0
+            {
+                *failures -= 1;
+                return Err(anyhow!("device lost"));
+            }
+            if let Some(logits) = logits {
+                logits.fill(-100.0);
+                logits[self.script.get(pos).copied().unwrap_or(EOS)] = 100.0;
+            }
+            Ok(())
+        }
+    }
+
+    fn sampler() -> TokenSampler {
+        // Mirostat leaves out unlikely tokens, so it always samples the scripted one
+        TokenSampler::new(DecodingMode::Mirostat { tau: 1.0, eta: 0.1 }, 8)
+    }
+
+    async fn run(model: &mut MockModel, retries: usize) -> (anyhow::Result<()>, Vec<String>) {
+        let (mut task, mut receiver) = InferenceTask::new(Vec::new());
+        let result = generate(
+            &mut task,
+            model,
+            &[1, 2],
+            10,
+            &mut sampler(),
+            &[],
+            retries,
+            None,
+            false,
+        )
+        .await;
+        drop(task);
+
+        let mut parts = Vec::new();
+        while let Some(part) = receiver.recv().await {
+            parts.push(part);
+        }
+        (result, parts)
+    }
+
+    #[tokio::test]
+    async fn test_transient_failure_is_retried() {
+        // The prompt is 2 tokens, so position 1 generates the first token
+        let mut model = MockModel::new(&[0, 5, 6, 7], &[(2, 2)]);
+
+        let (result, parts) = run(&mut model, 2).await;
+
+        assert!(result.is_ok());
+        assert_eq!(parts, ["<5>", "<6>", "<7>"]);
+        assert_eq!(model.passes, [0, 1, 2, 2, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_generation_fails_after_the_retries() {
+        let mut model = MockModel::new(&[0, 5, 6, 7], &[(2, 3)]);
+
+        let (result, parts) = run(&mut model, 2).await;
+
+        let error = result.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "forward pass of position 2 failed 3 times"
+        );
+        assert_eq!(parts, ["<5>"]);
+    }
+
+    #[tokio::test]
+    async fn test_next_generation_runs_after_a_failed_one() {
+        let mut model = MockModel::new(&[0, 5, 6], &[(1, 1)]);
+
+        let (failed, _) = run(&mut model, 0).await;
+        let (result, parts) = run(&mut model, 0).await;
+
+        assert!(failed.is_err());
+        assert!(result.is_ok());
+        assert_eq!(parts, ["<5>", "<6>"]);
+    }
+
+    #[tokio::test]
+    async fn test_cached_prompt_positions_are_not_run() {
+        let mut model = MockModel::new(&[0, 5, 6], &[]);
+        model.cached = 1;
+
+        let (result, parts) = run(&mut model, 0).await;
+
+        assert!(result.is_ok());
+        assert_eq!(parts, ["<5>", "<6>"]);
+        assert_eq!(model.passes, [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_whitespace_run_is_sent_together() {
+        let mut coalescer = WhitespaceCoalescer::default();
+
+        assert_eq!(coalescer.push("fn main() {"), "fn main() {");
+        assert_eq!(coalescer.push("\n"), "");
+        assert_eq!(coalescer.push("  "), "");
+        assert_eq!(coalescer.push("  let"), "\n    let");
+        assert_eq!(coalescer.push(" x = 1; "), " x = 1;");
+        assert_eq!(coalescer.finish(), " ");
+        assert_eq!(coalescer.finish(), "");
+    }
+
+    #[test]
+    fn test_detector_trips_on_a_repeating_token() {
+        let mut detector = RepetitionDetector::new(3);
+
+        // Repeats that are interrupted start over
+        let stream = [4, 4, 4, 7, 4, 4, 4];
+        assert!(stream.iter().all(|&token| !detector.push(token)));
+        assert!(detector.push(4));
+    }
+
+    #[test]
+    fn test_detector_allows_the_limit() {
+        let mut detector = RepetitionDetector::new(1);
+
+        assert!(!detector.push(2));
+        assert!(!detector.push(3));
+        assert!(detector.push(3));
+    }
+
+    #[test]
+    fn test_stop_sequence_spanning_parts_is_not_sent() {
+        let mut matcher = StopSequenceMatcher::new(vec!["\nUser:".to_owned()]);
+
+        assert_eq!(matcher.push("Hello"), ("Hello".to_owned(), false));
+        // Could be the start of the stop sequence, so it is held back
+        assert_eq!(matcher.push(" there\nUs"), (" there".to_owned(), false));
+        assert_eq!(matcher.push("er: hi"), (String::new(), true));
+        assert_eq!(matcher.finish(), "");
+    }
+
+    #[test]
+    fn test_held_back_text_is_released() {
+        let mut matcher = StopSequenceMatcher::new(vec!["###".to_owned()]);
+
+        assert_eq!(matcher.push("a#"), ("a".to_owned(), false));
+        assert_eq!(matcher.push("#b"), ("##b".to_owned(), false));
+        assert_eq!(matcher.push("c##"), ("c".to_owned(), false));
+        assert_eq!(matcher.finish(), "##");
+    }
+
+    #[tokio::test]
+    async fn test_generation_ends_at_a_stop_sequence() {
+        let mut model = MockModel::new(&[0, 5, 6, 7], &[]);
+        let (mut task, mut receiver) = InferenceTask::new(Vec::new());
+        task.set_sampling(SamplingParams {
+            stop: vec!["<6>".to_owned()],
+            ..SamplingParams::default()
+        });
+        let completion = task.track_completion();
+
+        let result = generate(
+            &mut task,
+            &mut model,
+            &[1, 2],
+            10,
+            &mut sampler(),
+            &[],
+            0,
+            None,
+            false,
+        )
+        .await;
+        drop(task);
+
+        let mut parts = Vec::new();
+        while let Some(part) = receiver.recv().await {
+            parts.push(part);
+        }
+        assert!(result.is_ok());
+        assert_eq!(parts, ["<5>"]);
+        assert_eq!(completion.await, Ok(CompletionReason::Stop));
+    }
+
+    #[tokio::test]
+    async fn test_collapsed_generation_is_stopped() {
+        let mut model = MockModel::new(&[0, 5, 6, 6, 6, 6, 6, 6, 6, 6], &[]);
+        let (mut task, mut receiver) = InferenceTask::new(Vec::new());
+        let completion = task.track_completion();
+
+        let result = generate(
+            &mut task,
+            &mut model,
+            &[1, 2],
+            20,
+            &mut sampler(),
+            &[],
+            0,
+            Some(3),
+            false,
+        )
+        .await;
+        drop(task);
+
+        let mut parts = Vec::new();
+        while let Some(part) = receiver.recv().await {
+            parts.push(part);
+        }
+        assert!(result.is_ok());
+        assert_eq!(parts, ["<5>", "<6>", "<6>", "<6>"]);
+        assert_eq!(completion.await, Ok(CompletionReason::RepetitionCollapse));
+    }
+}
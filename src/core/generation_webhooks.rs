@@ -0,0 +1,98 @@
+//! Generation webhooks of conversation messages, sent from the inference events.
+//!
+//! A handler that queues the generation of a message registers its task with [`watch`].
+//! [`notify_events`] follows the task on [`INFERENCE_EVENTS`] and sends the `started` webhook
+//! when the worker starts it and the `finished` one with the reason and token count the worker
+//! reports, see [`webhooks::notify`].
+
+use crate::core::inference_events::{CompletionReason, INFERENCE_EVENTS, InferenceEvent};
+use crate::infrastructure::webhooks::{self, FinishReason, GenerationWebhook};
+use log::warn;
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+/// The message each watched task generates, by task.
+static WATCHED: LazyLock<Mutex<HashMap<Uuid, Watched>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+struct Watched {
+    conversation_id: Uuid,
+    message_id: Uuid,
+    /// Tokens published so far, for a task that fails
+    completion_tokens: usize,
+}
+
+/// Sends the webhooks of the task, which generates `message_id` in `conversation_id`. Called
+/// before the task is queued.
+pub fn watch(task_id: Uuid, conversation_id: Uuid, message_id: Uuid) {
+    WATCHED.lock().unwrap().insert(
+        task_id,
+        Watched {
+            conversation_id,
+            message_id,
+            completion_tokens: 0,
+        },
+    );
+}
+
+/// The finish reason of a generation that ended for `reason`.
+pub fn finish_reason(reason: CompletionReason) -> FinishReason {
+    match reason {
+        CompletionReason::Stop => FinishReason::Stop,
+        CompletionReason::Length => FinishReason::Length,
+        CompletionReason::Cancelled => FinishReason::Cancelled,
+        CompletionReason::RepetitionCollapse => FinishReason::RepetitionCollapse,
+    }
+}
+
+/// Sends the webhooks of the watched tasks. Subscribes right away, so no task watched after the
+/// call is missed. Runs until the bus is dropped.
+pub fn notify_events() -> impl Future<Output = ()> {
+    let mut events = INFERENCE_EVENTS.subscribe();
+    async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("webhook notifier missed {skipped} inference events");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            let task_id = event.task_id();
+            let mut watched = WATCHED.lock().unwrap();
+            let Some(task) = watched.get_mut(&task_id) else {
+                continue;
+            };
+            let (finish_reason, completion_tokens) = match event {
+                InferenceEvent::Started { .. } => {
+                    webhooks::notify(GenerationWebhook::started(
+                        task.conversation_id,
+                        task.message_id,
+                    ));
+                    continue;
+                }
+                InferenceEvent::TokenBatch { tokens, .. } => {
+                    task.completion_tokens += tokens;
+                    continue;
+                }
+                InferenceEvent::Completed { reason, stats, .. } => {
+                    (finish_reason(reason), stats.completion_tokens)
+                }
+                InferenceEvent::Failed { .. } => (FinishReason::Error, task.completion_tokens),
+                InferenceEvent::Queued { .. } => continue,
+            };
+            if let Some(task) = watched.remove(&task_id) {
+                webhooks::notify(GenerationWebhook::finished(
+                    task.conversation_id,
+                    task.message_id,
+                    finish_reason,
+                    completion_tokens,
+                ));
+            }
+        }
+    }
+}
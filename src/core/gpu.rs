@@ -0,0 +1,229 @@
+//! GPU device creation with backend fallback.
+//!
+//! `wgcore::gpu::GpuInstance::new()` lets wgpu pick a backend and fails outright if that one is
+//! unusable (e.g. no Vulkan inside a container). [`create_gpu`] instead walks the backends listed
+//! in `GPU_BACKENDS` (default `vulkan,metal,dx12,gl`) and uses the first one that yields a device.
+//!
+//! Without a usable GPU the model can still run on the CPU: [`create_cpu`] asks wgpu for its
+//! software fallback adapter (lavapipe, llvmpipe or WARP), which runs the same compute kernels on
+//! the CPU. `INFERENCE_BACKEND` picks between the two, see [`InferenceBackend`].
+
+use anyhow::anyhow;
+use log::{info, warn};
+use serde::Serialize;
+use wgpu::{Adapter, Backend, Backends, Device, Instance, Queue};
+
+const DEFAULT_BACKENDS: &str = "vulkan,metal,dx12,gl";
+
+/// A wgpu device and queue, exposing the same accessors as `wgcore::gpu::GpuInstance`.
+pub struct GpuInstance {
+    _instance: Instance,
+    adapter: Adapter,
+    device: Device,
+    queue: Queue,
+}
+
+impl GpuInstance {
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn queue(&self) -> &Queue {
+        &self.queue
+    }
+
+    /// The backend the device was created on.
+    pub fn backend(&self) -> Backend {
+        self.adapter.get_info().backend
+    }
+}
+
+/// Where the model runs.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InferenceBackend {
+    /// On a GPU, failing to start without one.
+    #[default]
+    Gpu,
+    /// On the CPU, through a software adapter.
+    Cpu,
+    /// On a GPU if one can be created, otherwise on the CPU.
+    Auto,
+}
+
+impl InferenceBackend {
+    /// `INFERENCE_BACKEND`, `gpu` (default), `cpu` or `auto`.
+    pub fn from_env() -> Self {
+        match std::env::var("INFERENCE_BACKEND").as_deref() {
+            Ok("gpu") | Err(_) => InferenceBackend::Gpu,
+            Ok("cpu") => InferenceBackend::Cpu,
+            Ok("auto") => InferenceBackend::Auto,
+            Ok(backend) => {
+                warn!("unknown INFERENCE_BACKEND `{backend}`, using gpu");
+                InferenceBackend::Gpu
+            }
+        }
+    }
+}
+
+/// Creates the device the model runs on for `backend`.
+pub async fn create_device(backend: InferenceBackend) -> anyhow::Result<GpuInstance> {
+    select_device(backend, create_gpu, create_cpu).await
+}
+
+/// Creates a device with `gpu` or `cpu` as `backend` asks, falling back from `gpu` to `cpu` in
+/// [`InferenceBackend::Auto`].
+pub async fn select_device<T, G, C>(
+    backend: InferenceBackend,
+    gpu: impl FnOnce() -> G,
+    cpu: impl FnOnce() -> C,
+) -> anyhow::Result<T>
+where
+    G: Future<Output = anyhow::Result<T>>,
+    C: Future<Output = anyhow::Result<T>>,
+{
+    match backend {
+        InferenceBackend::Gpu => gpu().await,
+        InferenceBackend::Cpu => cpu().await,
+        InferenceBackend::Auto => match gpu().await {
+            Ok(device) => Ok(device),
+            Err(e) => {
+                warn!("GPU unavailable ({e}), running in degraded mode on the CPU");
+                cpu().await
+            }
+        },
+    }
+}
+
+/// Parses a comma separated list of backend names, skipping unknown ones.
+pub fn parse_backends(backends: &str) -> Vec<Backends> {
+    backends
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| match name.as_str() {
+            "vulkan" => Some(Backends::VULKAN),
+            "metal" => Some(Backends::METAL),
+            "dx12" => Some(Backends::DX12),
+            "gl" => Some(Backends::GL),
+            _ => {
+                warn!("Ignoring unknown GPU backend: {name}");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Creates a GPU instance on the first backend from `GPU_BACKENDS` that works.
+///
+/// Returns `Err` only once every configured backend has failed.
+pub async fn create_gpu() -> anyhow::Result<GpuInstance> {
+    let backends = std::env::var("GPU_BACKENDS").unwrap_or(DEFAULT_BACKENDS.to_owned());
+
+    for backends in parse_backends(&backends) {
+        match create_gpu_with_backends(backends, false).await {
+            Ok(gpu) => {
+                info!("GPU device created on backend {:?}.", gpu.backend());
+                return Ok(gpu);
+            }
+            Err(e) => warn!("GPU backend {backends:?} unavailable: {e}"),
+        }
+    }
+
+    Err(anyhow!("no usable GPU backend in `{backends}`"))
+}
+
+/// Creates a device on wgpu's software fallback adapter, which runs on the CPU.
+pub async fn create_cpu() -> anyhow::Result<GpuInstance> {
+    let cpu = create_gpu_with_backends(Backends::all(), true)
+        .await
+        .map_err(|e| anyhow!("no CPU fallback adapter: {e}"))?;
+    info!(
+        "CPU device created on adapter {} ({:?}).",
+        cpu.adapter.get_info().name,
+        cpu.backend()
+    );
+    Ok(cpu)
+}
+
+async fn create_gpu_with_backends(
+    backends: Backends,
+    force_fallback_adapter: bool,
+) -> anyhow::Result<GpuInstance> {
+    let instance = Instance::new(&wgpu::InstanceDescriptor {
+        backends,
+        ..Default::default()
+    });
+    let adapter = instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            force_fallback_adapter,
+            ..Default::default()
+        })
+        .await
+        .ok_or(anyhow!("no adapter found"))?;
+
+    // Model weights need large storage buffers, so ask for everything the adapter supports.
+    let (device, queue) = adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                label: None,
+                required_features: adapter.features(),
+                required_limits: adapter.limits(),
+                memory_hints: Default::default(),
+            },
+            None,
+        )
+        .await?;
+
+    Ok(GpuInstance {
+        _instance: instance,
+        adapter,
+        device,
+        queue,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_backends_keeps_order_and_skips_unknown() {
+        let backends = parse_backends("gl, Vulkan,,bogus,dx12");
+        assert_eq!(
+            backends,
+            vec![Backends::GL, Backends::VULKAN, Backends::DX12]
+        );
+    }
+
+    async fn failing_gpu() -> anyhow::Result<&'static str> {
+        Err(anyhow!("no adapter found"))
+    }
+
+    async fn working_gpu() -> anyhow::Result<&'static str> {
+        Ok("gpu")
+    }
+
+    async fn cpu() -> anyhow::Result<&'static str> {
+        Ok("cpu")
+    }
+
+    #[tokio::test]
+    async fn test_auto_falls_back_to_cpu_when_gpu_creation_fails() {
+        let device = select_device(InferenceBackend::Auto, failing_gpu, cpu).await;
+        assert_eq!(device.unwrap(), "cpu");
+
+        let device = select_device(InferenceBackend::Auto, working_gpu, cpu).await;
+        assert_eq!(device.unwrap(), "gpu");
+    }
+
+    #[tokio::test]
+    async fn test_gpu_backend_does_not_fall_back() {
+        let device = select_device(InferenceBackend::Gpu, failing_gpu, cpu).await;
+        assert!(device.is_err());
+
+        let device = select_device(InferenceBackend::Cpu, working_gpu, cpu).await;
+        assert_eq!(device.unwrap(), "cpu");
+    }
+}
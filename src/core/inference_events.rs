@@ -0,0 +1,188 @@
+//! In-process bus of inference lifecycle events.
+//!
+//! Every [`InferenceTask`](crate::core::assistant::InferenceTask) publishes what happens to it on
+//! [`INFERENCE_EVENTS`], from being queued to its generation completing or failing. Features that
+//! observe generations, like logging their timing, subscribe to the bus instead of hooking into
+//! the inference worker's loop.
+
+use log::{info, warn};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+/// Number of events buffered for a subscriber before it starts missing them.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Number of generated tokens reported by one [`InferenceEvent::TokenBatch`], so subscribers
+/// aren't woken up for every token. The first token of a generation gets a batch of its own.
+pub const TOKEN_BATCH_SIZE: usize = 16;
+
+/// The bus every inference task publishes its events on.
+pub static INFERENCE_EVENTS: LazyLock<InferenceEvents> =
+    LazyLock::new(|| InferenceEvents::new(CHANNEL_CAPACITY));
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InferenceEvent {
+    /// The task was created, with `position` generations to run before it
+    Queued { task_id: Uuid, position: u64 },
+    /// The worker has rendered the prompt and starts generating
+    Started { task_id: Uuid, prompt_tokens: usize },
+    /// The worker has generated `tokens` more tokens
+    TokenBatch { task_id: Uuid, tokens: usize },
+    /// The generation has ended
+    Completed {
+        task_id: Uuid,
+        reason: CompletionReason,
+        stats: GenerationStats,
+    },
+    /// The task could not be generated
+    Failed { task_id: Uuid, error: String },
+}
+
+impl InferenceEvent {
+    pub fn task_id(&self) -> Uuid {
+        match self {
+            InferenceEvent::Queued { task_id, .. }
+            | InferenceEvent::Started { task_id, .. }
+            | InferenceEvent::TokenBatch { task_id, .. }
+            | InferenceEvent::Completed { task_id, .. }
+            | InferenceEvent::Failed { task_id, .. } => *task_id,
+        }
+    }
+}
+
+/// Why a generation ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionReason {
+    /// The model generated the end of sequence token or one of the task's stop tokens.
+    Stop,
+    /// The generation reached its token limit.
+    Length,
+    /// Nobody reads the generated text anymore.
+    Cancelled,
+    /// The model kept generating the same token, see
+    /// [`RepetitionDetector`](crate::core::generation::RepetitionDetector).
+    RepetitionCollapse,
+}
+
+/// Token counts and timing of a generation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GenerationStats {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// Time spent processing the prompt
+    pub prefill: Duration,
+    /// Time spent generating the completion tokens
+    pub generation: Duration,
+}
+
+/// A broadcast channel of [`InferenceEvent`]s.
+pub struct InferenceEvents {
+    sender: broadcast::Sender<InferenceEvent>,
+}
+
+impl InferenceEvents {
+    pub fn new(capacity: usize) -> Self {
+        InferenceEvents {
+            sender: broadcast::channel(capacity).0,
+        }
+    }
+
+    /// Subscribes to the events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<InferenceEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Sends the event to the current subscribers, if any.
+    pub fn publish(&self, event: InferenceEvent) {
+        let _ = self.sender.send(event);
+    }
+}
+
+/// Logs the token counts and timing of every generation, and why any task failed. Runs until
+/// the bus is dropped.
+pub async fn log_events() {
+    let mut events = INFERENCE_EVENTS.subscribe();
+    loop {
+        match events.recv().await {
+            Ok(InferenceEvent::Completed { stats, .. }) => {
+                let total = stats.prefill + stats.generation;
+                info!(
+                    "Inference done, total time: {total:?} for {} tokens.",
+                    stats.completion_tokens
+                );
+                info!(
+                    "Prefill time: {:?}, or {:.2} tokens/s",
+                    stats.prefill,
+                    stats.prompt_tokens as f32 / stats.prefill.as_secs_f32()
+                );
+                info!(
+                    "Generation time: {:?} or {:.2} tokens/s",
+                    stats.generation,
+                    stats.completion_tokens as f32 / stats.generation.as_secs_f32()
+                );
+            }
+            Ok(InferenceEvent::Failed { task_id, error }) => {
+                warn!("inference task {task_id} failed: {error}");
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("inference event logger missed {skipped} events");
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::assistant::InferenceTask;
+
+    #[tokio::test]
+    async fn test_generated_tokens_are_published_in_batches() {
+        let mut events = INFERENCE_EVENTS.subscribe();
+        let (mut task, _receiver) = InferenceTask::new(Vec::new());
+        task.started(5);
+        for _ in 0..TOKEN_BATCH_SIZE + 5 {
+            task.generated_token();
+        }
+        task.completed(CompletionReason::Length, Duration::ZERO, Duration::ZERO);
+
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if event.task_id() == task.id() {
+                received.push(event);
+            }
+        }
+
+        let task_id = task.id();
+        assert!(matches!(received[0], InferenceEvent::Queued { .. }));
+        assert_eq!(
+            received[1..],
+            [
+                InferenceEvent::Started {
+                    task_id,
+                    prompt_tokens: 5
+                },
+                InferenceEvent::TokenBatch { task_id, tokens: 1 },
+                InferenceEvent::TokenBatch {
+                    task_id,
+                    tokens: TOKEN_BATCH_SIZE
+                },
+                InferenceEvent::TokenBatch { task_id, tokens: 4 },
+                InferenceEvent::Completed {
+                    task_id,
+                    reason: CompletionReason::Length,
+                    stats: GenerationStats {
+                        prompt_tokens: 5,
+                        completion_tokens: TOKEN_BATCH_SIZE + 5,
+                        ..Default::default()
+                    },
+                },
+            ]
+        );
+    }
+}
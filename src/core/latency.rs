@@ -0,0 +1,244 @@
+//! Streaming latency of generations, as the client experiences it.
+//!
+//! Time to first token runs from queueing the task to the worker generating its first token, so
+//! it includes the wait in the queue and the prefill. Inter-token latency is the time between two
+//! consecutive tokens. Both are observed into Prometheus histograms, see [`METRICS`], and the
+//! figures of a generation are recorded on its tracing span if it has one, see [`record_on`].
+//!
+//! Generations are timed from their [`InferenceEvent`]s by [`record_events`], as they reach it.
+//! The tokens after the first are published in batches, so the time between two batches is
+//! spread evenly over the tokens of the later one.
+
+use crate::core::inference_events::{INFERENCE_EVENTS, InferenceEvent};
+use log::{error, warn};
+use prometheus::{Encoder, Histogram, HistogramOpts, Registry, TextEncoder};
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Instant;
+use tracing::{Span, field, info_span};
+use uuid::Uuid;
+
+/// Buckets of the time to first token, in seconds.
+const TIME_TO_FIRST_TOKEN_BUCKETS: &[f64] =
+    &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// Buckets of the inter-token latency, in seconds.
+const INTER_TOKEN_LATENCY_BUCKETS: &[f64] =
+    &[0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// The streaming latency histograms every generation is observed into.
+pub static METRICS: LazyLock<LatencyMetrics> = LazyLock::new(LatencyMetrics::new);
+
+/// The spans the figures of a task are recorded on once its generation ends, by task.
+static SPANS: LazyLock<Mutex<HashMap<Uuid, Span>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub struct LatencyMetrics {
+    registry: Registry,
+    /// Seconds from queueing a task to its first part
+    pub time_to_first_token: Histogram,
+    /// Seconds between consecutive parts of a generation
+    pub inter_token_latency: Histogram,
+}
+
+impl LatencyMetrics {
+    fn new() -> Self {
+        let time_to_first_token = Histogram::with_opts(
+            HistogramOpts::new(
+                "time_to_first_token_seconds",
+                "Time from queueing a generation to its first streamed part",
+            )
+            .buckets(TIME_TO_FIRST_TOKEN_BUCKETS.to_vec()),
+        )
+        .expect("time to first token histogram is valid");
+        let inter_token_latency = Histogram::with_opts(
+            HistogramOpts::new(
+                "inter_token_latency_seconds",
+                "Time between consecutive streamed parts of a generation",
+            )
+            .buckets(INTER_TOKEN_LATENCY_BUCKETS.to_vec()),
+        )
+        .expect("inter-token latency histogram is valid");
+
+        let registry = Registry::new();
+        registry
+            .register(Box::new(time_to_first_token.clone()))
+            .expect("time to first token histogram is registered once");
+        registry
+            .register(Box::new(inter_token_latency.clone()))
+            .expect("inter-token latency histogram is registered once");
+
+        LatencyMetrics {
+            registry,
+            time_to_first_token,
+            inter_token_latency,
+        }
+    }
+
+    /// The histograms in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("failed to encode the latency metrics: {e}");
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+/// Measures the streaming latency of one generation. Started when the task is queued, and told
+/// about every batch of tokens the worker generates.
+#[derive(Debug)]
+pub struct StreamLatency {
+    queued: Instant,
+    last_batch: Option<Instant>,
+    time_to_first_token: Option<Duration>,
+    /// Sum of the times between parts
+    inter_token_total: Duration,
+    inter_token_count: u32,
+}
+
+impl StreamLatency {
+    pub fn start() -> Self {
+        StreamLatency {
+            queued: Instant::now(),
+            last_batch: None,
+            time_to_first_token: None,
+            inter_token_total: Duration::ZERO,
+            inter_token_count: 0,
+        }
+    }
+
+    /// Called for every batch of `tokens` generated tokens. The first batch of a generation is
+    /// its first token alone, see [`InferenceEvent::TokenBatch`].
+    pub fn tokens(&mut self, tokens: usize) {
+        let now = Instant::now();
+        match self.last_batch {
+            None => {
+                let time_to_first_token = now - self.queued;
+                self.time_to_first_token = Some(time_to_first_token);
+                METRICS
+                    .time_to_first_token
+                    .observe(time_to_first_token.as_secs_f64());
+            }
+            Some(last_batch) if tokens > 0 => {
+                let latency = (now - last_batch) / tokens as u32;
+                self.inter_token_total += latency * tokens as u32;
+                self.inter_token_count += tokens as u32;
+                for _ in 0..tokens {
+                    METRICS.inter_token_latency.observe(latency.as_secs_f64());
+                }
+            }
+            Some(_) => {}
+        }
+        self.last_batch = Some(now);
+    }
+
+    /// `None` until the first token is generated.
+    pub fn time_to_first_token(&self) -> Option<Duration> {
+        self.time_to_first_token
+    }
+
+    /// The mean time between tokens, `None` with less than two tokens.
+    pub fn mean_inter_token_latency(&self) -> Option<Duration> {
+        (self.inter_token_count > 0).then(|| self.inter_token_total / self.inter_token_count)
+    }
+
+    /// Records the figures on `span`, in milliseconds, as `ttft_ms` and `inter_token_ms`. See
+    /// [`generation_span`].
+    pub fn record(&self, span: &Span) {
+        if let Some(time_to_first_token) = self.time_to_first_token {
+            span.record("ttft_ms", time_to_first_token.as_millis() as u64);
+        }
+        if let Some(latency) = self.mean_inter_token_latency() {
+            span.record("inter_token_ms", latency.as_millis() as u64);
+        }
+    }
+}
+
+/// Records the figures of the task on `span` once its generation ends. Called before the task
+/// is queued.
+pub fn record_on(task_id: Uuid, span: Span) {
+    SPANS.lock().unwrap().insert(task_id, span);
+}
+
+/// Times every generation from the inference events and observes the figures into [`METRICS`].
+/// Subscribes right away, so no generation queued after the call is missed. Runs until the bus
+/// is dropped.
+pub fn record_events() -> impl Future<Output = ()> {
+    let mut events = INFERENCE_EVENTS.subscribe();
+    async move {
+        let mut generations: HashMap<Uuid, StreamLatency> = HashMap::new();
+        loop {
+            match events.recv().await {
+                Ok(InferenceEvent::Queued { task_id, .. }) => {
+                    generations.insert(task_id, StreamLatency::start());
+                }
+                Ok(InferenceEvent::TokenBatch { task_id, tokens }) => {
+                    if let Some(latency) = generations.get_mut(&task_id) {
+                        latency.tokens(tokens);
+                    }
+                }
+                Ok(
+                    InferenceEvent::Completed { task_id, .. }
+                    | InferenceEvent::Failed { task_id, .. },
+                ) => {
+                    let latency = generations.remove(&task_id);
+                    let span = SPANS.lock().unwrap().remove(&task_id);
+                    if let (Some(latency), Some(span)) = (latency, span) {
+                        latency.record(&span);
+                    }
+                }
+                Ok(InferenceEvent::Started { .. }) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("latency recorder missed {skipped} inference events");
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+/// The span of the generation of a message, with empty `ttft_ms` and `inter_token_ms` fields for
+/// [`StreamLatency::record`].
+pub fn generation_span(conversation_id: Uuid, message_id: Uuid) -> Span {
+    info_span!(
+        "generation",
+        %conversation_id,
+        %message_id,
+        ttft_ms = field::Empty,
+        inter_token_ms = field::Empty,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_latencies_of_the_token_batches() {
+        let mut latency = StreamLatency::start();
+        assert_eq!(latency.time_to_first_token(), None);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        latency.tokens(1);
+        assert_eq!(latency.mean_inter_token_latency(), None);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        latency.tokens(1);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        latency.tokens(2);
+
+        assert!(latency.time_to_first_token().unwrap() >= Duration::from_millis(50));
+        // 70 ms over 3 tokens
+        let inter_token = latency.mean_inter_token_latency().unwrap();
+        assert!(inter_token >= Duration::from_millis(23), "{inter_token:?}");
+        assert!(inter_token < Duration::from_millis(50), "{inter_token:?}");
+    }
+
+    #[test]
+    fn test_metrics_are_rendered() {
+        let metrics = METRICS.render();
+        assert!(metrics.contains("# TYPE time_to_first_token_seconds histogram"));
+        assert!(metrics.contains("# TYPE inter_token_latency_seconds histogram"));
+    }
+}
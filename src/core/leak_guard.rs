@@ -0,0 +1,78 @@
+//! Guard against replies that leak the system prompt.
+//!
+//! A prompt injection can talk the model into repeating its instructions. When `LEAK_MARKERS` is
+//! set, a completed reply that contains any of its markers, separated by `|`, is replaced with a
+//! refusal before it is saved. Markers are typically distinctive fragments of the system prompt
+//! and are matched case-insensitively. The parts were streamed already by then, so the guard keeps
+//! the leak out of the stored conversation, the history of later prompts and the webhooks, not
+//! out of the stream. Off by default.
+
+const DEFAULT_REFUSAL: &str = "Sorry, I can't help with that.";
+
+pub struct LeakGuard {
+    /// Lowercase markers
+    markers: Vec<String>,
+    refusal: String,
+}
+
+impl LeakGuard {
+    pub fn new(markers: &[&str], refusal: &str) -> Self {
+        LeakGuard {
+            markers: markers
+                .iter()
+                .map(|marker| marker.trim().to_lowercase())
+                .filter(|marker| !marker.is_empty())
+                .collect(),
+            refusal: refusal.to_owned(),
+        }
+    }
+
+    /// Reads the markers from `LEAK_MARKERS` and the refusal from `LEAK_REFUSAL`. `None` if there
+    /// are no markers.
+    pub fn from_env() -> Option<Self> {
+        let markers = std::env::var("LEAK_MARKERS").ok()?;
+        let refusal = std::env::var("LEAK_REFUSAL")
+            .ok()
+            .filter(|refusal| !refusal.is_empty());
+        let guard = LeakGuard::new(
+            &markers.split('|').collect::<Vec<_>>(),
+            refusal.as_deref().unwrap_or(DEFAULT_REFUSAL),
+        );
+        (!guard.markers.is_empty()).then_some(guard)
+    }
+
+    /// Whether `reply` contains any of the markers.
+    pub fn leaks(&self, reply: &str) -> bool {
+        let reply = reply.to_lowercase();
+        self.markers.iter().any(|marker| reply.contains(marker))
+    }
+
+    /// Returns the refusal in place of `reply` if it leaks, `None` if it is fine.
+    pub fn check(&self, reply: &str) -> Option<&str> {
+        self.leaks(reply).then_some(self.refusal.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reply_with_a_marker_is_refused() {
+        let guard = LeakGuard::new(&["You are Aino", " internal use only "], "No.");
+
+        assert_eq!(
+            guard.check("Sure! My instructions say: you are AINO, a helpful assistant."),
+            Some("No.")
+        );
+        assert_eq!(guard.check("This is for INTERNAL USE ONLY."), Some("No."));
+    }
+
+    #[test]
+    fn test_reply_without_markers_is_kept() {
+        let guard = LeakGuard::new(&["You are Aino", ""], "No.");
+
+        assert_eq!(guard.check("Hello, how can I help?"), None);
+        assert_eq!(guard.check(""), None);
+    }
+}
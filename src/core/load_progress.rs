@@ -0,0 +1,105 @@
+//! Progress reporting while the model is loaded.
+//!
+//! Uploading the weights with `Llama2Weights::from_gguf` happens in one opaque call, and most of
+//! its time goes to reading the memory-mapped model file from disk. The file is paged in up
+//! front, with its progress logged, so operators of large models can tell that loading is not
+//! hung. The upload then reads from the page cache.
+
+use log::info;
+use std::time::Instant;
+
+/// Bytes of the model file paged in between progress checks.
+const PAGE_IN_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Reports progress towards a known total in whole percentages, every `step` percent.
+pub struct LoadProgress {
+    total: u64,
+    done: u64,
+    step: u8,
+    /// Last percentage reported
+    reported: u8,
+}
+
+impl LoadProgress {
+    pub fn new(total: u64, step: u8) -> LoadProgress {
+        LoadProgress {
+            total,
+            done: 0,
+            step: step.clamp(1, 100),
+            reported: 0,
+        }
+    }
+
+    /// Records `amount` more done. Returns the percentage done if it reached the next step since
+    /// the last report, so each step is reported once and percentages only increase.
+    pub fn advance(&mut self, amount: u64) -> Option<u8> {
+        self.done = (self.done + amount).min(self.total);
+        let percent = (self.done * 100).checked_div(self.total).unwrap_or(100) as u8;
+
+        let next = self.reported.saturating_add(self.step).min(100);
+        if self.reported < 100 && percent >= next {
+            self.reported = percent;
+            Some(percent)
+        } else {
+            None
+        }
+    }
+}
+
+/// Reads every page of the memory-mapped model file, logging the progress every 10%.
+pub fn page_in(model: &[u8]) {
+    let start_time = Instant::now();
+    let mut progress = LoadProgress::new(model.len() as u64, 10);
+    let mut checksum = 0u8;
+
+    for chunk in model.chunks(PAGE_IN_CHUNK_SIZE) {
+        // Touching a byte of each page is enough to read it in
+        for page in chunk.chunks(4096) {
+            checksum ^= page[0];
+        }
+        if let Some(percent) = progress.advance(chunk.len() as u64) {
+            info!(
+                "Reading model: {percent}% ({} of {} MiB, {:.1} s)",
+                progress.done / (1024 * 1024),
+                progress.total / (1024 * 1024),
+                start_time.elapsed().as_secs_f32()
+            );
+        }
+    }
+    std::hint::black_box(checksum);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_increases_to_100_percent() {
+        let tensor_count = 7;
+        let mut progress = LoadProgress::new(tensor_count, 10);
+
+        let reported: Vec<u8> = (0..tensor_count)
+            .filter_map(|_| progress.advance(1))
+            .collect();
+
+        assert!(reported.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(reported.last(), Some(&100));
+        // Each tensor is more than a step, so every one of them is reported
+        assert_eq!(reported, vec![14, 28, 42, 57, 71, 85, 100]);
+    }
+
+    #[test]
+    fn test_progress_reports_each_step_once() {
+        let mut progress = LoadProgress::new(1000, 25);
+
+        let reported: Vec<u8> = (0..1000).filter_map(|_| progress.advance(1)).collect();
+
+        assert_eq!(reported, vec![25, 50, 75, 100]);
+        assert_eq!(progress.advance(1), None);
+    }
+
+    #[test]
+    fn test_progress_of_nothing_is_done() {
+        assert_eq!(LoadProgress::new(0, 10).advance(0), Some(100));
+    }
+}
@@ -0,0 +1,212 @@
+//! Precision of the logits read back from the GPU for every generated token.
+//!
+//! The sampler needs the logits of the whole vocabulary, `vocab_size` f32s per token, which for
+//! large vocabularies makes the readback a noticeable share of the time per token. With
+//! `LOGITS_READBACK_PRECISION=f16` a small kernel packs the logits into half floats on the GPU
+//! first, halving the transfer. Half floats keep about three significant digits, so the sampled
+//! distribution is slightly coarser; the default is the exact `f32`.
+
+use log::warn;
+use serde::Serialize;
+use std::num::NonZeroU64;
+use tokio::sync::oneshot;
+use wgpu::util::DeviceExt;
+use wgpu::{Buffer, BufferUsages, CommandEncoder, Device};
+
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogitsPrecision {
+    #[default]
+    F32,
+    F16,
+}
+
+impl LogitsPrecision {
+    /// `LOGITS_READBACK_PRECISION`, `f32` (default) or `f16`.
+    pub fn from_env() -> Self {
+        match std::env::var("LOGITS_READBACK_PRECISION").as_deref() {
+            Ok("f16") => LogitsPrecision::F16,
+            Ok("f32") | Err(_) => LogitsPrecision::F32,
+            Ok(precision) => {
+                warn!("unknown LOGITS_READBACK_PRECISION `{precision}`, using f32");
+                LogitsPrecision::F32
+            }
+        }
+    }
+}
+
+const PACK_SHADER: &str = r#"
+@group(0) @binding(0) var<storage, read> logits: array<f32>;
+@group(0) @binding(1) var<storage, read_write> packed: array<u32>;
+@group(0) @binding(2) var<uniform> len: u32;
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.x;
+    if i >= (len + 1u) / 2u {
+        return;
+    }
+    let low = logits[2u * i];
+    var high = 0.0;
+    if 2u * i + 1u < len {
+        high = logits[2u * i + 1u];
+    }
+    packed[i] = pack2x16float(vec2(low, high));
+}
+"#;
+
+const WORKGROUP_SIZE: u32 = 64;
+
+/// Reads `len` f32 logits back from the GPU as half floats.
+pub struct HalfReadback {
+    len: usize,
+    pipeline: wgpu::ComputePipeline,
+    bind_group: wgpu::BindGroup,
+    packed: Buffer,
+    staging: Buffer,
+}
+
+impl HalfReadback {
+    /// Prepares the readback of the first `len` values of `logits`, a storage buffer of f32s.
+    pub fn new(device: &Device, logits: &Buffer, len: usize) -> Self {
+        let packed_size = len.div_ceil(2) as u64 * 4;
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("pack_logits_f16"),
+            source: wgpu::ShaderSource::Wgsl(PACK_SHADER.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("pack_logits_f16"),
+            layout: None,
+            module: &module,
+            entry_point: Some("main"),
+            compilation_options: Default::default(),
+            cache: None,
+        });
+        let packed = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("packed_logits"),
+            size: packed_size,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let staging = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("packed_logits_readback"),
+            size: packed_size,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let len_uniform = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("packed_logits_len"),
+            contents: bytemuck::bytes_of(&(len as u32)),
+            usage: BufferUsages::UNIFORM,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("pack_logits_f16"),
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: logits,
+                        offset: 0,
+                        size: NonZeroU64::new(len as u64 * 4),
+                    }),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: packed.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: len_uniform.as_entire_binding(),
+                },
+            ],
+        });
+
+        HalfReadback {
+            len,
+            pipeline,
+            bind_group,
+            packed,
+            staging,
+        }
+    }
+
+    /// Records packing the logits and copying them to the readback buffer.
+    pub fn encode(&self, encoder: &mut CommandEncoder) {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("pack_logits_f16"),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        pass.dispatch_workgroups((self.len.div_ceil(2) as u32).div_ceil(WORKGROUP_SIZE), 1, 1);
+        drop(pass);
+        encoder.copy_buffer_to_buffer(&self.packed, 0, &self.staging, 0, self.packed.size());
+    }
+
+    /// Waits for the commands recorded by [`HalfReadback::encode`] and unpacks the logits into
+    /// `out`.
+    pub async fn read_to(&self, device: &Device, out: &mut [f32]) -> anyhow::Result<()> {
+        let slice = self.staging.slice(..);
+        let (sender, receiver) = oneshot::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        device.poll(wgpu::Maintain::Wait);
+        receiver.await??;
+
+        unpack_halves(bytemuck::cast_slice(&slice.get_mapped_range()), out);
+        self.staging.unmap();
+        Ok(())
+    }
+}
+
+/// Unpacks pairs of half floats, the first in the low bits as `pack2x16float` stores them, into
+/// `out`.
+fn unpack_halves(packed: &[u32], out: &mut [f32]) {
+    for (pair, out) in packed.iter().zip(out.chunks_mut(2)) {
+        out[0] = f16_to_f32(*pair as u16);
+        if let Some(high) = out.get_mut(1) {
+            *high = f16_to_f32((*pair >> 16) as u16);
+        }
+    }
+}
+
+/// Converts IEEE 754 half float bits to an f32.
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = (bits >> 10) & 0x1f;
+    let mantissa = (bits & 0x3ff) as f32;
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => sign * f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent as i32 - 15),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_f16_to_f32() {
+        assert_eq!(f16_to_f32(0x3c00), 1.0);
+        assert_eq!(f16_to_f32(0xc000), -2.0);
+        assert_eq!(f16_to_f32(0x7bff), 65504.0);
+        assert_eq!(f16_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0xfc00), f32::NEG_INFINITY);
+        assert!(f16_to_f32(0x7e00).is_nan());
+    }
+
+    #[test]
+    fn test_unpack_odd_number_of_halves() {
+        // 1.0 and -2.0, then 0.5 and the padding
+        let packed = [0xc000_3c00, 0x0000_3800];
+        let mut out = [0.0; 3];
+
+        unpack_halves(&packed, &mut out);
+
+        assert_eq!(out, [1.0, -2.0, 0.5]);
+    }
+}
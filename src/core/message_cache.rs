@@ -0,0 +1,193 @@
+//! In-memory cache of recent conversation message lists.
+//!
+//! Every message posted to a conversation lists its messages to build the prompt, and clients
+//! list them again to show the conversation. The cache keeps the message lists of the
+//! `MESSAGE_CACHE_SIZE` most recently used conversations, and is disabled when that is unset or
+//! zero.
+//!
+//! Entries are keyed by user and conversation, so a list cached for one user is never returned
+//! to another.
+
+use crate::infrastructure::entities::Message;
+use di::{inject, injectable};
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// `(user, conversation)`
+type Key = (Uuid, Uuid);
+
+pub struct MessageCache {
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Messages of each cached conversation, oldest first.
+    entries: HashMap<Key, Vec<Message>>,
+    /// Cached keys, least recently used first.
+    recency: VecDeque<Key>,
+    /// Number of invalidations so far, see [`MessageCache::generation`].
+    generation: u64,
+}
+
+#[injectable]
+impl MessageCache {
+    #[inject]
+    pub fn create() -> MessageCache {
+        dotenvy::dotenv().ok();
+
+        let capacity = std::env::var("MESSAGE_CACHE_SIZE")
+            .ok()
+            .and_then(|s| usize::from_str(&s).ok())
+            .unwrap_or(0);
+        MessageCache::new(capacity)
+    }
+}
+
+impl MessageCache {
+    /// A cache of at most `capacity` conversations. A zero capacity disables the cache.
+    pub fn new(capacity: usize) -> MessageCache {
+        MessageCache {
+            capacity,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Returns the cached messages of the conversation, oldest first.
+    pub fn get(&self, user_id: Uuid, conversation_id: Uuid) -> Option<Vec<Message>> {
+        let mut state = self.state.lock().unwrap();
+        let key = (user_id, conversation_id);
+        let messages = state.entries.get(&key)?.clone();
+        state.touch(key);
+        Some(messages)
+    }
+
+    /// Changes every time an entry is invalidated. Read it before loading messages from the
+    /// database and pass it to [`Self::insert`], so a list loaded before a write isn't cached
+    /// after the write invalidated the conversation.
+    pub fn generation(&self) -> u64 {
+        self.state.lock().unwrap().generation
+    }
+
+    /// Caches the messages of the conversation, oldest first, unless an entry was invalidated
+    /// since `generation` was read.
+    pub fn insert(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        messages: Vec<Message>,
+        generation: u64,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return;
+        }
+
+        let key = (user_id, conversation_id);
+        if state.entries.insert(key, messages).is_some() {
+            state.touch(key);
+            return;
+        }
+
+        state.recency.push_back(key);
+        if state.recency.len() > self.capacity
+            && let Some(evicted) = state.recency.pop_front()
+        {
+            state.entries.remove(&evicted);
+        }
+    }
+
+    /// Drops the cached messages of a conversation. Call it after every write to it.
+    pub fn invalidate(&self, user_id: Uuid, conversation_id: Uuid) {
+        let mut state = self.state.lock().unwrap();
+        let key = (user_id, conversation_id);
+        state.generation += 1;
+        if state.entries.remove(&key).is_some() {
+            state.recency.retain(|cached| *cached != key);
+        }
+    }
+}
+
+impl State {
+    /// Marks `key` as the most recently used one.
+    fn touch(&mut self, key: Key) {
+        self.recency.retain(|cached| *cached != key);
+        self.recency.push_back(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infrastructure::entities::MessageKind;
+    use chrono::Utc;
+    use sqlx::types::Json;
+
+    fn messages(text: &str) -> Vec<Message> {
+        vec![Message {
+            id: Uuid::new_v4(),
+            conversation_id: Uuid::nil(),
+            kind: MessageKind::User,
+            created_at: Utc::now(),
+            text: text.to_owned(),
+            token_count: 0,
+            attachments: Json(Vec::new()),
+            incomplete: false,
+        }]
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = MessageCache::new(2);
+        let user = Uuid::new_v4();
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        cache.insert(user, a, messages("a"), cache.generation());
+        cache.insert(user, b, messages("b"), cache.generation());
+        assert!(cache.get(user, a).is_some());
+        cache.insert(user, c, messages("c"), cache.generation());
+
+        assert!(cache.get(user, a).is_some());
+        assert!(cache.get(user, b).is_none());
+        assert!(cache.get(user, c).is_some());
+    }
+
+    #[test]
+    fn test_entries_are_user_scoped() {
+        let cache = MessageCache::new(2);
+        let conversation = Uuid::new_v4();
+
+        cache.insert(Uuid::new_v4(), conversation, messages("a"), 0);
+
+        assert!(cache.get(Uuid::new_v4(), conversation).is_none());
+    }
+
+    #[test]
+    fn test_skips_lists_loaded_before_an_invalidation() {
+        let cache = MessageCache::new(2);
+        let (user, conversation) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let generation = cache.generation();
+        cache.invalidate(user, conversation);
+        cache.insert(user, conversation, messages("stale"), generation);
+
+        assert!(cache.get(user, conversation).is_none());
+    }
+
+    #[test]
+    fn test_zero_capacity_disables_the_cache() {
+        let cache = MessageCache::new(0);
+        let (user, conversation) = (Uuid::new_v4(), Uuid::new_v4());
+
+        cache.insert(user, conversation, messages("a"), cache.generation());
+
+        assert!(cache.get(user, conversation).is_none());
+    }
+}
@@ -1,3 +1,30 @@
 pub mod assistant;
+pub mod compaction;
+pub mod config;
+pub mod conversation_events;
+pub mod conversation_locks;
+pub mod generation;
+pub mod generation_webhooks;
+pub mod gpu;
+pub mod inference_events;
+pub mod latency;
+pub mod leak_guard;
+pub mod load_progress;
+pub mod logits_readback;
+pub mod message_cache;
+pub mod model_overrides;
+pub mod model_reload;
+pub mod model_source;
+pub mod personas;
+pub mod prompt_cache;
+pub mod prompt_history;
+pub mod queue;
+pub mod response_prefix;
+pub mod sampling;
 pub mod services;
+pub mod stats;
+pub mod stub_inference;
+pub mod task_queue;
+pub mod thinking;
 pub mod traits;
+pub mod user_generations;
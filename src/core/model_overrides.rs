@@ -0,0 +1,137 @@
+//! Overrides of the model configuration read from the GGUF, for experimenting with other values
+//! without editing the model file.
+//!
+//! - `MODEL_TYPE`: the architecture to run the weights as, `llama` (default) or `qwen`
+//! - `CONTEXT_SIZE`: caps the context size, see [`AppConfig`](crate::core::config::AppConfig)
+//! - `ROPE_FREQ_BASE`: the RoPE frequency base, `llama.rope.freq_base` in the GGUF
+//!
+//! Invalid values are logged and ignored, so the model runs with its own configuration instead.
+
+use log::{info, warn};
+use std::fmt::Display;
+use std::str::FromStr;
+use wgml::models::llama2::LlamaModelType;
+use wgml::models::llama2::cpu::Llama2Config;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelOverrides {
+    pub model_type: LlamaModelType,
+    /// Upper limit of the context size
+    pub context_size: usize,
+    pub rope_freq_base: Option<f32>,
+}
+
+impl ModelOverrides {
+    /// Reads the overrides from the environment. `context_size` is `CONTEXT_SIZE` as already
+    /// read into the app config.
+    pub fn from_env(context_size: usize) -> ModelOverrides {
+        ModelOverrides {
+            model_type: parse_env("MODEL_TYPE", parse_model_type).unwrap_or(LlamaModelType::Llama),
+            context_size,
+            rope_freq_base: parse_env("ROPE_FREQ_BASE", |value| {
+                f32::from_str(value)
+                    .ok()
+                    .filter(|base| base.is_finite() && *base > 0.0)
+            }),
+        }
+    }
+
+    /// Applies the overrides to the configuration loaded from the GGUF, and logs every field
+    /// they changed.
+    pub fn apply(&self, config: &mut Llama2Config) {
+        for change in self.apply_to(&mut config.seq_len, &mut config.rope_theta) {
+            info!("Model config override: {change}");
+        }
+    }
+
+    /// Applies the overrides to the fields they cover. Returns a description of every changed
+    /// field, with its GGUF value and the value it was changed to.
+    fn apply_to(&self, seq_len: &mut usize, rope_theta: &mut f32) -> Vec<String> {
+        let mut changes = Vec::new();
+        set_field(
+            "seq_len",
+            seq_len,
+            Some((*seq_len).min(self.context_size)),
+            &mut changes,
+        );
+        set_field("rope_theta", rope_theta, self.rope_freq_base, &mut changes);
+        changes
+    }
+}
+
+fn parse_model_type(value: &str) -> Option<LlamaModelType> {
+    match value.to_ascii_lowercase().as_str() {
+        "llama" => Some(LlamaModelType::Llama),
+        "qwen" => Some(LlamaModelType::Qwen),
+        _ => None,
+    }
+}
+
+/// Parses the variable if it is set, warning about a value that doesn't parse.
+fn parse_env<T>(key: &str, parse: impl FnOnce(&str) -> Option<T>) -> Option<T> {
+    let value = std::env::var(key).ok()?;
+    let parsed = parse(&value);
+    if parsed.is_none() {
+        warn!("ignoring invalid {key} {value:?}");
+    }
+    parsed
+}
+
+fn set_field<T: Copy + PartialEq + Display>(
+    name: &str,
+    field: &mut T,
+    value: Option<T>,
+    changes: &mut Vec<String>,
+) {
+    if let Some(value) = value
+        && value != *field
+    {
+        changes.push(format!("{name}: {} -> {value}", *field));
+        *field = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_override_changes_only_its_field() {
+        let overrides = ModelOverrides {
+            model_type: LlamaModelType::Llama,
+            context_size: 32_768,
+            rope_freq_base: Some(500_000.0),
+        };
+        // As read from a GGUF whose context fits within CONTEXT_SIZE
+        let (mut seq_len, mut rope_theta) = (8_192, 10_000.0);
+
+        let changes = overrides.apply_to(&mut seq_len, &mut rope_theta);
+
+        assert_eq!(rope_theta, 500_000.0);
+        assert_eq!(seq_len, 8_192);
+        assert_eq!(changes, ["rope_theta: 10000 -> 500000"]);
+    }
+
+    #[test]
+    fn test_context_size_caps_the_gguf_context() {
+        let overrides = ModelOverrides {
+            model_type: LlamaModelType::Llama,
+            context_size: 4_096,
+            rope_freq_base: None,
+        };
+        let (mut seq_len, mut rope_theta) = (131_072, 10_000.0);
+
+        let changes = overrides.apply_to(&mut seq_len, &mut rope_theta);
+
+        assert_eq!(seq_len, 4_096);
+        assert_eq!(rope_theta, 10_000.0);
+        assert_eq!(changes, ["seq_len: 131072 -> 4096"]);
+    }
+
+    #[test]
+    fn test_model_type_names() {
+        assert_eq!(parse_model_type("Qwen"), Some(LlamaModelType::Qwen));
+        assert_eq!(parse_model_type("llama"), Some(LlamaModelType::Llama));
+        assert_eq!(parse_model_type("gpt2"), None);
+    }
+}
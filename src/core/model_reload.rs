@@ -0,0 +1,32 @@
+//! The window in which the model is reloaded.
+//!
+//! While the inference worker swaps the weights, new generations would only wait in its queue for
+//! an unknown time. A reload holds a [`ReloadWindow`] for as long as it runs, and requests that
+//! would start a generation are turned away until it ends, see
+//! [`reject_during_reload`](crate::api::reject_during_reload).
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static RELOADING: AtomicBool = AtomicBool::new(false);
+
+/// Marks the model as reloading until dropped.
+#[derive(Debug)]
+pub struct ReloadWindow(());
+
+impl ReloadWindow {
+    pub fn begin() -> ReloadWindow {
+        RELOADING.store(true, Ordering::Release);
+        ReloadWindow(())
+    }
+}
+
+impl Drop for ReloadWindow {
+    fn drop(&mut self) {
+        RELOADING.store(false, Ordering::Release);
+    }
+}
+
+/// Whether a reload of the model is in progress.
+pub fn is_reloading() -> bool {
+    RELOADING.load(Ordering::Acquire)
+}
@@ -0,0 +1,83 @@
+//! Where the model file is read from.
+//!
+//! By default the model is the file `MODEL_FILE_NAME`. Sandboxed deployments that can't open
+//! files by path can pass it as an open file descriptor in `MODEL_FD` instead, and embedders can
+//! hand over the bytes of a model fetched from elsewhere, e.g. object storage.
+
+use crate::core::assistant::model_file_name;
+use memmap2::Mmap;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[derive(Debug)]
+pub enum ModelSource {
+    /// A model file, memory-mapped
+    Path(PathBuf),
+    /// An open model file, memory-mapped. The file descriptor is closed once it is mapped.
+    #[cfg(unix)]
+    Fd(std::os::fd::RawFd),
+    /// The contents of a model file
+    Bytes(Vec<u8>),
+}
+
+/// The bytes of a loaded model file, to parse with `Gguf::from_bytes`.
+pub enum ModelBytes {
+    Mapped(Mmap),
+    Owned(Vec<u8>),
+}
+
+impl Deref for ModelBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            ModelBytes::Mapped(mmap) => mmap,
+            ModelBytes::Owned(bytes) => bytes,
+        }
+    }
+}
+
+impl ModelSource {
+    /// The file descriptor `MODEL_FD` if it is set, otherwise the file `MODEL_FILE_NAME`.
+    pub fn from_env() -> ModelSource {
+        #[cfg(unix)]
+        if let Some(fd) = std::env::var("MODEL_FD")
+            .ok()
+            .and_then(|fd| std::os::fd::RawFd::from_str(&fd).ok())
+        {
+            return ModelSource::Fd(fd);
+        }
+        ModelSource::Path(PathBuf::from(model_file_name()))
+    }
+
+    /// Maps the model file into memory, or takes over the bytes of a `Bytes` source.
+    pub fn load(self) -> std::io::Result<ModelBytes> {
+        let file = match self {
+            ModelSource::Path(path) => File::open(path)?,
+            #[cfg(unix)]
+            ModelSource::Fd(fd) => {
+                use std::os::fd::FromRawFd;
+                // The descriptor was handed to this process for the model only
+                unsafe { File::from_raw_fd(fd) }
+            }
+            ModelSource::Bytes(bytes) => return Ok(ModelBytes::Owned(bytes)),
+        };
+        // The mapping stays valid after the file is closed
+        let mmap = unsafe { Mmap::map(&file) }?;
+        Ok(ModelBytes::Mapped(mmap))
+    }
+}
+
+impl Display for ModelSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModelSource::Path(path) => write!(f, "{}", path.display()),
+            #[cfg(unix)]
+            ModelSource::Fd(fd) => write!(f, "file descriptor {fd}"),
+            ModelSource::Bytes(bytes) => write!(f, "{} bytes in memory", bytes.len()),
+        }
+    }
+}
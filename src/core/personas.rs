@@ -0,0 +1,64 @@
+//! Named system prompts ("personas") operators can offer as assistant personalities.
+//!
+//! Personas are loaded from the JSON file in `PERSONAS_FILE`, mapping each name to its prompt:
+//!
+//! ```json
+//! {
+//!     "pirate": {
+//!         "description": "Answers like a pirate",
+//!         "system_prompt": "You are a helpful assistant who talks like a pirate."
+//!     }
+//! }
+//! ```
+
+use anyhow::Context;
+use di::{inject, injectable};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Persona {
+    pub description: String,
+    pub system_prompt: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(transparent)]
+pub struct Personas {
+    personas: BTreeMap<String, Persona>,
+}
+
+#[injectable]
+impl Personas {
+    #[inject]
+    pub fn create() -> Personas {
+        dotenvy::dotenv().ok();
+
+        match std::env::var("PERSONAS_FILE") {
+            Ok(path) => Personas::from_file(&path).expect("failed to load personas"),
+            Err(_) => Personas::default(),
+        }
+    }
+}
+
+impl Personas {
+    pub fn from_file(path: &str) -> anyhow::Result<Personas> {
+        let json = std::fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+        Personas::from_json(&json).with_context(|| format!("parsing {path}"))
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Personas> {
+        serde_json::from_str(json)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Persona> {
+        self.personas.get(name)
+    }
+
+    /// The personas with their names, sorted by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Persona)> {
+        self.personas
+            .iter()
+            .map(|(name, persona)| (name.as_str(), persona))
+    }
+}
@@ -0,0 +1,101 @@
+//! Reuse of the attention keys and values of the previous generation.
+//!
+//! The worker runs every generation on the same `Llama2State`, whose key/value cache keeps the
+//! keys and values of every position the last generation ran. A prompt that starts with the same
+//! tokens, typically the long system prompt shared across turns or the history of the
+//! conversation the last reply was in, has the same keys and values for that prefix, so the
+//! forward passes of those positions can be skipped. Enabled with `PROMPT_CACHE`, see
+//! [`crate::core::config::prompt_cache`].
+//!
+//! The state holds a single sequence, so the cache only has to know the tokens of its positions
+//! and compares them with the prompt token by token.
+
+/// The tokens whose keys and values are in the state, by position.
+#[derive(Debug, Clone, Default)]
+pub struct PromptCache {
+    tokens: Vec<usize>,
+}
+
+impl PromptCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of leading positions of `prompt` whose keys and values are in the state. The last
+    /// token of the prompt is never counted, as its pass is needed for the logits of the reply.
+    pub fn reusable_prefix(&self, prompt: &[usize]) -> usize {
+        let common = self
+            .tokens
+            .iter()
+            .zip(prompt)
+            .take_while(|(cached, token)| cached == token)
+            .count();
+        common.min(prompt.len().saturating_sub(1))
+    }
+
+    /// Forgets the positions from `pos` on, before a pass overwrites the first of them. A pass
+    /// that fails may leave its position half written, so this goes before the pass.
+    pub fn truncate(&mut self, pos: usize) {
+        self.tokens.truncate(pos);
+    }
+
+    /// Records that the pass of `token` at `pos` has written its keys and values.
+    pub fn ran(&mut self, token: usize, pos: usize) {
+        self.tokens.truncate(pos);
+        // A gap would make later positions look cached under the wrong tokens
+        if self.tokens.len() == pos {
+            self.tokens.push(token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache_of(tokens: &[usize]) -> PromptCache {
+        let mut cache = PromptCache::new();
+        for (pos, &token) in tokens.iter().enumerate() {
+            cache.ran(token, pos);
+        }
+        cache
+    }
+
+    #[test]
+    fn test_shared_prefix_is_reusable() {
+        let cache = cache_of(&[1, 2, 3, 4, 5]);
+
+        assert_eq!(cache.reusable_prefix(&[1, 2, 3, 9, 9]), 3);
+        assert_eq!(cache.reusable_prefix(&[9, 2, 3]), 0);
+        assert_eq!(PromptCache::new().reusable_prefix(&[1, 2]), 0);
+    }
+
+    #[test]
+    fn test_last_prompt_token_is_always_run() {
+        let cache = cache_of(&[1, 2, 3, 4, 5]);
+
+        assert_eq!(cache.reusable_prefix(&[1, 2, 3]), 2);
+        assert_eq!(cache.reusable_prefix(&[1]), 0);
+        assert_eq!(cache.reusable_prefix(&[]), 0);
+    }
+
+    #[test]
+    fn test_overwritten_positions_are_forgotten() {
+        let mut cache = cache_of(&[1, 2, 3, 4, 5]);
+
+        // A failed pass at position 2
+        cache.truncate(2);
+        assert_eq!(cache.reusable_prefix(&[1, 2, 3, 4, 5, 6]), 2);
+
+        cache.ran(7, 2);
+        assert_eq!(cache.reusable_prefix(&[1, 2, 7, 4, 5]), 3);
+    }
+
+    #[test]
+    fn test_pass_after_a_gap_is_not_recorded() {
+        let mut cache = cache_of(&[1, 2]);
+
+        cache.ran(4, 3);
+        assert_eq!(cache.reusable_prefix(&[1, 2, 3, 4, 5]), 2);
+    }
+}
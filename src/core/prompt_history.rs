@@ -0,0 +1,161 @@
+//! Which messages of a conversation go into a prompt.
+//!
+//! A long conversation doesn't fit in the context with room left for the reply. The system prompt
+//! and the newest message are always kept, and the oldest messages in between are left out until
+//! the rest fits in the token budget.
+
+use crate::core::assistant::{ChatMessage, Role};
+use crate::infrastructure::entities;
+use wgml::models::gpt2::Gpt2Tokenizer;
+
+/// Tokens the chat template adds around every message, e.g. the role header, on top of its text.
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+/// Counts the tokens of a text.
+pub trait TokenCounter {
+    fn count_tokens(&self, text: &str) -> usize;
+}
+
+impl TokenCounter for Gpt2Tokenizer {
+    fn count_tokens(&self, text: &str) -> usize {
+        self.encode(text).len()
+    }
+}
+
+/// Estimates a token per 4 bytes of text, for callers without the model's tokenizer, like the
+/// HTTP handlers. English text averages about that.
+pub struct EstimatedTokens;
+
+impl TokenCounter for EstimatedTokens {
+    fn count_tokens(&self, text: &str) -> usize {
+        text.len().div_ceil(4)
+    }
+}
+
+/// Builds the messages of a prompt from the conversation's messages, oldest first, within
+/// `token_budget` tokens. The system prompt and the newest message are kept even if they alone
+/// exceed the budget.
+pub fn build_prompt_messages(
+    messages: Vec<entities::Message>,
+    token_budget: usize,
+    tokenizer: &impl TokenCounter,
+) -> Vec<ChatMessage> {
+    let mut messages: Vec<ChatMessage> = messages.into_iter().map(ChatMessage::from).collect();
+    let tokens =
+        |message: &ChatMessage| tokenizer.count_tokens(message.content()) + MESSAGE_OVERHEAD_TOKENS;
+
+    let system_prompt = match messages.first() {
+        Some(message) if message.role() == Role::System => Some(messages.remove(0)),
+        _ => None,
+    };
+    let mut used = system_prompt.as_ref().map_or(0, tokens);
+
+    // Walks back from the newest message and keeps messages while they fit
+    let mut kept = 0;
+    for (i, message) in messages.iter().rev().enumerate() {
+        let message_tokens = tokens(message);
+        if i > 0 && used + message_tokens > token_budget {
+            break;
+        }
+        used += message_tokens;
+        kept += 1;
+    }
+
+    let dropped = messages.len() - kept;
+    system_prompt
+        .into_iter()
+        .chain(messages.into_iter().skip(dropped))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use sqlx::types::Json;
+    use uuid::Uuid;
+
+    /// Counts a token per word
+    struct Words;
+
+    impl TokenCounter for Words {
+        fn count_tokens(&self, text: &str) -> usize {
+            text.split_whitespace().count()
+        }
+    }
+
+    fn message(kind: entities::MessageKind, text: &str) -> entities::Message {
+        entities::Message {
+            id: Uuid::new_v4(),
+            conversation_id: Uuid::new_v4(),
+            kind,
+            created_at: Utc::now(),
+            text: text.to_owned(),
+            token_count: 0,
+            attachments: Json(Vec::new()),
+            incomplete: false,
+        }
+    }
+
+    fn contents(messages: &[ChatMessage]) -> Vec<&str> {
+        messages.iter().map(ChatMessage::content).collect()
+    }
+
+    #[test]
+    fn test_long_history_keeps_system_prompt_and_newest_messages() {
+        let mut history = vec![message(entities::MessageKind::System, "be brief")];
+        for turn in 0..20 {
+            history.push(message(
+                entities::MessageKind::User,
+                &format!("question {turn} is here"),
+            ));
+            history.push(message(
+                entities::MessageKind::Bot,
+                &format!("answer {turn} is here"),
+            ));
+        }
+        history.push(message(entities::MessageKind::User, "last question"));
+
+        // The system prompt takes 6 tokens, the last question 6 and every other message 8
+        let prompt = build_prompt_messages(history, 34, &Words);
+
+        assert_eq!(
+            contents(&prompt),
+            [
+                "be brief",
+                "question 19 is here",
+                "answer 19 is here",
+                "last question"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_newest_message_is_kept_over_the_budget() {
+        let history = vec![
+            message(entities::MessageKind::User, "an old question"),
+            message(
+                entities::MessageKind::User,
+                "a question far too long to fit",
+            ),
+        ];
+
+        let prompt = build_prompt_messages(history, 2, &Words);
+
+        assert_eq!(contents(&prompt), ["a question far too long to fit"]);
+    }
+
+    #[test]
+    fn test_short_history_is_kept_whole() {
+        let history = vec![
+            message(entities::MessageKind::System, "be brief"),
+            message(entities::MessageKind::User, "hi"),
+            message(entities::MessageKind::Bot, "hello"),
+            message(entities::MessageKind::User, "bye"),
+        ];
+
+        let prompt = build_prompt_messages(history, 1_000, &Words);
+
+        assert_eq!(contents(&prompt), ["be brief", "hi", "hello", "bye"]);
+    }
+}
@@ -0,0 +1,98 @@
+//! Inference queue positions.
+//!
+//! Every [`InferenceTask`](crate::core::assistant::InferenceTask) takes a ticket when it is
+//! created, and hands it back when it is dropped, i.e. once the worker is done with it. While the
+//! task waits in the queue, the queue keeps the position of its ticket up to date as tasks are
+//! queued and received, see [`crate::core::task_queue`]. The worker receiving the task sets it to
+//! `0`, see [`QueueTicket::dequeue`].
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::Notify;
+
+static ISSUED: AtomicU64 = AtomicU64::new(0);
+static FINISHED: AtomicU64 = AtomicU64::new(0);
+/// Wakes the clients following a position when a position changes
+static QUEUE_CHANGED: Notify = Notify::const_new();
+
+/// Held by a task for as long as it is queued or being generated.
+#[derive(Debug)]
+pub struct QueueTicket {
+    position: Arc<AtomicU64>,
+}
+
+impl QueueTicket {
+    /// A ticket behind every task that is queued or being generated, until the task is queued.
+    pub fn take() -> Self {
+        // Tickets issued after this one can finish in between
+        let position = ISSUED
+            .fetch_add(1, Ordering::SeqCst)
+            .saturating_sub(FINISHED.load(Ordering::SeqCst));
+        QueueTicket {
+            position: Arc::new(AtomicU64::new(position)),
+        }
+    }
+
+    pub fn position(&self) -> QueuePosition {
+        QueuePosition {
+            position: self.position.clone(),
+        }
+    }
+
+    /// Called by the queue when the tasks ahead of this one change.
+    pub fn set_position(&self, position: u64) {
+        if self.position.swap(position, Ordering::SeqCst) != position {
+            QUEUE_CHANGED.notify_waiters();
+        }
+    }
+
+    /// Called when the worker receives the task. Its position is `0` from then on.
+    pub fn dequeue(&self) {
+        self.set_position(0);
+    }
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        FINISHED.fetch_add(1, Ordering::SeqCst);
+        // A task dropped before the worker got to it won't run, so nobody waits on it either
+        self.dequeue();
+    }
+}
+
+/// Number of tasks that are queued or being generated.
+pub fn depth() -> u64 {
+    // A ticket is issued before it can be handed back
+    let finished = FINISHED.load(Ordering::SeqCst);
+    ISSUED.load(Ordering::SeqCst).saturating_sub(finished)
+}
+
+/// Lets a client follow the position of a queued task without holding the task itself.
+#[derive(Debug, Clone)]
+pub struct QueuePosition {
+    position: Arc<AtomicU64>,
+}
+
+impl QueuePosition {
+    /// Number of generations that run before this task. `0` once the worker has received the
+    /// task.
+    pub fn get(&self) -> u64 {
+        self.position.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the position is no longer `position` and returns the new position. A task
+    /// with a higher priority queued ahead moves it back.
+    pub async fn changed_from(&self, position: u64) -> u64 {
+        loop {
+            // Created before checking so a change in between still wakes us up
+            let changed = QUEUE_CHANGED.notified();
+
+            let current = self.get();
+            if current != position {
+                return current;
+            }
+
+            changed.await;
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Stripping of speaker prefixes from generated replies.
+//!
+//! Some chat templates make the model start its reply with a speaker name such as `Assistant:`.
+//! The prefixes listed in `RESPONSE_PREFIXES`, separated by `|`, are removed from the start of a
+//! reply before any of it is streamed or saved.
+
+/// Reads the prefixes to strip from `RESPONSE_PREFIXES`. Empty if it isn't set.
+pub fn response_prefixes_from_env() -> Vec<String> {
+    std::env::var("RESPONSE_PREFIXES")
+        .map(|prefixes| {
+            prefixes
+                .split('|')
+                .map(str::trim)
+                .filter(|prefix| !prefix.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Removes a prefix from the start of a reply that is generated one part at a time.
+///
+/// A prefix may span several parts, so the start of the reply is held back for as long as it
+/// could still turn out to be one of the prefixes.
+pub struct PrefixStripper<'a> {
+    prefixes: &'a [String],
+    buffer: String,
+    done: bool,
+    /// Whether a prefix was just stripped, so whitespace after it is still to be dropped.
+    after_prefix: bool,
+}
+
+impl<'a> PrefixStripper<'a> {
+    pub fn new(prefixes: &'a [String]) -> Self {
+        PrefixStripper {
+            prefixes,
+            buffer: String::new(),
+            done: prefixes.is_empty(),
+            after_prefix: false,
+        }
+    }
+
+    /// Takes the next generated part and returns the text that can be sent on, if any.
+    pub fn push(&mut self, part: String) -> Option<String> {
+        if self.after_prefix {
+            let rest = part.trim_start();
+            if rest.is_empty() {
+                return None;
+            }
+            self.after_prefix = false;
+            return Some(rest.to_owned());
+        }
+        if self.done {
+            return Some(part);
+        }
+
+        self.buffer.push_str(&part);
+        // The model may put whitespace before the prefix
+        let start = self.buffer.trim_start();
+
+        if let Some(rest) = self
+            .prefixes
+            .iter()
+            .find_map(|prefix| start.strip_prefix(prefix.as_str()))
+        {
+            let rest = rest.trim_start().to_owned();
+            self.buffer.clear();
+            self.done = true;
+            self.after_prefix = rest.is_empty();
+            return (!rest.is_empty()).then_some(rest);
+        }
+
+        if start.is_empty() || self.prefixes.iter().any(|prefix| prefix.starts_with(start)) {
+            return None;
+        }
+
+        self.done = true;
+        Some(std::mem::take(&mut self.buffer))
+    }
+
+    /// Returns the text still held back once the reply is complete.
+    pub fn finish(self) -> Option<String> {
+        (!self.buffer.is_empty()).then_some(self.buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip(prefixes: &[&str], parts: &[&str]) -> Vec<String> {
+        let prefixes: Vec<String> = prefixes.iter().map(|p| p.to_string()).collect();
+        let mut stripper = PrefixStripper::new(&prefixes);
+        let mut output: Vec<String> = parts
+            .iter()
+            .filter_map(|part| stripper.push(part.to_string()))
+            .collect();
+        output.extend(stripper.finish());
+        output
+    }
+
+    #[test]
+    fn test_strips_prefix_split_across_parts() {
+        assert_eq!(
+            strip(&["Assistant:"], &["Ass", "istant", ":", " Hello", " there"]),
+            ["Hello", " there"]
+        );
+        assert_eq!(
+            strip(&["Assistant:"], &["Assist", "ant: Hi", "!"]),
+            ["Hi", "!"]
+        );
+    }
+
+    #[test]
+    fn test_keeps_replies_without_a_prefix() {
+        assert_eq!(
+            strip(&["Assistant:"], &["Ass", "ume", " nothing"]),
+            ["Assume", " nothing"]
+        );
+        assert_eq!(strip(&[], &["Assistant:", " Hi"]), ["Assistant:", " Hi"]);
+    }
+
+    #[test]
+    fn test_returns_held_back_text_at_the_end() {
+        assert_eq!(strip(&["Assistant:"], &["Assist"]), ["Assist"]);
+    }
+}
@@ -0,0 +1,622 @@
+//! Token sampling.
+//!
+//! The decoding mode is picked with `DECODING_MODE`:
+//!
+//! - `top_p` (default): temperature and nucleus sampling.
+//! - `mirostat`: Mirostat v2, which adapts the truncation to keep the surprise of the generated
+//!   tokens close to `MIROSTAT_TAU`, learning at rate `MIROSTAT_ETA`.
+//!
+//! A request can override the temperature and top-p of its generation and add a repetition
+//! penalty, see [`SamplingParams`], directly or by naming a [`SamplingPreset`]. It can also end
+//! the generation at stop sequences and bias the logits of tokens, within [`SamplingLimits`].
+
+use log::warn;
+use nalgebra::DVector;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use utoipa::ToSchema;
+use wgml::models::sampler::Sampler;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum DecodingMode {
+    TopP { temperature: f32, top_p: f32 },
+    Mirostat { tau: f32, eta: f32 },
+}
+
+/// Temperature of the default top-p sampling.
+const DEFAULT_TEMPERATURE: f32 = 0.9;
+
+/// Top-p of the default top-p sampling.
+const DEFAULT_TOP_P: f32 = 0.95;
+
+impl Default for DecodingMode {
+    fn default() -> Self {
+        DecodingMode::TopP {
+            temperature: DEFAULT_TEMPERATURE,
+            top_p: DEFAULT_TOP_P,
+        }
+    }
+}
+
+impl DecodingMode {
+    pub fn from_env() -> Self {
+        match std::env::var("DECODING_MODE").as_deref() {
+            Ok("mirostat") => DecodingMode::Mirostat {
+                tau: env_f32("MIROSTAT_TAU", 5.0),
+                eta: env_f32("MIROSTAT_ETA", 0.1),
+            },
+            Ok("top_p") | Err(_) => DecodingMode::default(),
+            Ok(mode) => {
+                warn!("unknown DECODING_MODE `{mode}`, using top_p");
+                DecodingMode::default()
+            }
+        }
+    }
+}
+
+/// Largest bias `logit_bias` can add to or take from a logit.
+const MAX_LOGIT_BIAS_VALUE: f32 = 100.0;
+
+/// Sampling parameters of one generation. Unset ones are left to the server's decoding mode.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct SamplingParams {
+    /// Higher is more random, `0` always picks the most likely token.
+    pub temperature: Option<f32>,
+    /// Samples only from the most likely tokens that together have this probability.
+    pub top_p: Option<f32>,
+    /// Divides the likelihood of the tokens generated so far, `1` leaves them as they are.
+    pub repetition_penalty: Option<f32>,
+    /// Texts that end the generation when generated. They aren't part of the reply.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    /// Added to the logits of the tokens with these ids, from -100 to 100.
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty",
+        deserialize_with = "deserialize_logit_bias"
+    )]
+    #[schema(value_type = BTreeMap<String, f32>)]
+    pub logit_bias: BTreeMap<u32, f32>,
+}
+
+impl SamplingParams {
+    /// `self` with the unset parameters taken from `fallback`.
+    pub fn or(self, fallback: SamplingParams) -> SamplingParams {
+        SamplingParams {
+            temperature: self.temperature.or(fallback.temperature),
+            top_p: self.top_p.or(fallback.top_p),
+            repetition_penalty: self.repetition_penalty.or(fallback.repetition_penalty),
+            stop: if self.stop.is_empty() {
+                fallback.stop
+            } else {
+                self.stop
+            },
+            logit_bias: if self.logit_bias.is_empty() {
+                fallback.logit_bias
+            } else {
+                self.logit_bias
+            },
+        }
+    }
+
+    /// The decoding mode of a generation with these parameters. A temperature or top-p makes it
+    /// top-p sampling, taking what isn't set from `default`, or from the default top-p sampling
+    /// if `default` is Mirostat.
+    pub fn decoding_mode(&self, default: DecodingMode) -> DecodingMode {
+        if self.temperature.is_none() && self.top_p.is_none() {
+            return default;
+        }
+        let (temperature, top_p) = match default {
+            DecodingMode::TopP { temperature, top_p } => (temperature, top_p),
+            DecodingMode::Mirostat { .. } => (DEFAULT_TEMPERATURE, DEFAULT_TOP_P),
+        };
+        DecodingMode::TopP {
+            temperature: self.temperature.unwrap_or(temperature),
+            top_p: self.top_p.unwrap_or(top_p),
+        }
+    }
+
+    /// Checks the parameters are in range: a temperature of at least 0, a top-p above 0 and at
+    /// most 1, a repetition penalty above 0 and logit biases from -100 to 100. The stop sequences
+    /// and logit biases must also fit in `limits`, as every generated token is matched against
+    /// them.
+    ///
+    /// Returns every problem found, not just the first, so a client can fix them at once.
+    pub fn validate(&self, limits: &SamplingLimits) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        let mut check = |valid: bool, field: &'static str, message: &'static str| {
+            if !valid {
+                errors.push(FieldError { field, message });
+            }
+        };
+
+        check(
+            self.temperature.is_none_or(|t| t >= 0.0 && t.is_finite()),
+            "temperature",
+            "`temperature` must be at least 0",
+        );
+        check(
+            self.top_p.is_none_or(|p| p > 0.0 && p <= 1.0),
+            "top_p",
+            "`top_p` must be above 0 and at most 1",
+        );
+        check(
+            self.repetition_penalty
+                .is_none_or(|penalty| penalty > 0.0 && penalty.is_finite()),
+            "repetition_penalty",
+            "`repetition_penalty` must be above 0",
+        );
+        check(
+            self.stop.len() <= limits.max_stop_sequences,
+            "stop",
+            "`stop` has too many sequences",
+        );
+        check(
+            self.stop.iter().all(|stop| !stop.is_empty()),
+            "stop",
+            "`stop` sequences must not be empty",
+        );
+        check(
+            self.stop.iter().map(String::len).sum::<usize>() <= limits.max_stop_sequences_length,
+            "stop",
+            "`stop` sequences are too long",
+        );
+        check(
+            self.logit_bias.len() <= limits.max_logit_bias,
+            "logit_bias",
+            "`logit_bias` has too many tokens",
+        );
+        check(
+            self.logit_bias
+                .values()
+                .all(|bias| (-MAX_LOGIT_BIAS_VALUE..=MAX_LOGIT_BIAS_VALUE).contains(bias)),
+            "logit_bias",
+            "`logit_bias` values must be from -100 to 100",
+        );
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Parses parameters like `temperature=1.1,top_p=0.98,repetition_penalty=1.1`. Any of them
+    /// can be left out.
+    pub fn parse(params: &str) -> Result<SamplingParams, String> {
+        let mut parsed = SamplingParams::default();
+        for param in params.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = param
+                .split_once('=')
+                .ok_or_else(|| format!("`{param}` is not `key=value`"))?;
+            let value: f32 = value
+                .trim()
+                .parse()
+                .map_err(|_| format!("`{}` is not a number", value.trim()))?;
+            match key.trim() {
+                "temperature" => parsed.temperature = Some(value),
+                "top_p" => parsed.top_p = Some(value),
+                "repetition_penalty" => parsed.repetition_penalty = Some(value),
+                key => return Err(format!("unknown sampling parameter `{key}`")),
+            }
+        }
+        parsed
+            .validate(&SamplingLimits::default())
+            .map_err(|errors| {
+                errors
+                    .iter()
+                    .map(|error| error.message)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })?;
+        Ok(parsed)
+    }
+}
+
+/// A sampling parameter that is out of range, see [`SamplingParams::validate`].
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct FieldError {
+    /// Name of the parameter in the request, e.g. `top_p`
+    pub field: &'static str,
+    /// What is wrong with it, for humans
+    pub message: &'static str,
+}
+
+/// Named bundles of [`SamplingParams`] for clients that don't want to pick numbers. Configured
+/// with `SAMPLING_PRESET_<NAME>`, see [`crate::core::config::sampling_preset`].
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum SamplingPreset {
+    Creative,
+    Balanced,
+    Precise,
+}
+
+impl SamplingPreset {
+    pub const ALL: [SamplingPreset; 3] = [
+        SamplingPreset::Creative,
+        SamplingPreset::Balanced,
+        SamplingPreset::Precise,
+    ];
+
+    /// The parameters of the preset when it isn't configured.
+    pub fn default_params(&self) -> SamplingParams {
+        let (temperature, top_p, repetition_penalty) = match self {
+            SamplingPreset::Creative => (1.1, 0.98, 1.1),
+            SamplingPreset::Balanced => (DEFAULT_TEMPERATURE, DEFAULT_TOP_P, 1.0),
+            SamplingPreset::Precise => (0.3, 0.8, 1.0),
+        };
+        SamplingParams {
+            temperature: Some(temperature),
+            top_p: Some(top_p),
+            repetition_penalty: Some(repetition_penalty),
+            ..SamplingParams::default()
+        }
+    }
+}
+
+/// Reads the token ids of a logit bias from the string keys of a JSON object. Parsing them as
+/// numbers directly fails when the parameters are flattened into a request.
+fn deserialize_logit_bias<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<u32, f32>, D::Error> {
+    BTreeMap::<String, f32>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(token, bias)| match token.parse() {
+            Ok(token) => Ok((token, bias)),
+            Err(_) => Err(D::Error::custom(format!(
+                "`{token}` in `logit_bias` is not a token id"
+            ))),
+        })
+        .collect()
+}
+
+/// How many stop sequences and logit biases a request can set, see
+/// [`crate::core::config::sampling_limits`].
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct SamplingLimits {
+    /// Number of stop sequences.
+    pub max_stop_sequences: usize,
+    /// Combined length of the stop sequences, in bytes.
+    pub max_stop_sequences_length: usize,
+    /// Number of tokens with a logit bias.
+    pub max_logit_bias: usize,
+}
+
+impl Default for SamplingLimits {
+    fn default() -> Self {
+        SamplingLimits {
+            max_stop_sequences: 4,
+            max_stop_sequences_length: 256,
+            max_logit_bias: 300,
+        }
+    }
+}
+
+impl Display for SamplingPreset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SamplingPreset::Creative => "creative",
+            SamplingPreset::Balanced => "balanced",
+            SamplingPreset::Precise => "precise",
+        })
+    }
+}
+
+fn env_f32(key: &str, default: f32) -> f32 {
+    std::env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Makes `tokens` impossible to sample by setting their logits to negative infinity. Tokens
+/// outside the vocabulary are ignored.
+pub fn suppress_tokens(logits: &mut [f32], tokens: impl IntoIterator<Item = usize>) {
+    for token in tokens {
+        if let Some(logit) = logits.get_mut(token) {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+}
+
+/// Adds the biases to the logits of their tokens. Tokens outside the vocabulary are ignored.
+pub fn apply_logit_bias(logits: &mut [f32], logit_bias: &BTreeMap<u32, f32>) {
+    for (&token, bias) in logit_bias {
+        if let Some(logit) = logits.get_mut(token as usize) {
+            *logit += bias;
+        }
+    }
+}
+
+/// Makes `tokens` less likely by dividing their positive logits by `penalty` and multiplying
+/// their negative ones by it. Each token is penalized once, however often it occurs. Tokens
+/// outside the vocabulary are ignored.
+pub fn apply_repetition_penalty(
+    logits: &mut [f32],
+    tokens: impl IntoIterator<Item = usize>,
+    penalty: f32,
+) {
+    let mut penalized = std::collections::HashSet::new();
+    for token in tokens {
+        if penalized.insert(token)
+            && let Some(logit) = logits.get_mut(token)
+        {
+            if *logit > 0.0 {
+                *logit /= penalty;
+            } else {
+                *logit *= penalty;
+            }
+        }
+    }
+}
+
+/// Samples the tokens of one generation. Stateful, so it must live as long as the generation.
+pub enum TokenSampler {
+    TopP(Sampler),
+    Mirostat(Mirostat),
+}
+
+impl TokenSampler {
+    pub fn new(mode: DecodingMode, vocab_size: usize) -> Self {
+        match mode {
+            DecodingMode::TopP { temperature, top_p } => {
+                TokenSampler::TopP(Sampler::new(vocab_size, temperature, top_p))
+            }
+            DecodingMode::Mirostat { tau, eta } => TokenSampler::Mirostat(Mirostat::new(tau, eta)),
+        }
+    }
+
+    pub fn sample(&mut self, logits: &mut DVector<f32>) -> usize {
+        match self {
+            TokenSampler::TopP(sampler) => sampler.sample(logits),
+            TokenSampler::Mirostat(mirostat) => mirostat.sample(logits.as_slice(), fastrand::f32()),
+        }
+    }
+}
+
+/// Mirostat v2 sampling state.
+#[derive(Debug, Clone)]
+pub struct Mirostat {
+    tau: f32,
+    eta: f32,
+    /// Maximum surprise a token may have to be sampled, learned across tokens.
+    mu: f32,
+}
+
+impl Mirostat {
+    pub fn new(tau: f32, eta: f32) -> Self {
+        Mirostat {
+            tau,
+            eta,
+            mu: 2.0 * tau,
+        }
+    }
+
+    pub fn mu(&self) -> f32 {
+        self.mu
+    }
+
+    /// Samples a token with the uniform random number `uniform` in `[0, 1)` and moves `mu` by
+    /// how far the surprise of the sampled token was from the target.
+    pub fn sample(&mut self, logits: &[f32], uniform: f32) -> usize {
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let mut candidates: Vec<(usize, f32)> = logits
+            .iter()
+            .map(|logit| (logit - max).exp())
+            .enumerate()
+            .collect();
+        let sum: f32 = candidates.iter().map(|(_, p)| p).sum();
+        candidates.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+
+        // Drop the tokens more surprising than `mu`, always keeping the most likely one
+        let kept = candidates
+            .iter()
+            .skip(1)
+            .take_while(|(_, p)| -(p / sum).log2() <= self.mu)
+            .count()
+            + 1;
+        candidates.truncate(kept);
+
+        let kept_sum: f32 = candidates.iter().map(|(_, p)| p).sum();
+        let mut threshold = uniform * kept_sum;
+        let (token, p) = candidates
+            .iter()
+            .copied()
+            .find(|(_, p)| {
+                threshold -= p;
+                threshold < 0.0
+            })
+            .unwrap_or(candidates[kept - 1]);
+
+        self.update(-(p / kept_sum).log2());
+        token
+    }
+
+    /// The feedback step: lower `mu` after too surprising tokens, raise it after too dull ones.
+    pub fn update(&mut self, observed_surprise: f32) {
+        self.mu -= self.eta * (observed_surprise - self.tau);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repetition_penalty_makes_generated_tokens_less_likely() {
+        let mut logits = [2.0, -1.0, 3.0, 0.5];
+        apply_repetition_penalty(&mut logits, [0, 1, 0, 9], 2.0);
+
+        // Repeated tokens are penalized once
+        assert_eq!(logits, [1.0, -2.0, 3.0, 0.5]);
+    }
+
+    #[test]
+    fn test_sampling_params_override_the_decoding_mode() {
+        let mirostat = DecodingMode::Mirostat { tau: 5.0, eta: 0.1 };
+        assert_eq!(SamplingParams::default().decoding_mode(mirostat), mirostat);
+
+        let params = SamplingParams {
+            top_p: Some(0.5),
+            ..SamplingParams::default()
+        };
+        assert_eq!(
+            params.decoding_mode(mirostat),
+            DecodingMode::TopP {
+                temperature: DEFAULT_TEMPERATURE,
+                top_p: 0.5
+            }
+        );
+        assert_eq!(
+            params.decoding_mode(DecodingMode::TopP {
+                temperature: 0.2,
+                top_p: 0.9
+            }),
+            DecodingMode::TopP {
+                temperature: 0.2,
+                top_p: 0.5
+            }
+        );
+    }
+
+    #[test]
+    fn test_sampling_params_are_parsed() {
+        assert_eq!(
+            SamplingParams::parse("temperature=1.2, repetition_penalty=1.1"),
+            Ok(SamplingParams {
+                temperature: Some(1.2),
+                repetition_penalty: Some(1.1),
+                ..SamplingParams::default()
+            })
+        );
+        assert!(SamplingParams::parse("heat=1").is_err());
+        assert!(SamplingParams::parse("top_p=2").is_err());
+    }
+
+    #[test]
+    fn test_stop_sequences_and_logit_bias_within_the_limits() {
+        let limits = SamplingLimits {
+            max_stop_sequences: 2,
+            max_stop_sequences_length: 10,
+            max_logit_bias: 2,
+        };
+        let params = SamplingParams {
+            stop: vec!["\n\n".to_owned(), "User:".to_owned()],
+            logit_bias: BTreeMap::from([(1, -100.0), (2, 5.0)]),
+            ..SamplingParams::default()
+        };
+        assert_eq!(params.validate(&limits), Ok(()));
+
+        let too_many_stops = SamplingParams {
+            stop: vec!["a".to_owned(), "b".to_owned(), "c".to_owned()],
+            ..SamplingParams::default()
+        };
+        assert!(too_many_stops.validate(&limits).is_err());
+        let too_long_stops = SamplingParams {
+            stop: vec!["Assistant:".to_owned(), "User:".to_owned()],
+            ..SamplingParams::default()
+        };
+        assert!(too_long_stops.validate(&limits).is_err());
+        let too_many_biases = SamplingParams {
+            logit_bias: BTreeMap::from([(1, 1.0), (2, 1.0), (3, 1.0)]),
+            ..SamplingParams::default()
+        };
+        assert!(too_many_biases.validate(&limits).is_err());
+        let too_large_bias = SamplingParams {
+            logit_bias: BTreeMap::from([(1, 101.0)]),
+            ..SamplingParams::default()
+        };
+        assert!(too_large_bias.validate(&limits).is_err());
+    }
+
+    #[test]
+    fn test_every_invalid_param_is_reported() {
+        let params = SamplingParams {
+            temperature: Some(-1.0),
+            top_p: Some(0.0),
+            logit_bias: BTreeMap::from([(1, 101.0)]),
+            ..SamplingParams::default()
+        };
+
+        let fields: Vec<_> = params
+            .validate(&SamplingLimits::default())
+            .unwrap_err()
+            .into_iter()
+            .map(|error| error.field)
+            .collect();
+        assert_eq!(fields, ["temperature", "top_p", "logit_bias"]);
+    }
+
+    #[test]
+    fn test_logit_bias_is_added_to_the_logits() {
+        let mut logits = [1.0, 2.0, 3.0];
+        apply_logit_bias(
+            &mut logits,
+            &BTreeMap::from([(0, 2.5), (2, -3.0), (9, 1.0)]),
+        );
+
+        assert_eq!(logits, [3.5, 2.0, 0.0]);
+    }
+
+    #[test]
+    fn test_suppressed_token_is_never_sampled() {
+        let mut logits = [0.0; 16];
+        logits[7] = 50.0;
+        suppress_tokens(&mut logits, [7, 99]);
+
+        assert_eq!(logits[7], f32::NEG_INFINITY);
+        for uniform in [0.0, 0.5, 0.99] {
+            assert_ne!(Mirostat::new(3.0, 0.5).sample(&logits, uniform), 7);
+        }
+    }
+
+    #[test]
+    fn test_mu_decreases_after_surprising_tokens() {
+        let mut mirostat = Mirostat::new(0.6, 0.5);
+
+        // Two equally likely tokens: either one is a 1 bit surprise, above the 0.6 bit target
+        let mut logits = [-50.0; 16];
+        logits[3] = 0.0;
+        logits[9] = 0.0;
+        let token = mirostat.sample(&logits, 0.7);
+
+        assert!(token == 3 || token == 9);
+        assert!((mirostat.mu() - (1.2 - 0.5 * (1.0 - 0.6))).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_mu_increases_after_predictable_tokens() {
+        let mut mirostat = Mirostat::new(3.0, 0.5);
+
+        // One token takes practically all probability, so sampling it is no surprise at all
+        let mut logits = [0.0; 16];
+        logits[7] = 50.0;
+        let token = mirostat.sample(&logits, 0.3);
+
+        assert_eq!(token, 7);
+        assert!((mirostat.mu() - (6.0 + 0.5 * 3.0)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_average_surprise_converges_to_target() {
+        let (tau, eta, steps) = (2.0, 0.1, 2000);
+        let mut mirostat = Mirostat::new(tau, eta);
+
+        // Token surprises range from well below to well above the target
+        let logits: Vec<f32> = (0..32).map(|i| -(i as f32) * 0.5).collect();
+        for step in 0..steps {
+            mirostat.sample(&logits, (step as f32 * 0.618_034).fract());
+        }
+
+        // Every step moves `mu` by `eta * (tau - surprise)`, so the total movement gives the
+        // average observed surprise
+        let average_surprise = tau - (mirostat.mu() - 2.0 * tau) / (eta * steps as f32);
+        assert!((average_surprise - tau).abs() < 0.05);
+    }
+}
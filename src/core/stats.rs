@@ -0,0 +1,132 @@
+//! Lightweight in-process statistics.
+//!
+//! A handful of counters for the `/stats` endpoint, for deployments that don't scrape the
+//! Prometheus metrics. The generation counters are updated from the
+//! [`INFERENCE_EVENTS`](crate::core::inference_events::INFERENCE_EVENTS) bus, the request counter
+//! by the HTTP middleware.
+
+use crate::core::inference_events::{GenerationStats, INFERENCE_EVENTS, InferenceEvent};
+use log::warn;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Instant;
+
+/// The counters of the server.
+pub static STATS: Stats = Stats::new();
+
+/// When the server started, see [`mark_start`].
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    requests_served: AtomicU64,
+    generations: AtomicU64,
+    tokens_generated: AtomicU64,
+    /// Time spent generating completion tokens, in microseconds
+    generation_micros: AtomicU64,
+}
+
+/// The counters at one point in time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatsSnapshot {
+    pub requests_served: u64,
+    pub generations: u64,
+    pub tokens_generated: u64,
+    /// Completion tokens generated per second of generation time, `0` before any was timed.
+    pub average_tokens_per_sec: f64,
+    pub uptime: Duration,
+}
+
+impl Stats {
+    pub const fn new() -> Self {
+        Stats {
+            requests_served: AtomicU64::new(0),
+            generations: AtomicU64::new(0),
+            tokens_generated: AtomicU64::new(0),
+            generation_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn request_served(&self) {
+        self.requests_served.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_generation(&self, stats: &GenerationStats) {
+        self.generations.fetch_add(1, Ordering::Relaxed);
+        self.tokens_generated
+            .fetch_add(stats.completion_tokens as u64, Ordering::Relaxed);
+        self.generation_micros
+            .fetch_add(stats.generation.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let tokens_generated = self.tokens_generated.load(Ordering::Relaxed);
+        let generation_micros = self.generation_micros.load(Ordering::Relaxed);
+        let average_tokens_per_sec = if generation_micros == 0 {
+            0.0
+        } else {
+            tokens_generated as f64 / Duration::from_micros(generation_micros).as_secs_f64()
+        };
+
+        StatsSnapshot {
+            requests_served: self.requests_served.load(Ordering::Relaxed),
+            generations: self.generations.load(Ordering::Relaxed),
+            tokens_generated,
+            average_tokens_per_sec,
+            uptime: STARTED.elapsed(),
+        }
+    }
+}
+
+/// Starts the uptime clock. Called when the server starts, otherwise the clock starts with the
+/// first snapshot.
+pub fn mark_start() {
+    LazyLock::force(&STARTED);
+}
+
+/// Counts every completed generation in [`STATS`]. Subscribes right away, so no generation that
+/// completes after the call is missed. Runs until the bus is dropped.
+pub fn record_events() -> impl Future<Output = ()> {
+    let mut events = INFERENCE_EVENTS.subscribe();
+    async move {
+        loop {
+            match events.recv().await {
+                Ok(InferenceEvent::Completed { stats, .. }) => STATS.record_generation(&stats),
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("stats recorder missed {skipped} inference events");
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_average_tokens_per_sec_is_over_generation_time() {
+        let stats = Stats::new();
+        assert_eq!(stats.snapshot().average_tokens_per_sec, 0.0);
+
+        for _ in 0..2 {
+            stats.record_generation(&GenerationStats {
+                prompt_tokens: 10,
+                completion_tokens: 20,
+                prefill: Duration::from_secs(1),
+                generation: Duration::from_secs(2),
+            });
+        }
+        stats.request_served();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.requests_served, 1);
+        assert_eq!(snapshot.generations, 2);
+        assert_eq!(snapshot.tokens_generated, 40);
+        assert_eq!(snapshot.average_tokens_per_sec, 10.0);
+    }
+}
@@ -0,0 +1,36 @@
+//! A stand-in for the inference worker when the server runs without a model.
+//!
+//! With `DISABLE_INFERENCE` the model isn't loaded and the worker isn't started. Every task is
+//! answered with [`STUB_RESPONSE`] at once instead, so the endpoints that generate replies still
+//! stream and save one. Meant for local development and CI of the web layer, which need no GPU.
+
+use crate::MODEL_LOADED;
+use crate::core::inference_events::CompletionReason;
+use crate::core::task_queue::TaskReceiver;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// The reply to every generation while inference is disabled.
+pub const STUB_RESPONSE: &str = "Inference is disabled, this is a canned reply.";
+
+/// Answers every task with [`STUB_RESPONSE`] in one part, until the task queue is closed. Marks
+/// the model as loaded, since the stub takes tasks like a worker with a model does.
+pub async fn background_task(mut task_queue: TaskReceiver) {
+    MODEL_LOADED.store(true, Ordering::Release);
+
+    while let Some(mut task) = task_queue.recv().await {
+        task.started(0);
+        let reason = if task
+            .return_channel()
+            .send(STUB_RESPONSE.to_owned())
+            .await
+            .is_ok()
+        {
+            task.generated_token();
+            CompletionReason::Stop
+        } else {
+            CompletionReason::Cancelled
+        };
+        task.completed(reason, Duration::ZERO, Duration::ZERO);
+    }
+}
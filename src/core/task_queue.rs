@@ -0,0 +1,343 @@
+//! The queue of tasks waiting for the inference worker.
+//!
+//! Works like a bounded `mpsc` channel, except that the worker receives the queued task with the
+//! highest [`Priority`] first, and tasks of the same priority in the order they were sent. So an
+//! interactive generation doesn't wait behind background ones like compaction summaries.
+//!
+//! The queue keeps the [`QueuePosition`](crate::core::queue::QueuePosition)s of the queued tasks
+//! in that order: a task's position is the number of tasks ahead of it, plus the generation the
+//! worker is on, if any. The worker generates one task at a time and comes back for the next one
+//! once it is done, so it is on a generation from receiving a task until it asks for the next.
+
+use crate::core::assistant::InferenceTask;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore, TryAcquireError};
+
+/// How urgently a task should be generated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Background work nobody is waiting on, e.g. compaction summaries.
+    Low,
+    /// Requests a client waits on.
+    #[default]
+    Normal,
+    High,
+}
+
+/// Creates a queue that holds up to `capacity` tasks the worker hasn't received yet.
+pub fn channel(capacity: usize) -> (TaskSender, TaskReceiver) {
+    let shared = Arc::new(Shared {
+        queued: Mutex::new(BinaryHeap::new()),
+        task_queued: Notify::new(),
+        capacity: Arc::new(Semaphore::new(capacity)),
+        senders: AtomicUsize::new(1),
+        sent: AtomicU64::new(0),
+        generating: AtomicBool::new(false),
+    });
+    (
+        TaskSender {
+            shared: shared.clone(),
+        },
+        TaskReceiver { shared },
+    )
+}
+
+struct Shared {
+    queued: Mutex<BinaryHeap<Queued>>,
+    /// Wakes the receiver when a task is queued or the last sender is dropped
+    task_queued: Notify,
+    /// A permit per free slot. Closed once the receiver is dropped.
+    capacity: Arc<Semaphore>,
+    senders: AtomicUsize,
+    /// Number of tasks sent so far, orders the tasks of the same priority
+    sent: AtomicU64,
+    /// Whether the worker is on a task it received. Only changed with `queued` locked.
+    generating: AtomicBool,
+}
+
+impl Shared {
+    fn push(&self, task: InferenceTask, permit: OwnedSemaphorePermit) {
+        let queued = Queued {
+            priority: task.priority(),
+            number: self.sent.fetch_add(1, atomic::Ordering::SeqCst),
+            task,
+            _permit: permit,
+        };
+        {
+            let mut queued_tasks = self.queued.lock().unwrap();
+            queued_tasks.push(queued);
+            update_positions(
+                &queued_tasks,
+                self.generating.load(atomic::Ordering::SeqCst),
+            );
+        }
+        self.task_queued.notify_one();
+    }
+}
+
+/// Tells every queued task how many generations run before it.
+fn update_positions(queued: &BinaryHeap<Queued>, generating: bool) {
+    let mut in_order: Vec<&Queued> = queued.iter().collect();
+    in_order.sort_unstable_by(|a, b| b.cmp(a));
+    for (ahead, queued) in in_order.into_iter().enumerate() {
+        queued
+            .task
+            .set_queue_position(ahead as u64 + u64::from(generating));
+    }
+}
+
+/// A task in the queue, holding its slot until it is received.
+struct Queued {
+    priority: Priority,
+    number: u64,
+    task: InferenceTask,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Ord for Queued {
+    /// Higher priority first, then the earlier sent
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.number.cmp(&self.number))
+    }
+}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+/// Queues tasks for the worker, see [`channel`].
+pub struct TaskSender {
+    shared: Arc<Shared>,
+}
+
+impl TaskSender {
+    /// Queues `task`, waiting for a free slot if the queue is full. Fails if the worker has
+    /// stopped.
+    pub async fn send(&self, task: InferenceTask) -> Result<(), SendError<InferenceTask>> {
+        match self.shared.capacity.clone().acquire_owned().await {
+            Ok(permit) => {
+                self.shared.push(task, permit);
+                Ok(())
+            }
+            Err(_) => Err(SendError(task)),
+        }
+    }
+
+    /// Queues `task` if there is a free slot.
+    // Hands the task back on failure like `mpsc::Sender::try_send`
+    #[allow(clippy::result_large_err)]
+    pub fn try_send(&self, task: InferenceTask) -> Result<(), TrySendError<InferenceTask>> {
+        match self.shared.capacity.clone().try_acquire_owned() {
+            Ok(permit) => {
+                self.shared.push(task, permit);
+                Ok(())
+            }
+            Err(TryAcquireError::NoPermits) => Err(TrySendError::Full(task)),
+            Err(TryAcquireError::Closed) => Err(TrySendError::Closed(task)),
+        }
+    }
+
+    /// Number of free slots.
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity.available_permits()
+    }
+}
+
+impl fmt::Debug for TaskSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskSender")
+            .field("capacity", &self.capacity())
+            .finish()
+    }
+}
+
+impl Clone for TaskSender {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, atomic::Ordering::SeqCst);
+        TaskSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for TaskSender {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, atomic::Ordering::SeqCst) == 1 {
+            self.shared.task_queued.notify_one();
+        }
+    }
+}
+
+/// The worker's end of the queue, see [`channel`].
+pub struct TaskReceiver {
+    shared: Arc<Shared>,
+}
+
+impl TaskReceiver {
+    /// Waits for the queued task with the highest priority. `None` once the queue is empty and
+    /// every sender is dropped.
+    pub async fn recv(&mut self) -> Option<InferenceTask> {
+        loop {
+            // Created before checking so a task queued in between still wakes us up
+            let task_queued = self.shared.task_queued.notified();
+
+            let received = {
+                let mut queued_tasks = self.shared.queued.lock().unwrap();
+                let received = queued_tasks.pop();
+                // Asking for a task means the worker is done with the previous one
+                let generating = received.is_some();
+                self.shared
+                    .generating
+                    .store(generating, atomic::Ordering::SeqCst);
+                update_positions(&queued_tasks, generating);
+                received
+            };
+            if let Some(queued) = received {
+                queued.task.dequeued();
+                return Some(queued.task);
+            }
+            if self.shared.senders.load(atomic::Ordering::SeqCst) == 0 {
+                return None;
+            }
+
+            task_queued.await;
+        }
+    }
+}
+
+impl fmt::Debug for TaskReceiver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskReceiver")
+            .field("queued", &self.shared.queued.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl Drop for TaskReceiver {
+    fn drop(&mut self) {
+        // Senders fail from now on, like those of a channel whose receiver is gone
+        self.shared.capacity.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(priority: Priority) -> InferenceTask {
+        let (mut task, _) = InferenceTask::new(Vec::new());
+        task.set_priority(priority);
+        task
+    }
+
+    #[tokio::test]
+    async fn test_high_priority_task_is_received_first() {
+        let (sender, mut receiver) = channel(10);
+        let low = task(Priority::Low);
+        let low_id = low.id();
+        let high = task(Priority::High);
+        let high_id = high.id();
+
+        sender.send(low).await.unwrap();
+        sender.send(high).await.unwrap();
+
+        assert_eq!(receiver.recv().await.unwrap().id(), high_id);
+        assert_eq!(receiver.recv().await.unwrap().id(), low_id);
+    }
+
+    #[tokio::test]
+    async fn test_tasks_of_the_same_priority_are_received_in_order() {
+        let (sender, mut receiver) = channel(10);
+        let tasks: Vec<_> = (0..3).map(|_| task(Priority::Normal)).collect();
+        let ids: Vec<_> = tasks.iter().map(InferenceTask::id).collect();
+        for task in tasks {
+            sender.send(task).await.unwrap();
+        }
+
+        for id in ids {
+            assert_eq!(receiver.recv().await.unwrap().id(), id);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_received_task_is_no_longer_queued() {
+        let (sender, mut receiver) = channel(10);
+        let task = task(Priority::Normal);
+        let position = task.queue_position();
+
+        sender.send(task).await.unwrap();
+        let task = receiver.recv().await.unwrap();
+
+        // Even while the worker still holds the task
+        assert_eq!(position.get(), 0);
+        assert_eq!(position.changed_from(1).await, 0);
+        drop(task);
+    }
+
+    #[tokio::test]
+    async fn test_positions_follow_the_priorities() {
+        let (sender, mut receiver) = channel(10);
+        let low = task(Priority::Low);
+        let low_position = low.queue_position();
+        let high = task(Priority::High);
+        let high_position = high.queue_position();
+
+        // Nothing runs before it while the worker is idle
+        sender.send(low).await.unwrap();
+        assert_eq!(low_position.get(), 0);
+
+        // The high priority task goes ahead of the older low priority one
+        sender.send(high).await.unwrap();
+        assert_eq!(high_position.get(), 0);
+        assert_eq!(low_position.get(), 1);
+
+        // The worker generating the high priority task keeps the other one waiting
+        let high = receiver.recv().await.unwrap();
+        assert_eq!(high_position.get(), 0);
+        assert_eq!(low_position.get(), 1);
+
+        drop(high);
+        let low = receiver.recv().await.unwrap();
+        assert_eq!(low_position.get(), 0);
+        drop(low);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_and_closed_ends() {
+        let (sender, mut receiver) = channel(1);
+        sender.try_send(task(Priority::Normal)).unwrap();
+        assert!(matches!(
+            sender.try_send(task(Priority::High)),
+            Err(TrySendError::Full(_))
+        ));
+        assert_eq!(sender.capacity(), 0);
+
+        // Receiving a task frees its slot
+        receiver.recv().await.unwrap();
+        assert_eq!(sender.capacity(), 1);
+
+        drop(sender);
+        assert!(receiver.recv().await.is_none());
+
+        let (sender, receiver) = channel(1);
+        drop(receiver);
+        assert!(sender.send(task(Priority::Normal)).await.is_err());
+    }
+}
@@ -0,0 +1,193 @@
+//! Splitting of the thinking section from generated replies.
+//!
+//! Reasoning models think out loud between special markers before they answer. When both
+//! `THINKING_OPEN_MARKER` and `THINKING_CLOSE_MARKER` are set, the text between them is streamed
+//! to clients as `thinking` events instead of message parts, and is kept out of the saved message.
+//! The markers themselves are dropped. Off by default.
+
+/// A piece of a generated reply, on either side of the thinking markers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Thinking(String),
+    Answer(String),
+}
+
+/// Splits a reply that is generated one part at a time into its thinking and its answer.
+///
+/// A marker may span several parts, so text that a marker could start with is held back until it
+/// is clear whether the marker follows.
+#[derive(Debug, Clone)]
+pub struct ThinkingSplitter {
+    open: String,
+    close: String,
+    thinking: bool,
+    pending: String,
+    /// Whether the thinking just ended, so whitespace before the answer is still to be dropped.
+    after_close: bool,
+}
+
+impl ThinkingSplitter {
+    pub fn new(open: &str, close: &str) -> Self {
+        ThinkingSplitter {
+            open: open.to_owned(),
+            close: close.to_owned(),
+            thinking: false,
+            pending: String::new(),
+            after_close: false,
+        }
+    }
+
+    /// Reads the markers from `THINKING_OPEN_MARKER` and `THINKING_CLOSE_MARKER`. `None` unless
+    /// both are set.
+    pub fn from_env() -> Option<Self> {
+        let marker = |name| std::env::var(name).ok().filter(|marker| !marker.is_empty());
+        let open = marker("THINKING_OPEN_MARKER")?;
+        let close = marker("THINKING_CLOSE_MARKER")?;
+        Some(ThinkingSplitter::new(&open, &close))
+    }
+
+    /// Takes the next generated part and returns the segments that can be sent on, in order.
+    pub fn push(&mut self, part: &str) -> Vec<Segment> {
+        self.pending.push_str(part);
+
+        let mut segments = Vec::new();
+        loop {
+            let marker = if self.thinking {
+                &self.close
+            } else {
+                &self.open
+            };
+
+            if let Some(start) = self.pending.find(marker.as_str()) {
+                let before = self.pending[..start].to_owned();
+                self.pending.drain(..start + marker.len());
+                self.emit(before, &mut segments);
+                self.after_close = self.thinking;
+                self.thinking = !self.thinking;
+                continue;
+            }
+
+            let held = self
+                .pending
+                .char_indices()
+                .map(|(i, _)| i)
+                .find(|&i| marker.starts_with(&self.pending[i..]))
+                .unwrap_or(self.pending.len());
+            let sendable = self.pending.drain(..held).collect();
+            self.emit(sendable, &mut segments);
+            return segments;
+        }
+    }
+
+    /// The held back text, once the generation has ended. A thinking section that was never
+    /// closed stays thinking.
+    pub fn finish(&mut self) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        let rest = std::mem::take(&mut self.pending);
+        self.emit(rest, &mut segments);
+        segments
+    }
+
+    fn emit(&mut self, mut text: String, segments: &mut Vec<Segment>) {
+        if self.thinking {
+            if !text.is_empty() {
+                segments.push(Segment::Thinking(text));
+            }
+            return;
+        }
+
+        if self.after_close {
+            text = text.trim_start().to_owned();
+            if text.is_empty() {
+                return;
+            }
+            self.after_close = false;
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Answer(text));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(parts: &[&str]) -> Vec<Segment> {
+        let mut splitter = ThinkingSplitter::new("<think>", "</think>");
+        let mut segments: Vec<Segment> =
+            parts.iter().flat_map(|part| splitter.push(part)).collect();
+        segments.extend(splitter.finish());
+        segments
+    }
+
+    /// Joins consecutive segments of the same kind, as a client would.
+    fn joined(segments: Vec<Segment>) -> Vec<Segment> {
+        let mut joined: Vec<Segment> = Vec::new();
+        for segment in segments {
+            match (joined.last_mut(), segment) {
+                (Some(Segment::Thinking(text)), Segment::Thinking(more))
+                | (Some(Segment::Answer(text)), Segment::Answer(more)) => text.push_str(&more),
+                (_, segment) => joined.push(segment),
+            }
+        }
+        joined
+    }
+
+    #[test]
+    fn test_thinking_and_answer_are_split_across_tokens() {
+        let segments = split(&[
+            "<th", "ink>", "Let me ", "count", ".</", "thi", "nk>", "\n\n", "Two", " apples.",
+        ]);
+        assert_eq!(
+            joined(segments),
+            vec![
+                Segment::Thinking("Let me count.".to_owned()),
+                Segment::Answer("Two apples.".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reply_without_thinking_is_all_answer() {
+        let segments = split(&["Hello", " <b>", "there"]);
+        assert_eq!(
+            joined(segments),
+            vec![Segment::Answer("Hello <b>there".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_text_that_could_start_a_marker_is_held_back() {
+        let mut splitter = ThinkingSplitter::new("<think>", "</think>");
+        assert_eq!(
+            splitter.push("Hi <thi"),
+            vec![Segment::Answer("Hi ".to_owned())]
+        );
+        assert_eq!(
+            splitter.push("s"),
+            vec![Segment::Answer("<this".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_unclosed_thinking_stays_thinking() {
+        let segments = split(&["<think>", "Hmm", "</thi"]);
+        assert_eq!(
+            joined(segments),
+            vec![Segment::Thinking("Hmm</thi".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_markers_in_a_single_part() {
+        let segments = split(&["<think>a</think>b"]);
+        assert_eq!(
+            segments,
+            vec![
+                Segment::Thinking("a".to_owned()),
+                Segment::Answer("b".to_owned()),
+            ]
+        );
+    }
+}
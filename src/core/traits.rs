@@ -1,17 +1,53 @@
 //! DI "Interfaces"
 
 use crate::infrastructure::entities;
-use crate::infrastructure::entities::MessageKind;
+use crate::infrastructure::entities::{MessageKind, MessageOrder, Rating};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
+/// Why a conversation couldn't be created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CreateConversationError {
+    UnknownPersona,
+    TooManyConversations,
+    /// The database failed.
+    Internal,
+}
+
+/// Why a user message couldn't be created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CreateMessageError {
+    /// The conversation has `MAX_MESSAGES_PER_CONVERSATION` messages already.
+    TooManyMessages,
+    /// The user has no such conversation, or the database failed.
+    Failed,
+}
+
 #[async_trait]
 pub trait ConversationService: Send + Sync {
-    /// Lists all conversations for the given user.
-    async fn list_conversations(&self, user_id: Uuid) -> Vec<entities::Conversation>;
+    /// Lists all conversations for the given user in the given order.
+    async fn list_conversations(
+        &self,
+        user_id: Uuid,
+        order: entities::ConversationOrder,
+    ) -> Vec<entities::Conversation>;
 
-    /// Creates a new conversation for the given user.
-    async fn create_conversation(&self, user_id: Uuid) -> entities::Conversation;
+    /// Creates a new conversation for the given user, starting with the system prompt of the
+    /// given persona or the default one. It gets the id `conversation_id` if one is given, and a
+    /// new one otherwise.
+    ///
+    /// Returns `Err` if `persona` doesn't name a configured persona, or the user already has
+    /// `MAX_CONVERSATIONS_PER_USER` conversations.
+    async fn create_conversation(
+        &self,
+        user_id: Uuid,
+        conversation_id: Option<Uuid>,
+        persona: Option<String>,
+    ) -> Result<entities::Conversation, CreateConversationError>;
+
+    /// The user who owns a conversation, `None` if there is no such conversation.
+    async fn conversation_owner(&self, conversation_id: Uuid) -> Result<Option<Uuid>, ()>;
 
     /// Deletes a given conversation from the given user.
     ///
@@ -19,18 +55,172 @@ pub trait ConversationService: Send + Sync {
     /// delete it.
     async fn delete_conversation(&self, user_id: Uuid) -> Result<(), ()>;
 
-    /// List all messages in a conversation.
+    /// List all messages in a conversation in the given order.
     ///
     /// Returns `Err` if the user doesn't have permissions to view this conversation.
     async fn list_messages(
         &self,
         user_id: Uuid,
         conversation_id: Uuid,
+        order: MessageOrder,
     ) -> Result<Vec<entities::Message>, ()>;
 
+    /// Rates a bot message. Rating the same message again replaces the earlier feedback.
+    ///
+    /// Returns `Err` if the message is not a bot message in one of the user's conversations.
+    async fn set_message_feedback(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        rating: Rating,
+        comment: Option<String>,
+    ) -> Result<entities::MessageFeedback, ()>;
+
+    /// Returns the user's feedback on a message, if any.
+    async fn get_message_feedback(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<Option<entities::MessageFeedback>, ()>;
+
+    /// Replaces the `replaced` messages of a conversation with a system message holding
+    /// `summary`, placed where the first replaced message was.
+    ///
+    /// The token counts of the replaced messages go with them, so usage totals only cover the
+    /// messages still in the conversation.
+    ///
+    /// Returns `Err` if any of the messages is not in one of the user's conversations.
+    async fn compact_conversation(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        replaced: Vec<entities::Message>,
+        summary: String,
+    ) -> Result<entities::Message, ()>;
+
+    /// Records the number of tokens of a message once it is known.
+    ///
+    /// Returns `Err` if the message is not in one of the user's conversations.
+    async fn set_message_token_count(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        token_count: u32,
+    ) -> Result<(), ()>;
+
+    /// Marks whether the generation of a message stopped before it was finished.
+    ///
+    /// Returns `Err` if the message is not in one of the user's conversations.
+    async fn set_message_incomplete(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        incomplete: bool,
+    ) -> Result<(), ()>;
+
+    /// Stores the thinking the model did before it wrote a message, see
+    /// [`crate::core::config::store_thinking`].
+    ///
+    /// Returns `Err` if the message is not in one of the user's conversations.
+    async fn set_message_thinking(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        thinking: String,
+    ) -> Result<(), ()>;
+
+    /// Appends generated text to a bot message, adding `token_count` to its token count.
+    ///
+    /// Returns `Err` if the message is not a bot message in one of the user's conversations.
+    async fn extend_bot_message(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message: entities::Message,
+        text: String,
+        token_count: u32,
+    ) -> Result<entities::Message, ()>;
+
+    /// Replaces the text of the first system message of a conversation, so the following
+    /// generations are prompted with it. Its token count is reset, as it no longer applies.
+    ///
+    /// Returns `Ok(None)` if the conversation has no system message, and `Err` if it is not one
+    /// of the user's conversations.
+    async fn update_system_message(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        text: String,
+    ) -> Result<Option<entities::Message>, ()>;
+
+    /// Copies a conversation and its messages to a new conversation of the user.
+    ///
+    /// The copies keep the kinds, texts, attachments and creation times of the messages, so they
+    /// are in the same order, but get new ids and no token counts.
+    ///
+    /// Returns `Ok(None)` if the user has no such conversation.
+    async fn duplicate_conversation(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+    ) -> Result<Option<entities::Conversation>, ()>;
+
+    /// The user's conversations and messages changed at or after the cursor `since`, for
+    /// clients that keep a copy of them. Without a cursor everything is listed.
+    async fn changes_since(
+        &self,
+        user_id: Uuid,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<entities::Changes, ()>;
+
+    /// Total prompt and completion tokens of a conversation.
+    ///
+    /// Returns `Ok(None)` if the user has no such conversation.
+    async fn conversation_usage(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+    ) -> Result<Option<entities::TokenUsage>, ()>;
+
+    /// Total prompt and completion tokens across all of the user's conversations.
+    async fn user_usage(&self, user_id: Uuid) -> Result<entities::TokenUsage, ()>;
+
+    /// Tags a conversation. Tags are the user's own strings, adding one the conversation has
+    /// already changes nothing.
+    ///
+    /// Returns `Err` if the user has no such conversation.
+    async fn add_tag(&self, user_id: Uuid, conversation_id: Uuid, tag: String) -> Result<(), ()>;
+
+    /// Removes a tag from a conversation.
+    ///
+    /// Returns `Ok(false)` if the user has no such conversation or it doesn't have the tag.
+    async fn remove_tag(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        tag: String,
+    ) -> Result<bool, ()>;
+
+    /// Lists the user's conversations tagged with `tag` in the given order.
+    async fn list_conversations_by_tag(
+        &self,
+        user_id: Uuid,
+        tag: String,
+        order: entities::ConversationOrder,
+    ) -> Vec<entities::Conversation>;
+
+    /// Stores a generation in the request log, see [`crate::core::config::log_requests_to_db`].
+    async fn log_request(&self, log: entities::RequestLog) -> Result<(), ()>;
+
     /// Creates a new message in a conversation.
     ///
     /// The helper functions `create_X_message` should be used instead for clarity.
+    #[allow(clippy::too_many_arguments)]
     async fn create_raw_message(
         &self,
         user_id: Uuid,
@@ -38,27 +228,22 @@ pub trait ConversationService: Send + Sync {
         kind: MessageKind,
         content: String,
         message_id: Uuid,
+        token_count: u32,
+        attachments: Vec<entities::Attachment>,
+        incomplete: bool,
     ) -> Result<entities::Message, ()>;
 
     /// Create a new user message in a conversation.
     ///
-    /// Returns `Err` if conversation does not exist or the user doesn't have permissions to post
-    /// to it.
+    /// Returns `Err` if the conversation has `MAX_MESSAGES_PER_CONVERSATION` messages already,
+    /// does not exist or the user doesn't have permissions to post to it.
     async fn create_user_message(
         &self,
         user_id: Uuid,
         conversation_id: Uuid,
         message: String,
-    ) -> Result<entities::Message, ()> {
-        self.create_raw_message(
-            user_id,
-            conversation_id,
-            MessageKind::User,
-            message,
-            Uuid::new_v4(),
-        )
-        .await
-    }
+        attachments: Vec<entities::Attachment>,
+    ) -> Result<entities::Message, CreateMessageError>;
 
     /// Create a new bot message in a conversation.
     ///
@@ -75,16 +260,25 @@ pub trait ConversationService: Send + Sync {
             MessageKind::Bot,
             message,
             Uuid::new_v4(),
+            0,
+            Vec::new(),
+            false,
         )
         .await
     }
 
+    /// Create a bot message with a given id in a conversation, `incomplete` if its generation
+    /// stopped before it was finished.
+    ///
+    /// Returns `Err` if the conversation doesn't exist.
     async fn create_bot_message_with_id(
         &self,
         user_id: Uuid,
         conversation_id: Uuid,
         message: String,
         message_id: Uuid,
+        token_count: u32,
+        incomplete: bool,
     ) -> Result<entities::Message, ()> {
         self.create_raw_message(
             user_id,
@@ -92,6 +286,9 @@ pub trait ConversationService: Send + Sync {
             MessageKind::Bot,
             message,
             message_id,
+            token_count,
+            Vec::new(),
+            incomplete,
         )
         .await
     }
@@ -111,6 +308,9 @@ pub trait ConversationService: Send + Sync {
             MessageKind::System,
             message,
             Uuid::new_v4(),
+            0,
+            Vec::new(),
+            false,
         )
         .await
     }
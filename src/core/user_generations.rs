@@ -0,0 +1,69 @@
+//! Per-user counts of the generations that are queued or running.
+//!
+//! A user that starts many generations at once would fill the inference queue and keep everyone
+//! else waiting. Each generation holds a [`UserGeneration`] for as long as its task lives, so the
+//! number of a user's generations can be capped with `MAX_CONCURRENT_GENERATIONS_PER_USER`.
+
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use uuid::Uuid;
+
+/// Number of queued or running generations of the users that have any.
+static ACTIVE: LazyLock<Mutex<HashMap<Uuid, usize>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Counts as one of the user's generations until dropped.
+#[derive(Debug)]
+pub struct UserGeneration {
+    user_id: Uuid,
+}
+
+impl Drop for UserGeneration {
+    fn drop(&mut self) {
+        // The map only keeps users with generations
+        let mut active = ACTIVE.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.user_id) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.user_id);
+            }
+        }
+    }
+}
+
+/// Starts counting a generation of the user, or returns `None` if the user has `limit`
+/// generations already.
+pub fn try_start(user_id: Uuid, limit: Option<usize>) -> Option<UserGeneration> {
+    let mut active = ACTIVE.lock().unwrap();
+    let count = active.entry(user_id).or_default();
+    if limit.is_some_and(|limit| *count >= limit) {
+        return None;
+    }
+    *count += 1;
+    Some(UserGeneration { user_id })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generations_are_capped_per_user() {
+        let user_id = Uuid::new_v4();
+        let first = try_start(user_id, Some(2)).unwrap();
+        let _second = try_start(user_id, Some(2)).unwrap();
+
+        assert!(try_start(user_id, Some(2)).is_none());
+        assert!(try_start(Uuid::new_v4(), Some(2)).is_some());
+
+        drop(first);
+        assert!(try_start(user_id, Some(2)).is_some());
+    }
+
+    #[test]
+    fn test_finished_users_are_forgotten() {
+        let user_id = Uuid::new_v4();
+        drop(try_start(user_id, None).unwrap());
+
+        assert!(!ACTIVE.lock().unwrap().contains_key(&user_id));
+    }
+}
@@ -2,11 +2,23 @@
 
 use di::inject;
 use di::injectable;
+use log::{error, info};
 use sqlx::SqlitePool;
-use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::migrate::{MigrateError, Migration, Migrator};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use std::collections::HashSet;
 use std::env;
 use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
 use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The migrations embedded in the binary.
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Database used when `DATABASE_URL` isn't set. It is created on first run.
+pub const DEFAULT_DATABASE_URL: &str = "sqlite:data/app.db";
 
 pub struct DatabaseConnection {
     connection: SqlitePool,
@@ -30,6 +42,11 @@ impl DatabaseConnection {
         let mut guard = TEST_POOL.lock().unwrap();
         *guard = None;
     }
+
+    /// Whether a test pool is set.
+    pub fn has_test_pool() -> bool {
+        TEST_POOL.lock().unwrap().is_some()
+    }
 }
 
 #[injectable]
@@ -47,7 +64,8 @@ impl DatabaseConnection {
         }
 
         dotenvy::dotenv().ok();
-        let mut connection_string = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let mut connection_string =
+            env::var("DATABASE_URL").unwrap_or(DEFAULT_DATABASE_URL.to_owned());
 
         // For tests, add shared cache mode so multiple connections see the same data
         if env::var("DATABASE_SHARED_CACHE").is_ok() && !connection_string.contains("cache=shared")
@@ -59,12 +77,128 @@ impl DatabaseConnection {
             }
         }
 
+        DatabaseConnection::open(&connection_string).expect("Cannot connect to database")
+    }
+
+    /// Opens a pool to the database at `url`. A database file that doesn't exist yet is created,
+    /// along with its directory.
+    pub fn open(url: &str) -> Result<DatabaseConnection, sqlx::Error> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+
+        // In-memory databases have no directory
+        if let Some(directory) = options.get_filename().parent()
+            && !directory.as_os_str().is_empty()
+        {
+            std::fs::create_dir_all(directory)?;
+        }
+
         let pool = SqlitePoolOptions::new()
             .max_connections(5)
-            .connect_lazy(&connection_string)
-            .expect("Cannot connect to database");
+            .connect_lazy_with(options);
 
-        DatabaseConnection { connection: pool }
+        Ok(DatabaseConnection { connection: pool })
+    }
+
+    /// Brings the schema up to date by applying the migrations that haven't been applied yet,
+    /// logging each one. Returns the number of applied migrations.
+    pub async fn migrate(&self) -> Result<usize, MigrateError> {
+        let pending = self.pending_migrations().await?;
+
+        MIGRATOR.run(&self.connection).await?;
+
+        for migration in &pending {
+            info!(
+                "Applied migration {} ({})",
+                migration.version, migration.description
+            );
+        }
+        Ok(pending.len())
+    }
+
+    /// The migrations embedded in the binary that haven't been applied to the database yet,
+    /// oldest first.
+    pub async fn pending_migrations(&self) -> Result<Vec<&'static Migration>, sqlx::Error> {
+        // The migrations table doesn't exist before the first run
+        let (migrated,): (bool,) = sqlx::query_as(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type='table' AND name='_sqlx_migrations')",
+        )
+        .fetch_one(&self.connection)
+        .await?;
+        let applied: HashSet<i64> = if migrated {
+            sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+                .fetch_all(&self.connection)
+                .await?
+                .into_iter()
+                .collect()
+        } else {
+            HashSet::new()
+        };
+
+        Ok(MIGRATOR
+            .iter()
+            .filter(|migration| {
+                migration.migration_type.is_up_migration() && !applied.contains(&migration.version)
+            })
+            .collect())
+    }
+
+    /// Whether the database answers a trivial query within `timeout`.
+    pub async fn ping(&self, timeout: Duration) -> bool {
+        let query = sqlx::query("SELECT 1").execute(&self.connection);
+        match tokio::time::timeout(timeout, query).await {
+            Ok(Ok(_)) => true,
+            Ok(Err(e)) => {
+                error!("database ping failed: {e}");
+                false
+            }
+            Err(_) => {
+                error!("database ping timed out after {timeout:?}");
+                false
+            }
+        }
+    }
+
+    /// Size of the database in bytes.
+    pub async fn size(&self) -> Result<u64, sqlx::Error> {
+        let page_count: i64 = sqlx::query_scalar("PRAGMA page_count")
+            .fetch_one(&self.connection)
+            .await?;
+        let page_size: i64 = sqlx::query_scalar("PRAGMA page_size")
+            .fetch_one(&self.connection)
+            .await?;
+        Ok((page_count * page_size) as u64)
+    }
+
+    /// Runs `PRAGMA optimize`, which refreshes the statistics the query planner relies on, and
+    /// with `vacuum` also `VACUUM`, which rebuilds the file without its free pages.
+    ///
+    /// `VACUUM` holds an exclusive lock while it copies the whole database, so every other query
+    /// waits for it, and it needs free disk space for a copy of the database.
+    pub async fn optimize(&self, vacuum: bool) -> Result<OptimizeReport, sqlx::Error> {
+        let size_before = self.size().await?;
+        let start = Instant::now();
+
+        sqlx::query("PRAGMA optimize")
+            .execute(&self.connection)
+            .await?;
+        if vacuum {
+            sqlx::query("VACUUM").execute(&self.connection).await?;
+        }
+
+        let report = OptimizeReport {
+            vacuumed: vacuum,
+            duration: start.elapsed(),
+            size_before,
+            size_after: self.size().await?,
+        };
+        info!(
+            "Database optimized in {:?}{}, {} -> {} bytes",
+            report.duration,
+            if vacuum { " with VACUUM" } else { "" },
+            report.size_before,
+            report.size_after
+        );
+        Ok(report)
     }
 
     /// Create from an existing pool (for testing)
@@ -73,6 +207,17 @@ impl DatabaseConnection {
     }
 }
 
+/// What [`DatabaseConnection::optimize`] did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptimizeReport {
+    pub vacuumed: bool,
+    pub duration: Duration,
+    /// Size of the database in bytes before
+    pub size_before: u64,
+    /// Size of the database in bytes after
+    pub size_after: u64,
+}
+
 impl Deref for DatabaseConnection {
     type Target = SqlitePool;
 
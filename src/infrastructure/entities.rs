@@ -1,7 +1,9 @@
 //! Database entities
 
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use sqlx::types::Json;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, FromRow)]
@@ -9,6 +11,11 @@ pub struct Conversation {
     pub id: Uuid,
     pub user: Uuid,
     pub created_at: DateTime<Utc>,
+    /// Fingerprint of the model the conversation was started with, `None` if it is unknown.
+    pub model_fingerprint: Option<String>,
+    /// When the conversation or any of its messages last changed. Kept by the database, so it is
+    /// `None` in a conversation that isn't stored yet.
+    pub updated_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone, sqlx::Type)]
@@ -26,4 +33,103 @@ pub struct Message {
     pub kind: MessageKind,
     pub created_at: DateTime<Utc>,
     pub text: String,
+    /// Tokens generated for a bot message, or the prompt tokens processed to answer a user
+    /// message. `0` for messages that were never part of a generation.
+    pub token_count: u32,
+    pub attachments: Json<Vec<Attachment>>,
+    /// The generation of a bot message stopped before the model finished it, e.g. because the
+    /// client disconnected.
+    pub incomplete: bool,
+}
+
+/// Describes a file referenced by a message. Only the metadata is stored, the file itself lives
+/// at `uri`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub name: String,
+    pub mime: String,
+    pub uri: String,
+}
+
+/// Order messages are listed in, by their creation time. Messages created at the same time are in
+/// the order they were created in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MessageOrder {
+    #[default]
+    OldestFirst,
+    NewestFirst,
+}
+
+/// Order conversations are listed in: a key of
+/// [`CONVERSATION_SORT_KEYS`](crate::infrastructure::repositories::CONVERSATION_SORT_KEYS) and a
+/// direction. Oldest first by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConversationOrder {
+    pub sort: &'static str,
+    pub descending: bool,
+}
+
+impl Default for ConversationOrder {
+    fn default() -> Self {
+        ConversationOrder {
+            sort: "created_at",
+            descending: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[repr(u8)]
+pub enum Rating {
+    Up = 1,
+    Down = 2,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct MessageFeedback {
+    pub message_id: Uuid,
+    pub user_id: Uuid,
+    pub rating: Rating,
+    pub comment: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A generation and what it answered, kept with `LOG_REQUESTS_TO_DB` so it can be replayed
+/// against another model.
+#[derive(Debug, Clone, FromRow)]
+pub struct RequestLog {
+    pub id: Uuid,
+    pub user: Uuid,
+    pub conversation_id: Uuid,
+    /// The bot message the generation replied with.
+    pub message_id: Uuid,
+    /// The messages of the prompt, as `[{"role": ..., "content": ...}]`.
+    pub messages: Json<serde_json::Value>,
+    /// The sampling parameters the request set.
+    pub sampling: Json<serde_json::Value>,
+    /// The text generated, even when the generation didn't finish.
+    pub response: String,
+    /// How the generation finished, e.g. `stop` or `cancelled`.
+    pub outcome: String,
+    pub completion_tokens: u32,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+}
+
+/// A user's conversations and messages changed at or after a sync cursor. Deleted messages aren't
+/// listed, but their conversation is.
+#[derive(Debug, Clone)]
+pub struct Changes {
+    pub conversations: Vec<Conversation>,
+    pub messages: Vec<Message>,
+    /// When the changes were read, the cursor of the next sync
+    pub cursor: DateTime<Utc>,
+}
+
+/// Token counts summed over messages, with the completion tokens of bot messages kept apart
+/// from the prompt tokens of everything else.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, FromRow)]
+pub struct TokenUsage {
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
 }
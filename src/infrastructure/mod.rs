@@ -1,4 +1,7 @@
 pub mod database;
 pub mod entities;
 pub mod repositories;
+pub mod runtime;
 pub mod traits;
+pub mod user_context;
+pub mod webhooks;
@@ -1,39 +1,118 @@
 //! DB Repository abstractions
 
 use crate::infrastructure::database::DatabaseConnection;
-use crate::infrastructure::entities::{Conversation, Message};
+use crate::infrastructure::entities::{
+    Changes, Conversation, ConversationOrder, Message, MessageFeedback, MessageKind, MessageOrder,
+    RequestLog, TokenUsage,
+};
 use crate::infrastructure::traits::ConversationRepository;
+use crate::infrastructure::user_context::UserContext;
 use async_trait::async_trait;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use di::{Ref, injectable};
 use log::error;
 use uuid::Uuid;
 
+/// User-facing sort keys of a listing, each mapped to the fixed SQL it orders by. A sort key from
+/// a request is only ever looked up here, so user input never reaches a query string.
+pub struct SortKeys(&'static [(&'static str, &'static str)]);
+
+/// Sort keys of conversations. `last_message_at` orders conversations without messages by their
+/// creation time.
+pub const CONVERSATION_SORT_KEYS: SortKeys = SortKeys(&[
+    (
+        "created_at",
+        "julianday(conversations.created_at) {direction}",
+    ),
+    (
+        "last_message_at",
+        "COALESCE((SELECT MAX(julianday(messages.created_at)) FROM messages WHERE messages.conversation_id = conversations.id), julianday(conversations.created_at)) {direction}",
+    ),
+]);
+
+/// Sort keys of messages. Messages with equal timestamps are in the order they were created in.
+pub const MESSAGE_SORT_KEYS: SortKeys = SortKeys(&[(
+    "created_at",
+    "julianday(messages.created_at) {direction}, messages.seq {direction}",
+)]);
+
+impl SortKeys {
+    /// The allow-listed key equal to `key`, or `None` if it isn't one.
+    pub fn key(&self, key: &str) -> Option<&'static str> {
+        self.0
+            .iter()
+            .find(|(allowed, _)| *allowed == key)
+            .map(|(allowed, _)| *allowed)
+    }
+
+    /// The `ORDER BY` expressions of `key` in the given direction, or `None` if `key` isn't
+    /// allow-listed.
+    pub fn order_by(&self, key: &str, descending: bool) -> Option<String> {
+        let direction = if descending { "DESC" } else { "ASC" };
+        self.0
+            .iter()
+            .find(|(allowed, _)| *allowed == key)
+            .map(|(_, sql)| sql.replace("{direction}", direction))
+    }
+}
+
+/// Queries are scoped to the user they are made for. In a request, that has to be the user of
+/// the request's [`UserContext`], so a query for another user fails instead of leaking or
+/// changing their data.
 #[injectable(ConversationRepository)]
 pub struct DbConversationRepository {
     connection: Ref<DatabaseConnection>,
+    user_context: Ref<UserContext>,
+}
+
+impl DbConversationRepository {
+    pub fn new(connection: Ref<DatabaseConnection>, user_context: Ref<UserContext>) -> Self {
+        DbConversationRepository {
+            connection,
+            user_context,
+        }
+    }
 }
 
 #[async_trait]
 impl ConversationRepository for DbConversationRepository {
-    async fn list_conversations(&self, user_id: Uuid) -> Result<Vec<Conversation>, ()> {
-        sqlx::query_as(
-            "SELECT * FROM conversations WHERE user = ? ORDER BY datetime(created_at) ASC",
-        )
+    async fn list_conversations(
+        &self,
+        user_id: Uuid,
+        order: ConversationOrder,
+    ) -> Result<Vec<Conversation>, ()> {
+        let user_id = self.user_context.scope_to(user_id)?;
+        let order_by = CONVERSATION_SORT_KEYS
+            .order_by(order.sort, order.descending)
+            .ok_or_else(|| error!("unknown conversation sort key {}", order.sort))?;
+
+        sqlx::query_as(&format!(
+            "SELECT * FROM conversations WHERE user = ? ORDER BY {order_by}"
+        ))
         .bind(user_id)
         .fetch_all(&**self.connection)
         .await
         .map_err(|e| error!("{e}"))
     }
 
-    async fn create_conversation(&self, conversation: Conversation) -> Result<Conversation, ()> {
+    async fn create_conversation(
+        &self,
+        conversation: Conversation,
+        limit: Option<usize>,
+    ) -> Result<Option<Conversation>, ()> {
+        self.user_context.scope_to(conversation.user)?;
+        // Nothing is inserted, and no row returned, once the user has `limit` conversations
         sqlx::query_as(
-            "INSERT INTO conversations (id, user, created_at) VALUES (?, ?, ?) RETURNING *",
+            "INSERT INTO conversations (id, user, created_at, model_fingerprint) SELECT ?, ?, ?, ? WHERE ? IS NULL OR (SELECT COUNT(*) FROM conversations WHERE user = ?) < ? RETURNING *",
         )
         .bind(conversation.id)
         .bind(conversation.user)
         .bind(conversation.created_at)
-        .fetch_one(&**self.connection)
+        .bind(conversation.model_fingerprint)
+        .bind(limit.map(|limit| limit as i64))
+        .bind(conversation.user)
+        .bind(limit.map(|limit| limit as i64))
+        .fetch_optional(&**self.connection)
         .await
         .map_err(|e| error!("{e}"))
     }
@@ -42,14 +121,28 @@ impl ConversationRepository for DbConversationRepository {
         todo!()
     }
 
+    async fn conversation_owner(&self, conversation_id: Uuid) -> Result<Option<Uuid>, ()> {
+        sqlx::query_scalar("SELECT user FROM conversations WHERE id = ?")
+            .bind(conversation_id)
+            .fetch_optional(&**self.connection)
+            .await
+            .map_err(|e| error!("{e}"))
+    }
+
     async fn list_conversation_messages(
         &self,
         user_id: Uuid,
         conversation: Uuid,
+        order: MessageOrder,
     ) -> Result<Vec<Message>, ()> {
-        sqlx::query_as(
-            "SELECT messages.id, messages.conversation_id, messages.created_at, messages.kind, messages.text FROM messages INNER JOIN conversations ON conversations.id = messages.conversation_id WHERE conversation_id = ? AND user = ? ORDER BY datetime(messages.created_at) ASC",
-        )
+        let user_id = self.user_context.scope_to(user_id)?;
+        let order_by = MESSAGE_SORT_KEYS
+            .order_by("created_at", matches!(order, MessageOrder::NewestFirst))
+            .ok_or(())?;
+
+        sqlx::query_as(&format!(
+            "SELECT messages.id, messages.conversation_id, messages.created_at, messages.kind, messages.text, messages.token_count, messages.attachments, messages.incomplete FROM messages INNER JOIN conversations ON conversations.id = messages.conversation_id WHERE conversation_id = ? AND user = ? ORDER BY {order_by}",
+        ))
             .bind(conversation)
             .bind(user_id)
             .fetch_all(&**self.connection)
@@ -63,17 +156,460 @@ impl ConversationRepository for DbConversationRepository {
         conversation_id: Uuid,
         message: Message,
     ) -> Result<Message, ()> {
-        // TODO: check user id
+        let user_id = self.user_context.scope_to(user_id)?;
+        // Nothing is inserted, and fetching the row fails, unless the user owns the conversation
         sqlx::query_as(
-            "INSERT INTO messages (id, conversation_id, kind, created_at, text) VALUES (?, ?, ?, ?, ?) RETURNING *",
+            "INSERT INTO messages (id, conversation_id, kind, created_at, text, token_count, attachments, incomplete) SELECT ?, ?, ?, ?, ?, ?, ?, ? WHERE EXISTS (SELECT 1 FROM conversations WHERE id = ? AND user = ?) RETURNING *",
         )
-            .bind(Uuid::new_v4())
+            .bind(message.id)
             .bind(conversation_id)
             .bind(message.kind)
             .bind(message.created_at)
             .bind(message.text)
+            .bind(message.token_count)
+            .bind(message.attachments)
+            .bind(message.incomplete)
+            .bind(conversation_id)
+            .bind(user_id)
+            .fetch_one(&**self.connection)
+            .await
+            .map_err(|e| error!("{e}"))
+    }
+
+    async fn replace_messages(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        replaced: Vec<Uuid>,
+        replacement: Message,
+    ) -> Result<Message, ()> {
+        let user_id = self.user_context.scope_to(user_id)?;
+        // Returning early drops the transaction, which rolls it back
+        let mut transaction = self.connection.begin().await.map_err(|e| error!("{e}"))?;
+
+        for message_id in replaced {
+            let result = sqlx::query(
+                "DELETE FROM messages WHERE id = ? AND conversation_id = (SELECT id FROM conversations WHERE id = ? AND user = ?)",
+            )
+                .bind(message_id)
+                .bind(conversation_id)
+                .bind(user_id)
+                .execute(&mut *transaction)
+                .await
+                .map_err(|e| error!("{e}"))?;
+
+            if result.rows_affected() != 1 {
+                return Err(());
+            }
+        }
+
+        let message = sqlx::query_as(
+            "INSERT INTO messages (id, conversation_id, kind, created_at, text, token_count, attachments) VALUES (?, ?, ?, ?, ?, ?, ?) RETURNING *",
+        )
+            .bind(replacement.id)
+            .bind(conversation_id)
+            .bind(replacement.kind)
+            .bind(replacement.created_at)
+            .bind(replacement.text)
+            .bind(replacement.token_count)
+            .bind(replacement.attachments)
+            .fetch_one(&mut *transaction)
+            .await
+            .map_err(|e| error!("{e}"))?;
+
+        transaction.commit().await.map_err(|e| error!("{e}"))?;
+
+        Ok(message)
+    }
+
+    async fn set_message_token_count(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        token_count: u32,
+    ) -> Result<(), ()> {
+        let user_id = self.user_context.scope_to(user_id)?;
+        let result = sqlx::query(
+            "UPDATE messages SET token_count = ? WHERE id = ? AND conversation_id = (SELECT id FROM conversations WHERE id = ? AND user = ?)",
+        )
+            .bind(token_count)
+            .bind(message_id)
+            .bind(conversation_id)
+            .bind(user_id)
+            .execute(&**self.connection)
+            .await
+            .map_err(|e| error!("{e}"))?;
+
+        if result.rows_affected() == 1 {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    async fn set_message_incomplete(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        incomplete: bool,
+    ) -> Result<(), ()> {
+        let user_id = self.user_context.scope_to(user_id)?;
+        let result = sqlx::query(
+            "UPDATE messages SET incomplete = ? WHERE id = ? AND conversation_id = (SELECT id FROM conversations WHERE id = ? AND user = ?)",
+        )
+            .bind(incomplete)
+            .bind(message_id)
+            .bind(conversation_id)
+            .bind(user_id)
+            .execute(&**self.connection)
+            .await
+            .map_err(|e| error!("{e}"))?;
+
+        if result.rows_affected() == 1 {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    async fn set_message_thinking(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        thinking: String,
+    ) -> Result<(), ()> {
+        let user_id = self.user_context.scope_to(user_id)?;
+        let result = sqlx::query(
+            "UPDATE messages SET thinking = ? WHERE id = ? AND conversation_id = (SELECT id FROM conversations WHERE id = ? AND user = ?)",
+        )
+            .bind(thinking)
+            .bind(message_id)
+            .bind(conversation_id)
+            .bind(user_id)
+            .execute(&**self.connection)
+            .await
+            .map_err(|e| error!("{e}"))?;
+
+        if result.rows_affected() == 1 {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    async fn update_message(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        text: String,
+        token_count: u32,
+    ) -> Result<Message, ()> {
+        let user_id = self.user_context.scope_to(user_id)?;
+        sqlx::query_as(
+            "UPDATE messages SET text = ?, token_count = ? WHERE id = ? AND conversation_id = (SELECT id FROM conversations WHERE id = ? AND user = ?) RETURNING *",
+        )
+            .bind(text)
+            .bind(token_count)
+            .bind(message_id)
+            .bind(conversation_id)
+            .bind(user_id)
+            .fetch_one(&**self.connection)
+            .await
+            .map_err(|e| error!("{e}"))
+    }
+
+    async fn duplicate_conversation(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        duplicate_id: Uuid,
+    ) -> Result<Option<Conversation>, ()> {
+        let user_id = self.user_context.scope_to(user_id)?;
+        // Returning early drops the transaction, which rolls it back
+        let mut transaction = self.connection.begin().await.map_err(|e| error!("{e}"))?;
+
+        let original: Option<Conversation> =
+            sqlx::query_as("SELECT * FROM conversations WHERE id = ? AND user = ?")
+                .bind(conversation_id)
+                .bind(user_id)
+                .fetch_optional(&mut *transaction)
+                .await
+                .map_err(|e| error!("{e}"))?;
+        let Some(original) = original else {
+            return Ok(None);
+        };
+
+        let duplicate: Conversation = sqlx::query_as(
+            "INSERT INTO conversations (id, user, created_at, model_fingerprint) VALUES (?, ?, ?, ?) RETURNING *",
+        )
+        .bind(duplicate_id)
+        .bind(user_id)
+        .bind(Utc::now())
+        .bind(original.model_fingerprint)
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(|e| error!("{e}"))?;
+
+        let messages: Vec<Message> = sqlx::query_as(
+            "SELECT * FROM messages WHERE conversation_id = ? ORDER BY julianday(created_at) ASC, seq ASC",
+        )
+        .bind(conversation_id)
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(|e| error!("{e}"))?;
+
+        // Messages keep their creation times, which is what orders them. Their token counts
+        // aren't copied, as no tokens were spent on the copies.
+        for message in messages {
+            sqlx::query(
+                "INSERT INTO messages (id, conversation_id, kind, created_at, text, token_count, attachments, incomplete) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+                .bind(Uuid::new_v4())
+                .bind(duplicate_id)
+                .bind(message.kind)
+                .bind(message.created_at)
+                .bind(message.text)
+                .bind(0)
+                .bind(message.attachments)
+                .bind(message.incomplete)
+                .execute(&mut *transaction)
+                .await
+                .map_err(|e| error!("{e}"))?;
+        }
+
+        transaction.commit().await.map_err(|e| error!("{e}"))?;
+
+        Ok(Some(duplicate))
+    }
+
+    async fn changes_since(
+        &self,
+        user_id: Uuid,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Changes, ()> {
+        let user_id = self.user_context.scope_to(user_id)?;
+        // Taken before reading, so a change made meanwhile is listed again rather than missed
+        let cursor = Utc::now();
+        // Both lists are read from the same snapshot
+        let mut transaction = self.connection.begin().await.map_err(|e| error!("{e}"))?;
+
+        let conversations = sqlx::query_as(
+            "SELECT * FROM conversations WHERE user = ? AND (? IS NULL OR julianday(updated_at) >= julianday(?)) ORDER BY julianday(updated_at) ASC",
+        )
+            .bind(user_id)
+            .bind(since)
+            .bind(since)
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(|e| error!("{e}"))?;
+
+        let messages = sqlx::query_as(
+            "SELECT messages.id, messages.conversation_id, messages.created_at, messages.kind, messages.text, messages.token_count, messages.attachments, messages.incomplete FROM messages INNER JOIN conversations ON conversations.id = messages.conversation_id WHERE user = ? AND (? IS NULL OR julianday(messages.updated_at) >= julianday(?)) ORDER BY julianday(messages.updated_at) ASC, messages.seq ASC",
+        )
+            .bind(user_id)
+            .bind(since)
+            .bind(since)
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(|e| error!("{e}"))?;
+
+        Ok(Changes {
+            conversations,
+            messages,
+            cursor,
+        })
+    }
+
+    async fn conversation_usage(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+    ) -> Result<Option<TokenUsage>, ()> {
+        let user_id = self.user_context.scope_to(user_id)?;
+        // Grouping by the conversation yields no row at all when the user doesn't own it, rather
+        // than a row of zeros.
+        sqlx::query_as(
+            "SELECT COALESCE(SUM(CASE WHEN messages.kind = ? THEN 0 ELSE messages.token_count END), 0) AS prompt_tokens, COALESCE(SUM(CASE WHEN messages.kind = ? THEN messages.token_count ELSE 0 END), 0) AS completion_tokens FROM conversations LEFT JOIN messages ON messages.conversation_id = conversations.id WHERE conversations.id = ? AND conversations.user = ? GROUP BY conversations.id",
+        )
+            .bind(MessageKind::Bot)
+            .bind(MessageKind::Bot)
+            .bind(conversation_id)
+            .bind(user_id)
+            .fetch_optional(&**self.connection)
+            .await
+            .map_err(|e| error!("{e}"))
+    }
+
+    async fn user_usage(&self, user_id: Uuid) -> Result<TokenUsage, ()> {
+        let user_id = self.user_context.scope_to(user_id)?;
+        sqlx::query_as(
+            "SELECT COALESCE(SUM(CASE WHEN messages.kind = ? THEN 0 ELSE messages.token_count END), 0) AS prompt_tokens, COALESCE(SUM(CASE WHEN messages.kind = ? THEN messages.token_count ELSE 0 END), 0) AS completion_tokens FROM messages INNER JOIN conversations ON conversations.id = messages.conversation_id WHERE conversations.user = ?",
+        )
+            .bind(MessageKind::Bot)
+            .bind(MessageKind::Bot)
+            .bind(user_id)
+            .fetch_one(&**self.connection)
+            .await
+            .map_err(|e| error!("{e}"))
+    }
+
+    async fn upsert_message_feedback(
+        &self,
+        conversation_id: Uuid,
+        feedback: MessageFeedback,
+    ) -> Result<MessageFeedback, ()> {
+        let user_id = self.user_context.scope_to(feedback.user_id)?;
+        // Selecting from the joined tables only yields a row if the message is a bot message in
+        // a conversation owned by the user, so feedback can't be left on someone else's message.
+        sqlx::query_as(
+            "INSERT INTO message_feedback (message_id, user_id, rating, comment, created_at) SELECT messages.id, ?, ?, ?, ? FROM messages INNER JOIN conversations ON conversations.id = messages.conversation_id WHERE messages.id = ? AND messages.conversation_id = ? AND messages.kind = ? AND conversations.user = ? ON CONFLICT (message_id, user_id) DO UPDATE SET rating = excluded.rating, comment = excluded.comment, created_at = excluded.created_at RETURNING *",
+        )
+            .bind(user_id)
+            .bind(feedback.rating)
+            .bind(feedback.comment)
+            .bind(feedback.created_at)
+            .bind(feedback.message_id)
+            .bind(conversation_id)
+            .bind(MessageKind::Bot)
+            .bind(user_id)
             .fetch_one(&**self.connection)
             .await
             .map_err(|e| error!("{e}"))
     }
+
+    async fn get_message_feedback(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<Option<MessageFeedback>, ()> {
+        let user_id = self.user_context.scope_to(user_id)?;
+        sqlx::query_as(
+            "SELECT message_feedback.* FROM message_feedback INNER JOIN messages ON messages.id = message_feedback.message_id INNER JOIN conversations ON conversations.id = messages.conversation_id WHERE message_feedback.message_id = ? AND messages.conversation_id = ? AND message_feedback.user_id = ? AND conversations.user = ?",
+        )
+            .bind(message_id)
+            .bind(conversation_id)
+            .bind(user_id)
+            .bind(user_id)
+            .fetch_optional(&**self.connection)
+            .await
+            .map_err(|e| error!("{e}"))
+    }
+
+    async fn add_tag(&self, user_id: Uuid, conversation_id: Uuid, tag: String) -> Result<(), ()> {
+        let user_id = self.user_context.scope_to(user_id)?;
+        // The upsert changes a row for a tag the conversation already has too, so only a
+        // conversation the user doesn't own changes none
+        let result = sqlx::query(
+            "INSERT INTO conversation_tags (conversation_id, tag) SELECT id, ? FROM conversations WHERE id = ? AND user = ? ON CONFLICT (conversation_id, tag) DO UPDATE SET tag = excluded.tag",
+        )
+            .bind(tag)
+            .bind(conversation_id)
+            .bind(user_id)
+            .execute(&**self.connection)
+            .await
+            .map_err(|e| error!("{e}"))?;
+
+        if result.rows_affected() == 1 {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    async fn remove_tag(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        tag: String,
+    ) -> Result<bool, ()> {
+        let user_id = self.user_context.scope_to(user_id)?;
+        let result = sqlx::query(
+            "DELETE FROM conversation_tags WHERE conversation_id = (SELECT id FROM conversations WHERE id = ? AND user = ?) AND tag = ?",
+        )
+            .bind(conversation_id)
+            .bind(user_id)
+            .bind(tag)
+            .execute(&**self.connection)
+            .await
+            .map_err(|e| error!("{e}"))?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn list_conversations_by_tag(
+        &self,
+        user_id: Uuid,
+        tag: String,
+        order: ConversationOrder,
+    ) -> Result<Vec<Conversation>, ()> {
+        let user_id = self.user_context.scope_to(user_id)?;
+        let order_by = CONVERSATION_SORT_KEYS
+            .order_by(order.sort, order.descending)
+            .ok_or_else(|| error!("unknown conversation sort key {}", order.sort))?;
+
+        sqlx::query_as(&format!(
+            "SELECT conversations.* FROM conversations INNER JOIN conversation_tags ON conversation_tags.conversation_id = conversations.id WHERE conversations.user = ? AND conversation_tags.tag = ? ORDER BY {order_by}"
+        ))
+        .bind(user_id)
+        .bind(tag)
+        .fetch_all(&**self.connection)
+        .await
+        .map_err(|e| error!("{e}"))
+    }
+
+    async fn create_request_log(&self, log: RequestLog) -> Result<(), ()> {
+        let user_id = self.user_context.scope_to(log.user)?;
+        sqlx::query(
+            "INSERT INTO request_log (id, user, conversation_id, message_id, messages, sampling, response, outcome, completion_tokens, started_at, finished_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+            .bind(log.id)
+            .bind(user_id)
+            .bind(log.conversation_id)
+            .bind(log.message_id)
+            .bind(log.messages)
+            .bind(log.sampling)
+            .bind(log.response)
+            .bind(log.outcome)
+            .bind(log.completion_tokens)
+            .bind(log.started_at)
+            .bind(log.finished_at)
+            .execute(&**self.connection)
+            .await
+            .map_err(|e| error!("{e}"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_sort_key_maps_to_its_sql() {
+        assert_eq!(CONVERSATION_SORT_KEYS.key("created_at"), Some("created_at"));
+        assert_eq!(
+            CONVERSATION_SORT_KEYS
+                .order_by("created_at", true)
+                .as_deref(),
+            Some("julianday(conversations.created_at) DESC")
+        );
+        assert_eq!(
+            MESSAGE_SORT_KEYS.order_by("created_at", false).as_deref(),
+            Some("julianday(messages.created_at) ASC, messages.seq ASC")
+        );
+    }
+
+    #[test]
+    fn test_unknown_sort_key_is_rejected() {
+        for key in ["title", "created_at; DROP TABLE messages", "CREATED_AT", ""] {
+            assert_eq!(CONVERSATION_SORT_KEYS.key(key), None);
+            assert_eq!(CONVERSATION_SORT_KEYS.order_by(key, false), None);
+            assert_eq!(MESSAGE_SORT_KEYS.order_by(key, false), None);
+        }
+    }
 }
@@ -0,0 +1,49 @@
+//! Tokio runtimes of the server.
+//!
+//! The web server runs on a multi-threaded runtime, with `TOKIO_WORKER_THREADS` worker threads
+//! or one per CPU core when that is unset. The inference worker drives a single GPU one task at
+//! a time, so it gets a single-threaded runtime on a thread of its own instead of occupying one
+//! of the web server's workers.
+
+use std::str::FromStr;
+use tokio::runtime::{Builder, Runtime};
+
+/// Reads the number of web server worker threads from `TOKIO_WORKER_THREADS`.
+pub fn worker_threads_from_env() -> Option<usize> {
+    std::env::var("TOKIO_WORKER_THREADS")
+        .ok()
+        .and_then(|s| usize::from_str(&s).ok())
+        .filter(|threads| *threads > 0)
+}
+
+/// Builds the web server runtime. Tokio picks the number of worker threads if `worker_threads`
+/// is `None`.
+pub fn web_runtime(worker_threads: Option<usize>) -> std::io::Result<Runtime> {
+    let mut builder = Builder::new_multi_thread();
+    if let Some(worker_threads) = worker_threads {
+        builder.worker_threads(worker_threads);
+    }
+    builder.enable_all().build()
+}
+
+/// Builds the runtime of the inference worker.
+pub fn inference_runtime() -> std::io::Result<Runtime> {
+    Builder::new_current_thread().enable_all().build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_web_runtime_uses_configured_worker_threads() {
+        let runtime = web_runtime(Some(3)).unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 3);
+    }
+
+    #[test]
+    fn test_inference_runtime_is_single_threaded() {
+        let runtime = inference_runtime().unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 1);
+    }
+}
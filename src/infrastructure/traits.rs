@@ -2,22 +2,36 @@
 
 use crate::infrastructure::entities;
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
 #[async_trait]
 pub trait ConversationRepository: Send + Sync {
-    async fn list_conversations(&self, user_id: Uuid) -> Result<Vec<entities::Conversation>, ()>;
+    async fn list_conversations(
+        &self,
+        user_id: Uuid,
+        order: entities::ConversationOrder,
+    ) -> Result<Vec<entities::Conversation>, ()>;
+    /// Creates the conversation, unless its user already has `limit` conversations, in which case
+    /// `None` is returned. The count and the insert are one statement, so concurrent requests
+    /// can't both slip under the limit.
     async fn create_conversation(
         &self,
         conversation: entities::Conversation,
-    ) -> Result<entities::Conversation, ()>;
+        limit: Option<usize>,
+    ) -> Result<Option<entities::Conversation>, ()>;
 
     async fn delete_conversation(&self, conversation_id: Uuid) -> Result<(), ()>;
 
+    /// The user who owns a conversation, `None` if there is no such conversation. Not scoped to
+    /// a user, so only the owner's id is read.
+    async fn conversation_owner(&self, conversation_id: Uuid) -> Result<Option<Uuid>, ()>;
+
     async fn list_conversation_messages(
         &self,
         user_id: Uuid,
         conversation: Uuid,
+        order: entities::MessageOrder,
     ) -> Result<Vec<entities::Message>, ()>;
 
     async fn create_message_in_conversation(
@@ -26,4 +40,115 @@ pub trait ConversationRepository: Send + Sync {
         conversation_id: Uuid,
         message: entities::Message,
     ) -> Result<entities::Message, ()>;
+
+    /// Deletes the `replaced` messages and inserts `replacement` in one transaction. Nothing is
+    /// changed if any of the messages isn't in a conversation owned by the user.
+    async fn replace_messages(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        replaced: Vec<Uuid>,
+        replacement: entities::Message,
+    ) -> Result<entities::Message, ()>;
+
+    async fn set_message_token_count(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        token_count: u32,
+    ) -> Result<(), ()>;
+
+    /// Marks whether the generation of a message in a conversation owned by the user stopped
+    /// before it was finished.
+    async fn set_message_incomplete(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        incomplete: bool,
+    ) -> Result<(), ()>;
+
+    /// Stores the thinking the model did before it wrote a message in a conversation owned by the
+    /// user.
+    async fn set_message_thinking(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        thinking: String,
+    ) -> Result<(), ()>;
+
+    /// Replaces the text and token count of a message in a conversation owned by the user.
+    async fn update_message(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        text: String,
+        token_count: u32,
+    ) -> Result<entities::Message, ()>;
+
+    /// Copies a conversation owned by the user and its messages to a new conversation with the id
+    /// `duplicate_id`, in one transaction. `None` if the user has no such conversation.
+    async fn duplicate_conversation(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        duplicate_id: Uuid,
+    ) -> Result<Option<entities::Conversation>, ()>;
+
+    /// The user's conversations and messages created or changed at or after `since`, or all of
+    /// them without a cursor.
+    async fn changes_since(
+        &self,
+        user_id: Uuid,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<entities::Changes, ()>;
+
+    /// Sums the token counts of a conversation. `None` if the user has no such conversation.
+    async fn conversation_usage(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+    ) -> Result<Option<entities::TokenUsage>, ()>;
+
+    /// Sums the token counts of all the user's conversations.
+    async fn user_usage(&self, user_id: Uuid) -> Result<entities::TokenUsage, ()>;
+
+    /// Inserts or replaces the user's feedback on a bot message in one of their conversations.
+    async fn upsert_message_feedback(
+        &self,
+        conversation_id: Uuid,
+        feedback: entities::MessageFeedback,
+    ) -> Result<entities::MessageFeedback, ()>;
+
+    async fn get_message_feedback(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<Option<entities::MessageFeedback>, ()>;
+
+    /// Tags a conversation owned by the user. Adding a tag it has already changes nothing.
+    async fn add_tag(&self, user_id: Uuid, conversation_id: Uuid, tag: String) -> Result<(), ()>;
+
+    /// Removes a tag from a conversation owned by the user. `false` if it didn't have the tag.
+    async fn remove_tag(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        tag: String,
+    ) -> Result<bool, ()>;
+
+    /// The user's conversations tagged with `tag`, in the given order.
+    async fn list_conversations_by_tag(
+        &self,
+        user_id: Uuid,
+        tag: String,
+        order: entities::ConversationOrder,
+    ) -> Result<Vec<entities::Conversation>, ()>;
+
+    /// Stores a generation in the request log.
+    async fn create_request_log(&self, log: entities::RequestLog) -> Result<(), ()>;
 }
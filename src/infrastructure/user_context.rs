@@ -0,0 +1,82 @@
+//! The user a request is made by, for the services resolved while handling it.
+//!
+//! `more-di` can't take values from the request into its scope, so the user is carried in a
+//! task-local instead. [`crate::api::scope_user_context`] sets it from the `X-User-ID` header
+//! for the rest of the request, and the scoped [`UserContext`] reads it when the DI container
+//! creates it. Services created outside of a request, or in a request without a valid user, get
+//! an empty context.
+
+use di::{inject, injectable};
+use log::error;
+use std::future::Future;
+use uuid::Uuid;
+
+tokio::task_local! {
+    static CURRENT_USER: Uuid;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserContext {
+    user_id: Option<Uuid>,
+}
+
+#[injectable]
+impl UserContext {
+    #[inject]
+    pub fn create() -> UserContext {
+        UserContext::new(CURRENT_USER.try_with(|user_id| *user_id).ok())
+    }
+}
+
+impl UserContext {
+    pub fn new(user_id: Option<Uuid>) -> UserContext {
+        UserContext { user_id }
+    }
+
+    /// Runs `future` with `user_id` as the user of the contexts created in it.
+    pub async fn scope<F: Future>(user_id: Uuid, future: F) -> F::Output {
+        CURRENT_USER.scope(user_id, future).await
+    }
+
+    /// The user of the request, if any.
+    pub fn user_id(&self) -> Option<Uuid> {
+        self.user_id
+    }
+
+    /// Returns the user to scope a query to, or `Err` if the query is for another user than the
+    /// one of the request. A query outside of a request is scoped to `user_id`.
+    pub fn scope_to(&self, user_id: Uuid) -> Result<Uuid, ()> {
+        match self.user_id {
+            Some(current_user) if current_user != user_id => {
+                error!("query for user {user_id} in a request of user {current_user}");
+                Err(())
+            }
+            _ => Ok(user_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_context_carries_the_scoped_user() {
+        let user_id = Uuid::new_v4();
+
+        let context = UserContext::scope(user_id, async { UserContext::create() }).await;
+
+        assert_eq!(context.user_id(), Some(user_id));
+        assert_eq!(context.scope_to(user_id), Ok(user_id));
+        assert_eq!(context.scope_to(Uuid::new_v4()), Err(()));
+    }
+
+    #[test]
+    fn test_context_outside_of_a_request_is_empty() {
+        let context = UserContext::create();
+        let user_id = Uuid::new_v4();
+
+        assert_eq!(context.user_id(), None);
+        assert_eq!(context.scope_to(user_id), Ok(user_id));
+    }
+}
@@ -0,0 +1,123 @@
+//! Outbound webhooks for generation lifecycle events.
+//!
+//! When `WEBHOOK_URL` is set, a JSON payload is POSTed to it when a generation starts and when
+//! it finishes. Delivery is fire-and-forget: it runs on its own task with a bounded number of
+//! attempts and a per-request timeout, so it never blocks the response stream.
+
+use log::{debug, warn};
+use serde::Serialize;
+use std::sync::OnceLock;
+use std::time::Duration;
+use uuid::Uuid;
+
+const MAX_ATTEMPTS: u32 = 3;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GenerationEvent {
+    Started,
+    Finished,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The model finished the message normally.
+    Stop,
+    /// The message was cut off at its token limit.
+    Length,
+    /// The model failed to generate the message. In the request log, also a message that could
+    /// not be persisted.
+    Error,
+    /// The client disconnected before the message was finished. What was generated so far is
+    /// saved as an incomplete message.
+    Cancelled,
+    /// The generation was stopped because the model kept repeating the same token.
+    RepetitionCollapse,
+}
+
+impl FinishReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FinishReason::Stop => "stop",
+            FinishReason::Length => "length",
+            FinishReason::Error => "error",
+            FinishReason::Cancelled => "cancelled",
+            FinishReason::RepetitionCollapse => "repetition_collapse",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct GenerationWebhook {
+    pub event: GenerationEvent,
+    pub conversation_id: Uuid,
+    pub message_id: Uuid,
+    pub finish_reason: Option<FinishReason>,
+    pub completion_tokens: usize,
+}
+
+impl GenerationWebhook {
+    pub fn started(conversation_id: Uuid, message_id: Uuid) -> Self {
+        GenerationWebhook {
+            event: GenerationEvent::Started,
+            conversation_id,
+            message_id,
+            finish_reason: None,
+            completion_tokens: 0,
+        }
+    }
+
+    pub fn finished(
+        conversation_id: Uuid,
+        message_id: Uuid,
+        finish_reason: FinishReason,
+        completion_tokens: usize,
+    ) -> Self {
+        GenerationWebhook {
+            event: GenerationEvent::Finished,
+            conversation_id,
+            message_id,
+            finish_reason: Some(finish_reason),
+            completion_tokens,
+        }
+    }
+}
+
+/// Sends the payload to `WEBHOOK_URL` in the background, if one is configured.
+pub fn notify(payload: GenerationWebhook) {
+    let Ok(url) = std::env::var("WEBHOOK_URL") else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let client = CLIENT.get_or_init(reqwest::Client::new);
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            let result = client
+                .post(&url)
+                .timeout(REQUEST_TIMEOUT)
+                .json(&payload)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+
+            match result {
+                Ok(_) => {
+                    debug!("webhook {:?} delivered to {url}", payload.event);
+                    return;
+                }
+                Err(e) => {
+                    warn!("webhook delivery attempt {attempt}/{MAX_ATTEMPTS} failed: {e}");
+                    if attempt < MAX_ATTEMPTS {
+                        tokio::time::sleep(RETRY_BACKOFF * attempt).await;
+                    }
+                }
+            }
+        }
+    });
+}
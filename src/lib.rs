@@ -5,9 +5,26 @@
 pub mod api;
 pub mod core;
 pub mod infrastructure;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 
-use crate::core::assistant::InferenceTask;
+use crate::core::task_queue::TaskSender;
+use std::sync::atomic::AtomicBool;
 use tokio::sync::OnceCell;
-use tokio::sync::mpsc;
 
-pub static TASK_SENDER: OnceCell<mpsc::Sender<InferenceTask>> = OnceCell::const_new();
+pub static TASK_SENDER: OnceCell<TaskSender> = OnceCell::const_new();
+
+/// Fingerprint of the loaded model, set once by the inference worker when the model is loaded.
+/// See [`core::assistant::model_fingerprint`].
+pub static MODEL_FINGERPRINT: OnceCell<String> = OnceCell::const_new();
+
+/// Quantization type of most of the loaded model's weights, set once by the inference worker when
+/// the model is loaded. See [`core::assistant::dominant_quantization`].
+pub static MODEL_QUANTIZATION: OnceCell<String> = OnceCell::const_new();
+
+/// The chat template prompts are rendered with, set once by the inference worker when the model
+/// is loaded. Taken from the model's GGUF metadata, or a fallback if the model has none.
+pub static CHAT_TEMPLATE: OnceCell<String> = OnceCell::const_new();
+
+/// Whether the inference worker has loaded the model and takes tasks.
+pub static MODEL_LOADED: AtomicBool = AtomicBool::new(false);
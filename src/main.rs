@@ -6,14 +6,18 @@ use tokio_local_llm_api::TASK_SENDER;
 use tokio_local_llm_api::api;
 use tokio_local_llm_api::core;
 use tokio_local_llm_api::core::assistant::{ChatMessage, InferenceTask};
+use tokio_local_llm_api::core::config::AppConfig;
+use tokio_local_llm_api::core::message_cache::MessageCache;
+use tokio_local_llm_api::core::personas::Personas;
 use tokio_local_llm_api::core::services::MyConversationService;
 use tokio_local_llm_api::core::traits::ConversationService;
 use tokio_local_llm_api::infrastructure::database::DatabaseConnection;
 use tokio_local_llm_api::infrastructure::repositories::DbConversationRepository;
+use tokio_local_llm_api::infrastructure::runtime;
+use tokio_local_llm_api::infrastructure::user_context::UserContext;
 
 use anyhow::anyhow;
 use axum::http::{HeaderValue, Method};
-use axum::response::Html;
 use axum::{
     Json, Router,
     http::StatusCode,
@@ -23,19 +27,17 @@ use di::{
     InjectBuilder, Injectable, ServiceCollection, ServiceLifetime, ServiceProvider, injectable,
 };
 use di_axum::RouterServiceProviderExtensions;
-use log::info;
+use log::{error, info};
 use serde::{Deserialize, Serialize};
 use teloxide::handler;
 use teloxide::prelude::*;
 use teloxide::types::{MediaKind, MessageKind};
 use teloxide::types::{MediaText, ParseMode};
-use tokio::runtime::{Builder, Runtime};
+use tokio::runtime::Runtime;
 use tokio::sync::{OnceCell, mpsc};
 use tokio::task;
 use tokio::task::JoinHandle;
-use tower::ServiceBuilder;
 use tower_http::cors::{Any, CorsLayer};
-use tower_http::services::ServeDir;
 use uuid::Uuid;
 
 const ENABLE_TELEGRAM_HANDLER: bool = false;
@@ -43,50 +45,114 @@ const ENABLE_TELEGRAM_HANDLER: bool = false;
 fn main() -> anyhow::Result<()> {
     // initialize tracing
     tracing_subscriber::fmt::init();
+    let config = AppConfig::create();
 
-    let runtime: Runtime = Builder::new_multi_thread().enable_all().build()?;
+    let runtime: Runtime = runtime::web_runtime(runtime::worker_threads_from_env())?;
+    let inference_runtime = runtime::inference_runtime()?;
 
-    // background task for local LLM
-    let (task_sender, task_receiver) = mpsc::channel(10);
-    let assistant_join_handle = runtime.spawn(core::assistant::background_task(task_receiver));
+    // background task for local LLM, on its own thread
+    let (task_sender, task_receiver) = core::task_queue::channel(config.queue_size);
+    let assistant_join_handle = if config.disable_inference {
+        info!("Inference is disabled, generations get a canned reply");
+        runtime.spawn(core::stub_inference::background_task(task_receiver));
+        None
+    } else {
+        Some(std::thread::spawn(move || {
+            inference_runtime.block_on(core::assistant::background_task(task_receiver))
+        }))
+    };
     TASK_SENDER
         .set(task_sender)
         .expect("task sender should not be set");
 
-    let web_task_handle = runtime.spawn(web_server_task());
+    runtime.spawn(core::inference_events::log_events());
+    core::stats::mark_start();
+    runtime.spawn(core::stats::record_events());
+    runtime.spawn(core::latency::record_events());
+    runtime.spawn(core::generation_webhooks::notify_events());
+    #[cfg(feature = "grpc")]
+    runtime.spawn(grpc_server_task(config.grpc_bind_address));
+    let web_task_handle = runtime.spawn(web_server_task(config.bind_address));
 
     runtime.block_on(async {
         web_task_handle
             .await
             .expect("failed to join web_task_handle");
-        assistant_join_handle
-            .await
-            .expect("failed to join assistant_join_handle");
     });
+    if let Some(assistant_join_handle) = assistant_join_handle {
+        assistant_join_handle
+            .join()
+            .map_err(|_| anyhow!("the inference worker panicked"))?;
+    }
 
     Ok(())
 }
 
-async fn web_server_task() {
+async fn web_server_task(bind_address: String) {
     let provider = ServiceCollection::new()
+        .add(AppConfig::singleton())
         .add(DatabaseConnection::singleton())
+        .add(UserContext::scoped())
+        .add(Personas::singleton())
+        .add(MessageCache::singleton())
         .add(DbConversationRepository::scoped())
         .add(MyConversationService::scoped())
         .build_provider()
         .unwrap();
 
+    // Serving against an outdated schema would fail on the first request instead
+    let database = provider.get_required::<DatabaseConnection>();
+    let pending = match database.pending_migrations().await {
+        Ok(pending) => pending,
+        Err(e) => {
+            error!("Failed to read the applied database migrations, shutting down: {e}");
+            std::process::exit(1);
+        }
+    };
+    if !pending.is_empty() && !provider.get_required::<AppConfig>().auto_migrate {
+        let pending: Vec<String> = pending
+            .iter()
+            .map(|migration| format!("{} ({})", migration.version, migration.description))
+            .collect();
+        error!(
+            "The database is missing {} migration(s): {}. Apply them or start with AUTO_MIGRATE=true, shutting down",
+            pending.len(),
+            pending.join(", ")
+        );
+        std::process::exit(1);
+    }
+    if let Err(e) = database.migrate().await {
+        error!("Failed to migrate the database, shutting down: {e}");
+        std::process::exit(1);
+    }
+
+    // Conversations created without a persona would otherwise be rejected
+    if let Some(name) = &provider.get_required::<AppConfig>().default_persona
+        && provider.get_required::<Personas>().get(name).is_none()
+    {
+        error!(
+            "DEFAULT_PERSONA `{name}` is not one of the personas in PERSONAS_FILE, shutting down"
+        );
+        std::process::exit(1);
+    }
+
     // build our application with a route
     let app = Router::new()
-        .route("/", get(index))
-        .nest_service(
-            "/static",
-            ServiceBuilder::new().service(ServeDir::new("static")),
-        )
+        .merge(api::static_files::router())
+        .merge(api::health::router())
+        .merge(api::metrics::router())
+        .merge(api::stats::router())
+        .merge(api::openapi::router())
         .nest("/conversations", api::conversations::router())
+        .nest("/usage", api::usage::router())
+        .nest("/v1", api::openai::router().merge(api::personas::router()))
+        .nest("/admin", api::admin::router())
+        .layer(axum::middleware::from_fn(api::scope_user_context))
+        .layer(axum::middleware::from_fn(api::count_requests))
         .layer(
             CorsLayer::new()
                 .allow_headers(Any)
-                .allow_methods([Method::GET, Method::POST])
+                .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
                 .allow_origin([
                     "http://localhost:3000".parse::<HeaderValue>().unwrap(),
                     "http://localhost:5173".parse::<HeaderValue>().unwrap(),
@@ -94,13 +160,16 @@ async fn web_server_task() {
         )
         .with_provider(provider);
 
-    // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    // run our app with hyper, by default listening globally on port 3000
+    let listener = tokio::net::TcpListener::bind(&bind_address).await.unwrap();
     info!("listening on {}", listener.local_addr().unwrap());
     axum::serve(listener, app).await.unwrap();
     info!("Shutting down...");
 }
 
-async fn index() -> Html<&'static str> {
-    Html(include_str!("../static/index.html"))
+#[cfg(feature = "grpc")]
+async fn grpc_server_task(bind_address: String) {
+    let listener = tokio::net::TcpListener::bind(&bind_address).await.unwrap();
+    info!("gRPC listening on {}", listener.local_addr().unwrap());
+    api::grpc::serve(listener).await;
 }
@@ -0,0 +1,66 @@
+//! Helpers for tests, also available to integration tests with the `test-util` feature.
+
+use crate::infrastructure::database::DatabaseConnection;
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+/// A migrated in-memory database, set as the test pool that DI-created
+/// [`DatabaseConnection`]s use for as long as the `TestDb` lives.
+///
+/// Each `TestDb` has its own uniquely named shared-cache database, so connections of one test
+/// never see another test's data. Dropping it clears the test pool, so a test can't forget to
+/// clean up, even when it fails. Tests that use it still have to be serialized, since there is
+/// only one test pool.
+pub struct TestDb {
+    pool: SqlitePool,
+}
+
+impl TestDb {
+    pub async fn new() -> TestDb {
+        let url = format!(
+            "sqlite:file:testdb-{}?mode=memory&cache=shared",
+            Uuid::new_v4().simple()
+        );
+        let pool = SqlitePool::connect(&url)
+            .await
+            .expect("failed to open the test database");
+        sqlx::migrate!()
+            .run(&pool)
+            .await
+            .expect("failed to migrate the test database");
+
+        DatabaseConnection::set_test_pool(pool.clone());
+        TestDb { pool }
+    }
+
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        DatabaseConnection::clear_test_pool();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_test_pool_is_cleared_on_drop() {
+        let db = TestDb::new().await;
+        assert!(DatabaseConnection::has_test_pool());
+
+        // The migrations ran on the test database
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM conversations")
+            .fetch_one(db.pool())
+            .await
+            .unwrap();
+        assert_eq!(count, 0);
+
+        drop(db);
+        assert!(!DatabaseConnection::has_test_pool());
+    }
+}
@@ -0,0 +1,172 @@
+//! Admin endpoint tests
+//!
+//! The configuration is read from the environment, so every test sets the variables it relies on.
+
+use axum::{
+    Router,
+    body::Body,
+    http::{Request, StatusCode},
+};
+use di::{Injectable, ServiceCollection};
+use di_axum::RouterServiceProviderExtensions;
+use serde_json::Value;
+use serial_test::serial;
+use tokio_local_llm_api::core::config::AppConfig;
+use tokio_local_llm_api::infrastructure::database::DatabaseConnection;
+use tokio_local_llm_api::test_util::TestDb;
+use tokio_local_llm_api::{CHAT_TEMPLATE, api};
+use tower::ServiceExt;
+
+const ADMIN_TOKEN: &str = "s3cret-admin-token";
+
+fn configure_env(admin_token: Option<&str>) {
+    unsafe {
+        std::env::set_var("MODEL_FILE_NAME", "models/test-model.gguf");
+        std::env::set_var("CONTEXT_SIZE", "4096");
+        std::env::set_var("DEFAULT_MAX_TOKENS", "256");
+        std::env::set_var("QUEUE_SIZE", "3");
+        std::env::set_var("DECODING_MODE", "mirostat");
+        std::env::set_var("MIROSTAT_TAU", "4.0");
+        std::env::set_var("BIND_ADDRESS", "127.0.0.1:8080");
+        match admin_token {
+            Some(token) => std::env::set_var("ADMIN_TOKEN", token),
+            None => std::env::remove_var("ADMIN_TOKEN"),
+        }
+    }
+}
+
+fn create_admin_app() -> Router {
+    let provider = ServiceCollection::new()
+        .add(AppConfig::transient())
+        .add(DatabaseConnection::transient())
+        .build_provider()
+        .unwrap();
+    Router::new()
+        .nest("/admin", api::admin::router())
+        .with_provider(provider)
+}
+
+async fn get_config(authorization: Option<&str>) -> (StatusCode, String) {
+    get_admin("/admin/config", authorization).await
+}
+
+async fn get_admin(uri: &str, authorization: Option<&str>) -> (StatusCode, String) {
+    let mut request = Request::builder().uri(uri);
+    if let Some(authorization) = authorization {
+        request = request.header("Authorization", authorization);
+    }
+    let response = create_admin_app()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_config_returns_configured_values_and_redacts_secrets() {
+    configure_env(Some(ADMIN_TOKEN));
+
+    let (status, body) = get_config(Some(&format!("Bearer {ADMIN_TOKEN}"))).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert!(!body.contains(ADMIN_TOKEN), "secret leaked: {body}");
+    let config: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(config["model_file_name"], "models/test-model.gguf");
+    assert_eq!(config["context_size"], 4096);
+    assert_eq!(config["default_max_tokens"], 256);
+    assert_eq!(config["queue_size"], 3);
+    assert_eq!(config["decoding_mode"]["mode"], "mirostat");
+    assert_eq!(config["decoding_mode"]["tau"], 4.0);
+    assert_eq!(config["bind_address"], "127.0.0.1:8080");
+    assert_eq!(config["admin_token"], "[redacted]");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_config_requires_the_admin_token() {
+    configure_env(Some(ADMIN_TOKEN));
+
+    let (status, body) = get_config(None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["code"], "unauthorized");
+
+    let (status, _) = get_config(Some("Bearer wrong-token")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_config_is_disabled_without_an_admin_token() {
+    configure_env(None);
+
+    let (status, body) = get_config(Some("Bearer anything")).await;
+
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["code"], "admin_disabled");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_chat_template_returns_the_loaded_template() {
+    configure_env(Some(ADMIN_TOKEN));
+    let template = "{% for m in messages %}<|{{ m.role }}|>{{ m.content }}{% endfor %}";
+    CHAT_TEMPLATE.set(template.to_owned()).unwrap();
+
+    let (status, _) = get_admin("/admin/chat_template", None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, body) = get_admin(
+        "/admin/chat_template",
+        Some(&format!("Bearer {ADMIN_TOKEN}")),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, template);
+}
+
+async fn post_admin(uri: &str, authorization: Option<&str>) -> (StatusCode, String) {
+    let mut request = Request::builder().method("POST").uri(uri);
+    if let Some(authorization) = authorization {
+        request = request.header("Authorization", authorization);
+    }
+    let response = create_admin_app()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_optimize_runs_on_the_database() {
+    let _db = TestDb::new().await;
+    configure_env(Some(ADMIN_TOKEN));
+    unsafe { std::env::set_var("VACUUM_ON_OPTIMIZE", "true") };
+
+    let (status, _) = post_admin("/admin/db/optimize", None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, body) =
+        post_admin("/admin/db/optimize", Some(&format!("Bearer {ADMIN_TOKEN}"))).await;
+    unsafe { std::env::remove_var("VACUUM_ON_OPTIMIZE") };
+
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let result: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(result["vacuumed"], true);
+    assert!(result["duration_ms"].is_u64());
+    assert!(result["size_before_bytes"].as_u64().unwrap() > 0);
+    assert!(result["size_after_bytes"].as_u64().unwrap() > 0);
+}
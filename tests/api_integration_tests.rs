@@ -1,75 +1,46 @@
 //! API Integration Tests
 //!
 //! Tests the HTTP API endpoints with a real database.
-//! These tests focus on the read-only API endpoints that don't require
-//! the LLM inference backend.
+//! The message-posting endpoints run against a fake inference worker
+//! (see `common::init_test_task_sender`) instead of the LLM backend.
 //!
 //! Tests are serialized because they share a global test pool.
 //!
 //! Note: The `more-di` DI framework doesn't support injecting custom pools.
-//! We work around this by using `DatabaseConnection::set_test_pool()` to set
-//! a global pool that the DI-created DatabaseConnection will use.
+//! We work around this with `TestDb`, which sets a global pool that the
+//! DI-created DatabaseConnection will use until the `TestDb` is dropped.
 
 use axum::{
     body::Body,
     http::{Request, StatusCode},
 };
-use chrono::Utc;
-use di::{Injectable, ServiceCollection};
-use di_axum::RouterServiceProviderExtensions;
-use serde_json::Value;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use serde_json::{Value, json};
 use serial_test::serial;
 use sqlx::SqlitePool;
-use std::sync::atomic::{AtomicU32, Ordering};
+use tokio_local_llm_api::core::sampling::{SamplingParams, SamplingPreset};
 use tokio_local_llm_api::{
-    api, core::services::MyConversationService, infrastructure::database::DatabaseConnection,
-    infrastructure::repositories::DbConversationRepository,
+    api, core::compaction::SUMMARY_PREFIX, core::model_reload::ReloadWindow, test_util::TestDb,
 };
 use tower::ServiceExt;
 use uuid::Uuid;
 
-/// Counter for unique test database URIs
-static TEST_DB_COUNTER: AtomicU32 = AtomicU32::new(0);
-
-/// Setup test database with migrations and returns pool
-/// Uses in-memory SQLite for test isolation
-async fn setup_test_db() -> SqlitePool {
-    let db_num = TEST_DB_COUNTER.fetch_add(1, Ordering::SeqCst);
-    // Use file URI format with shared cache - each test gets a unique DB
-    let db_url = format!("sqlite:file:testdb{}?mode=memory&cache=shared", db_num);
-
-    let pool = SqlitePool::connect(&db_url).await.unwrap();
-    sqlx::migrate!().run(&pool).await.unwrap();
-
-    // Set this pool as the global test pool so DI uses it
-    DatabaseConnection::set_test_pool(pool.clone());
-
-    pool
-}
-
-/// Clean up after test
-fn cleanup_test_db() {
-    DatabaseConnection::clear_test_pool();
-}
+mod common;
+use common::{
+    CANNED_RESPONSE, FAKE_MODEL_FINGERPRINT, FAKE_PROMPT_TOKENS, init_test_task_sender,
+    insert_conversation_with_turns, last_prompt, last_sampling, parse_sse_events,
+};
 
-/// Create test app - uses the global test pool set by setup_test_db()
+/// Create test app - uses the global test pool set by `TestDb`
 fn create_test_app() -> axum::Router {
-    let provider = ServiceCollection::new()
-        .add(DatabaseConnection::transient())
-        .add(DbConversationRepository::scoped())
-        .add(MyConversationService::scoped())
-        .build_provider()
-        .unwrap();
-
-    axum::Router::new()
-        .nest("/conversations", api::conversations::router())
-        .with_provider(provider)
+    common::create_test_app_with(|_| {}, |app| app.nest("/usage", api::usage::router()))
 }
 
 #[tokio::test]
 #[serial]
 async fn test_list_conversations_empty() {
-    let _pool = setup_test_db().await;
+    let _db = TestDb::new().await;
 
     let app = create_test_app();
 
@@ -93,14 +64,12 @@ async fn test_list_conversations_empty() {
     let json: Value = serde_json::from_slice(&body).unwrap();
 
     assert_eq!(json["conversations"].as_array().unwrap().len(), 0);
-
-    cleanup_test_db();
 }
 
 #[tokio::test]
 #[serial]
 async fn test_list_conversations_requires_auth() {
-    let _pool = setup_test_db().await;
+    let _db = TestDb::new().await;
 
     let app = create_test_app();
 
@@ -116,14 +85,12 @@ async fn test_list_conversations_requires_auth() {
 
     // Should fail without X-User-ID header
     assert_eq!(response.status(), StatusCode::BAD_REQUEST);
-
-    cleanup_test_db();
 }
 
 #[tokio::test]
 #[serial]
 async fn test_get_messages_nonexistent_conversation() {
-    let _pool = setup_test_db().await;
+    let _db = TestDb::new().await;
 
     let app = create_test_app();
 
@@ -150,14 +117,13 @@ async fn test_get_messages_nonexistent_conversation() {
         .unwrap();
     let json: Value = serde_json::from_slice(&body).unwrap();
     assert_eq!(json["messages"].as_array().unwrap().len(), 0);
-
-    cleanup_test_db();
 }
 
 #[tokio::test]
 #[serial]
 async fn test_get_messages_wrong_user() {
-    let pool = setup_test_db().await;
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
 
     let owner = Uuid::new_v4();
     let other_user = Uuid::new_v4();
@@ -193,14 +159,13 @@ async fn test_get_messages_wrong_user() {
         .unwrap();
     let json: Value = serde_json::from_slice(&body).unwrap();
     assert_eq!(json["messages"].as_array().unwrap().len(), 0);
-
-    cleanup_test_db();
 }
 
 #[tokio::test]
 #[serial]
 async fn test_get_messages_success() {
-    let pool = setup_test_db().await;
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
 
     let user_id = Uuid::new_v4();
     let conversation_id = Uuid::new_v4();
@@ -251,14 +216,13 @@ async fn test_get_messages_success() {
     let messages = json["messages"].as_array().unwrap();
     assert_eq!(messages.len(), 1);
     assert_eq!(messages[0]["text"], "Hello!");
-
-    cleanup_test_db();
 }
 
 #[tokio::test]
 #[serial]
 async fn test_list_conversations_with_data() {
-    let pool = setup_test_db().await;
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
 
     let user_id = Uuid::new_v4();
     let conversation_id = Uuid::new_v4();
@@ -295,14 +259,61 @@ async fn test_list_conversations_with_data() {
     let conversations = json["conversations"].as_array().unwrap();
     assert_eq!(conversations.len(), 1);
     assert_eq!(conversations[0]["id"], conversation_id.to_string());
+}
 
-    cleanup_test_db();
+#[tokio::test]
+#[serial]
+async fn test_unchanged_conversation_list_is_not_modified() {
+    let db = TestDb::new().await;
+    let user_id = Uuid::new_v4();
+    let insert_conversation = || async {
+        sqlx::query("INSERT INTO conversations (id, user, created_at) VALUES (?, ?, ?)")
+            .bind(Uuid::new_v4())
+            .bind(user_id)
+            .bind(Utc::now().to_rfc3339())
+            .execute(db.pool())
+            .await
+            .unwrap();
+    };
+    let list = |if_none_match: Option<String>| {
+        let mut request = Request::builder()
+            .uri("/conversations")
+            .header("X-User-ID", user_id.to_string());
+        if let Some(etag) = if_none_match {
+            request = request.header("If-None-Match", etag);
+        }
+        create_test_app().oneshot(request.body(Body::empty()).unwrap())
+    };
+    let etag_of = |response: &axum::response::Response| {
+        response.headers()["ETag"].to_str().unwrap().to_owned()
+    };
+
+    insert_conversation().await;
+    let response = list(None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = etag_of(&response);
+    assert!(etag.starts_with("W/\""));
+
+    let response = list(Some(etag.clone())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(etag_of(&response), etag);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert!(body.is_empty());
+
+    // A new conversation changes the list
+    insert_conversation().await;
+    let response = list(Some(etag.clone())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(etag_of(&response), etag);
 }
 
 #[tokio::test]
 #[serial]
 async fn test_user_isolation() {
-    let pool = setup_test_db().await;
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
 
     let user1 = Uuid::new_v4();
     let user2 = Uuid::new_v4();
@@ -358,6 +369,2410 @@ async fn test_user_isolation() {
         .unwrap();
     let json: Value = serde_json::from_slice(&body).unwrap();
     assert_eq!(json["conversations"].as_array().unwrap().len(), 3);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_conversations_sorted_by_allowed_key() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    let user_id = Uuid::new_v4();
+
+    // The older conversation has the newer message
+    let older = Uuid::new_v4();
+    let newer = Uuid::new_v4();
+    for (conversation_id, created_at, message_at) in [
+        (older, "2025-01-01T00:00:00Z", "2025-01-03T00:00:00Z"),
+        (newer, "2025-01-02T00:00:00Z", "2025-01-02T00:00:00Z"),
+    ] {
+        sqlx::query("INSERT INTO conversations (id, user, created_at) VALUES (?, ?, ?)")
+            .bind(conversation_id)
+            .bind(user_id)
+            .bind(created_at)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO messages (id, conversation_id, kind, created_at, text) VALUES (?, ?, 3, ?, 'Hi!')",
+        )
+        .bind(Uuid::new_v4())
+        .bind(conversation_id)
+        .bind(message_at)
+        .execute(&pool)
+        .await
+        .unwrap();
+    }
+
+    let conversation_ids = |json: Value| -> Vec<String> {
+        json["conversations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|conversation| conversation["id"].as_str().unwrap().to_owned())
+            .collect()
+    };
+
+    let (status, json) = get_json(user_id, "/conversations?sort=created_at&order=desc").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        conversation_ids(json),
+        [newer.to_string(), older.to_string()]
+    );
+
+    let (status, json) = get_json(user_id, "/conversations?sort=last_message_at&order=desc").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        conversation_ids(json),
+        [older.to_string(), newer.to_string()]
+    );
+}
+
+#[tokio::test]
+#[serial]
+async fn test_unknown_sort_key_is_rejected() {
+    let db = TestDb::new().await;
+    let user_id = Uuid::new_v4();
+    let (conversation_id, _) = insert_conversation_with_bot_message(db.pool(), user_id).await;
+
+    for uri in [
+        "/conversations?sort=user".to_owned(),
+        "/conversations?sort=created_at%20DESC%3B%20DROP%20TABLE%20conversations".to_owned(),
+        format!("/conversations/{conversation_id}/messages?sort=text"),
+    ] {
+        let response = create_test_app()
+            .oneshot(
+                Request::builder()
+                    .uri(&uri)
+                    .header("X-User-ID", user_id.to_string())
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+    }
+
+    let (status, _) = get_json(
+        user_id,
+        &format!("/conversations/{conversation_id}/messages?sort=created_at&order=desc"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_sync_returns_only_changes_after_the_cursor() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    let user_id = Uuid::new_v4();
+
+    let (old_conversation, _) = insert_conversation_with_bot_message(&pool, user_id).await;
+    let (edited_conversation, edited_message) =
+        insert_conversation_with_bot_message(&pool, user_id).await;
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+    let (status, json) = get_json(user_id, "/conversations/sync").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(json["conversations"].as_array().unwrap().len(), 2);
+    assert_eq!(json["messages"].as_array().unwrap().len(), 2);
+    let cursor = json["cursor"].as_str().unwrap().to_owned();
+
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    let (new_conversation, new_message) =
+        insert_conversation_with_bot_message(&pool, user_id).await;
+    sqlx::query("UPDATE messages SET text = 'Edited' WHERE id = ?")
+        .bind(edited_message)
+        .execute(&pool)
+        .await
+        .unwrap();
+    // Changes of other users are never listed
+    insert_conversation_with_bot_message(&pool, Uuid::new_v4()).await;
+
+    let (status, json) = get_json(user_id, &format!("/conversations/sync?since={cursor}")).await;
+    assert_eq!(status, StatusCode::OK);
+    let ids = |key: &str| -> Vec<String> {
+        let mut ids: Vec<String> = json[key]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entity| entity["id"].as_str().unwrap().to_owned())
+            .collect();
+        ids.sort();
+        ids
+    };
+    let mut expected_conversations = vec![
+        new_conversation.to_string(),
+        edited_conversation.to_string(),
+    ];
+    expected_conversations.sort();
+    assert_eq!(ids("conversations"), expected_conversations);
+    assert!(!ids("conversations").contains(&old_conversation.to_string()));
+    let mut expected_messages = vec![new_message.to_string(), edited_message.to_string()];
+    expected_messages.sort();
+    assert_eq!(ids("messages"), expected_messages);
+    assert_ne!(json["cursor"], cursor.as_str());
+}
+
+fn tag_request(user_id: Uuid, method: &str, conversation_id: Uuid, tag: &str) -> Request<Body> {
+    Request::builder()
+        .method(method)
+        .uri(format!("/conversations/{conversation_id}/tags"))
+        .header("X-User-ID", user_id.to_string())
+        .header("Content-Type", "application/json")
+        .body(Body::from(json!({ "tag": tag }).to_string()))
+        .unwrap()
+}
+
+async fn send_tag_request(
+    user_id: Uuid,
+    method: &str,
+    conversation_id: Uuid,
+    tag: &str,
+) -> StatusCode {
+    create_test_app()
+        .oneshot(tag_request(user_id, method, conversation_id, tag))
+        .await
+        .unwrap()
+        .status()
+}
+
+async fn listed_conversation_ids(user_id: Uuid, uri: &str) -> Vec<String> {
+    let (status, json) = get_json(user_id, uri).await;
+    assert_eq!(status, StatusCode::OK);
+    let mut ids: Vec<String> = json["conversations"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|conversation| conversation["id"].as_str().unwrap().to_owned())
+        .collect();
+    ids.sort();
+    ids
+}
+
+#[tokio::test]
+#[serial]
+async fn test_conversations_are_filtered_by_tag() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    let user_id = Uuid::new_v4();
+
+    let (work, _) = insert_conversation_with_bot_message(&pool, user_id).await;
+    let (work_and_travel, _) = insert_conversation_with_bot_message(&pool, user_id).await;
+    let (untagged, _) = insert_conversation_with_bot_message(&pool, user_id).await;
+    assert_eq!(
+        send_tag_request(user_id, "POST", work, "work").await,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(
+        send_tag_request(user_id, "POST", work_and_travel, " work ").await,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(
+        send_tag_request(user_id, "POST", work_and_travel, "travel").await,
+        StatusCode::NO_CONTENT
+    );
+    // Tagging again changes nothing
+    assert_eq!(
+        send_tag_request(user_id, "POST", work, "work").await,
+        StatusCode::NO_CONTENT
+    );
+
+    let mut expected = vec![work.to_string(), work_and_travel.to_string()];
+    expected.sort();
+    assert_eq!(
+        listed_conversation_ids(user_id, "/conversations?tag=work").await,
+        expected
+    );
+    assert_eq!(
+        listed_conversation_ids(user_id, "/conversations?tag=travel").await,
+        [work_and_travel.to_string()]
+    );
+    assert!(
+        listed_conversation_ids(user_id, "/conversations?tag=home")
+            .await
+            .is_empty()
+    );
+    assert_eq!(
+        listed_conversation_ids(user_id, "/conversations")
+            .await
+            .len(),
+        3
+    );
+    assert!(
+        !listed_conversation_ids(user_id, "/conversations?tag=work")
+            .await
+            .contains(&untagged.to_string())
+    );
+}
+
+#[tokio::test]
+#[serial]
+async fn test_untagged_conversation_is_no_longer_listed_by_tag() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    let user_id = Uuid::new_v4();
+
+    let (conversation_id, _) = insert_conversation_with_bot_message(&pool, user_id).await;
+    assert_eq!(
+        send_tag_request(user_id, "POST", conversation_id, "work").await,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(
+        send_tag_request(user_id, "DELETE", conversation_id, "work").await,
+        StatusCode::NO_CONTENT
+    );
+
+    assert!(
+        listed_conversation_ids(user_id, "/conversations?tag=work")
+            .await
+            .is_empty()
+    );
+    // The tag is gone already
+    assert_eq!(
+        send_tag_request(user_id, "DELETE", conversation_id, "work").await,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+#[serial]
+async fn test_tags_are_scoped_to_the_owner() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    let owner = Uuid::new_v4();
+    let other_user = Uuid::new_v4();
+
+    let (conversation_id, _) = insert_conversation_with_bot_message(&pool, owner).await;
+    assert_eq!(
+        send_tag_request(owner, "POST", conversation_id, "work").await,
+        StatusCode::NO_CONTENT
+    );
+
+    assert_eq!(
+        send_tag_request(other_user, "POST", conversation_id, "mine").await,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        send_tag_request(other_user, "DELETE", conversation_id, "work").await,
+        StatusCode::NOT_FOUND
+    );
+    assert!(
+        listed_conversation_ids(other_user, "/conversations?tag=work")
+            .await
+            .is_empty()
+    );
+    assert_eq!(
+        listed_conversation_ids(owner, "/conversations?tag=work").await,
+        [conversation_id.to_string()]
+    );
+}
+
+#[tokio::test]
+#[serial]
+async fn test_empty_and_too_long_tags_are_rejected() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    let user_id = Uuid::new_v4();
+
+    let (conversation_id, _) = insert_conversation_with_bot_message(&pool, user_id).await;
+
+    assert_eq!(
+        send_tag_request(user_id, "POST", conversation_id, "  ").await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        send_tag_request(user_id, "POST", conversation_id, &"a".repeat(65)).await,
+        StatusCode::BAD_REQUEST
+    );
+    // Like adding one, not like removing a tag the conversation doesn't have
+    assert_eq!(
+        send_tag_request(user_id, "DELETE", conversation_id, "  ").await,
+        StatusCode::BAD_REQUEST
+    );
+}
+
+/// Insert a conversation owned by `user_id` containing a single bot message
+async fn insert_conversation_with_bot_message(pool: &SqlitePool, user_id: Uuid) -> (Uuid, Uuid) {
+    let conversation_id = Uuid::new_v4();
+    let message_id = Uuid::new_v4();
+
+    sqlx::query("INSERT INTO conversations (id, user, created_at) VALUES (?, ?, ?)")
+        .bind(conversation_id)
+        .bind(user_id)
+        .bind(Utc::now().to_rfc3339())
+        .execute(pool)
+        .await
+        .unwrap();
+
+    sqlx::query(
+        "INSERT INTO messages (id, conversation_id, kind, created_at, text) VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(message_id)
+    .bind(conversation_id)
+    .bind(2) // Bot message
+    .bind(Utc::now().to_rfc3339())
+    .bind("Hi, how can I help?")
+    .execute(pool)
+    .await
+    .unwrap();
+
+    (conversation_id, message_id)
+}
+
+fn feedback_request(user_id: Uuid, uri: &str, body: Option<&str>) -> Request<Body> {
+    let builder = Request::builder()
+        .uri(uri)
+        .header("X-User-ID", user_id.to_string());
+
+    match body {
+        Some(body) => builder
+            .method("POST")
+            .header("Content-Type", "application/json")
+            .body(Body::from(body.to_owned()))
+            .unwrap(),
+        None => builder.body(Body::empty()).unwrap(),
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn test_message_feedback_create_and_update() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+
+    let user_id = Uuid::new_v4();
+    let (conversation_id, message_id) = insert_conversation_with_bot_message(&pool, user_id).await;
+    let uri = format!("/conversations/{conversation_id}/messages/{message_id}/feedback");
+
+    // Create
+    let response = create_test_app()
+        .oneshot(feedback_request(user_id, &uri, Some(r#"{"rating": "up"}"#)))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Update replaces the earlier rating instead of adding a second one
+    let response = create_test_app()
+        .oneshot(feedback_request(
+            user_id,
+            &uri,
+            Some(r#"{"rating": "down", "comment": "Not helpful"}"#),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM message_feedback")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(count.0, 1);
+
+    // Read
+    let response = create_test_app()
+        .oneshot(feedback_request(user_id, &uri, None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["message_id"], message_id.to_string());
+    assert_eq!(json["rating"], "down");
+    assert_eq!(json["comment"], "Not helpful");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_message_feedback_scoped_to_owner() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+
+    let owner = Uuid::new_v4();
+    let other_user = Uuid::new_v4();
+    let (conversation_id, message_id) = insert_conversation_with_bot_message(&pool, owner).await;
+    let uri = format!("/conversations/{conversation_id}/messages/{message_id}/feedback");
+
+    let response = create_test_app()
+        .oneshot(feedback_request(owner, &uri, Some(r#"{"rating": "up"}"#)))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Another user can neither rate the message nor see the owner's feedback
+    let response = create_test_app()
+        .oneshot(feedback_request(
+            other_user,
+            &uri,
+            Some(r#"{"rating": "down"}"#),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = create_test_app()
+        .oneshot(feedback_request(other_user, &uri, None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+fn post_json_request(user_id: Uuid, uri: &str, body: &str) -> Request<Body> {
+    Request::builder()
+        .method("POST")
+        .uri(uri)
+        .header("X-User-ID", user_id.to_string())
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_owned()))
+        .unwrap()
+}
+
+/// Reads the whole SSE body, which also waits for the bot message to be saved
+async fn read_sse_events(response: axum::response::Response) -> Vec<(String, Value)> {
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    parse_sse_events(std::str::from_utf8(&body).unwrap())
+        .into_iter()
+        .map(|(event, data)| (event, serde_json::from_str(&data).unwrap()))
+        .collect()
+}
+
+#[tokio::test]
+#[serial]
+async fn test_post_new_conversation_streams_response() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    init_test_task_sender();
+
+    let user_id = Uuid::new_v4();
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            "/conversations",
+            r#"{"message": "Hi!"}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let events = read_sse_events(response).await;
+    assert_eq!(events[0].0, "new_message");
+    assert_eq!(events[0].1["text"], "Hi!");
+
+    let (last_event, _) = events.last().unwrap();
+    assert_eq!(last_event, "done");
+    let parts: Vec<&str> = events[1..events.len() - 1]
+        .iter()
+        .map(|(event, data)| {
+            assert_eq!(event, "message_part");
+            data["message_part"].as_str().unwrap()
+        })
+        .collect();
+    assert_eq!(parts, CANNED_RESPONSE);
+
+    // The conversation holds the system prompt, the user message and the bot reply
+    let conversation_id = events[0].1["conversation_id"].as_str().unwrap();
+    let texts: Vec<(i64, String)> = sqlx::query_as(
+        "SELECT kind, text FROM messages WHERE conversation_id = ? ORDER BY datetime(created_at) ASC",
+    )
+    .bind(Uuid::parse_str(conversation_id).unwrap())
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+    assert_eq!(texts.len(), 3);
+    assert!(texts.contains(&(3, "Hi!".to_owned())));
+    assert!(texts.contains(&(2, CANNED_RESPONSE.concat())));
+}
+
+#[tokio::test]
+#[serial]
+async fn test_messages_are_rejected_while_the_model_reloads() {
+    let _db = TestDb::new().await;
+    init_test_task_sender();
+    let user_id = Uuid::new_v4();
+
+    let reload = ReloadWindow::begin();
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            "/conversations",
+            r#"{"message": "Hi!"}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()["retry-after"], "5");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], "model_reloading");
+
+    drop(reload);
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            "/conversations",
+            r#"{"message": "Hi!"}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_compaction_and_completions_are_rejected_while_the_model_reloads() {
+    let _db = TestDb::new().await;
+    init_test_task_sender();
+    let user_id = Uuid::new_v4();
+    // Rejected before the conversation is looked up
+    let conversation_id = Uuid::new_v4();
+
+    let _reload = ReloadWindow::begin();
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            &format!("/conversations/{conversation_id}/compact"),
+            "{}",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()["retry-after"], "5");
+
+    let response = api::openai::router()
+        .oneshot(post_json_request(
+            user_id,
+            "/chat/completions",
+            r#"{"model": "x", "messages": [{"role": "user", "content": "Hi!"}]}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()["retry-after"], "5");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"]["code"], "model_reloading");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_text_format_streams_raw_message_parts() {
+    let _db = TestDb::new().await;
+    init_test_task_sender();
+    let user_id = Uuid::new_v4();
+
+    for (uri, text) in [
+        ("/conversations", false),
+        ("/conversations?format=text", true),
+    ] {
+        let response = create_test_app()
+            .oneshot(post_json_request(user_id, uri, r#"{"message": "Hi!"}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let events = parse_sse_events(std::str::from_utf8(&body).unwrap());
+
+        let parts: Vec<&str> = events
+            .iter()
+            .filter(|(event, _)| event == "message_part")
+            .map(|(_, data)| data.as_str())
+            .collect();
+        assert_eq!(parts.len(), CANNED_RESPONSE.len());
+        for (part, expected) in parts.iter().zip(CANNED_RESPONSE) {
+            if text {
+                // The event parser trims the data
+                assert_eq!(*part, expected.trim());
+            } else {
+                let part: Value = serde_json::from_str(part).unwrap();
+                assert_eq!(part["message_part"], expected);
+            }
+        }
+
+        // Metadata stays JSON
+        for (event, data) in &events {
+            if event != "message_part" {
+                assert!(serde_json::from_str::<Value>(data).unwrap().is_object());
+            }
+        }
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn test_stream_checksum_matches_full_message() {
+    let _db = TestDb::new().await;
+    init_test_task_sender();
+
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            Uuid::new_v4(),
+            "/conversations",
+            r#"{"message": "Hi!"}"#,
+        ))
+        .await
+        .unwrap();
+    let events = read_sse_events(response).await;
+
+    // Every part carries the running length and checksum of the text up to and including it
+    let mut text = String::new();
+    for (_, data) in events.iter().filter(|(event, _)| event == "message_part") {
+        text.push_str(data["message_part"].as_str().unwrap());
+        assert_eq!(data["length"], text.len());
+        assert_eq!(data["crc32"], crc32fast::hash(text.as_bytes()));
+    }
+    assert_eq!(text, CANNED_RESPONSE.concat());
 
-    cleanup_test_db();
+    let (event, done) = events.last().unwrap();
+    assert_eq!(event, "done");
+    assert_eq!(done["length"], text.len());
+    assert_eq!(done["crc32"], crc32fast::hash(text.as_bytes()));
+    assert_eq!(done["message_id"], events[1].1["message_id"]);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_stream_sends_configured_retry_once() {
+    let _db = TestDb::new().await;
+    init_test_task_sender();
+    unsafe { std::env::set_var("SSE_RETRY_MS", "2500") };
+
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            Uuid::new_v4(),
+            "/conversations",
+            r#"{"message": "Hi!"}"#,
+        ))
+        .await
+        .unwrap();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+
+    unsafe { std::env::remove_var("SSE_RETRY_MS") };
+
+    // Only the first event tells the browser how long to wait before reconnecting
+    let events: Vec<&str> = body.split("\n\n").collect();
+    assert!(events[0].lines().any(|line| line == "retry:2500"));
+    assert!(events[1..].iter().all(|event| !event.contains("retry:")));
+}
+
+#[tokio::test]
+#[serial]
+async fn test_post_message_to_conversation_streams_response() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    init_test_task_sender();
+
+    let user_id = Uuid::new_v4();
+    let conversation_id = Uuid::new_v4();
+    sqlx::query("INSERT INTO conversations (id, user, created_at) VALUES (?, ?, ?)")
+        .bind(conversation_id)
+        .bind(user_id)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            &format!("/conversations/{conversation_id}/messages"),
+            r#"{"text": "How are you?"}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let events = read_sse_events(response).await;
+    assert_eq!(events[0].0, "new_message");
+    assert_eq!(events[0].1["conversation_id"], conversation_id.to_string());
+
+    // Every part refers to the same bot message, which is stored under that id
+    let message_id = events[1].1["message_id"].clone();
+    assert!(
+        events[1..]
+            .iter()
+            .all(|(_, data)| data["message_id"] == message_id)
+    );
+
+    let (text,): (String,) = sqlx::query_as("SELECT text FROM messages WHERE id = ?")
+        .bind(Uuid::parse_str(message_id.as_str().unwrap()).unwrap())
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(text, CANNED_RESPONSE.concat());
+}
+
+#[tokio::test]
+#[serial]
+async fn test_events_subscriber_receives_the_posters_parts() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    init_test_task_sender();
+
+    let user_id = Uuid::new_v4();
+    let conversation_id = Uuid::new_v4();
+    sqlx::query("INSERT INTO conversations (id, user, created_at) VALUES (?, ?, ?)")
+        .bind(conversation_id)
+        .bind(user_id)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .uri(format!("/conversations/{conversation_id}/events"))
+                .header("X-User-ID", user_id.to_string())
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut subscriber = response.into_body().into_data_stream();
+
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            &format!("/conversations/{conversation_id}/messages"),
+            r#"{"text": "How are you?"}"#,
+        ))
+        .await
+        .unwrap();
+    let posted: Vec<(String, Value)> = read_sse_events(response)
+        .await
+        .into_iter()
+        .filter(|(event, _)| event != "new_message")
+        .collect();
+
+    // The events stream never ends, so read it until the message is done
+    let mut text = String::new();
+    let received = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            let chunk = subscriber.next().await.expect("stream ended").unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+
+            let events = parse_sse_events(&text);
+            if events.iter().any(|(event, _)| event == "done") {
+                return events;
+            }
+        }
+    })
+    .await
+    .expect("no done event received");
+    let received: Vec<(String, Value)> = received
+        .into_iter()
+        .map(|(event, data)| (event, serde_json::from_str(&data).unwrap()))
+        .collect();
+
+    assert_eq!(received, posted);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_events_of_other_users_conversation_is_not_found() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+
+    let conversation_id = Uuid::new_v4();
+    sqlx::query("INSERT INTO conversations (id, user, created_at) VALUES (?, ?, ?)")
+        .bind(conversation_id)
+        .bind(Uuid::new_v4())
+        .bind(Utc::now().to_rfc3339())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let (status, _) = get_json(
+        Uuid::new_v4(),
+        &format!("/conversations/{conversation_id}/events"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+/// Point `PERSONAS_FILE` at a temporary file containing a single "pirate" persona
+fn configure_test_personas() -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("personas-{}.json", Uuid::new_v4()));
+    std::fs::write(
+        &path,
+        r#"{"pirate": {"description": "Talks like a pirate", "system_prompt": "Arr, ye be helpful."}}"#,
+    )
+    .unwrap();
+    unsafe { std::env::set_var("PERSONAS_FILE", &path) };
+    path
+}
+
+fn clear_test_personas(path: std::path::PathBuf) {
+    unsafe { std::env::remove_var("PERSONAS_FILE") };
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+#[serial]
+async fn test_create_conversation_with_persona() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    init_test_task_sender();
+    let personas = configure_test_personas();
+
+    let user_id = Uuid::new_v4();
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            "/conversations",
+            r#"{"message": "Hi!", "persona": "pirate"}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    read_sse_events(response).await;
+
+    let (system_prompt,): (String,) = sqlx::query_as(
+        "SELECT text FROM messages INNER JOIN conversations ON conversations.id = messages.conversation_id WHERE user = ? AND kind = 1",
+    )
+    .bind(user_id)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(system_prompt, "Arr, ye be helpful.");
+
+    clear_test_personas(personas);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_create_conversation_with_the_default_persona() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    init_test_task_sender();
+    let personas = configure_test_personas();
+    unsafe { std::env::set_var("DEFAULT_PERSONA", "pirate") };
+
+    let user_id = Uuid::new_v4();
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            "/conversations",
+            r#"{"message": "Hi!"}"#,
+        ))
+        .await
+        .unwrap();
+    unsafe { std::env::remove_var("DEFAULT_PERSONA") };
+    assert_eq!(response.status(), StatusCode::OK);
+    read_sse_events(response).await;
+
+    let (system_prompt,): (String,) = sqlx::query_as(
+        "SELECT text FROM messages INNER JOIN conversations ON conversations.id = messages.conversation_id WHERE user = ? AND kind = 1",
+    )
+    .bind(user_id)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(system_prompt, "Arr, ye be helpful.");
+
+    clear_test_personas(personas);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_create_conversation_with_unknown_persona() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    let personas = configure_test_personas();
+
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            Uuid::new_v4(),
+            "/conversations",
+            r#"{"message": "Hi!", "persona": "ninja"}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // Nothing is created for a rejected persona
+    let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM conversations")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(count.0, 0);
+
+    clear_test_personas(personas);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_create_conversation_over_limit_is_rejected() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    init_test_task_sender();
+    unsafe { std::env::set_var("MAX_CONVERSATIONS_PER_USER", "2") };
+
+    let user_id = Uuid::new_v4();
+    for _ in 0..2 {
+        let response = create_test_app()
+            .oneshot(post_json_request(
+                user_id,
+                "/conversations",
+                r#"{"message": "Hi!"}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        read_sse_events(response).await;
+    }
+
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            "/conversations",
+            r#"{"message": "Hi!"}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // The limit is per user
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            Uuid::new_v4(),
+            "/conversations",
+            r#"{"message": "Hi!"}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    read_sse_events(response).await;
+
+    unsafe { std::env::remove_var("MAX_CONVERSATIONS_PER_USER") };
+    let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM conversations WHERE user = ?")
+        .bind(user_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(count.0, 2);
+}
+
+/// Posts `body` to `uri` with the `Content-Type` header `content_type`, if any, and returns the
+/// status and JSON body of the response.
+async fn post_with_content_type(
+    user_id: Uuid,
+    uri: &str,
+    content_type: Option<&str>,
+    body: &'static str,
+) -> (StatusCode, Value) {
+    let mut request = Request::builder()
+        .method("POST")
+        .uri(uri)
+        .header("X-User-ID", user_id.to_string());
+    if let Some(content_type) = content_type {
+        request = request.header("Content-Type", content_type);
+    }
+    let response = create_test_app()
+        .oneshot(request.body(Body::from(body)).unwrap())
+        .await
+        .unwrap();
+
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_post_with_wrong_content_type_is_unsupported_media_type() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    let user_id = Uuid::new_v4();
+    let (conversation_id, _) = insert_conversation_with_bot_message(&pool, user_id).await;
+
+    for uri in [
+        "/conversations".to_owned(),
+        format!("/conversations/{conversation_id}/messages"),
+        format!("/conversations/{conversation_id}/tags"),
+    ] {
+        let (status, body) =
+            post_with_content_type(user_id, &uri, Some("text/plain"), r#"{"message": "Hi!"}"#)
+                .await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE, "{uri}");
+        assert_eq!(body["code"], "unsupported_media_type", "{uri}");
+        assert!(body["error"].as_str().unwrap().contains("application/json"));
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn test_post_without_content_type_is_unsupported_media_type() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    let user_id = Uuid::new_v4();
+    let (conversation_id, _) = insert_conversation_with_bot_message(&pool, user_id).await;
+
+    let (status, body) = post_with_content_type(
+        user_id,
+        &format!("/conversations/{conversation_id}/messages"),
+        None,
+        r#"{"text": "Hi!"}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(body["code"], "unsupported_media_type");
+
+    // Nothing is saved from a rejected request
+    let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM messages")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(count.0, 1);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_malformed_json_body_has_error_envelope() {
+    let _db = TestDb::new().await;
+
+    let (status, body) = post_with_content_type(
+        Uuid::new_v4(),
+        "/conversations",
+        Some("application/json"),
+        r#"{"message": "#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "invalid_json");
+
+    let (status, body) = post_with_content_type(
+        Uuid::new_v4(),
+        "/conversations",
+        Some("application/json"),
+        r#"{"text": "Hi!"}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["code"], "invalid_body");
+}
+
+/// Insert a message with a token count into an existing conversation
+async fn insert_message_with_tokens(
+    pool: &SqlitePool,
+    conversation_id: Uuid,
+    kind: i64,
+    token_count: i64,
+) {
+    sqlx::query(
+        "INSERT INTO messages (id, conversation_id, kind, created_at, text, token_count) VALUES (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(Uuid::new_v4())
+    .bind(conversation_id)
+    .bind(kind)
+    .bind(Utc::now().to_rfc3339())
+    .bind("text")
+    .bind(token_count)
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+async fn get_json(user_id: Uuid, uri: &str) -> (StatusCode, Value) {
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .uri(uri)
+                .header("X-User-ID", user_id.to_string())
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+#[serial]
+async fn test_usage_totals_match_stored_token_counts() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+
+    let user_id = Uuid::new_v4();
+    let (first, _) = insert_conversation_with_bot_message(&pool, user_id).await;
+    insert_message_with_tokens(&pool, first, 1, 20).await;
+    insert_message_with_tokens(&pool, first, 3, 35).await;
+    insert_message_with_tokens(&pool, first, 2, 12).await;
+    let (second, _) = insert_conversation_with_bot_message(&pool, user_id).await;
+    insert_message_with_tokens(&pool, second, 3, 50).await;
+    insert_message_with_tokens(&pool, second, 2, 7).await;
+
+    // Another user's tokens don't count
+    let (other, _) = insert_conversation_with_bot_message(&pool, Uuid::new_v4()).await;
+    insert_message_with_tokens(&pool, other, 2, 1000).await;
+
+    let sum = |conversation_ids: Vec<Uuid>, bot: bool| {
+        let pool = pool.clone();
+        async move {
+            let mut total = 0;
+            for conversation_id in conversation_ids {
+                let (sum,): (i64,) = sqlx::query_as(
+                    "SELECT COALESCE(SUM(token_count), 0) FROM messages WHERE conversation_id = ? AND (kind = 2) = ?",
+                )
+                .bind(conversation_id)
+                .bind(bot)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+                total += sum;
+            }
+            total
+        }
+    };
+
+    let (status, usage) = get_json(user_id, &format!("/conversations/{first}/usage")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(usage["prompt_tokens"], sum(vec![first], false).await);
+    assert_eq!(usage["completion_tokens"], sum(vec![first], true).await);
+    assert_eq!(usage["total_tokens"], 67);
+
+    let (status, usage) = get_json(user_id, "/usage").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        usage["prompt_tokens"],
+        sum(vec![first, second], false).await
+    );
+    assert_eq!(
+        usage["completion_tokens"],
+        sum(vec![first, second], true).await
+    );
+    assert_eq!(usage["total_tokens"], 124);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_usage_of_other_users_conversation_is_not_found() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+
+    let (conversation_id, _) = insert_conversation_with_bot_message(&pool, Uuid::new_v4()).await;
+
+    let (status, _) = get_json(
+        Uuid::new_v4(),
+        &format!("/conversations/{conversation_id}/usage"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_generation_records_token_counts() {
+    let _db = TestDb::new().await;
+    init_test_task_sender();
+
+    let user_id = Uuid::new_v4();
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            "/conversations",
+            r#"{"message": "Hi!"}"#,
+        ))
+        .await
+        .unwrap();
+    let events = read_sse_events(response).await;
+    let conversation_id = events[0].1["conversation_id"].as_str().unwrap();
+
+    let (status, usage) =
+        get_json(user_id, &format!("/conversations/{conversation_id}/usage")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(usage["prompt_tokens"], FAKE_PROMPT_TOKENS);
+    assert_eq!(usage["completion_tokens"], CANNED_RESPONSE.len());
+}
+
+#[tokio::test]
+#[serial]
+async fn test_response_prefix_and_suffix_wrap_reply_without_counting_tokens() {
+    let db = TestDb::new().await;
+    init_test_task_sender();
+    unsafe {
+        std::env::set_var("RESPONSE_PREFIX", "[beta] ");
+        std::env::set_var("RESPONSE_SUFFIX", " (AI generated)");
+    }
+
+    let user_id = Uuid::new_v4();
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            "/conversations",
+            r#"{"message": "Hi!"}"#,
+        ))
+        .await
+        .unwrap();
+    let events = read_sse_events(response).await;
+
+    unsafe {
+        std::env::remove_var("RESPONSE_PREFIX");
+        std::env::remove_var("RESPONSE_SUFFIX");
+    }
+
+    let parts: Vec<&str> = events
+        .iter()
+        .filter(|(event, _)| event == "message_part")
+        .map(|(_, data)| data["message_part"].as_str().unwrap())
+        .collect();
+    let (first, rest) = parts.split_first().unwrap();
+    let (last, generated) = rest.split_last().unwrap();
+    assert_eq!(*first, "[beta] ");
+    assert_eq!(generated, CANNED_RESPONSE);
+    assert_eq!(*last, " (AI generated)");
+
+    let (text,): (String,) = sqlx::query_as("SELECT text FROM messages WHERE kind = 2")
+        .fetch_one(db.pool())
+        .await
+        .unwrap();
+    assert_eq!(
+        text,
+        format!("[beta] {} (AI generated)", CANNED_RESPONSE.concat())
+    );
+
+    let conversation_id = events[0].1["conversation_id"].as_str().unwrap();
+    let (_, usage) = get_json(user_id, &format!("/conversations/{conversation_id}/usage")).await;
+    assert_eq!(usage["completion_tokens"], CANNED_RESPONSE.len());
+}
+
+#[tokio::test]
+#[serial]
+async fn test_compact_conversation_replaces_old_turns_with_summary() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    init_test_task_sender();
+
+    let user_id = Uuid::new_v4();
+    let conversation_id = Uuid::new_v4();
+    sqlx::query("INSERT INTO conversations (id, user, created_at) VALUES (?, ?, ?)")
+        .bind(conversation_id)
+        .bind(user_id)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    // A minute apart, since messages are ordered by their timestamp
+    let started = Utc::now() - chrono::Duration::hours(1);
+    let messages = [
+        (1, "You are helpful"),
+        (3, "First question"),
+        (2, "First answer"),
+        (3, "Second question"),
+        (2, "Second answer"),
+        (3, "Third question"),
+        (2, "Third answer"),
+    ];
+    for (minute, (kind, text)) in messages.into_iter().enumerate() {
+        sqlx::query(
+            "INSERT INTO messages (id, conversation_id, kind, created_at, text) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(Uuid::new_v4())
+        .bind(conversation_id)
+        .bind(kind)
+        .bind(started + chrono::Duration::minutes(minute as i64))
+        .bind(text)
+        .execute(&pool)
+        .await
+        .unwrap();
+    }
+
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            &format!("/conversations/{conversation_id}/compact"),
+            r#"{"keep_turns": 1}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    let messages: Vec<(&str, &str)> = json["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| (m["kind"].as_str().unwrap(), m["text"].as_str().unwrap()))
+        .collect();
+
+    let summary = format!("{SUMMARY_PREFIX}{}", CANNED_RESPONSE.concat());
+    assert_eq!(
+        messages,
+        [
+            ("system", "You are helpful"),
+            ("system", summary.as_str()),
+            ("user", "Third question"),
+            ("assistant", "Third answer"),
+        ]
+    );
+
+    let (count,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM messages WHERE conversation_id = ?")
+            .bind(conversation_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(count, 4);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_message_over_the_limit_is_rejected() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    init_test_task_sender();
+    unsafe { std::env::set_var("MAX_MESSAGES_PER_CONVERSATION", "5") };
+
+    let user_id = Uuid::new_v4();
+    let conversation_id = insert_conversation_with_turns(&pool, user_id, 2).await;
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            &format!("/conversations/{conversation_id}/messages"),
+            r#"{"text": "One more question"}"#,
+        ))
+        .await
+        .unwrap();
+
+    unsafe { std::env::remove_var("MAX_MESSAGES_PER_CONVERSATION") };
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["code"], "message_limit_reached");
+    let (count,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM messages WHERE conversation_id = ?")
+            .bind(conversation_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(count, 5);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_message_over_the_limit_compacts_the_conversation() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    init_test_task_sender();
+    unsafe {
+        std::env::set_var("MAX_MESSAGES_PER_CONVERSATION", "13");
+        std::env::set_var("MESSAGE_LIMIT_POLICY", "compact");
+    }
+
+    let user_id = Uuid::new_v4();
+    let conversation_id = insert_conversation_with_turns(&pool, user_id, 6).await;
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            &format!("/conversations/{conversation_id}/messages"),
+            r#"{"text": "One more question"}"#,
+        ))
+        .await
+        .unwrap();
+    let status = response.status();
+    let events = read_sse_events(response).await;
+
+    unsafe {
+        std::env::remove_var("MAX_MESSAGES_PER_CONVERSATION");
+        std::env::remove_var("MESSAGE_LIMIT_POLICY");
+    }
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(events.last().unwrap().0, "done");
+
+    // The 2 oldest turns are summarized, the default 4 most recent ones kept
+    let (_, json) = get_json(
+        user_id,
+        &format!("/conversations/{conversation_id}/messages"),
+    )
+    .await;
+    let texts: Vec<&str> = json["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["text"].as_str().unwrap())
+        .collect();
+    let summary = format!("{SUMMARY_PREFIX}{}", CANNED_RESPONSE.concat());
+    assert_eq!(
+        texts[..3],
+        ["You are helpful", summary.as_str(), "Question 3"]
+    );
+    assert_eq!(
+        texts[texts.len() - 2..],
+        ["One more question", CANNED_RESPONSE.concat().as_str()]
+    );
+    assert_eq!(texts.len(), 12);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_get_messages_in_either_order() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+
+    let user_id = Uuid::new_v4();
+    let conversation_id = Uuid::new_v4();
+    sqlx::query("INSERT INTO conversations (id, user, created_at) VALUES (?, ?, ?)")
+        .bind(conversation_id)
+        .bind(user_id)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let started = Utc::now() - chrono::Duration::hours(1);
+    for (minute, text) in ["first", "second", "third"].into_iter().enumerate() {
+        sqlx::query(
+            "INSERT INTO messages (id, conversation_id, kind, created_at, text) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(Uuid::new_v4())
+        .bind(conversation_id)
+        .bind(3)
+        .bind(started + chrono::Duration::minutes(minute as i64))
+        .bind(text)
+        .execute(&pool)
+        .await
+        .unwrap();
+    }
+
+    let texts = |json: Value| -> Vec<String> {
+        json["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["text"].as_str().unwrap().to_owned())
+            .collect()
+    };
+
+    let uri = format!("/conversations/{conversation_id}/messages");
+    let (status, json) = get_json(user_id, &uri).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(texts(json), ["first", "second", "third"]);
+
+    let (_, json) = get_json(user_id, &format!("{uri}?order=asc")).await;
+    assert_eq!(texts(json), ["first", "second", "third"]);
+
+    let (_, json) = get_json(user_id, &format!("{uri}?order=desc")).await;
+    assert_eq!(texts(json), ["third", "second", "first"]);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_user_id_forms_refer_to_the_same_user() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    init_test_task_sender();
+
+    let user_id = Uuid::new_v4();
+
+    // Create the conversation with the simple form of the user id
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations")
+                .header("X-User-ID", user_id.simple().to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"message": "Hi!"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    read_sse_events(response).await;
+
+    // The stored owner is the same `Uuid` the hyphenated form parses to
+    let (owner,): (Uuid,) = sqlx::query_as("SELECT user FROM conversations")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(owner, user_id);
+
+    for user_header in [
+        user_id.hyphenated().to_string(),
+        user_id.simple().to_string(),
+        user_id.braced().to_string(),
+    ] {
+        let response = create_test_app()
+            .oneshot(
+                Request::builder()
+                    .uri("/conversations")
+                    .header("X-User-ID", user_header)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["conversations"].as_array().unwrap().len(), 1);
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn test_message_attachments_round_trip() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    init_test_task_sender();
+
+    let user_id = Uuid::new_v4();
+    let conversation_id = Uuid::new_v4();
+    sqlx::query("INSERT INTO conversations (id, user, created_at) VALUES (?, ?, ?)")
+        .bind(conversation_id)
+        .bind(user_id)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let attachments = json!([
+        {"name": "report.pdf", "mime": "application/pdf", "uri": "s3://bucket/report.pdf"},
+        {"name": "chart.png", "mime": "image/png", "uri": "https://example.com/chart.png"},
+    ]);
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            &format!("/conversations/{conversation_id}/messages"),
+            &json!({"text": "See attached", "attachments": attachments}).to_string(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Echoed back in the new message event
+    let events = read_sse_events(response).await;
+    assert_eq!(events[0].0, "new_message");
+    assert_eq!(events[0].1["attachments"], attachments);
+
+    // And stored with the message
+    let (_, json) = get_json(
+        user_id,
+        &format!("/conversations/{conversation_id}/messages"),
+    )
+    .await;
+    let messages = json["messages"].as_array().unwrap();
+    let user_message = messages
+        .iter()
+        .find(|m| m["text"] == "See attached")
+        .unwrap();
+    assert_eq!(user_message["attachments"], attachments);
+
+    // Messages without attachments have an empty list
+    let bot_message = messages.iter().find(|m| m["kind"] == "assistant").unwrap();
+    assert_eq!(bot_message["attachments"], json!([]));
+}
+
+#[tokio::test]
+#[serial]
+async fn test_new_conversation_records_model_fingerprint() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    init_test_task_sender();
+
+    let user_id = Uuid::new_v4();
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            "/conversations",
+            r#"{"message": "Hi!"}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    read_sse_events(response).await;
+
+    let (fingerprint,): (Option<String>,) =
+        sqlx::query_as("SELECT model_fingerprint FROM conversations WHERE user = ?")
+            .bind(user_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(fingerprint.as_deref(), Some(FAKE_MODEL_FINGERPRINT));
+
+    let (status, json) = get_json(user_id, "/conversations").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        json["conversations"][0]["model_fingerprint"],
+        FAKE_MODEL_FINGERPRINT
+    );
+}
+
+#[tokio::test]
+#[serial]
+async fn test_large_conversation_list_is_compressed() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    init_test_task_sender();
+
+    let user_id = Uuid::new_v4();
+    for _ in 0..200 {
+        sqlx::query("INSERT INTO conversations (id, user, created_at) VALUES (?, ?, ?)")
+            .bind(Uuid::new_v4())
+            .bind(user_id)
+            .bind(Utc::now())
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    let list_conversations = |accept_encoding: &str| {
+        Request::builder()
+            .uri("/conversations")
+            .header("X-User-ID", user_id.to_string())
+            .header("Accept-Encoding", accept_encoding)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let plain = create_test_app()
+        .oneshot(list_conversations("identity"))
+        .await
+        .unwrap();
+    assert!(plain.headers().get("Content-Encoding").is_none());
+    let plain = axum::body::to_bytes(plain.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    let gzipped = create_test_app()
+        .oneshot(list_conversations("gzip"))
+        .await
+        .unwrap();
+    assert_eq!(gzipped.status(), StatusCode::OK);
+    assert_eq!(gzipped.headers()["Content-Encoding"], "gzip");
+    let gzipped = axum::body::to_bytes(gzipped.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert!(gzipped.len() < plain.len());
+
+    // The event stream of a new conversation is never compressed
+    let mut request = post_json_request(user_id, "/conversations", r#"{"message": "Hi!"}"#);
+    request
+        .headers_mut()
+        .insert("Accept-Encoding", "gzip".parse().unwrap());
+    let response = create_test_app().oneshot(request).await.unwrap();
+    assert!(response.headers().get("Content-Encoding").is_none());
+    read_sse_events(response).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn test_continue_extends_bot_message() {
+    let _db = TestDb::new().await;
+    init_test_task_sender();
+
+    let user_id = Uuid::new_v4();
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            "/conversations",
+            r#"{"message": "Hi!"}"#,
+        ))
+        .await
+        .unwrap();
+    let events = read_sse_events(response).await;
+    let conversation_id = events[0].1["conversation_id"].as_str().unwrap().to_owned();
+    let user_message_id = events[0].1["id"].as_str().unwrap().to_owned();
+    let bot_message_id = events
+        .iter()
+        .find(|(event, _)| event == "message_part")
+        .map(|(_, data)| data["message_id"].as_str().unwrap().to_owned())
+        .unwrap();
+
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            &format!("/conversations/{conversation_id}/messages/{bot_message_id}/continue"),
+            "",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let events = read_sse_events(response).await;
+    let parts: Vec<&str> = events
+        .iter()
+        .filter(|(event, _)| event == "message_part")
+        .map(|(_, data)| {
+            assert_eq!(data["message_id"], bot_message_id);
+            data["message_part"].as_str().unwrap()
+        })
+        .collect();
+    assert_eq!(parts, CANNED_RESPONSE);
+
+    let (status, json) = get_json(
+        user_id,
+        &format!("/conversations/{conversation_id}/messages"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let messages = json["messages"].as_array().unwrap();
+    // Continuing doesn't add a message
+    assert_eq!(messages.len(), 3);
+    let bot_message = messages.iter().find(|m| m["id"] == bot_message_id).unwrap();
+    assert_eq!(
+        bot_message["text"],
+        CANNED_RESPONSE.concat() + &CANNED_RESPONSE.concat()
+    );
+
+    // Only bot messages can be continued
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            &format!("/conversations/{conversation_id}/messages/{user_message_id}/continue"),
+            "",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_replay_streams_the_stored_message() {
+    let db = TestDb::new().await;
+    let user_id = Uuid::new_v4();
+    let (conversation_id, message_id) =
+        insert_conversation_with_bot_message(db.pool(), user_id).await;
+    unsafe { std::env::set_var("REPLAY_CHARS_PER_EVENT", "4") };
+
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .uri(format!(
+                    "/conversations/{conversation_id}/messages/{message_id}/stream"
+                ))
+                .header("X-User-ID", user_id.to_string())
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    unsafe { std::env::remove_var("REPLAY_CHARS_PER_EVENT") };
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let events = read_sse_events(response).await;
+    let parts: Vec<&str> = events
+        .iter()
+        .filter(|(event, _)| event == "message_part")
+        .map(|(_, data)| data["message_part"].as_str().unwrap())
+        .collect();
+    assert_eq!(parts.concat(), "Hi, how can I help?");
+    assert_eq!(parts.len(), 5);
+    assert!(parts.iter().all(|part| part.chars().count() <= 4));
+    let (event, done) = events.last().unwrap();
+    assert_eq!(event, "done");
+    assert_eq!(done["length"], "Hi, how can I help?".len());
+
+    // Other users can't replay it
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .uri(format!(
+                    "/conversations/{conversation_id}/messages/{message_id}/stream"
+                ))
+                .header("X-User-ID", Uuid::new_v4().to_string())
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_messages_have_lowercase_roles() {
+    let _db = TestDb::new().await;
+    init_test_task_sender();
+
+    let user_id = Uuid::new_v4();
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            "/conversations",
+            r#"{"message": "Hi!"}"#,
+        ))
+        .await
+        .unwrap();
+    let events = read_sse_events(response).await;
+    assert_eq!(events[0].1["kind"], "user");
+    let conversation_id = events[0].1["conversation_id"].as_str().unwrap().to_owned();
+
+    let (status, json) = get_json(
+        user_id,
+        &format!("/conversations/{conversation_id}/messages"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let kinds: Vec<&str> = json["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["kind"].as_str().unwrap())
+        .collect();
+    assert_eq!(kinds, ["system", "user", "assistant"]);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_duplicate_conversation_copies_messages_in_order() {
+    let _db = TestDb::new().await;
+    init_test_task_sender();
+
+    let user_id = Uuid::new_v4();
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            "/conversations",
+            r#"{"message": "Hi!"}"#,
+        ))
+        .await
+        .unwrap();
+    let events = read_sse_events(response).await;
+    let conversation_id = events[0].1["conversation_id"].as_str().unwrap().to_owned();
+
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            &format!("/conversations/{conversation_id}/duplicate"),
+            "",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let duplicate: Value = serde_json::from_slice(&body).unwrap();
+    let duplicate_id = duplicate["id"].as_str().unwrap();
+    assert_ne!(duplicate_id, conversation_id);
+
+    let (_, original) = get_json(
+        user_id,
+        &format!("/conversations/{conversation_id}/messages"),
+    )
+    .await;
+    let (status, copy) =
+        get_json(user_id, &format!("/conversations/{duplicate_id}/messages")).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let original = original["messages"].as_array().unwrap();
+    let copy = copy["messages"].as_array().unwrap();
+    assert_eq!(original.len(), 3);
+    assert_eq!(copy.len(), original.len());
+    for (original, copy) in original.iter().zip(copy) {
+        assert_eq!(copy["kind"], original["kind"]);
+        assert_eq!(copy["text"], original["text"]);
+        assert_ne!(copy["id"], original["id"]);
+        assert_eq!(copy["conversation_id"], duplicate_id);
+    }
+
+    // Other users can't fork the conversation
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            Uuid::new_v4(),
+            &format!("/conversations/{conversation_id}/duplicate"),
+            "",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+fn put_system_request(user_id: Uuid, conversation_id: Uuid, text: &str) -> Request<Body> {
+    Request::builder()
+        .method("PUT")
+        .uri(format!("/conversations/{conversation_id}/system"))
+        .header("X-User-ID", user_id.to_string())
+        .header("Content-Type", "application/json")
+        .body(Body::from(json!({ "text": text }).to_string()))
+        .unwrap()
+}
+
+#[tokio::test]
+#[serial]
+async fn test_updated_system_message_is_listed() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+
+    let user_id = Uuid::new_v4();
+    let conversation_id = insert_conversation_with_turns(&pool, user_id, 1).await;
+    // Cache the messages before the update
+    let (_, before) = get_json(
+        user_id,
+        &format!("/conversations/{conversation_id}/messages"),
+    )
+    .await;
+    assert_eq!(before["messages"][0]["text"], "You are helpful");
+
+    let response = create_test_app()
+        .oneshot(put_system_request(
+            user_id,
+            conversation_id,
+            "Answer like a pirate",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let updated: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(updated["id"], before["messages"][0]["id"]);
+
+    let (_, after) = get_json(
+        user_id,
+        &format!("/conversations/{conversation_id}/messages"),
+    )
+    .await;
+    let messages = after["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 3);
+    assert_eq!(messages[0]["kind"], "system");
+    assert_eq!(messages[0]["text"], "Answer like a pirate");
+    assert_eq!(messages[1]["text"], "Question 1");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_system_message_update_is_rejected() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+
+    let user_id = Uuid::new_v4();
+    let conversation_id = insert_conversation_with_turns(&pool, user_id, 1).await;
+    let response = create_test_app()
+        .oneshot(put_system_request(Uuid::new_v4(), conversation_id, "Hi"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = create_test_app()
+        .oneshot(put_system_request(user_id, Uuid::new_v4(), "Hi"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    sqlx::query("DELETE FROM messages WHERE conversation_id = ? AND kind = 1")
+        .bind(conversation_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    let response = create_test_app()
+        .oneshot(put_system_request(user_id, conversation_id, "Hi"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_message_context_is_prompted_but_not_stored() {
+    let _db = TestDb::new().await;
+    init_test_task_sender();
+
+    let user_id = Uuid::new_v4();
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            "/conversations",
+            r#"{"message": "Hi!"}"#,
+        ))
+        .await
+        .unwrap();
+    let events = read_sse_events(response).await;
+    let conversation_id = events[0].1["conversation_id"].as_str().unwrap().to_owned();
+
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            &format!("/conversations/{conversation_id}/messages"),
+            r#"{"text": "What is the capital?", "context": "Finland's capital is Helsinki."}"#,
+        ))
+        .await
+        .unwrap();
+    read_sse_events(response).await;
+
+    // The context is a system message right before the question it helps answer
+    let prompt = last_prompt().unwrap();
+    assert!(
+        prompt.ends_with("system: Finland's capital is Helsinki.\nuser: What is the capital?\n"),
+        "{prompt}"
+    );
+
+    let (_, json) = get_json(
+        user_id,
+        &format!("/conversations/{conversation_id}/messages"),
+    )
+    .await;
+    let messages = json["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 5);
+    assert!(
+        messages
+            .iter()
+            .all(|message| !message["text"].as_str().unwrap().contains("Helsinki"))
+    );
+}
+
+#[tokio::test]
+#[serial]
+async fn test_sampling_preset_is_overridden_by_explicit_params() {
+    let _db = TestDb::new().await;
+    init_test_task_sender();
+
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            Uuid::new_v4(),
+            "/conversations",
+            r#"{"message": "Hi!", "preset": "precise", "temperature": 0.5}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    read_sse_events(response).await;
+
+    let precise = SamplingPreset::Precise.default_params();
+    assert_eq!(
+        last_sampling(),
+        Some(SamplingParams {
+            temperature: Some(0.5),
+            ..precise
+        })
+    );
+}
+
+#[tokio::test]
+#[serial]
+async fn test_stop_sequences_and_logit_bias_are_limited() {
+    let _db = TestDb::new().await;
+    init_test_task_sender();
+
+    // Within the default limits of 4 stop sequences and 300 logit biases
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            Uuid::new_v4(),
+            "/conversations",
+            r#"{"message": "Hi!", "stop": ["\n\n", "User:"], "logit_bias": {"42": -100}}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    read_sse_events(response).await;
+    let sampling = last_sampling().unwrap();
+    assert_eq!(sampling.stop, ["\n\n", "User:"]);
+    assert_eq!(sampling.logit_bias.get(&42), Some(&-100.0));
+
+    let too_many_stops = json!({"message": "Hi!", "stop": ["a", "b", "c", "d", "e"]});
+    let too_long_stops = json!({"message": "Hi!", "stop": ["x".repeat(257)]});
+    let logit_bias: serde_json::Map<String, Value> = (0..301)
+        .map(|token| (token.to_string(), json!(1)))
+        .collect();
+    let too_many_biases = json!({"message": "Hi!", "logit_bias": logit_bias});
+    for body in [too_many_stops, too_long_stops, too_many_biases] {
+        let response = create_test_app()
+            .oneshot(post_json_request(
+                Uuid::new_v4(),
+                "/conversations",
+                &body.to_string(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "invalid_sampling");
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn test_invalid_sampling_is_rejected() {
+    let db = TestDb::new().await;
+    init_test_task_sender();
+
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            Uuid::new_v4(),
+            "/conversations",
+            r#"{"message": "Hi!", "top_p": 1.5}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], "invalid_sampling");
+
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            Uuid::new_v4(),
+            "/conversations",
+            r#"{"message": "Hi!", "preset": "wild"}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // Nothing is created for a rejected request
+    let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM conversations")
+        .fetch_one(db.pool())
+        .await
+        .unwrap();
+    assert_eq!(count.0, 0);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_every_invalid_sampling_param_is_reported() {
+    let _db = TestDb::new().await;
+    init_test_task_sender();
+
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            Uuid::new_v4(),
+            "/conversations",
+            r#"{"message": "Hi!", "temperature": -1, "top_p": 1.5, "repetition_penalty": 0, "stop": [""]}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], "invalid_sampling");
+    let fields: Vec<&str> = body["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|error| error["field"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        fields,
+        ["temperature", "top_p", "repetition_penalty", "stop"]
+    );
+    assert!(
+        body["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|error| error["message"].is_string())
+    );
+}
+
+#[tokio::test]
+#[serial]
+async fn test_completed_generation_is_written_to_the_request_log() {
+    let db = TestDb::new().await;
+    init_test_task_sender();
+
+    // Off by default
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            Uuid::new_v4(),
+            "/conversations",
+            r#"{"message": "Not logged"}"#,
+        ))
+        .await
+        .unwrap();
+    read_sse_events(response).await;
+
+    unsafe { std::env::set_var("LOG_REQUESTS_TO_DB", "1") };
+    let user_id = Uuid::new_v4();
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            "/conversations",
+            r#"{"message": "Hi!", "temperature": 0.5}"#,
+        ))
+        .await
+        .unwrap();
+    unsafe { std::env::remove_var("LOG_REQUESTS_TO_DB") };
+    let events = read_sse_events(response).await;
+    let conversation_id: Uuid = events[0].1["conversation_id"]
+        .as_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    // The log is written after the stream has ended
+    let mut rows = Vec::new();
+    for _ in 0..50 {
+        rows = sqlx::query_as::<_, (Uuid, Uuid, String, String, String, String, u32, DateTime<Utc>, DateTime<Utc>)>(
+            "SELECT user, conversation_id, messages, sampling, response, outcome, completion_tokens, started_at, finished_at FROM request_log",
+        )
+        .fetch_all(db.pool())
+        .await
+        .unwrap();
+        if !rows.is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+
+    assert_eq!(rows.len(), 1);
+    let (
+        user,
+        logged_conversation,
+        messages,
+        sampling,
+        response,
+        outcome,
+        tokens,
+        started,
+        finished,
+    ) = rows.remove(0);
+    assert_eq!(user, user_id);
+    assert_eq!(logged_conversation, conversation_id);
+    let messages: Value = serde_json::from_str(&messages).unwrap();
+    let last = messages.as_array().unwrap().last().unwrap();
+    assert_eq!(last["role"], "user");
+    assert_eq!(last["content"], "Hi!");
+    let sampling: Value = serde_json::from_str(&sampling).unwrap();
+    assert_eq!(sampling["temperature"], 0.5);
+    assert_eq!(response, CANNED_RESPONSE.concat());
+    assert_eq!(outcome, "stop");
+    assert_eq!(tokens as usize, CANNED_RESPONSE.len());
+    assert!(started <= finished);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_conversation_is_created_with_the_client_id() {
+    let db = TestDb::new().await;
+    init_test_task_sender();
+
+    let user_id = Uuid::new_v4();
+    let conversation_id = Uuid::new_v4();
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            user_id,
+            "/conversations",
+            &json!({"message": "Hi!", "conversation_id": conversation_id}).to_string(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let events = read_sse_events(response).await;
+    assert_eq!(events[0].1["conversation_id"], conversation_id.to_string());
+
+    let (user,): (Uuid,) = sqlx::query_as("SELECT user FROM conversations WHERE id = ?")
+        .bind(conversation_id)
+        .fetch_one(db.pool())
+        .await
+        .unwrap();
+    assert_eq!(user, user_id);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_existing_conversation_with_the_client_id_is_continued() {
+    let db = TestDb::new().await;
+    init_test_task_sender();
+
+    let user_id = Uuid::new_v4();
+    let conversation_id = Uuid::new_v4();
+    for message in ["Hi!", "Again"] {
+        let response = create_test_app()
+            .oneshot(post_json_request(
+                user_id,
+                "/conversations",
+                &json!({"message": message, "conversation_id": conversation_id}).to_string(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        read_sse_events(response).await;
+    }
+
+    let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM conversations")
+        .fetch_one(db.pool())
+        .await
+        .unwrap();
+    assert_eq!(count.0, 1);
+    let (_, json) = get_json(
+        user_id,
+        &format!("/conversations/{conversation_id}/messages"),
+    )
+    .await;
+    let texts: Vec<_> = json["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|message| message["kind"] == "user")
+        .map(|message| message["text"].as_str().unwrap().to_owned())
+        .collect();
+    assert_eq!(texts, ["Hi!", "Again"]);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_client_id_of_another_users_conversation_is_forbidden() {
+    let db = TestDb::new().await;
+    init_test_task_sender();
+
+    let conversation_id = Uuid::new_v4();
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            Uuid::new_v4(),
+            "/conversations",
+            &json!({"message": "Mine", "conversation_id": conversation_id}).to_string(),
+        ))
+        .await
+        .unwrap();
+    read_sse_events(response).await;
+
+    let response = create_test_app()
+        .oneshot(post_json_request(
+            Uuid::new_v4(),
+            "/conversations",
+            &json!({"message": "Theirs", "conversation_id": conversation_id}).to_string(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], "conversation_forbidden");
+
+    let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM messages WHERE text = 'Theirs'")
+        .fetch_one(db.pool())
+        .await
+        .unwrap();
+    assert_eq!(count.0, 0);
 }
@@ -2,9 +2,22 @@
 
 use axum::extract::FromRequestParts;
 use axum::http::{Request, StatusCode};
-use tokio_local_llm_api::api::ExtractUser;
+use axum::response::IntoResponse;
+use serde_json::Value;
+use serial_test::serial;
+use tokio_local_llm_api::api::{DEFAULT_USER_ID, ExtractUser, UserRejection};
 use uuid::Uuid;
 
+/// Turns a rejection into its response and returns the status and JSON body.
+async fn rejection_response(rejection: UserRejection) -> (StatusCode, Value) {
+    let response = rejection.into_response();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
 #[tokio::test]
 async fn test_extract_user_valid_uuid() {
     let user_id = Uuid::new_v4();
@@ -21,16 +34,19 @@ async fn test_extract_user_valid_uuid() {
 }
 
 #[tokio::test]
+#[serial]
 async fn test_extract_user_missing_header() {
     let mut req = Request::builder().body(()).unwrap();
 
     let (mut parts, _) = req.into_parts();
     let result = ExtractUser::from_request_parts(&mut parts, &()).await;
 
-    assert!(result.is_err());
-    let (status, message) = result.unwrap_err();
+    let rejection = result.unwrap_err();
+    assert_eq!(rejection, UserRejection::Missing);
+    let (status, body) = rejection_response(rejection).await;
     assert_eq!(status, StatusCode::BAD_REQUEST);
-    assert!(message.contains("missing"));
+    assert_eq!(body["code"], "missing_user_id");
+    assert!(body["error"].as_str().unwrap().contains("missing"));
 }
 
 #[tokio::test]
@@ -43,10 +59,12 @@ async fn test_extract_user_invalid_uuid() {
     let (mut parts, _) = req.into_parts();
     let result = ExtractUser::from_request_parts(&mut parts, &()).await;
 
-    assert!(result.is_err());
-    let (status, message) = result.unwrap_err();
+    let rejection = result.unwrap_err();
+    assert_eq!(rejection, UserRejection::Invalid);
+    let (status, body) = rejection_response(rejection).await;
     assert_eq!(status, StatusCode::BAD_REQUEST);
-    assert!(message.contains("invalid"));
+    assert_eq!(body["code"], "invalid_user_id");
+    assert!(body["error"].as_str().unwrap().contains("not a valid"));
 }
 
 #[tokio::test]
@@ -60,6 +78,79 @@ async fn test_extract_user_invalid_utf8() {
     let (mut parts, _) = req.into_parts();
     let result = ExtractUser::from_request_parts(&mut parts, &()).await;
 
-    assert!(result.is_err());
-    assert_eq!(result.unwrap_err().0, StatusCode::BAD_REQUEST);
+    let (status, body) = rejection_response(result.unwrap_err()).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "invalid_user_id");
+}
+
+fn set_single_user_mode(enabled: bool) {
+    unsafe {
+        if enabled {
+            std::env::set_var("SINGLE_USER_MODE", "true");
+        } else {
+            std::env::remove_var("SINGLE_USER_MODE");
+        }
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn test_single_user_mode_defaults_missing_header() {
+    set_single_user_mode(true);
+    let (mut parts, _) = Request::builder().body(()).unwrap().into_parts();
+    let result = ExtractUser::from_request_parts(&mut parts, &()).await;
+    set_single_user_mode(false);
+
+    assert_eq!(result.unwrap().0, DEFAULT_USER_ID);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_single_user_mode_honors_header() {
+    let user_id = Uuid::new_v4();
+    set_single_user_mode(true);
+    let (mut parts, _) = Request::builder()
+        .header("X-User-ID", user_id.to_string())
+        .body(())
+        .unwrap()
+        .into_parts();
+    let result = ExtractUser::from_request_parts(&mut parts, &()).await;
+    set_single_user_mode(false);
+
+    assert_eq!(result.unwrap().0, user_id);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_missing_header_is_rejected_without_single_user_mode() {
+    set_single_user_mode(false);
+    let (mut parts, _) = Request::builder().body(()).unwrap().into_parts();
+    let result = ExtractUser::from_request_parts(&mut parts, &()).await;
+
+    let (status, _) = rejection_response(result.unwrap_err()).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[cfg(debug_assertions)]
+#[tokio::test]
+async fn test_bypass_extension_sets_the_user() {
+    use tokio_local_llm_api::api::TestUser;
+
+    let test_user = Uuid::new_v4();
+    for header in [
+        None,
+        Some(Uuid::new_v4().to_string()),
+        Some("invalid".to_owned()),
+    ] {
+        let mut req = Request::builder();
+        if let Some(header) = &header {
+            req = req.header("X-User-ID", header);
+        }
+        let req = req.extension(TestUser(test_user)).body(()).unwrap();
+
+        let (mut parts, _) = req.into_parts();
+        let result = ExtractUser::from_request_parts(&mut parts, &()).await;
+
+        assert_eq!(result.unwrap().0, test_user, "header {header:?}");
+    }
 }
@@ -0,0 +1,183 @@
+//! Shared helpers for the integration tests.
+
+#![allow(dead_code)]
+
+use axum::Router;
+use chrono::Utc;
+use di::{Injectable, ServiceCollection};
+use di_axum::RouterServiceProviderExtensions;
+use sqlx::SqlitePool;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio_local_llm_api::core::inference_events::CompletionReason;
+use tokio_local_llm_api::core::message_cache::MessageCache;
+use tokio_local_llm_api::core::personas::Personas;
+use tokio_local_llm_api::core::sampling::SamplingParams;
+use tokio_local_llm_api::core::services::MyConversationService;
+use tokio_local_llm_api::core::task_queue;
+use tokio_local_llm_api::infrastructure::database::DatabaseConnection;
+use tokio_local_llm_api::infrastructure::repositories::DbConversationRepository;
+use tokio_local_llm_api::infrastructure::user_context::UserContext;
+use tokio_local_llm_api::{MODEL_FINGERPRINT, MODEL_LOADED, TASK_SENDER, api};
+use uuid::Uuid;
+
+/// The response the fake worker streams back for every task, one entry per message part.
+pub const CANNED_RESPONSE: [&str; 3] = ["Hello", ", ", "world!"];
+
+/// The prompt length the fake worker reports for every task.
+pub const FAKE_PROMPT_TOKENS: usize = 42;
+
+/// The model fingerprint the fake worker sets when it starts.
+pub const FAKE_MODEL_FINGERPRINT: &str = "fake-model.gguf:0123456789abcdef";
+
+/// Chat template the fake worker renders prompts with, one `role: content` line per message.
+const FAKE_CHAT_TEMPLATE: &str =
+    "{% for m in messages %}{{ m.role }}: {{ m.content }}\n{% endfor %}";
+
+/// The prompt the fake worker rendered for the last task.
+static LAST_PROMPT: Mutex<Option<String>> = Mutex::new(None);
+
+/// The prompt the fake worker rendered for the last task, see [`FAKE_CHAT_TEMPLATE`].
+pub fn last_prompt() -> Option<String> {
+    LAST_PROMPT.lock().unwrap().clone()
+}
+
+/// The sampling parameters of the last task.
+static LAST_SAMPLING: Mutex<Option<SamplingParams>> = Mutex::new(None);
+
+/// The sampling parameters of the last task the fake worker got.
+pub fn last_sampling() -> Option<SamplingParams> {
+    LAST_SAMPLING.lock().unwrap().clone()
+}
+
+/// Sets `TASK_SENDER` to a fake inference worker that answers every task with
+/// [`CANNED_RESPONSE`], one token per part, after reporting a prompt of [`FAKE_PROMPT_TOKENS`].
+/// It publishes the task's inference events like the real worker. The prompt it renders is kept
+/// for [`last_prompt`], and the task's sampling parameters for [`last_sampling`].
+/// Like the real worker loading a model, it also sets `MODEL_FINGERPRINT` and `MODEL_LOADED`.
+///
+/// Safe to call from every test: only the first call installs the worker. The worker runs on its
+/// own thread because each `#[tokio::test]` has a runtime that is torn down when the test ends,
+/// while `TASK_SENDER` lives for the whole test binary.
+pub fn init_test_task_sender() {
+    let (sender, mut receiver) = task_queue::channel(10);
+    if TASK_SENDER.set(sender).is_err() {
+        return;
+    }
+    let _ = MODEL_FINGERPRINT.set(FAKE_MODEL_FINGERPRINT.to_owned());
+    MODEL_LOADED.store(true, std::sync::atomic::Ordering::Release);
+
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async move {
+            let env = minijinja::Environment::new();
+            while let Some(mut task) = receiver.recv().await {
+                let prompt = env
+                    .render_str(FAKE_CHAT_TEMPLATE, task.as_jinja_input())
+                    .unwrap();
+                *LAST_PROMPT.lock().unwrap() = Some(prompt);
+                *LAST_SAMPLING.lock().unwrap() = Some(task.sampling().clone());
+                task.started(FAKE_PROMPT_TOKENS);
+                let mut reason = CompletionReason::Stop;
+                for part in CANNED_RESPONSE {
+                    if task.return_channel().send(part.to_owned()).await.is_err() {
+                        reason = CompletionReason::Cancelled;
+                        break;
+                    }
+                    task.generated_token();
+                }
+                task.completed(reason, Duration::ZERO, Duration::ZERO);
+            }
+        });
+    });
+}
+
+/// Splits an SSE body into `(event, data)` pairs.
+pub fn parse_sse_events(body: &str) -> Vec<(String, String)> {
+    body.split("\n\n")
+        .filter_map(|event| {
+            let mut name = None;
+            let mut data = None;
+            for line in event.lines() {
+                if let Some(value) = line.strip_prefix("event:") {
+                    name = Some(value.trim().to_owned());
+                } else if let Some(value) = line.strip_prefix("data:") {
+                    data = Some(value.trim().to_owned());
+                }
+            }
+            Some((name?, data.unwrap_or_default()))
+        })
+        .collect()
+}
+
+/// The conversations API at `/conversations`, with the services it resolves.
+pub fn create_test_app() -> Router {
+    create_test_app_with(|_| {}, |app| app)
+}
+
+/// Like [`create_test_app`], with the test's own services and routes.
+///
+/// `services` registers its services before the defaults, so a service it registers replaces
+/// the default one, e.g. a repository that fails on purpose. `routes` gets the app with the
+/// conversations API, to merge other routers into and layer middleware on.
+pub fn create_test_app_with(
+    services: impl FnOnce(&mut ServiceCollection),
+    routes: impl FnOnce(Router) -> Router,
+) -> Router {
+    let mut collection = ServiceCollection::new();
+    services(&mut collection);
+    let provider = collection
+        .try_add(DatabaseConnection::transient())
+        .try_add(UserContext::scoped())
+        .try_add(Personas::transient())
+        .try_add(MessageCache::transient())
+        .try_add(DbConversationRepository::scoped())
+        .try_add(MyConversationService::scoped())
+        .build_provider()
+        .unwrap();
+
+    routes(Router::new().nest("/conversations", api::conversations::router()))
+        .with_provider(provider)
+}
+
+/// Inserts a conversation owned by `user_id` with a system prompt and `turns` questions and
+/// answers, a minute apart. Returns the conversation's id.
+pub async fn insert_conversation_with_turns(
+    pool: &SqlitePool,
+    user_id: Uuid,
+    turns: usize,
+) -> Uuid {
+    let conversation_id = Uuid::new_v4();
+    sqlx::query("INSERT INTO conversations (id, user, created_at) VALUES (?, ?, ?)")
+        .bind(conversation_id)
+        .bind(user_id)
+        .bind(Utc::now().to_rfc3339())
+        .execute(pool)
+        .await
+        .unwrap();
+
+    let started = Utc::now() - chrono::Duration::hours(1);
+    let mut messages = vec![(1, "You are helpful".to_owned())];
+    for turn in 1..=turns {
+        messages.push((3, format!("Question {turn}")));
+        messages.push((2, format!("Answer {turn}")));
+    }
+    for (minute, (kind, text)) in messages.into_iter().enumerate() {
+        sqlx::query(
+            "INSERT INTO messages (id, conversation_id, kind, created_at, text) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(Uuid::new_v4())
+        .bind(conversation_id)
+        .bind(kind)
+        .bind(started + chrono::Duration::minutes(minute as i64))
+        .bind(text)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+    conversation_id
+}
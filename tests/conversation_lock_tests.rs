@@ -0,0 +1,169 @@
+//! Tests of generations posted to the same conversation at once
+//!
+//! Runs against a mock inference engine that takes a while to answer, and answers with the
+//! number of messages it was given, so a reply shows which history it saw.
+
+mod common;
+
+use axum::{
+    Router,
+    body::Body,
+    http::{Request, StatusCode},
+    response::Response,
+};
+use di::Injectable;
+use serde_json::Value;
+use serial_test::serial;
+use std::time::Duration;
+use tokio_local_llm_api::core::task_queue;
+use tokio_local_llm_api::{TASK_SENDER, api, core::message_cache::MessageCache, test_util::TestDb};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+/// Starts a mock engine that answers every task with the number of its messages, slowly.
+fn init_slow_engine() {
+    let (sender, mut receiver) = task_queue::channel(10);
+    if TASK_SENDER.set(sender).is_err() {
+        return;
+    }
+
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async move {
+            while let Some(task) = receiver.recv().await {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let reply = task.messages().len().to_string();
+                let _ = task.return_channel().send(reply).await;
+            }
+        });
+    });
+}
+
+fn create_test_app() -> Router {
+    common::create_test_app_with(
+        |services| {
+            services.add(MessageCache::singleton());
+        },
+        |app| app.layer(axum::middleware::from_fn(api::scope_user_context)),
+    )
+}
+
+async fn post(app: &Router, user_id: Uuid, uri: &str, text: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("X-User-ID", user_id.to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "message": text, "text": text }).to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+async fn read_body(response: Response) -> String {
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+/// Starts a conversation and returns its id once the first reply is saved.
+async fn start_conversation(app: &Router, user_id: Uuid) -> Uuid {
+    let body = read_body(post(app, user_id, "/conversations", "Hi!").await).await;
+    let data = body
+        .lines()
+        .find_map(|line| line.strip_prefix("data: "))
+        .unwrap();
+    let message: Value = serde_json::from_str(data).unwrap();
+    message["conversation_id"]
+        .as_str()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+async fn message_texts(app: &Router, user_id: Uuid, conversation_id: Uuid) -> Vec<String> {
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri(format!("/conversations/{conversation_id}/messages"))
+                .header("X-User-ID", user_id.to_string())
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let json: Value = serde_json::from_str(&read_body(response).await).unwrap();
+    json["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["text"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+#[tokio::test]
+#[serial]
+async fn test_concurrent_posts_are_serialized() {
+    let _db = TestDb::new().await;
+    init_slow_engine();
+    unsafe { std::env::remove_var("BUSY_CONVERSATION_POLICY") };
+    let app = create_test_app();
+    let user_id = Uuid::new_v4();
+    let conversation_id = start_conversation(&app, user_id).await;
+    let uri = format!("/conversations/{conversation_id}/messages");
+
+    let (first, second) = tokio::join!(
+        async { read_body(post(&app, user_id, &uri, "First").await).await },
+        async {
+            // Posted while the first reply is generated
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            read_body(post(&app, user_id, &uri, "Second").await).await
+        },
+    );
+    assert!(first.contains("event: done"), "{first}");
+    assert!(second.contains("event: done"), "{second}");
+
+    // The second reply saw the first one: system, Hi!, reply, First, reply, Second
+    let texts = message_texts(&app, user_id, conversation_id).await;
+    assert_eq!(texts[1..], ["Hi!", "2", "First", "4", "Second", "6"]);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_concurrent_post_is_rejected_with_reject_policy() {
+    let _db = TestDb::new().await;
+    init_slow_engine();
+    unsafe { std::env::set_var("BUSY_CONVERSATION_POLICY", "reject") };
+    let app = create_test_app();
+    let user_id = Uuid::new_v4();
+    let conversation_id = start_conversation(&app, user_id).await;
+    let uri = format!("/conversations/{conversation_id}/messages");
+
+    let first = post(&app, user_id, &uri, "First").await;
+    assert_eq!(first.status(), StatusCode::OK);
+
+    let second = post(&app, user_id, &uri, "Second").await;
+    assert_eq!(second.status(), StatusCode::CONFLICT);
+    let body: Value = serde_json::from_str(&read_body(second).await).unwrap();
+    assert_eq!(body["code"], "conversation_busy");
+
+    // Once the first reply is saved, the conversation takes messages again
+    read_body(first).await;
+    let third = post(&app, user_id, &uri, "Third").await;
+    unsafe { std::env::remove_var("BUSY_CONVERSATION_POLICY") };
+    assert_eq!(third.status(), StatusCode::OK);
+    read_body(third).await;
+
+    let texts = message_texts(&app, user_id, conversation_id).await;
+    assert_eq!(texts[1..], ["Hi!", "2", "First", "4", "Third", "6"]);
+}
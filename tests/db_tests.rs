@@ -3,20 +3,17 @@
 //! Tests SQLite migrations, entity storage, and schema constraints
 
 use chrono::Utc;
+use serial_test::serial;
 use sqlx::SqlitePool;
+use tokio_local_llm_api::test_util::TestDb;
 use uuid::Uuid;
 
-/// Setup test database with migrations
-async fn setup_test_db() -> SqlitePool {
-    let pool = SqlitePool::connect(":memory:").await.unwrap();
-    sqlx::migrate!().run(&pool).await.unwrap();
-    pool
-}
-
 #[tokio::test]
+#[serial]
 async fn test_database_migrations_work() {
     // This test verifies migrations apply successfully
-    let pool = setup_test_db().await;
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
 
     // Verify tables exist
     let result = sqlx::query("SELECT name FROM sqlite_master WHERE type='table'")
@@ -28,46 +25,50 @@ async fn test_database_migrations_work() {
 }
 
 #[tokio::test]
+#[serial]
 async fn test_uuid_storage_in_sqlite() {
-    let pool = setup_test_db().await;
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
 
     let user_id = Uuid::new_v4();
     let conversation_id = Uuid::new_v4();
 
-    // Insert as TEXT
+    // Bind the `Uuid` type like the repositories do
     sqlx::query("INSERT INTO conversations (id, user, created_at) VALUES (?, ?, ?)")
-        .bind(conversation_id.to_string())
-        .bind(user_id.to_string())
+        .bind(conversation_id)
+        .bind(user_id)
         .bind(Utc::now().to_rfc3339())
         .execute(&pool)
         .await
         .unwrap();
 
-    // Retrieve and parse back
-    let row: (String, String, String) =
+    // Retrieve and decode back
+    let row: (Uuid, Uuid, String) =
         sqlx::query_as("SELECT id, user, created_at FROM conversations WHERE id = ?")
-            .bind(conversation_id.to_string())
+            .bind(conversation_id)
             .fetch_one(&pool)
             .await
             .unwrap();
 
-    assert_eq!(Uuid::parse_str(&row.0).unwrap(), conversation_id);
-    assert_eq!(Uuid::parse_str(&row.1).unwrap(), user_id);
+    assert_eq!(row.0, conversation_id);
+    assert_eq!(row.1, user_id);
 }
 
 #[tokio::test]
+#[serial]
 async fn test_message_kind_enum_storage() {
     use tokio_local_llm_api::infrastructure::entities::MessageKind;
 
-    let pool = setup_test_db().await;
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
 
     let conversation_id = Uuid::new_v4();
     let user_id = Uuid::new_v4();
 
     // Create conversation first
     sqlx::query("INSERT INTO conversations (id, user, created_at) VALUES (?, ?, ?)")
-        .bind(conversation_id.to_string())
-        .bind(user_id.to_string())
+        .bind(conversation_id)
+        .bind(user_id)
         .bind(Utc::now().to_rfc3339())
         .execute(&pool)
         .await
@@ -81,8 +82,8 @@ async fn test_message_kind_enum_storage() {
     ] {
         let msg_id = Uuid::new_v4();
         sqlx::query("INSERT INTO messages (id, conversation_id, kind, created_at, text) VALUES (?, ?, ?, ?, ?)")
-            .bind(msg_id.to_string())
-            .bind(conversation_id.to_string())
+            .bind(msg_id)
+            .bind(conversation_id)
             .bind(value)
             .bind(Utc::now().to_rfc3339())
             .bind(format!("Test {:?}", kind))
@@ -93,7 +94,7 @@ async fn test_message_kind_enum_storage() {
 
     // Verify all were stored
     let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM messages WHERE conversation_id = ?")
-        .bind(conversation_id.to_string())
+        .bind(conversation_id)
         .fetch_one(&pool)
         .await
         .unwrap();
@@ -102,16 +103,18 @@ async fn test_message_kind_enum_storage() {
 }
 
 #[tokio::test]
+#[serial]
 async fn test_conversation_cascade_delete() {
-    let pool = setup_test_db().await;
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
 
     let user_id = Uuid::new_v4();
     let conversation_id = Uuid::new_v4();
 
     // Create conversation
     sqlx::query("INSERT INTO conversations (id, user, created_at) VALUES (?, ?, ?)")
-        .bind(conversation_id.to_string())
-        .bind(user_id.to_string())
+        .bind(conversation_id)
+        .bind(user_id)
         .bind(Utc::now().to_rfc3339())
         .execute(&pool)
         .await
@@ -121,8 +124,8 @@ async fn test_conversation_cascade_delete() {
     sqlx::query(
         "INSERT INTO messages (id, conversation_id, kind, created_at, text) VALUES (?, ?, ?, ?, ?)",
     )
-    .bind(Uuid::new_v4().to_string())
-    .bind(conversation_id.to_string())
+    .bind(Uuid::new_v4())
+    .bind(conversation_id)
     .bind(3) // User message
     .bind(Utc::now().to_rfc3339())
     .bind("Test")
@@ -132,17 +135,243 @@ async fn test_conversation_cascade_delete() {
 
     // Delete conversation (should cascade to messages)
     sqlx::query("DELETE FROM conversations WHERE id = ?")
-        .bind(conversation_id.to_string())
+        .bind(conversation_id)
         .execute(&pool)
         .await
         .unwrap();
 
     // Verify messages were deleted
     let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM messages WHERE conversation_id = ?")
-        .bind(conversation_id.to_string())
+        .bind(conversation_id)
         .fetch_one(&pool)
         .await
         .unwrap();
 
     assert_eq!(count.0, 0);
 }
+
+#[tokio::test]
+async fn test_fresh_database_file_is_created_and_migrated() {
+    use tokio_local_llm_api::infrastructure::database::DatabaseConnection;
+
+    let directory = std::env::temp_dir().join(format!("db-test-{}", Uuid::new_v4()));
+    let path = directory.join("nested").join("app.db");
+    assert!(!path.exists());
+
+    let connection = DatabaseConnection::open(&format!("sqlite:{}", path.display())).unwrap();
+    connection.migrate().await.unwrap();
+    assert!(path.exists());
+
+    let tables: Vec<(String,)> =
+        sqlx::query_as("SELECT name FROM sqlite_master WHERE type='table' ORDER BY name")
+            .fetch_all(&*connection)
+            .await
+            .unwrap();
+    let tables: Vec<&str> = tables.iter().map(|(name,)| name.as_str()).collect();
+    assert!(tables.contains(&"conversations"));
+    assert!(tables.contains(&"messages"));
+
+    connection.close().await;
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+#[serial]
+async fn test_startup_migrates_an_unmigrated_database() {
+    use di::{Injectable, ServiceCollection};
+    use tokio_local_llm_api::infrastructure::database::DatabaseConnection;
+
+    let pool = SqlitePool::connect("sqlite:file:startupdb?mode=memory&cache=shared")
+        .await
+        .unwrap();
+    DatabaseConnection::set_test_pool(pool.clone());
+
+    // Resolve the connection the way the server does at startup
+    let provider = ServiceCollection::new()
+        .add(DatabaseConnection::singleton())
+        .build_provider()
+        .unwrap();
+    let database = provider.get_required::<DatabaseConnection>();
+
+    let applied = database.migrate().await.unwrap();
+    let migrations = sqlx::migrate!()
+        .iter()
+        .filter(|migration| migration.migration_type.is_up_migration())
+        .count();
+    assert_eq!(applied, migrations);
+
+    let (count,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name IN ('conversations', 'messages')",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(count, 2);
+
+    // A restart has nothing left to apply
+    assert_eq!(database.migrate().await.unwrap(), 0);
+
+    DatabaseConnection::clear_test_pool();
+}
+
+#[tokio::test]
+async fn test_database_missing_the_latest_migration_is_detected() {
+    use tokio_local_llm_api::infrastructure::database::DatabaseConnection;
+
+    let database = DatabaseConnection::from_pool(SqlitePool::connect(":memory:").await.unwrap());
+    database.migrate().await.unwrap();
+    assert!(database.pending_migrations().await.unwrap().is_empty());
+
+    // Roll the latest migration back, as if the database was last used by an older release
+    let migrator = sqlx::migrate!();
+    let latest = migrator
+        .iter()
+        .rfind(|migration| migration.migration_type.is_up_migration())
+        .unwrap();
+    let down = migrator
+        .iter()
+        .find(|migration| {
+            migration.version == latest.version && migration.migration_type.is_down_migration()
+        })
+        .unwrap();
+    sqlx::raw_sql(&down.sql).execute(&*database).await.unwrap();
+    sqlx::query("DELETE FROM _sqlx_migrations WHERE version = ?")
+        .bind(latest.version)
+        .execute(&*database)
+        .await
+        .unwrap();
+
+    let pending = database.pending_migrations().await.unwrap();
+    let pending: Vec<i64> = pending.iter().map(|migration| migration.version).collect();
+    assert_eq!(pending, vec![latest.version]);
+
+    // Starting with auto-migration applies just that one
+    assert_eq!(database.migrate().await.unwrap(), 1);
+    assert!(database.pending_migrations().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+#[serial]
+async fn test_messages_with_equal_timestamps_keep_creation_order() {
+    use di::Ref;
+    use tokio_local_llm_api::infrastructure::database::DatabaseConnection;
+    use tokio_local_llm_api::infrastructure::entities::{Conversation, MessageKind, MessageOrder};
+    use tokio_local_llm_api::infrastructure::repositories::DbConversationRepository;
+    use tokio_local_llm_api::infrastructure::traits::ConversationRepository;
+    use tokio_local_llm_api::infrastructure::user_context::UserContext;
+
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    let repository = DbConversationRepository::new(
+        Ref::new(DatabaseConnection::from_pool(pool)),
+        Ref::new(UserContext::new(None)),
+    );
+
+    let user_id = Uuid::new_v4();
+    let created_at = Utc::now();
+    let conversation = repository
+        .create_conversation(
+            Conversation {
+                id: Uuid::new_v4(),
+                user: user_id,
+                created_at,
+                model_fingerprint: None,
+                updated_at: None,
+            },
+            None,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+    // Ids in descending order, so sorting by id would reverse them
+    let kinds = [
+        MessageKind::System,
+        MessageKind::User,
+        MessageKind::Bot,
+        MessageKind::User,
+        MessageKind::Bot,
+    ];
+    let mut ids = Vec::new();
+    for (index, kind) in kinds.into_iter().enumerate() {
+        let message = repository
+            .create_message_in_conversation(
+                user_id,
+                conversation.id,
+                tokio_local_llm_api::infrastructure::entities::Message {
+                    id: Uuid::from_u128(u128::MAX - index as u128),
+                    conversation_id: conversation.id,
+                    kind,
+                    created_at,
+                    text: format!("message {index}"),
+                    token_count: 0,
+                    attachments: sqlx::types::Json(Vec::new()),
+                    incomplete: false,
+                },
+            )
+            .await
+            .unwrap();
+        ids.push(message.id);
+    }
+
+    for _ in 0..3 {
+        let listed: Vec<Uuid> = repository
+            .list_conversation_messages(user_id, conversation.id, MessageOrder::OldestFirst)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|message| message.id)
+            .collect();
+        assert_eq!(listed, ids);
+    }
+
+    let newest_first: Vec<Uuid> = repository
+        .list_conversation_messages(user_id, conversation.id, MessageOrder::NewestFirst)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|message| message.id)
+        .collect();
+    assert_eq!(newest_first, ids.iter().rev().copied().collect::<Vec<_>>());
+}
+
+#[tokio::test]
+#[serial]
+async fn test_concurrent_conversations_stay_within_the_limit() {
+    use di::Ref;
+    use tokio_local_llm_api::infrastructure::database::DatabaseConnection;
+    use tokio_local_llm_api::infrastructure::entities::Conversation;
+    use tokio_local_llm_api::infrastructure::repositories::DbConversationRepository;
+    use tokio_local_llm_api::infrastructure::traits::ConversationRepository;
+    use tokio_local_llm_api::infrastructure::user_context::UserContext;
+
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    let repository = DbConversationRepository::new(
+        Ref::new(DatabaseConnection::from_pool(pool)),
+        Ref::new(UserContext::new(None)),
+    );
+
+    let user_id = Uuid::new_v4();
+    let create = |user| {
+        repository.create_conversation(
+            Conversation {
+                id: Uuid::new_v4(),
+                user,
+                created_at: Utc::now(),
+                model_fingerprint: None,
+                updated_at: None,
+            },
+            Some(2),
+        )
+    };
+    let created = futures_util::future::join_all((0..5).map(|_| create(user_id))).await;
+    let created = created
+        .into_iter()
+        .filter(|created| matches!(created, Ok(Some(_))))
+        .count();
+    assert_eq!(created, 2);
+
+    // The limit is per user
+    assert!(create(Uuid::new_v4()).await.unwrap().is_some());
+}
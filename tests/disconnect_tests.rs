@@ -0,0 +1,98 @@
+//! Client disconnect tests
+//!
+//! Runs a generation against a mock inference engine that keeps generating until its output is
+//! dropped, and disconnects the client halfway through the message.
+
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use common::create_test_app;
+use common::parse_sse_events;
+use futures_util::StreamExt;
+use serial_test::serial;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio_local_llm_api::core::task_queue;
+use tokio_local_llm_api::{TASK_SENDER, test_util::TestDb};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+/// Set once the engine has noticed that nobody reads its output anymore.
+static GENERATION_STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// Starts a mock engine that answers every task with an endless stream of parts.
+fn init_endless_engine() {
+    let (sender, mut receiver) = task_queue::channel(10);
+    TASK_SENDER.set(sender).expect("task sender already set");
+
+    tokio::spawn(async move {
+        while let Some(task) = receiver.recv().await {
+            while task.return_channel().send("part ".to_owned()).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            GENERATION_STOPPED.store(true, Ordering::SeqCst);
+        }
+    });
+}
+
+#[tokio::test]
+#[serial]
+async fn test_disconnect_saves_partial_message_as_incomplete() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    init_endless_engine();
+
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations")
+                .header("X-User-ID", Uuid::new_v4().to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"message": "Hi!"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Read a few parts, then go away
+    let mut body = response.into_body().into_data_stream();
+    let mut text = String::new();
+    while parse_sse_events(&text)
+        .iter()
+        .filter(|(event, _)| event == "message_part")
+        .count()
+        < 3
+    {
+        let chunk = body.next().await.expect("stream ended").unwrap();
+        text.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+    drop(body);
+
+    // The message is saved incomplete in one write, so it is never seen complete
+    let (text, incomplete) = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let row: Option<(String, bool)> =
+                sqlx::query_as("SELECT text, incomplete FROM messages WHERE kind = 2")
+                    .fetch_optional(&pool)
+                    .await
+                    .unwrap();
+            if let Some(row) = row {
+                return row;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("partial message was not saved");
+
+    assert!(incomplete);
+
+    assert!(text.starts_with("part part part "));
+    assert_eq!(text.replace("part ", ""), "");
+    assert!(GENERATION_STOPPED.load(Ordering::SeqCst));
+}
@@ -0,0 +1,127 @@
+//! Per-user generation cap tests
+//!
+//! Runs against a mock inference engine that holds on to every task until the test releases it,
+//! with `MAX_CONCURRENT_GENERATIONS_PER_USER` set to 1.
+
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    response::Response,
+};
+use common::create_test_app;
+use serde_json::{Value, json};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio_local_llm_api::core::assistant::model_id;
+use tokio_local_llm_api::core::inference_events::CompletionReason;
+use tokio_local_llm_api::core::task_queue;
+use tokio_local_llm_api::{TASK_SENDER, api, test_util::TestDb};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+/// A permit lets the engine finish one task.
+static RELEASE: Semaphore = Semaphore::const_new(0);
+
+/// Starts a mock engine that answers a task once it is released, and drops it.
+fn init_held_engine() {
+    let (sender, mut receiver) = task_queue::channel(10);
+    TASK_SENDER.set(sender).expect("task sender already set");
+
+    tokio::spawn(async move {
+        while let Some(mut task) = receiver.recv().await {
+            RELEASE.acquire().await.unwrap().forget();
+            let _ = task.return_channel().send("Hi".to_owned()).await;
+            task.generated_token();
+            task.completed(CompletionReason::Stop, Duration::ZERO, Duration::ZERO);
+        }
+    });
+}
+
+async fn new_conversation(user_id: Uuid) -> Response {
+    create_test_app()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations")
+                .header("X-User-ID", user_id.to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"message": "Hi!"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+async fn post_completion(user_id: Uuid, n: usize) -> (StatusCode, Value) {
+    let request = json!({
+        "model": model_id(),
+        "messages": [{"role": "user", "content": "Hi!"}],
+        "n": n,
+    });
+    let response = api::openai::router()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/chat/completions")
+                .header("X-User-ID", user_id.to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(request.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+#[tokio::test]
+async fn test_user_over_the_cap_is_rejected_while_others_are_not() {
+    let _db = TestDb::new().await;
+    init_held_engine();
+    // SAFETY: this is the only test in this binary
+    unsafe { std::env::set_var("MAX_CONCURRENT_GENERATIONS_PER_USER", "1") };
+
+    let busy_user = Uuid::new_v4();
+    let first = new_conversation(busy_user).await;
+    assert_eq!(first.status(), StatusCode::OK);
+
+    // The first generation is still queued or running
+    let response = new_conversation(busy_user).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], "too_many_generations");
+
+    // Completions count against the same cap, one generation per choice
+    let (status, body) = post_completion(busy_user, 1).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(body["error"]["code"], "rate_limit_exceeded");
+    let (status, _) = post_completion(Uuid::new_v4(), 2).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+    // Another user has generations of their own
+    let other = new_conversation(Uuid::new_v4()).await;
+    assert_eq!(other.status(), StatusCode::OK);
+
+    // Once the first generation is done, the user can start another one
+    RELEASE.add_permits(1);
+    axum::body::to_bytes(first.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    RELEASE.add_permits(2);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while new_conversation(busy_user).await.status() != StatusCode::OK {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the finished generation still counts against the cap");
+}
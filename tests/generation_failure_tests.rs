@@ -0,0 +1,169 @@
+//! Tests of a generation the inference worker fails halfway through
+//!
+//! Runs against a mock inference engine that streams one part of every message and then fails the
+//! task, like the worker does once a GPU error persists through its retries. A summary the engine
+//! fails doesn't compact the conversation, nor make room for a message over the limit.
+
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use common::create_test_app;
+use common::{insert_conversation_with_turns, parse_sse_events};
+use serial_test::serial;
+use std::time::Duration;
+use tokio_local_llm_api::core::task_queue;
+use tokio_local_llm_api::{TASK_SENDER, test_util::TestDb};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+/// Starts a mock engine that fails every task after its first part. Only the first call starts
+/// it, on its own thread so it outlives the runtime of the test that started it.
+fn init_failing_engine() {
+    let (sender, mut receiver) = task_queue::channel(10);
+    if TASK_SENDER.set(sender).is_err() {
+        return;
+    }
+
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async move {
+            while let Some(mut task) = receiver.recv().await {
+                let _ = task.return_channel().send("Hello".to_owned()).await;
+                task.failed("forward pass of position 3 failed 3 times".to_owned());
+            }
+        });
+    });
+}
+
+#[tokio::test]
+#[serial]
+async fn test_failed_generation_is_saved_as_incomplete() {
+    let db = TestDb::new().await;
+    init_failing_engine();
+
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations")
+                .header("X-User-ID", Uuid::new_v4().to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"message": "Hi!"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = tokio::time::timeout(
+        Duration::from_secs(5),
+        axum::body::to_bytes(response.into_body(), usize::MAX),
+    )
+    .await
+    .expect("stream did not end")
+    .unwrap();
+    let events = parse_sse_events(std::str::from_utf8(&body).unwrap());
+
+    // The stream ends without `done`, like one the client was cut off from
+    let names: Vec<&str> = events.iter().map(|(event, _)| event.as_str()).collect();
+    assert_eq!(names, ["new_message", "message_part"]);
+
+    // The stream ends before the message is saved
+    let text = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let row: Option<String> =
+                sqlx::query_scalar("SELECT text FROM messages WHERE kind = 2 AND incomplete")
+                    .fetch_optional(db.pool())
+                    .await
+                    .unwrap();
+            if let Some(row) = row {
+                return row;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("partial message was not saved as incomplete");
+    assert_eq!(text, "Hello");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_failed_summary_keeps_the_turns() {
+    let db = TestDb::new().await;
+    init_failing_engine();
+
+    let user_id = Uuid::new_v4();
+    let conversation_id = insert_conversation_with_turns(db.pool(), user_id, 2).await;
+    let before = message_texts(&db, conversation_id).await;
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri(format!("/conversations/{conversation_id}/compact"))
+                .header("X-User-ID", user_id.to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"keep_turns": 1}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    assert_eq!(message_texts(&db, conversation_id).await, before);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_message_over_the_limit_is_rejected_when_compaction_fails() {
+    let db = TestDb::new().await;
+    init_failing_engine();
+    unsafe {
+        std::env::set_var("MAX_MESSAGES_PER_CONVERSATION", "13");
+        std::env::set_var("MESSAGE_LIMIT_POLICY", "compact");
+    }
+
+    let user_id = Uuid::new_v4();
+    let conversation_id = insert_conversation_with_turns(db.pool(), user_id, 6).await;
+    let before = message_texts(&db, conversation_id).await;
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri(format!("/conversations/{conversation_id}/messages"))
+                .header("X-User-ID", user_id.to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"text": "One more question"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    unsafe {
+        std::env::remove_var("MAX_MESSAGES_PER_CONVERSATION");
+        std::env::remove_var("MESSAGE_LIMIT_POLICY");
+    }
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["code"], "message_limit_reached");
+    assert_eq!(message_texts(&db, conversation_id).await, before);
+}
+
+/// The texts of the conversation's messages, oldest first.
+async fn message_texts(db: &TestDb, conversation_id: Uuid) -> Vec<String> {
+    sqlx::query_scalar("SELECT text FROM messages WHERE conversation_id = ? ORDER BY created_at")
+        .bind(conversation_id)
+        .fetch_all(db.pool())
+        .await
+        .unwrap()
+}
@@ -0,0 +1,283 @@
+//! Tests of the gRPC interface, with an in-process client
+//!
+//! The server answers from the fake inference worker of `common::init_test_task_sender`. The
+//! messages written out in `api::grpc::proto` are checked against `proto/generation.proto`.
+
+mod common;
+
+use common::{CANNED_RESPONSE, FAKE_PROMPT_TOKENS, init_test_task_sender};
+use futures_util::StreamExt;
+use prost::Message as _;
+use std::collections::{BTreeMap, HashSet};
+use tokio::net::TcpListener;
+use tokio_local_llm_api::api::grpc::proto::generate_response::Event;
+use tokio_local_llm_api::api::grpc::proto::{
+    Finished, GenerateRequest, GenerateResponse, Message, Role, Sampling,
+};
+use tokio_local_llm_api::api::grpc::{self, GenerationClient};
+use tonic::{Code, Request};
+use uuid::Uuid;
+
+/// Starts the gRPC server on a free port, and connects a client to it.
+async fn connect() -> GenerationClient {
+    init_test_task_sender();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(grpc::serve(listener));
+    GenerationClient::connect(address).await.unwrap()
+}
+
+fn hello_request(request_id: &str) -> GenerateRequest {
+    GenerateRequest {
+        request_id: request_id.to_owned(),
+        messages: vec![Message {
+            role: Role::User as i32,
+            content: "Hello!".to_owned(),
+        }],
+        sampling: None,
+        max_tokens: None,
+    }
+}
+
+/// `requests` as a call of `user_id`.
+fn call_as(
+    user_id: Uuid,
+    requests: Vec<GenerateRequest>,
+) -> Request<impl futures_util::Stream<Item = GenerateRequest>> {
+    let mut request = Request::new(futures_util::stream::iter(requests));
+    request
+        .metadata_mut()
+        .insert("x-user-id", user_id.to_string().parse().unwrap());
+    request
+}
+
+#[tokio::test]
+async fn test_generate_streams_chunks_then_finished() {
+    let mut client = connect().await;
+
+    let responses: Vec<GenerateResponse> = client
+        .generate(call_as(Uuid::new_v4(), vec![hello_request("first")]))
+        .await
+        .unwrap()
+        .into_inner()
+        .map(Result::unwrap)
+        .collect()
+        .await;
+
+    let (last, chunks) = responses.split_last().unwrap();
+    let chunks: Vec<&str> = chunks
+        .iter()
+        .map(|response| {
+            assert_eq!(response.request_id, "first");
+            match &response.event {
+                Some(Event::Chunk(chunk)) => chunk.as_str(),
+                event => panic!("expected a chunk, got {event:?}"),
+            }
+        })
+        .collect();
+    assert_eq!(chunks, CANNED_RESPONSE);
+
+    assert_eq!(last.request_id, "first");
+    assert_eq!(
+        last.event,
+        Some(Event::Finished(Finished {
+            finish_reason: "stop".to_owned(),
+            prompt_tokens: FAKE_PROMPT_TOKENS as u32,
+            completion_tokens: CANNED_RESPONSE.len() as u32,
+        }))
+    );
+}
+
+#[tokio::test]
+async fn test_generate_answers_the_requests_of_a_call_in_order() {
+    let mut client = connect().await;
+
+    let requests = vec![hello_request("first"), hello_request("second")];
+    let responses: Vec<GenerateResponse> = client
+        .generate(call_as(Uuid::new_v4(), requests))
+        .await
+        .unwrap()
+        .into_inner()
+        .map(Result::unwrap)
+        .collect()
+        .await;
+
+    let answered: Vec<(&str, bool)> = responses
+        .iter()
+        .map(|response| {
+            let finished = matches!(response.event, Some(Event::Finished(_)));
+            (response.request_id.as_str(), finished)
+        })
+        .collect();
+    let mut expected = Vec::new();
+    for request_id in ["first", "second"] {
+        expected.extend(CANNED_RESPONSE.map(|_| (request_id, false)));
+        expected.push((request_id, true));
+    }
+    assert_eq!(answered, expected);
+}
+
+#[tokio::test]
+async fn test_generate_without_user_is_invalid_argument() {
+    let mut client = connect().await;
+
+    let requests = futures_util::stream::iter(vec![hello_request("first")]);
+    let status = match client.generate(requests).await {
+        Ok(response) => response.into_inner().next().await.unwrap().unwrap_err(),
+        Err(status) => status,
+    };
+    assert_eq!(status.code(), Code::InvalidArgument);
+    assert_eq!(status.message(), "`X-User-ID` header is missing");
+}
+
+#[tokio::test]
+async fn test_generate_with_an_unspecified_role_is_invalid_argument() {
+    let mut client = connect().await;
+
+    let mut request = hello_request("first");
+    request.messages[0].role = Role::Unspecified as i32;
+    let status = client
+        .generate(call_as(Uuid::new_v4(), vec![request]))
+        .await
+        .unwrap()
+        .into_inner()
+        .next()
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+/// A field of a message as `(message, field, tag, wire type)`.
+type Field = (String, String, u32, u8);
+
+/// The fields of the messages in `proto/generation.proto`, and the values of its enums as
+/// `(value, number)`.
+fn proto_file() -> (Vec<Field>, Vec<(String, i32)>) {
+    let proto = include_str!("../proto/generation.proto");
+    let enums: HashSet<&str> = proto
+        .lines()
+        .filter_map(|line| line.strip_prefix("enum ")?.strip_suffix(" {"))
+        .collect();
+
+    let mut fields = Vec::new();
+    let mut values = Vec::new();
+    let mut message = None;
+    let mut in_enum = false;
+    // `oneof`s open in the current message
+    let mut depth = 0;
+    for line in proto.lines().map(str::trim) {
+        if line.starts_with("//") {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix("message ") {
+            message = Some(name.trim_end_matches(" {"));
+        } else if line.starts_with("enum ") {
+            in_enum = true;
+        } else if line.starts_with("oneof ") {
+            depth += 1;
+        } else if line == "}" {
+            if depth > 0 {
+                depth -= 1;
+            } else {
+                message = None;
+                in_enum = false;
+            }
+        } else if let Some((declaration, number)) = line
+            .strip_suffix(';')
+            .and_then(|line| line.split_once(" = "))
+        {
+            if in_enum {
+                values.push((declaration.to_owned(), number.parse().unwrap()));
+            } else if let Some(message) = message {
+                let (kind, field) = declaration.rsplit_once(' ').unwrap();
+                let kind = kind.strip_prefix("optional ").unwrap_or(kind);
+                let wire_type = match kind {
+                    "float" => 5,
+                    "uint32" => 0,
+                    kind if enums.contains(kind) => 0,
+                    // Strings, messages, maps and repeated fields are length-delimited
+                    _ => 2,
+                };
+                let tag = number.parse().unwrap();
+                fields.push((message.to_owned(), field.to_owned(), tag, wire_type));
+            }
+        }
+    }
+    (fields, values)
+}
+
+#[test]
+fn test_messages_match_the_proto_file() {
+    // A message with only the field set encodes only that field
+    macro_rules! encoded {
+        ($message:ident, $field:ident, $value:expr) => {
+            (
+                stringify!($message),
+                stringify!($field),
+                $message {
+                    $field: $value,
+                    ..Default::default()
+                }
+                .encode_to_vec(),
+            )
+        };
+    }
+    let chunk = GenerateResponse {
+        event: Some(Event::Chunk("Hi".to_owned())),
+        ..Default::default()
+    };
+    let finished = GenerateResponse {
+        event: Some(Event::Finished(Finished::default())),
+        ..Default::default()
+    };
+    let encoded = [
+        encoded!(Message, role, Role::User as i32),
+        encoded!(Message, content, "Hi".to_owned()),
+        encoded!(Sampling, temperature, Some(0.5)),
+        encoded!(Sampling, top_p, Some(0.5)),
+        encoded!(Sampling, repetition_penalty, Some(1.1)),
+        encoded!(Sampling, stop, vec!["\n".to_owned()]),
+        encoded!(Sampling, logit_bias, BTreeMap::from([(1, 2.0)])),
+        encoded!(GenerateRequest, request_id, "a".to_owned()),
+        encoded!(GenerateRequest, messages, vec![Message::default()]),
+        encoded!(GenerateRequest, sampling, Some(Sampling::default())),
+        encoded!(GenerateRequest, max_tokens, Some(16)),
+        encoded!(GenerateResponse, request_id, "a".to_owned()),
+        // The fields of the `event` oneof
+        ("GenerateResponse", "chunk", chunk.encode_to_vec()),
+        ("GenerateResponse", "finished", finished.encode_to_vec()),
+        encoded!(Finished, finish_reason, "stop".to_owned()),
+        encoded!(Finished, prompt_tokens, 1),
+        encoded!(Finished, completion_tokens, 1),
+    ];
+    let mut written_out: Vec<Field> = encoded
+        .into_iter()
+        .map(|(message, field, bytes)| {
+            let key = prost::encoding::decode_varint(&mut bytes.as_slice()).unwrap();
+            (
+                message.to_owned(),
+                field.to_owned(),
+                (key >> 3) as u32,
+                (key & 7) as u8,
+            )
+        })
+        .collect();
+    written_out.sort();
+
+    let (mut fields, values) = proto_file();
+    fields.sort();
+    assert_eq!(written_out, fields);
+
+    let roles = [
+        ("ROLE_UNSPECIFIED", Role::Unspecified),
+        ("ROLE_USER", Role::User),
+        ("ROLE_ASSISTANT", Role::Assistant),
+        ("ROLE_SYSTEM", Role::System),
+    ];
+    let roles: Vec<(String, i32)> = roles
+        .into_iter()
+        .map(|(name, role)| (name.to_owned(), role as i32))
+        .collect();
+    assert_eq!(roles, values);
+}
@@ -0,0 +1,76 @@
+//! Readiness endpoint tests
+
+mod common;
+
+use axum::{
+    Router,
+    body::Body,
+    http::{Request, StatusCode},
+};
+use common::init_test_task_sender;
+use di::{Injectable, ServiceCollection};
+use di_axum::RouterServiceProviderExtensions;
+use serde_json::Value;
+use serial_test::serial;
+use sqlx::SqlitePool;
+use tokio_local_llm_api::api;
+use tokio_local_llm_api::infrastructure::database::DatabaseConnection;
+use tower::ServiceExt;
+
+fn create_test_app() -> Router {
+    let provider = ServiceCollection::new()
+        .add(DatabaseConnection::transient())
+        .build_provider()
+        .unwrap();
+
+    api::health::router().with_provider(provider)
+}
+
+async fn get_ready() -> (StatusCode, Value) {
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .uri("/ready")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_ready_with_model_and_database() {
+    init_test_task_sender();
+    let pool = SqlitePool::connect(":memory:").await.unwrap();
+    DatabaseConnection::set_test_pool(pool);
+
+    let (status, body) = get_ready().await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["model"], true);
+    assert_eq!(body["database"], true);
+    DatabaseConnection::clear_test_pool();
+}
+
+#[tokio::test]
+#[serial]
+async fn test_closed_pool_is_database_unready() {
+    init_test_task_sender();
+    let pool = SqlitePool::connect(":memory:").await.unwrap();
+    pool.close().await;
+    DatabaseConnection::set_test_pool(pool);
+
+    let (status, body) = get_ready().await;
+
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["model"], true);
+    assert_eq!(body["database"], false);
+    DatabaseConnection::clear_test_pool();
+}
@@ -0,0 +1,73 @@
+//! Inference event bus tests
+//!
+//! Follows the events a task publishes while the fake worker generates it.
+
+mod common;
+
+use common::{CANNED_RESPONSE, FAKE_PROMPT_TOKENS, init_test_task_sender};
+use std::time::Duration;
+use tokio_local_llm_api::TASK_SENDER;
+use tokio_local_llm_api::core::assistant::{ChatMessage, InferenceTask, Role};
+use tokio_local_llm_api::core::inference_events::{
+    CompletionReason, INFERENCE_EVENTS, InferenceEvent,
+};
+
+#[tokio::test]
+async fn test_generation_publishes_its_lifecycle() {
+    init_test_task_sender();
+    let mut events = INFERENCE_EVENTS.subscribe();
+
+    let (task, mut receiver) =
+        InferenceTask::new(vec![ChatMessage::new(Role::User, "Hi!".to_owned())]);
+    let task_id = task.id();
+    TASK_SENDER.get().unwrap().send(task).await.unwrap();
+    while receiver.recv().await.is_some() {}
+
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let event = events.recv().await.unwrap();
+            if event.task_id() != task_id {
+                continue;
+            }
+            let completed = matches!(event, InferenceEvent::Completed { .. });
+            received.push(event);
+            if completed {
+                return;
+            }
+        }
+    })
+    .await
+    .expect("the generation did not complete");
+
+    assert!(matches!(
+        received[0],
+        InferenceEvent::Queued { task_id: id, .. } if id == task_id
+    ));
+    assert_eq!(
+        received[1],
+        InferenceEvent::Started {
+            task_id,
+            prompt_tokens: FAKE_PROMPT_TOKENS
+        }
+    );
+    // The first token is published on its own
+    assert_eq!(
+        received[2],
+        InferenceEvent::TokenBatch { task_id, tokens: 1 }
+    );
+    assert_eq!(
+        received[3],
+        InferenceEvent::TokenBatch {
+            task_id,
+            tokens: CANNED_RESPONSE.len() - 1
+        }
+    );
+    let InferenceEvent::Completed { reason, stats, .. } = &received[4] else {
+        panic!("expected the generation to complete, got {:?}", received[4]);
+    };
+    assert_eq!(*reason, CompletionReason::Stop);
+    assert_eq!(stats.prompt_tokens, FAKE_PROMPT_TOKENS);
+    assert_eq!(stats.completion_tokens, CANNED_RESPONSE.len());
+    assert_eq!(received.len(), 5);
+}
@@ -20,6 +20,7 @@
 //! ```
 
 use std::path::Path;
+use tokio_local_llm_api::core::task_queue;
 use wgml::gguf::Gguf;
 use wgml::models::gpt2::Gpt2Tokenizer;
 use wgml::models::llama2::cpu::Llama2Config;
@@ -256,6 +257,27 @@ async fn test_gpu_instance_creation() {
     println!("Device features: {:?}", gpu.device().features());
 }
 
+#[tokio::test]
+#[ignore = "requires GPU"]
+async fn test_create_gpu_with_backend_fallback() {
+    use tokio_local_llm_api::core::gpu::{create_gpu, parse_backends};
+    use wgpu::Backends;
+
+    let gpu = create_gpu().await;
+    assert!(gpu.is_ok(), "Failed to create GPU: {:?}", gpu.err());
+
+    // The chosen backend must be one of the configured candidates
+    let backend = gpu.unwrap().backend();
+    let candidates = std::env::var("GPU_BACKENDS").unwrap_or("vulkan,metal,dx12,gl".to_owned());
+    assert!(
+        parse_backends(&candidates)
+            .into_iter()
+            .any(|b| b.contains(Backends::from(backend))),
+        "Unexpected backend {backend:?}"
+    );
+    println!("GPU device created on backend {backend:?}");
+}
+
 // =============================================================================
 // Full Model Loading Tests (Heavy - requires significant GPU memory)
 // =============================================================================
@@ -437,3 +459,99 @@ async fn test_single_forward_pass() {
         token, prompt, next_token
     );
 }
+
+// =============================================================================
+// Stop Token Test (Integration)
+// =============================================================================
+
+#[tokio::test]
+#[ignore = "requires model file and GPU - heavy integration test"]
+async fn test_generation_halts_on_stop_token_id() {
+    use tokio_local_llm_api::core::assistant::{ChatMessage, InferenceTask, Role, background_task};
+
+    require_model();
+    if !model_exists() {
+        return;
+    }
+
+    let model_path = get_model_path();
+    let file = std::fs::File::open(&model_path).expect("failed to open model file");
+    let mmap = unsafe { memmap2::Mmap::map(&file) }.expect("failed to mmap file");
+    let gguf = Gguf::from_bytes(&mmap[..]).expect("failed to parse GGUF");
+    let vocab_size = Llama2Config::from_gguf(&gguf).vocab_size as u32;
+
+    let (task_sender, task_receiver) = task_queue::channel(1);
+    tokio::spawn(background_task(task_receiver));
+
+    // Whichever token the sampler picks first is a stop token
+    let (mut task, mut receiver) =
+        InferenceTask::new(vec![ChatMessage::new(Role::User, "Hello".to_owned())]);
+    task.set_stop_token_ids(0..vocab_size);
+    task.set_max_tokens(16);
+    task_sender.send(task).await.unwrap();
+
+    let mut generated = String::new();
+    while let Some(part) = receiver.recv().await {
+        generated.push_str(&part);
+    }
+    assert_eq!(generated, "");
+}
+
+// =============================================================================
+// Prompt Cache Test (Integration)
+// =============================================================================
+
+#[tokio::test]
+#[ignore = "requires model file and GPU - heavy integration test"]
+async fn test_shared_system_prompt_lowers_time_to_first_token() {
+    use std::time::{Duration, Instant};
+    use tokio_local_llm_api::core::assistant::{ChatMessage, InferenceTask, Role, background_task};
+
+    require_model();
+    if !model_exists() {
+        return;
+    }
+
+    // SAFETY: set before the worker reads its configuration, no other thread reads it
+    unsafe { std::env::set_var("PROMPT_CACHE", "true") };
+    let (task_sender, task_receiver) = task_queue::channel(1);
+    tokio::spawn(background_task(task_receiver));
+
+    /// Time until the first part of the reply to `messages` arrives.
+    async fn time_to_first_token(
+        task_sender: &task_queue::TaskSender,
+        messages: Vec<ChatMessage>,
+    ) -> Duration {
+        let (mut task, mut receiver) = InferenceTask::new(messages);
+        task.set_max_tokens(1);
+        let started = Instant::now();
+        task_sender.send(task).await.unwrap();
+        receiver.recv().await.expect("no reply");
+        let elapsed = started.elapsed();
+        while receiver.recv().await.is_some() {}
+        elapsed
+    }
+
+    let system_prompt = "You are a meticulous assistant. ".repeat(200);
+    let conversation = |question: &str| {
+        vec![
+            ChatMessage::new(Role::System, system_prompt.clone()),
+            ChatMessage::new(Role::User, question.to_owned()),
+        ]
+    };
+
+    // Compiles the shaders, so the cold request doesn't pay for it
+    time_to_first_token(
+        &task_sender,
+        vec![ChatMessage::new(Role::User, "Hi".to_owned())],
+    )
+    .await;
+    let cold = time_to_first_token(&task_sender, conversation("What is Rust?")).await;
+    let warm = time_to_first_token(&task_sender, conversation("What is Tokio?")).await;
+    println!("Time to first token: cold {cold:?}, sharing the system prompt {warm:?}");
+
+    assert!(
+        warm < cold / 2,
+        "cold {cold:?}, sharing the system prompt {warm:?}"
+    );
+}
@@ -0,0 +1,123 @@
+//! Tests of the streaming latency metrics
+//!
+//! Runs against a mock inference engine that waits a set time before the first token of every
+//! message, and between the tokens after it.
+
+mod common;
+
+use axum::{
+    Router,
+    body::Body,
+    http::{Request, StatusCode},
+};
+use std::time::Duration;
+use tokio_local_llm_api::core::inference_events::CompletionReason;
+use tokio_local_llm_api::core::latency::{self, METRICS};
+use tokio_local_llm_api::core::task_queue;
+use tokio_local_llm_api::{TASK_SENDER, api, test_util::TestDb};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+/// How long the engine takes to the first part.
+const FIRST_PART_DELAY: Duration = Duration::from_millis(300);
+
+/// How long the engine takes between parts.
+const PART_DELAY: Duration = Duration::from_millis(20);
+
+/// Starts a mock engine that answers every task with three parts, after the delays.
+fn init_slow_engine() {
+    let (sender, mut receiver) = task_queue::channel(10);
+    TASK_SENDER.set(sender).expect("task sender already set");
+
+    tokio::spawn(async move {
+        while let Some(mut task) = receiver.recv().await {
+            tokio::time::sleep(FIRST_PART_DELAY).await;
+            for (i, part) in ["Hello", ", ", "world!"].into_iter().enumerate() {
+                if i > 0 {
+                    tokio::time::sleep(PART_DELAY).await;
+                }
+                let _ = task.return_channel().send(part.to_owned()).await;
+                task.generated_token();
+            }
+            task.completed(CompletionReason::Stop, Duration::ZERO, Duration::ZERO);
+        }
+    });
+}
+
+fn create_test_app() -> Router {
+    common::create_test_app_with(|_| {}, |app| app.merge(api::metrics::router()))
+}
+
+#[tokio::test]
+async fn test_time_to_first_token_reflects_the_engine_delay() {
+    let _db = TestDb::new().await;
+    init_slow_engine();
+    tokio::spawn(latency::record_events());
+
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations")
+                .header("X-User-ID", Uuid::new_v4().to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"message": "Hi!"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    tokio::time::timeout(
+        Duration::from_secs(5),
+        axum::body::to_bytes(response.into_body(), usize::MAX),
+    )
+    .await
+    .expect("stream did not end")
+    .unwrap();
+
+    // The recorder times the generation once it sees the completion event
+    let inter_token = &METRICS.inter_token_latency;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while inter_token.get_sample_count() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the generation was not timed");
+
+    let ttft = &METRICS.time_to_first_token;
+    assert_eq!(ttft.get_sample_count(), 1);
+    let seconds = ttft.get_sample_sum();
+    assert!(seconds >= FIRST_PART_DELAY.as_secs_f64(), "{seconds}");
+    assert!(seconds < 2.0, "{seconds}");
+
+    // The tokens after the first one are timed apart from it
+    assert_eq!(inter_token.get_sample_count(), 2);
+    let seconds = inter_token.get_sample_sum();
+    assert!(seconds >= 2.0 * PART_DELAY.as_secs_f64(), "{seconds}");
+    assert!(seconds < FIRST_PART_DELAY.as_secs_f64(), "{seconds}");
+
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .uri("/metrics")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(
+        body.contains("time_to_first_token_seconds_count 1"),
+        "{body}"
+    );
+    // The engine delay puts it above the 0.25 s bucket
+    assert!(
+        body.contains(r#"time_to_first_token_seconds_bucket{le="0.25"} 0"#),
+        "{body}"
+    );
+}
@@ -0,0 +1,91 @@
+//! Benchmark of reading the logits back from the GPU in f32 and in f16.
+//!
+//! Needs a GPU, so it is ignored by default. Run it with:
+//!
+//! ```bash
+//! cargo test --release --test logits_readback_tests -- --ignored --nocapture
+//! ```
+
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tokio_local_llm_api::core::gpu::create_gpu;
+use tokio_local_llm_api::core::logits_readback::HalfReadback;
+use wgpu::util::DeviceExt;
+use wgpu::{Buffer, BufferUsages, Device, Queue};
+
+/// Vocabulary size of Llama 3
+const VOCAB_SIZE: usize = 128_256;
+const ITERATIONS: u32 = 200;
+
+async fn read_f32(device: &Device, queue: &Queue, logits: &Buffer, staging: &Buffer) -> Vec<f32> {
+    let mut encoder = device.create_command_encoder(&Default::default());
+    encoder.copy_buffer_to_buffer(logits, 0, staging, 0, staging.size());
+    queue.submit(Some(encoder.finish()));
+
+    let slice = staging.slice(..);
+    let (sender, receiver) = oneshot::channel();
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = sender.send(result);
+    });
+    device.poll(wgpu::Maintain::Wait);
+    receiver.await.unwrap().unwrap();
+    let values = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+    staging.unmap();
+    values
+}
+
+#[tokio::test]
+#[ignore = "requires a GPU"]
+async fn bench_full_and_half_logits_readback() {
+    let gpu = create_gpu().await.expect("failed to create GPU");
+    let (device, queue) = (gpu.device(), gpu.queue());
+
+    let expected: Vec<f32> = (0..VOCAB_SIZE)
+        .map(|i| ((i * 7919) % 4000) as f32 / 100.0 - 20.0)
+        .collect();
+    let logits = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("logits"),
+        contents: bytemuck::cast_slice(&expected),
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+    });
+    let staging = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("logits_readback"),
+        size: logits.size(),
+        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    let half_readback = HalfReadback::new(device, &logits, VOCAB_SIZE);
+
+    let mut full = Duration::ZERO;
+    let mut half = Duration::ZERO;
+    let mut half_values = vec![0.0; VOCAB_SIZE];
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        let full_values = read_f32(device, queue, &logits, &staging).await;
+        full += start.elapsed();
+        assert_eq!(full_values, expected);
+
+        let start = Instant::now();
+        let mut encoder = device.create_command_encoder(&Default::default());
+        half_readback.encode(&mut encoder);
+        queue.submit(Some(encoder.finish()));
+        half_readback
+            .read_to(device, &mut half_values)
+            .await
+            .unwrap();
+        half += start.elapsed();
+    }
+
+    println!(
+        "logits readback of {VOCAB_SIZE} values: f32 {:?}, f16 {:?} per token",
+        full / ITERATIONS,
+        half / ITERATIONS
+    );
+    // Half floats keep 11 significant bits
+    for (half, exact) in half_values.iter().zip(&expected) {
+        assert!(
+            (half - exact).abs() <= exact.abs() / 1024.0,
+            "{half} != {exact}"
+        );
+    }
+}
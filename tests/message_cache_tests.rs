@@ -0,0 +1,294 @@
+//! Message cache tests
+//!
+//! Runs the conversation service with `MESSAGE_CACHE_SIZE` set over a repository that counts
+//! how often messages are listed, to check that cached reads skip the repository and that
+//! writes invalidate the cached list.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use di::{Injectable, Ref, ServiceCollection, ServiceProvider, injectable};
+use serial_test::serial;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio_local_llm_api::core::traits::ConversationService;
+use tokio_local_llm_api::infrastructure::entities::{
+    Changes, Conversation, ConversationOrder, Message, MessageFeedback, MessageOrder, RequestLog,
+    TokenUsage,
+};
+use tokio_local_llm_api::infrastructure::traits::ConversationRepository;
+use tokio_local_llm_api::{
+    core::message_cache::MessageCache, core::personas::Personas,
+    core::services::MyConversationService, infrastructure::database::DatabaseConnection,
+    infrastructure::repositories::DbConversationRepository,
+    infrastructure::user_context::UserContext, test_util::TestDb,
+};
+use uuid::Uuid;
+
+/// Number of times the repository has listed the messages of a conversation.
+static MESSAGE_LISTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Delegates to the database repository, counting the message lists.
+#[injectable(ConversationRepository)]
+struct CountingConversationRepository {
+    connection: Ref<DatabaseConnection>,
+    user_context: Ref<UserContext>,
+}
+
+impl CountingConversationRepository {
+    fn inner(&self) -> DbConversationRepository {
+        DbConversationRepository::new(self.connection.clone(), self.user_context.clone())
+    }
+}
+
+#[async_trait]
+impl ConversationRepository for CountingConversationRepository {
+    async fn list_conversations(
+        &self,
+        user_id: Uuid,
+        order: ConversationOrder,
+    ) -> Result<Vec<Conversation>, ()> {
+        self.inner().list_conversations(user_id, order).await
+    }
+
+    async fn create_conversation(
+        &self,
+        conversation: Conversation,
+        limit: Option<usize>,
+    ) -> Result<Option<Conversation>, ()> {
+        self.inner().create_conversation(conversation, limit).await
+    }
+
+    async fn delete_conversation(&self, conversation_id: Uuid) -> Result<(), ()> {
+        self.inner().delete_conversation(conversation_id).await
+    }
+
+    async fn conversation_owner(&self, conversation_id: Uuid) -> Result<Option<Uuid>, ()> {
+        self.inner().conversation_owner(conversation_id).await
+    }
+
+    async fn list_conversation_messages(
+        &self,
+        user_id: Uuid,
+        conversation: Uuid,
+        order: MessageOrder,
+    ) -> Result<Vec<Message>, ()> {
+        MESSAGE_LISTS.fetch_add(1, Ordering::SeqCst);
+        self.inner()
+            .list_conversation_messages(user_id, conversation, order)
+            .await
+    }
+
+    async fn create_message_in_conversation(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message: Message,
+    ) -> Result<Message, ()> {
+        self.inner()
+            .create_message_in_conversation(user_id, conversation_id, message)
+            .await
+    }
+
+    async fn replace_messages(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        replaced: Vec<Uuid>,
+        replacement: Message,
+    ) -> Result<Message, ()> {
+        self.inner()
+            .replace_messages(user_id, conversation_id, replaced, replacement)
+            .await
+    }
+
+    async fn set_message_token_count(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        token_count: u32,
+    ) -> Result<(), ()> {
+        self.inner()
+            .set_message_token_count(user_id, conversation_id, message_id, token_count)
+            .await
+    }
+
+    async fn set_message_incomplete(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        incomplete: bool,
+    ) -> Result<(), ()> {
+        self.inner()
+            .set_message_incomplete(user_id, conversation_id, message_id, incomplete)
+            .await
+    }
+
+    async fn set_message_thinking(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        thinking: String,
+    ) -> Result<(), ()> {
+        self.inner()
+            .set_message_thinking(user_id, conversation_id, message_id, thinking)
+            .await
+    }
+
+    async fn update_message(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        text: String,
+        token_count: u32,
+    ) -> Result<Message, ()> {
+        self.inner()
+            .update_message(user_id, conversation_id, message_id, text, token_count)
+            .await
+    }
+
+    async fn duplicate_conversation(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        duplicate_id: Uuid,
+    ) -> Result<Option<Conversation>, ()> {
+        self.inner()
+            .duplicate_conversation(user_id, conversation_id, duplicate_id)
+            .await
+    }
+
+    async fn changes_since(
+        &self,
+        user_id: Uuid,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Changes, ()> {
+        self.inner().changes_since(user_id, since).await
+    }
+
+    async fn conversation_usage(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+    ) -> Result<Option<TokenUsage>, ()> {
+        self.inner()
+            .conversation_usage(user_id, conversation_id)
+            .await
+    }
+
+    async fn user_usage(&self, user_id: Uuid) -> Result<TokenUsage, ()> {
+        self.inner().user_usage(user_id).await
+    }
+
+    async fn upsert_message_feedback(
+        &self,
+        conversation_id: Uuid,
+        feedback: MessageFeedback,
+    ) -> Result<MessageFeedback, ()> {
+        self.inner()
+            .upsert_message_feedback(conversation_id, feedback)
+            .await
+    }
+
+    async fn get_message_feedback(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<Option<MessageFeedback>, ()> {
+        self.inner()
+            .get_message_feedback(user_id, conversation_id, message_id)
+            .await
+    }
+
+    async fn add_tag(&self, user_id: Uuid, conversation_id: Uuid, tag: String) -> Result<(), ()> {
+        self.inner().add_tag(user_id, conversation_id, tag).await
+    }
+
+    async fn remove_tag(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        tag: String,
+    ) -> Result<bool, ()> {
+        self.inner().remove_tag(user_id, conversation_id, tag).await
+    }
+
+    async fn list_conversations_by_tag(
+        &self,
+        user_id: Uuid,
+        tag: String,
+        order: ConversationOrder,
+    ) -> Result<Vec<Conversation>, ()> {
+        self.inner()
+            .list_conversations_by_tag(user_id, tag, order)
+            .await
+    }
+
+    async fn create_request_log(&self, log: RequestLog) -> Result<(), ()> {
+        self.inner().create_request_log(log).await
+    }
+}
+
+fn create_test_provider() -> ServiceProvider {
+    // SAFETY: this is the only test in this binary
+    unsafe { std::env::set_var("MESSAGE_CACHE_SIZE", "16") };
+
+    ServiceCollection::new()
+        .add(DatabaseConnection::transient())
+        .add(UserContext::scoped())
+        .add(Personas::transient())
+        .add(MessageCache::singleton())
+        .add(CountingConversationRepository::scoped())
+        .add(MyConversationService::scoped())
+        .build_provider()
+        .unwrap()
+}
+
+#[tokio::test]
+#[serial]
+async fn test_cached_messages_skip_the_repository_until_a_write() {
+    let _db = TestDb::new().await;
+    let provider = create_test_provider();
+    let service = provider.get_required::<dyn ConversationService>();
+
+    let user_id = Uuid::new_v4();
+    let conversation = service
+        .create_conversation(user_id, None, None)
+        .await
+        .unwrap();
+
+    let first = service
+        .list_messages(user_id, conversation.id, MessageOrder::OldestFirst)
+        .await
+        .unwrap();
+    let second = service
+        .list_messages(user_id, conversation.id, MessageOrder::OldestFirst)
+        .await
+        .unwrap();
+    assert_eq!(MESSAGE_LISTS.load(Ordering::SeqCst), 1);
+    assert_eq!(second.len(), first.len());
+
+    // Another user asking for the same conversation doesn't get the cached list
+    let other_user = service
+        .list_messages(Uuid::new_v4(), conversation.id, MessageOrder::OldestFirst)
+        .await
+        .unwrap();
+    assert!(other_user.is_empty());
+    assert_eq!(MESSAGE_LISTS.load(Ordering::SeqCst), 2);
+
+    service
+        .create_user_message(user_id, conversation.id, "Hi!".to_owned(), Vec::new())
+        .await
+        .unwrap();
+
+    let newest_first = service
+        .list_messages(user_id, conversation.id, MessageOrder::NewestFirst)
+        .await
+        .unwrap();
+    assert_eq!(MESSAGE_LISTS.load(Ordering::SeqCst), 3);
+    assert_eq!(newest_first.len(), first.len() + 1);
+    assert_eq!(newest_first[0].text, "Hi!");
+}
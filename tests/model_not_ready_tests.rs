@@ -0,0 +1,65 @@
+//! Tests of requests made before the inference worker has started
+//!
+//! Nothing in this test binary sets `TASK_SENDER`, like a server whose model is still loading.
+
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use common::create_test_app;
+use serde_json::Value;
+use serial_test::serial;
+use tokio_local_llm_api::test_util::TestDb;
+use tower::ServiceExt;
+use uuid::Uuid;
+
+async fn post_json(user_id: Uuid, uri: &str, body: &str) -> (StatusCode, Value) {
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("X-User-ID", user_id.to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.to_owned()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+#[serial]
+async fn test_posting_before_the_worker_starts_is_unavailable() {
+    let db = TestDb::new().await;
+    let user_id = Uuid::new_v4();
+
+    let (status, body) = post_json(user_id, "/conversations", r#"{"message": "Hi!"}"#).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["code"], "model_not_ready");
+
+    // Nothing is saved for a message that can't be answered
+    let conversations: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM conversations")
+        .fetch_one(db.pool())
+        .await
+        .unwrap();
+    assert_eq!(conversations, 0);
+
+    let conversation_id = Uuid::new_v4();
+    let (status, body) = post_json(
+        user_id,
+        &format!("/conversations/{conversation_id}/messages"),
+        r#"{"text": "Hi!"}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["code"], "model_not_ready");
+}
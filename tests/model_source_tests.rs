@@ -0,0 +1,71 @@
+//! Model source tests
+//!
+//! Loads a tiny synthetic GGUF, a header and metadata without any tensors, from each source.
+
+use tokio_local_llm_api::core::model_source::ModelSource;
+use wgml::gguf::Gguf;
+
+/// GGUF metadata value type of strings
+const GGUF_TYPE_STRING: u32 = 8;
+
+fn push_string(bytes: &mut Vec<u8>, value: &str) {
+    bytes.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(value.as_bytes());
+}
+
+/// A GGUF v3 file with the given string metadata and no tensors.
+fn synthetic_gguf(metadata: &[(&str, &str)]) -> Vec<u8> {
+    let mut bytes = b"GGUF".to_vec();
+    bytes.extend_from_slice(&3u32.to_le_bytes());
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    bytes.extend_from_slice(&(metadata.len() as u64).to_le_bytes());
+    for (key, value) in metadata {
+        push_string(&mut bytes, key);
+        bytes.extend_from_slice(&GGUF_TYPE_STRING.to_le_bytes());
+        push_string(&mut bytes, value);
+    }
+    bytes
+}
+
+fn assert_parses_as_tiny_model(bytes: &[u8]) {
+    let gguf = Gguf::from_bytes(bytes).expect("failed to parse the synthetic GGUF");
+    assert_eq!(gguf.metadata["general.name"].as_string(), "tiny");
+    assert_eq!(gguf.metadata["general.architecture"].as_string(), "llama");
+}
+
+const METADATA: [(&str, &str); 2] = [("general.architecture", "llama"), ("general.name", "tiny")];
+
+#[test]
+fn test_load_from_bytes() {
+    let bytes = ModelSource::Bytes(synthetic_gguf(&METADATA))
+        .load()
+        .unwrap();
+
+    assert_parses_as_tiny_model(&bytes);
+}
+
+#[test]
+fn test_load_from_path_maps_the_file() {
+    let path = std::env::temp_dir().join(format!("{}.gguf", uuid::Uuid::new_v4()));
+    std::fs::write(&path, synthetic_gguf(&METADATA)).unwrap();
+
+    let bytes = ModelSource::Path(path.clone()).load().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_parses_as_tiny_model(&bytes);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_load_from_fd() {
+    use std::os::fd::IntoRawFd;
+
+    let path = std::env::temp_dir().join(format!("{}.gguf", uuid::Uuid::new_v4()));
+    std::fs::write(&path, synthetic_gguf(&METADATA)).unwrap();
+    let fd = std::fs::File::open(&path).unwrap().into_raw_fd();
+    std::fs::remove_file(&path).unwrap();
+
+    let bytes = ModelSource::Fd(fd).load().unwrap();
+
+    assert_parses_as_tiny_model(&bytes);
+}
@@ -0,0 +1,71 @@
+//! Tests of multiple choices (`n`) on the OpenAI compatible endpoint, against the fake inference
+//! worker
+
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use common::{CANNED_RESPONSE, FAKE_PROMPT_TOKENS, init_test_task_sender};
+use serde_json::{Value, json};
+use tokio_local_llm_api::api;
+use tokio_local_llm_api::core::assistant::model_id;
+use tower::ServiceExt;
+use uuid::Uuid;
+
+async fn post_completion(n: usize) -> (StatusCode, Value) {
+    init_test_task_sender();
+
+    let request = json!({
+        "model": model_id(),
+        "messages": [{"role": "user", "content": "Hello"}],
+        "n": n,
+    });
+    let response = api::openai::router()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/chat/completions")
+                .header("X-User-ID", Uuid::new_v4().to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(request.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+#[tokio::test]
+async fn test_n_generates_that_many_choices() {
+    let (status, body) = post_completion(3).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let choices = body["choices"].as_array().unwrap();
+    assert_eq!(choices.len(), 3);
+    for (index, choice) in choices.iter().enumerate() {
+        assert_eq!(choice["index"], index);
+        assert_eq!(choice["message"]["content"], CANNED_RESPONSE.concat());
+    }
+    assert_eq!(body["usage"]["prompt_tokens"], FAKE_PROMPT_TOKENS);
+    assert_eq!(
+        body["usage"]["completion_tokens"],
+        3 * CANNED_RESPONSE.len()
+    );
+}
+
+#[tokio::test]
+async fn test_n_above_the_cap_is_rejected() {
+    // `MAX_COMPLETION_CHOICES` defaults to 4
+    for n in [0, 5] {
+        let (status, body) = post_completion(n).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "n = {n}");
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+    }
+}
@@ -0,0 +1,100 @@
+//! Streaming tests of the OpenAI compatible endpoint, against the fake inference worker
+
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use common::{CANNED_RESPONSE, FAKE_PROMPT_TOKENS, init_test_task_sender};
+use serde_json::{Value, json};
+use tokio_local_llm_api::api;
+use tokio_local_llm_api::core::assistant::model_id;
+use tower::ServiceExt;
+use uuid::Uuid;
+
+/// Streams a completion and returns the data of its chunks, `[DONE]` included.
+async fn stream_completion(stream_options: Option<Value>) -> Vec<String> {
+    init_test_task_sender();
+
+    let mut request = json!({
+        "model": model_id(),
+        "messages": [{"role": "user", "content": "Hello"}],
+        "stream": true,
+    });
+    if let Some(stream_options) = stream_options {
+        request["stream_options"] = stream_options;
+    }
+
+    let response = api::openai::router()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/chat/completions")
+                .header("X-User-ID", Uuid::new_v4().to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(request.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    std::str::from_utf8(&body)
+        .unwrap()
+        .split("\n\n")
+        .filter_map(|event| event.strip_prefix("data: "))
+        .map(str::to_owned)
+        .collect()
+}
+
+fn parse_chunks(data: &[String]) -> Vec<Value> {
+    assert_eq!(data.last().unwrap(), "[DONE]");
+    data[..data.len() - 1]
+        .iter()
+        .map(|chunk| serde_json::from_str(chunk).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_stream_sends_content_chunks() {
+    let chunks = parse_chunks(&stream_completion(None).await);
+
+    assert_eq!(chunks[0]["object"], "chat.completion.chunk");
+    assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+    let content: Vec<&str> = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str())
+        .collect();
+    assert_eq!(content, CANNED_RESPONSE);
+    assert_eq!(
+        chunks.last().unwrap()["choices"][0]["finish_reason"],
+        "stop"
+    );
+}
+
+#[tokio::test]
+async fn test_stream_usage_only_when_requested() {
+    let chunks = parse_chunks(&stream_completion(None).await);
+    assert!(chunks.iter().all(|chunk| chunk.get("usage").is_none()));
+
+    let chunks = parse_chunks(&stream_completion(Some(json!({"include_usage": false}))).await);
+    assert!(chunks.iter().all(|chunk| chunk.get("usage").is_none()));
+
+    let chunks = parse_chunks(&stream_completion(Some(json!({"include_usage": true}))).await);
+    let (usage_chunk, others) = chunks.split_last().unwrap();
+    assert!(others.iter().all(|chunk| chunk.get("usage").is_none()));
+    assert_eq!(usage_chunk["choices"], json!([]));
+    assert_eq!(usage_chunk["usage"]["prompt_tokens"], FAKE_PROMPT_TOKENS);
+    assert_eq!(
+        usage_chunk["usage"]["completion_tokens"],
+        CANNED_RESPONSE.len()
+    );
+    assert_eq!(
+        usage_chunk["usage"]["total_tokens"],
+        FAKE_PROMPT_TOKENS + CANNED_RESPONSE.len()
+    );
+}
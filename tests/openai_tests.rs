@@ -0,0 +1,143 @@
+//! OpenAI compatible endpoint tests
+//!
+//! The inference worker of this test binary never picks up a task, so its queue stays full once
+//! filled.
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use serde_json::{Value, json};
+use std::sync::OnceLock;
+use tokio_local_llm_api::core::assistant::{InferenceTask, model_id};
+use tokio_local_llm_api::core::task_queue::{self, TaskReceiver};
+use tokio_local_llm_api::{TASK_SENDER, api};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+/// Keeps the queue open without ever reading from it.
+static STALLED_RECEIVER: OnceLock<TaskReceiver> = OnceLock::new();
+
+fn init_stalled_worker() {
+    let (sender, receiver) = task_queue::channel(1);
+    if TASK_SENDER.set(sender).is_ok() {
+        STALLED_RECEIVER.set(receiver).unwrap();
+    }
+}
+
+async fn post_completion(body: &str) -> (StatusCode, Value) {
+    let response = api::openai::router()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/chat/completions")
+                .header("X-User-ID", Uuid::new_v4().to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.to_owned()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+fn hello_request(model: &str) -> String {
+    json!({"model": model, "messages": [{"role": "user", "content": "Hello"}]}).to_string()
+}
+
+#[tokio::test]
+async fn test_malformed_request_is_invalid_request_error() {
+    let (status, body) = post_completion(r#"{"model": "x", "messages": "#).await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+    assert!(body["error"]["message"].is_string());
+    assert_eq!(body["error"]["code"], Value::Null);
+}
+
+#[tokio::test]
+async fn test_empty_messages_is_invalid_request_error() {
+    let (status, body) =
+        post_completion(&json!({"model": model_id(), "messages": []}).to_string()).await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+    assert_eq!(
+        body["error"]["message"],
+        "`messages` must contain at least one message"
+    );
+}
+
+#[tokio::test]
+async fn test_missing_user_is_invalid_request_error() {
+    let response = api::openai::router()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/chat/completions")
+                .header("Content-Type", "application/json")
+                .body(Body::from(hello_request(&model_id())))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+    assert_eq!(body["error"]["message"], "`X-User-ID` header is missing");
+}
+
+#[tokio::test]
+async fn test_unknown_model_is_model_not_found() {
+    let (status, body) = post_completion(&hello_request("gpt-nonexistent")).await;
+
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+    assert_eq!(body["error"]["code"], "model_not_found");
+}
+
+#[tokio::test]
+async fn test_full_queue_is_rate_limit_exceeded() {
+    init_stalled_worker();
+
+    // Fill the only slot of the queue
+    let (task, _) = InferenceTask::new(Vec::new());
+    let _ = TASK_SENDER.get().unwrap().try_send(task);
+
+    let (status, body) = post_completion(&hello_request(&model_id())).await;
+
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(body["error"]["code"], "rate_limit_exceeded");
+}
+
+#[tokio::test]
+async fn test_models_lists_the_served_model() {
+    let response = api::openai::router()
+        .oneshot(
+            Request::builder()
+                .uri("/models")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(body["object"], "list");
+    assert_eq!(body["data"][0]["id"], model_id());
+    // No model is loaded in tests
+    assert_eq!(body["data"][0]["quantization"], Value::Null);
+}
@@ -0,0 +1,67 @@
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use serde_json::Value;
+use tokio_local_llm_api::api;
+use tower::ServiceExt;
+
+#[tokio::test]
+async fn test_openapi_document_lists_conversation_endpoints() {
+    let response = api::openapi::router()
+        .oneshot(
+            Request::builder()
+                .uri("/openapi.json")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let document: Value = serde_json::from_slice(&body).unwrap();
+
+    let paths = &document["paths"];
+    for (path, method) in [
+        ("/conversations", "get"),
+        ("/conversations", "post"),
+        ("/conversations/sync", "get"),
+        ("/conversations/{id}/messages", "get"),
+        ("/conversations/{id}/messages", "post"),
+        ("/conversations/{id}/messages/{message_id}/continue", "post"),
+        ("/conversations/{id}/usage", "get"),
+    ] {
+        assert!(
+            paths[path][method].is_object(),
+            "{method} {path} is missing"
+        );
+    }
+
+    let post_message = &paths["/conversations/{id}/messages"]["post"];
+    assert_eq!(
+        post_message["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+        "#/components/schemas/CreateMessage"
+    );
+    assert_eq!(
+        paths["/conversations"]["get"]["responses"]["200"]["content"]["application/json"]["schema"]
+            ["$ref"],
+        "#/components/schemas/ConversationList"
+    );
+
+    let schemas = &document["components"]["schemas"];
+    for schema in [
+        "Conversation",
+        "ConversationList",
+        "CreateConversation",
+        "CreateMessage",
+        "Message",
+        "MessagePart",
+        "Role",
+        "SamplingParams",
+        "SamplingPreset",
+    ] {
+        assert!(schemas[schema].is_object(), "schema {schema} is missing");
+    }
+}
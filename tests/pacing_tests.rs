@@ -0,0 +1,111 @@
+//! Paced streaming tests
+//!
+//! Runs against a mock inference engine that generates every message in one burst, with the
+//! delivery to clients capped by `MAX_TOKENS_PER_SEC_PER_STREAM`.
+
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use common::create_test_app;
+use common::parse_sse_events;
+use futures_util::StreamExt;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_local_llm_api::core::inference_events::CompletionReason;
+use tokio_local_llm_api::core::task_queue;
+use tokio_local_llm_api::{TASK_SENDER, test_util::TestDb};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+/// Parts the engine generates for every message.
+const PARTS: usize = 10;
+
+/// The configured delivery rate.
+const TOKENS_PER_SEC: u32 = 20;
+
+/// When the engine finished its last generation.
+static ENGINE_FINISHED: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Starts a mock engine that answers every task with all of its parts at once.
+fn init_burst_engine() {
+    let (sender, mut receiver) = task_queue::channel(10);
+    TASK_SENDER.set(sender).expect("task sender already set");
+
+    tokio::spawn(async move {
+        while let Some(mut task) = receiver.recv().await {
+            for i in 0..PARTS {
+                let _ = task.return_channel().send(format!("{i} ")).await;
+                task.generated_token();
+            }
+            task.completed(CompletionReason::Stop, Duration::ZERO, Duration::ZERO);
+            *ENGINE_FINISHED.lock().unwrap() = Some(Instant::now());
+        }
+    });
+}
+
+#[tokio::test]
+async fn test_burst_is_delivered_at_the_configured_rate() {
+    let _db = TestDb::new().await;
+    init_burst_engine();
+    // SAFETY: this is the only test in this binary
+    unsafe { std::env::set_var("MAX_TOKENS_PER_SEC_PER_STREAM", TOKENS_PER_SEC.to_string()) };
+
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations")
+                .header("X-User-ID", Uuid::new_v4().to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"message": "Hi!"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // When each part arrived
+    let mut arrivals = Vec::new();
+    let mut text = String::new();
+    let mut body = response.into_body().into_data_stream();
+    while let Some(chunk) = tokio::time::timeout(Duration::from_secs(5), body.next())
+        .await
+        .expect("stream stalled")
+    {
+        text.push_str(std::str::from_utf8(&chunk.unwrap()).unwrap());
+        let parts = parse_sse_events(&text)
+            .iter()
+            .filter(|(event, _)| event == "message_part")
+            .count();
+        arrivals.resize(parts, Instant::now());
+    }
+    let stream_ended = Instant::now();
+
+    assert_eq!(arrivals.len(), PARTS);
+    assert!(
+        parse_sse_events(&text)
+            .iter()
+            .any(|(event, _)| event == "done")
+    );
+    let period = Duration::from_secs(1) / TOKENS_PER_SEC;
+    for (i, arrival) in arrivals.iter().enumerate() {
+        // Measured from the first part, since a slow read can bunch up the gaps between parts.
+        // Timers fire on whole milliseconds.
+        let elapsed = *arrival - arrivals[0];
+        assert!(
+            elapsed >= (period - Duration::from_millis(2)) * i as u32,
+            "part {i} after {elapsed:?}"
+        );
+    }
+
+    // The engine isn't held up by the pace of the client
+    let engine_finished = ENGINE_FINISHED
+        .lock()
+        .unwrap()
+        .expect("engine did not finish");
+    assert!(stream_ended - engine_finished >= period * (PARTS as u32 - 2));
+}
@@ -0,0 +1,307 @@
+//! Bot message persistence tests
+//!
+//! Runs a generation against a repository that fails to save the first bot message, to check
+//! that the client still gets the whole response and the message is saved on a retry.
+
+mod common;
+
+use async_trait::async_trait;
+use axum::{
+    Router,
+    body::Body,
+    http::{Request, StatusCode},
+};
+use chrono::{DateTime, Utc};
+use common::{CANNED_RESPONSE, init_test_task_sender, parse_sse_events};
+use di::{Injectable, Ref, injectable};
+use serial_test::serial;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio_local_llm_api::infrastructure::entities::{
+    Changes, Conversation, ConversationOrder, Message, MessageFeedback, MessageKind, MessageOrder,
+    RequestLog, TokenUsage,
+};
+use tokio_local_llm_api::infrastructure::traits::ConversationRepository;
+use tokio_local_llm_api::{
+    infrastructure::database::DatabaseConnection,
+    infrastructure::repositories::DbConversationRepository,
+    infrastructure::user_context::UserContext, test_util::TestDb,
+};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+/// Number of bot messages the repository has refused to save.
+static FAILED_BOT_INSERTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Delegates to the database repository, but fails the first attempt to save a bot message.
+#[injectable(ConversationRepository)]
+struct FlakyConversationRepository {
+    connection: Ref<DatabaseConnection>,
+    user_context: Ref<UserContext>,
+}
+
+impl FlakyConversationRepository {
+    fn inner(&self) -> DbConversationRepository {
+        DbConversationRepository::new(self.connection.clone(), self.user_context.clone())
+    }
+}
+
+#[async_trait]
+impl ConversationRepository for FlakyConversationRepository {
+    async fn list_conversations(
+        &self,
+        user_id: Uuid,
+        order: ConversationOrder,
+    ) -> Result<Vec<Conversation>, ()> {
+        self.inner().list_conversations(user_id, order).await
+    }
+
+    async fn create_conversation(
+        &self,
+        conversation: Conversation,
+        limit: Option<usize>,
+    ) -> Result<Option<Conversation>, ()> {
+        self.inner().create_conversation(conversation, limit).await
+    }
+
+    async fn delete_conversation(&self, conversation_id: Uuid) -> Result<(), ()> {
+        self.inner().delete_conversation(conversation_id).await
+    }
+
+    async fn conversation_owner(&self, conversation_id: Uuid) -> Result<Option<Uuid>, ()> {
+        self.inner().conversation_owner(conversation_id).await
+    }
+
+    async fn list_conversation_messages(
+        &self,
+        user_id: Uuid,
+        conversation: Uuid,
+        order: MessageOrder,
+    ) -> Result<Vec<Message>, ()> {
+        self.inner()
+            .list_conversation_messages(user_id, conversation, order)
+            .await
+    }
+
+    async fn create_message_in_conversation(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message: Message,
+    ) -> Result<Message, ()> {
+        if matches!(message.kind, MessageKind::Bot)
+            && FAILED_BOT_INSERTS.fetch_add(1, Ordering::SeqCst) == 0
+        {
+            return Err(());
+        }
+
+        self.inner()
+            .create_message_in_conversation(user_id, conversation_id, message)
+            .await
+    }
+
+    async fn replace_messages(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        replaced: Vec<Uuid>,
+        replacement: Message,
+    ) -> Result<Message, ()> {
+        self.inner()
+            .replace_messages(user_id, conversation_id, replaced, replacement)
+            .await
+    }
+
+    async fn set_message_token_count(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        token_count: u32,
+    ) -> Result<(), ()> {
+        self.inner()
+            .set_message_token_count(user_id, conversation_id, message_id, token_count)
+            .await
+    }
+
+    async fn set_message_incomplete(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        incomplete: bool,
+    ) -> Result<(), ()> {
+        self.inner()
+            .set_message_incomplete(user_id, conversation_id, message_id, incomplete)
+            .await
+    }
+
+    async fn set_message_thinking(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        thinking: String,
+    ) -> Result<(), ()> {
+        self.inner()
+            .set_message_thinking(user_id, conversation_id, message_id, thinking)
+            .await
+    }
+
+    async fn update_message(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+        text: String,
+        token_count: u32,
+    ) -> Result<Message, ()> {
+        self.inner()
+            .update_message(user_id, conversation_id, message_id, text, token_count)
+            .await
+    }
+
+    async fn duplicate_conversation(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        duplicate_id: Uuid,
+    ) -> Result<Option<Conversation>, ()> {
+        self.inner()
+            .duplicate_conversation(user_id, conversation_id, duplicate_id)
+            .await
+    }
+
+    async fn changes_since(
+        &self,
+        user_id: Uuid,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Changes, ()> {
+        self.inner().changes_since(user_id, since).await
+    }
+
+    async fn conversation_usage(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+    ) -> Result<Option<TokenUsage>, ()> {
+        self.inner()
+            .conversation_usage(user_id, conversation_id)
+            .await
+    }
+
+    async fn user_usage(&self, user_id: Uuid) -> Result<TokenUsage, ()> {
+        self.inner().user_usage(user_id).await
+    }
+
+    async fn upsert_message_feedback(
+        &self,
+        conversation_id: Uuid,
+        feedback: MessageFeedback,
+    ) -> Result<MessageFeedback, ()> {
+        self.inner()
+            .upsert_message_feedback(conversation_id, feedback)
+            .await
+    }
+
+    async fn get_message_feedback(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<Option<MessageFeedback>, ()> {
+        self.inner()
+            .get_message_feedback(user_id, conversation_id, message_id)
+            .await
+    }
+
+    async fn add_tag(&self, user_id: Uuid, conversation_id: Uuid, tag: String) -> Result<(), ()> {
+        self.inner().add_tag(user_id, conversation_id, tag).await
+    }
+
+    async fn remove_tag(
+        &self,
+        user_id: Uuid,
+        conversation_id: Uuid,
+        tag: String,
+    ) -> Result<bool, ()> {
+        self.inner().remove_tag(user_id, conversation_id, tag).await
+    }
+
+    async fn list_conversations_by_tag(
+        &self,
+        user_id: Uuid,
+        tag: String,
+        order: ConversationOrder,
+    ) -> Result<Vec<Conversation>, ()> {
+        self.inner()
+            .list_conversations_by_tag(user_id, tag, order)
+            .await
+    }
+
+    async fn create_request_log(&self, log: RequestLog) -> Result<(), ()> {
+        self.inner().create_request_log(log).await
+    }
+}
+
+fn create_test_app() -> Router {
+    common::create_test_app_with(
+        |services| {
+            services.add(FlakyConversationRepository::scoped());
+        },
+        |app| app,
+    )
+}
+
+#[tokio::test]
+#[serial]
+async fn test_bot_message_saved_on_retry() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    init_test_task_sender();
+
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations")
+                .header("X-User-ID", Uuid::new_v4().to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"message": "Hi!"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // The client gets every part even though saving the message failed
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let parts: Vec<String> = parse_sse_events(std::str::from_utf8(&body).unwrap())
+        .into_iter()
+        .filter(|(event, _)| event == "message_part")
+        .map(|(_, data)| {
+            let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+            data["message_part"].as_str().unwrap().to_owned()
+        })
+        .collect();
+    assert_eq!(parts, CANNED_RESPONSE);
+
+    let text = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let row: Option<(String,)> = sqlx::query_as("SELECT text FROM messages WHERE kind = 2")
+                .fetch_optional(&pool)
+                .await
+                .unwrap();
+            if let Some((text,)) = row {
+                return text;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("bot message was not saved on retry");
+    assert_eq!(text, CANNED_RESPONSE.concat());
+    assert_eq!(FAILED_BOT_INSERTS.load(Ordering::SeqCst), 2);
+}
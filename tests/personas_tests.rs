@@ -0,0 +1,66 @@
+//! Personas endpoint tests
+
+use axum::{
+    Router,
+    body::Body,
+    http::{Request, StatusCode},
+};
+use di::{Injectable, ServiceCollection};
+use di_axum::RouterServiceProviderExtensions;
+use serde_json::{Value, json};
+use tokio_local_llm_api::{api, core::personas::Personas};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+fn create_test_app() -> Router {
+    let provider = ServiceCollection::new()
+        .add(Personas::transient())
+        .build_provider()
+        .unwrap();
+
+    Router::new()
+        .nest("/v1", api::personas::router())
+        .with_provider(provider)
+}
+
+#[tokio::test]
+async fn test_personas_lists_configured_personas() {
+    let path = std::env::temp_dir().join(format!("personas-{}.json", Uuid::new_v4()));
+    std::fs::write(
+        &path,
+        r#"{
+            "pirate": {"description": "Talks like a pirate", "system_prompt": "Arr, ye be helpful."},
+            "butler": {"description": "Very formal", "system_prompt": "You are a butler."}
+        }"#,
+    )
+    .unwrap();
+    unsafe { std::env::set_var("PERSONAS_FILE", &path) };
+
+    // No user or admin token is needed
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .uri("/v1/personas")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    // System prompts aren't exposed
+    assert_eq!(
+        body,
+        json!({"personas": [
+            {"name": "butler", "description": "Very formal"},
+            {"name": "pirate", "description": "Talks like a pirate"},
+        ]})
+    );
+
+    unsafe { std::env::remove_var("PERSONAS_FILE") };
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,126 @@
+//! SSE ping tests
+//!
+//! Runs against a mock inference engine that holds on to every task until the test releases it,
+//! so the stream stays idle, with `SSE_PING_INTERVAL_MS` set low.
+
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use chrono::{DateTime, Utc};
+use common::create_test_app;
+use common::parse_sse_events;
+use futures_util::StreamExt;
+use serde_json::Value;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+use tokio_local_llm_api::core::inference_events::CompletionReason;
+use tokio_local_llm_api::core::task_queue;
+use tokio_local_llm_api::{TASK_SENDER, test_util::TestDb};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+/// The configured ping interval.
+const PING_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Pings the test waits for before letting the generation finish.
+const PINGS: usize = 3;
+
+/// A permit lets the engine finish one task.
+static RELEASE: Semaphore = Semaphore::const_new(0);
+
+/// Starts a mock engine that answers a task once it is released.
+fn init_held_engine() {
+    let (sender, mut receiver) = task_queue::channel(10);
+    TASK_SENDER.set(sender).expect("task sender already set");
+
+    tokio::spawn(async move {
+        while let Some(mut task) = receiver.recv().await {
+            RELEASE.acquire().await.unwrap().forget();
+            let _ = task.return_channel().send("Hi".to_owned()).await;
+            task.generated_token();
+            task.completed(CompletionReason::Stop, Duration::ZERO, Duration::ZERO);
+        }
+    });
+}
+
+#[tokio::test]
+async fn test_idle_stream_is_pinged_at_the_configured_interval() {
+    let _db = TestDb::new().await;
+    init_held_engine();
+    // SAFETY: this is the only test in this binary
+    unsafe {
+        std::env::set_var(
+            "SSE_PING_INTERVAL_MS",
+            PING_INTERVAL.as_millis().to_string(),
+        )
+    };
+
+    let started = Instant::now();
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations")
+                .header("X-User-ID", Uuid::new_v4().to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"message": "Hi!"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // When each ping arrived
+    let mut arrivals = Vec::new();
+    let mut text = String::new();
+    let mut released = false;
+    let mut body = response.into_body().into_data_stream();
+    while let Some(chunk) = tokio::time::timeout(Duration::from_secs(5), body.next())
+        .await
+        .expect("stream stalled")
+    {
+        text.push_str(std::str::from_utf8(&chunk.unwrap()).unwrap());
+        let pings = parse_sse_events(&text)
+            .iter()
+            .filter(|(event, _)| event == "ping")
+            .count();
+        if pings > arrivals.len() {
+            arrivals.resize(pings, Instant::now());
+        }
+        if arrivals.len() >= PINGS && !released {
+            RELEASE.add_permits(1);
+            released = true;
+        }
+    }
+
+    let events = parse_sse_events(&text);
+    let pings: Vec<Value> = events
+        .iter()
+        .filter(|(event, _)| event == "ping")
+        .map(|(_, data)| serde_json::from_str(data).unwrap())
+        .collect();
+    assert!(pings.len() >= PINGS);
+    assert!(events.iter().any(|(event, _)| event == "done"));
+
+    let mut previous = None;
+    for (ping, arrival) in pings.iter().zip(&arrivals) {
+        let timestamp: DateTime<Utc> = ping["timestamp"].as_str().unwrap().parse().unwrap();
+        assert!(timestamp <= Utc::now());
+        if let Some(previous) = previous {
+            assert!(timestamp > previous);
+        }
+        previous = Some(timestamp);
+        // Timers fire on whole milliseconds
+        assert!(*arrival - started >= PING_INTERVAL - Duration::from_millis(2));
+    }
+    // Measured from the first ping, since a slow read can bunch up the gaps between pings
+    let elapsed = arrivals[PINGS - 1] - arrivals[0];
+    assert!(
+        elapsed >= (PING_INTERVAL - Duration::from_millis(2)) * (PINGS as u32 - 1),
+        "{PINGS} pings in {elapsed:?}"
+    );
+}
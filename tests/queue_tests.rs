@@ -0,0 +1,103 @@
+//! Queue position tests
+//!
+//! Runs two generations against a mock inference engine that holds every task until the test
+//! releases it, so the second client has to wait in the queue.
+
+mod common;
+
+use axum::{
+    body::{Body, BodyDataStream},
+    http::{Request, StatusCode},
+};
+use common::create_test_app;
+use common::parse_sse_events;
+use futures_util::StreamExt;
+use serial_test::serial;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio_local_llm_api::core::task_queue;
+use tokio_local_llm_api::{TASK_SENDER, test_util::TestDb};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+/// Starts a mock engine that answers a task with a single part once the returned semaphore
+/// hands out a permit for it.
+fn init_gated_engine() -> Arc<Semaphore> {
+    let (sender, mut receiver) = task_queue::channel(10);
+    TASK_SENDER.set(sender).expect("task sender already set");
+    let gate = Arc::new(Semaphore::new(0));
+
+    let engine_gate = gate.clone();
+    tokio::spawn(async move {
+        while let Some(task) = receiver.recv().await {
+            engine_gate.acquire().await.unwrap().forget();
+            let _ = task.return_channel().send("done".to_owned()).await;
+        }
+    });
+
+    gate
+}
+
+async fn start_conversation() -> BodyDataStream {
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations")
+                .header("X-User-ID", Uuid::new_v4().to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"message": "Hi!"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    response.into_body().into_data_stream()
+}
+
+/// Reads the SSE stream until an event named `event` arrives and returns all events so far.
+async fn read_until(body: &mut BodyDataStream, event: &str) -> Vec<(String, String)> {
+    let mut text = String::new();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let chunk = body.next().await.expect("stream ended").unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+
+            let events = parse_sse_events(&text);
+            if events.iter().any(|(name, _)| name == event) {
+                return events;
+            }
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("no {event} event received"))
+}
+
+#[tokio::test]
+#[serial]
+async fn test_waiting_client_is_told_its_queue_position() {
+    let _db = TestDb::new().await;
+    let gate = init_gated_engine();
+
+    let mut first = start_conversation().await;
+    let mut second = start_conversation().await;
+
+    // The first generation is held by the engine, so the second one is queued behind it
+    let events = read_until(&mut second, "queued").await;
+    let (_, data) = events.iter().find(|(name, _)| name == "queued").unwrap();
+    let queued: serde_json::Value = serde_json::from_str(data).unwrap();
+    assert_eq!(queued["position"], 1);
+
+    gate.add_permits(2);
+
+    read_until(&mut first, "message_part").await;
+    let events = read_until(&mut second, "message_part").await;
+    let (_, data) = events
+        .iter()
+        .find(|(name, _)| name == "message_part")
+        .unwrap();
+    let part: serde_json::Value = serde_json::from_str(data).unwrap();
+    assert_eq!(part["message_part"], "done");
+}
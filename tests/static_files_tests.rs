@@ -0,0 +1,63 @@
+//! Static file serving tests
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use serial_test::serial;
+use tokio_local_llm_api::api;
+use tower::ServiceExt;
+use uuid::Uuid;
+
+/// Creates an empty directory and points `STATIC_DIR` at it
+fn configure_static_dir() -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("static-{}", Uuid::new_v4()));
+    std::fs::create_dir(&path).unwrap();
+    unsafe { std::env::set_var("STATIC_DIR", &path) };
+    path
+}
+
+fn clear_static_dir(path: std::path::PathBuf) {
+    unsafe { std::env::remove_var("STATIC_DIR") };
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+async fn get(uri: &str) -> (StatusCode, String) {
+    let response = api::static_files::router()
+        .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_index_served_from_static_dir() {
+    let path = configure_static_dir();
+    std::fs::write(path.join("index.html"), "<p>hello</p>").unwrap();
+    std::fs::write(path.join("app.js"), "console.log(1)").unwrap();
+
+    assert_eq!(get("/").await, (StatusCode::OK, "<p>hello</p>".to_owned()));
+    assert_eq!(
+        get("/static/app.js").await,
+        (StatusCode::OK, "console.log(1)".to_owned())
+    );
+
+    clear_static_dir(path);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_missing_index_is_not_found() {
+    let path = configure_static_dir();
+
+    let (status, _) = get("/").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    clear_static_dir(path);
+}
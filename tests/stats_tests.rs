@@ -0,0 +1,116 @@
+//! Tests of the in-memory stats endpoint
+//!
+//! Runs against a mock inference engine that reports a fixed generation time, with the stats
+//! recorder subscribed to the inference events like in the server.
+
+mod common;
+
+use axum::{
+    Router,
+    body::Body,
+    http::{Request, StatusCode},
+};
+use serde_json::Value;
+use std::time::Duration;
+use tokio_local_llm_api::core::inference_events::CompletionReason;
+use tokio_local_llm_api::core::{stats, task_queue};
+use tokio_local_llm_api::{TASK_SENDER, api, test_util::TestDb};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+/// The parts the engine generates for every message.
+const PARTS: [&str; 4] = ["One", " two", " three", " four"];
+
+/// How long the engine reports generating the parts took.
+const GENERATION_TIME: Duration = Duration::from_secs(2);
+
+/// Starts a mock engine that answers every task with [`PARTS`].
+fn init_engine() {
+    let (sender, mut receiver) = task_queue::channel(10);
+    TASK_SENDER.set(sender).expect("task sender already set");
+
+    tokio::spawn(async move {
+        while let Some(mut task) = receiver.recv().await {
+            for part in PARTS {
+                let _ = task.return_channel().send(part.to_owned()).await;
+                task.generated_token();
+            }
+            task.completed(CompletionReason::Stop, Duration::ZERO, GENERATION_TIME);
+        }
+    });
+}
+
+fn create_test_app() -> Router {
+    common::create_test_app_with(
+        |_| {},
+        |app| {
+            app.merge(api::stats::router())
+                .layer(axum::middleware::from_fn(api::count_requests))
+        },
+    )
+}
+
+async fn get_stats() -> Value {
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .uri("/stats")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn test_counters_increment_after_a_generation() {
+    let _db = TestDb::new().await;
+    init_engine();
+    tokio::spawn(stats::record_events());
+
+    let before = get_stats().await;
+    assert_eq!(before["generations"], 0);
+    assert_eq!(before["tokens_generated"], 0);
+    assert_eq!(before["queue_depth"], 0);
+
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations")
+                .header("X-User-ID", Uuid::new_v4().to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"message": "Count to four"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    // The recorder counts the generation once it sees the completion event
+    let after = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let stats = get_stats().await;
+            if stats["generations"] == 1 {
+                return stats;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the generation was not counted");
+
+    assert_eq!(after["tokens_generated"], PARTS.len());
+    assert_eq!(after["average_tokens_per_sec"], 2.0);
+    assert_eq!(after["queue_depth"], 0);
+    // The first stats request and the generation
+    assert!(after["requests_served"].as_u64().unwrap() >= 2);
+}
@@ -0,0 +1,108 @@
+//! Streaming behaviour tests
+//!
+//! Exercises the SSE message path against a mock inference engine that floods the return
+//! channel, to check how slow clients are handled.
+
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use common::{create_test_app, parse_sse_events};
+use serial_test::serial;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_local_llm_api::core::task_queue;
+use tokio_local_llm_api::{TASK_SENDER, test_util::TestDb};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+/// More parts than the return channel and the client buffer can hold together.
+const FLOOD_SIZE: usize = 2_000;
+
+/// Starts a mock engine that answers every task with `FLOOD_SIZE` parts and reports the number
+/// of finished generations through the returned watch.
+fn init_flooding_engine() -> watch::Receiver<usize> {
+    let (sender, mut receiver) = task_queue::channel(10);
+    TASK_SENDER.set(sender).expect("task sender already set");
+    let (finished_sender, finished_receiver) = watch::channel(0);
+
+    tokio::spawn(async move {
+        while let Some(task) = receiver.recv().await {
+            for _ in 0..FLOOD_SIZE {
+                if task.return_channel().send("x".to_owned()).await.is_err() {
+                    break;
+                }
+            }
+            finished_sender.send_modify(|finished| *finished += 1);
+        }
+    });
+
+    finished_receiver
+}
+
+#[tokio::test]
+#[serial]
+async fn test_detach_policy_completes_with_stalled_client() {
+    let db = TestDb::new().await;
+    let pool = db.pool().clone();
+    let mut finished = init_flooding_engine();
+    unsafe { std::env::set_var("SLOW_CLIENT_POLICY", "detach") };
+
+    // Keep the response alive but never read it, like a stalled client
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations")
+                .header("X-User-ID", Uuid::new_v4().to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"message": "Hi!"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    tokio::time::timeout(Duration::from_secs(5), finished.wait_for(|n| *n == 1))
+        .await
+        .expect("generation was blocked by the stalled client")
+        .unwrap();
+
+    // The full text is persisted even though the client never received it
+    let text = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let row: Option<(String,)> = sqlx::query_as("SELECT text FROM messages WHERE kind = 2")
+                .fetch_optional(&pool)
+                .await
+                .unwrap();
+            if let Some((text,)) = row {
+                return text;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("bot message was not persisted");
+    assert_eq!(text.len(), FLOOD_SIZE);
+
+    // What the client had room for, and then why the stream ends before the whole message
+    let body = tokio::time::timeout(
+        Duration::from_secs(5),
+        axum::body::to_bytes(response.into_body(), usize::MAX),
+    )
+    .await
+    .expect("stream did not end")
+    .unwrap();
+    let events = parse_sse_events(std::str::from_utf8(&body).unwrap());
+    let (name, data) = events.last().unwrap();
+    assert_eq!(name, "detached");
+    let detached: serde_json::Value = serde_json::from_str(data).unwrap();
+    let message_id: Uuid = sqlx::query_scalar("SELECT id FROM messages WHERE kind = 2")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(detached["message_id"], message_id.to_string());
+    assert!(events.iter().all(|(name, _)| name != "done"));
+}
@@ -0,0 +1,69 @@
+//! Tests of the server with inference disabled
+//!
+//! Runs the stub worker of `DISABLE_INFERENCE` in place of the inference worker, as the server
+//! does without a model.
+
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use common::create_test_app;
+use common::parse_sse_events;
+use serde_json::Value;
+use std::time::Duration;
+use tokio_local_llm_api::core::stub_inference::{self, STUB_RESPONSE};
+use tokio_local_llm_api::core::task_queue;
+use tokio_local_llm_api::{TASK_SENDER, test_util::TestDb};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+#[tokio::test]
+async fn test_posted_message_gets_the_canned_reply() {
+    let db = TestDb::new().await;
+    let (sender, receiver) = task_queue::channel(10);
+    TASK_SENDER.set(sender).expect("task sender already set");
+    tokio::spawn(stub_inference::background_task(receiver));
+
+    let user_id = Uuid::new_v4();
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations")
+                .header("X-User-ID", user_id.to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"message": "Hi!"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = tokio::time::timeout(
+        Duration::from_secs(5),
+        axum::body::to_bytes(response.into_body(), usize::MAX),
+    )
+    .await
+    .expect("stream did not end")
+    .unwrap();
+    let events = parse_sse_events(std::str::from_utf8(&body).unwrap());
+
+    let parts: Vec<String> = events
+        .iter()
+        .filter(|(event, _)| event == "message_part")
+        .map(|(_, data)| {
+            let part: Value = serde_json::from_str(data).unwrap();
+            part["message_part"].as_str().unwrap().to_owned()
+        })
+        .collect();
+    assert_eq!(parts, [STUB_RESPONSE]);
+    assert!(events.iter().any(|(event, _)| event == "done"));
+
+    let text: String = sqlx::query_scalar("SELECT text FROM messages WHERE kind = 2")
+        .fetch_one(db.pool())
+        .await
+        .unwrap();
+    assert_eq!(text, STUB_RESPONSE);
+}
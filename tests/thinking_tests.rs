@@ -0,0 +1,102 @@
+//! Thinking stream tests
+//!
+//! Runs against a mock inference engine that thinks between `<think>` markers before it answers,
+//! with the markers configured and `STORE_THINKING` on.
+
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use common::create_test_app;
+use common::parse_sse_events;
+use serde_json::Value;
+use std::time::Duration;
+use tokio_local_llm_api::core::inference_events::CompletionReason;
+use tokio_local_llm_api::core::task_queue;
+use tokio_local_llm_api::{TASK_SENDER, test_util::TestDb};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+/// The parts the engine generates for every message, with markers split across parts.
+const PARTS: [&str; 6] = ["<thi", "nk>Two ", "and two.</", "think>\n", "Four", "."];
+
+/// Starts a mock engine that answers every task with [`PARTS`].
+fn init_thinking_engine() {
+    let (sender, mut receiver) = task_queue::channel(10);
+    TASK_SENDER.set(sender).expect("task sender already set");
+
+    tokio::spawn(async move {
+        while let Some(mut task) = receiver.recv().await {
+            for part in PARTS {
+                let _ = task.return_channel().send(part.to_owned()).await;
+                task.generated_token();
+            }
+            task.completed(CompletionReason::Stop, Duration::ZERO, Duration::ZERO);
+        }
+    });
+}
+
+#[tokio::test]
+async fn test_thinking_is_streamed_and_stored_apart_from_the_answer() {
+    let db = TestDb::new().await;
+    init_thinking_engine();
+    // SAFETY: this is the only test in this binary
+    unsafe {
+        std::env::set_var("THINKING_OPEN_MARKER", "<think>");
+        std::env::set_var("THINKING_CLOSE_MARKER", "</think>");
+        std::env::set_var("STORE_THINKING", "true");
+    }
+
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations")
+                .header("X-User-ID", Uuid::new_v4().to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"message": "Two and two?"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let events = parse_sse_events(std::str::from_utf8(&body).unwrap());
+    let text = |name: &str, field: &str| -> String {
+        events
+            .iter()
+            .filter(|(event, _)| event == name)
+            .map(|(_, data)| {
+                let data: Value = serde_json::from_str(data).unwrap();
+                data[field].as_str().unwrap().to_owned()
+            })
+            .collect()
+    };
+    assert_eq!(text("thinking", "thinking_part"), "Two and two.");
+    assert_eq!(text("message_part", "message_part"), "Four.");
+    assert!(events.iter().any(|(event, _)| event == "done"));
+
+    // The thinking is stored once the message is saved
+    let (saved, thinking) = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let row: Option<(String, Option<String>)> =
+                sqlx::query_as("SELECT text, thinking FROM messages WHERE kind = 2")
+                    .fetch_optional(db.pool())
+                    .await
+                    .unwrap();
+            if let Some((text, Some(thinking))) = row {
+                return (text, thinking);
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("thinking was not stored");
+    assert_eq!(saved, "Four.");
+    assert_eq!(thinking, "Two and two.");
+}
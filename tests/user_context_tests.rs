@@ -0,0 +1,84 @@
+//! Request-scoped user context tests
+//!
+//! Resolves the repository in a request made through the user context middleware, the way the
+//! server does, and checks which user its queries are scoped to.
+
+use axum::{
+    Router,
+    body::Body,
+    http::{Request, StatusCode},
+    middleware,
+    routing::get,
+};
+use di::{Injectable, ServiceCollection};
+use di_axum::{Inject, RouterServiceProviderExtensions};
+use serde_json::{Value, json};
+use tokio_local_llm_api::infrastructure::entities::ConversationOrder;
+use tokio_local_llm_api::infrastructure::traits::ConversationRepository;
+use tokio_local_llm_api::{
+    api, api::ExtractUser, infrastructure::database::DatabaseConnection,
+    infrastructure::repositories::DbConversationRepository,
+    infrastructure::user_context::UserContext, test_util::TestDb,
+};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+/// Reports the user of the context, and whether the repository serves that user and another one.
+async fn probe(
+    Inject(user_context): Inject<UserContext>,
+    Inject(repository): Inject<dyn ConversationRepository>,
+    ExtractUser(current_user): ExtractUser,
+) -> axum::Json<Value> {
+    axum::Json(json!({
+        "context_user": user_context.user_id(),
+        "own_query": repository
+            .list_conversations(current_user, ConversationOrder::default())
+            .await
+            .is_ok(),
+        "other_query": repository
+            .list_conversations(Uuid::new_v4(), ConversationOrder::default())
+            .await
+            .is_ok(),
+    }))
+}
+
+fn create_test_app() -> Router {
+    let provider = ServiceCollection::new()
+        .add(DatabaseConnection::transient())
+        .add(UserContext::scoped())
+        .add(DbConversationRepository::scoped())
+        .build_provider()
+        .unwrap();
+
+    Router::new()
+        .route("/probe", get(probe))
+        .layer(middleware::from_fn(api::scope_user_context))
+        .with_provider(provider)
+}
+
+#[tokio::test]
+async fn test_scoped_context_carries_the_request_user_into_the_repository() {
+    let _db = TestDb::new().await;
+    let user_id = Uuid::new_v4();
+
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .uri("/probe")
+                .header("X-User-ID", user_id.to_string())
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(body["context_user"], user_id.to_string());
+    assert_eq!(body["own_query"], true);
+    // Querying another user's data in this request is refused
+    assert_eq!(body["other_query"], false);
+}
@@ -0,0 +1,91 @@
+//! Webhook delivery tests
+//!
+//! Posts a message through the API against a mock inference engine and asserts that the
+//! configured `WEBHOOK_URL` receives the generation lifecycle payloads.
+
+use axum::{
+    Json, Router,
+    body::Body,
+    http::{Request, StatusCode},
+    routing::post,
+};
+use common::create_test_app;
+use serde_json::Value;
+use serial_test::serial;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_local_llm_api::core::generation_webhooks;
+use tokio_local_llm_api::test_util::TestDb;
+use tower::ServiceExt;
+use uuid::Uuid;
+
+mod common;
+use common::{CANNED_RESPONSE, init_test_task_sender};
+
+/// Starts a local HTTP server that forwards every received webhook body to the returned channel.
+async fn start_webhook_fixture() -> (String, mpsc::UnboundedReceiver<Value>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    let app = Router::new().route(
+        "/hook",
+        post(move |Json(body): Json<Value>| {
+            let sender = sender.clone();
+            async move {
+                sender.send(body).unwrap();
+                StatusCode::OK
+            }
+        }),
+    );
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    (url, receiver)
+}
+
+async fn next_webhook(webhooks: &mut mpsc::UnboundedReceiver<Value>) -> Value {
+    tokio::time::timeout(Duration::from_secs(5), webhooks.recv())
+        .await
+        .expect("webhook was not delivered")
+        .unwrap()
+}
+
+#[tokio::test]
+#[serial]
+async fn test_webhook_receives_completed_generation() {
+    let _db = TestDb::new().await;
+    init_test_task_sender();
+    let (url, mut webhooks) = start_webhook_fixture().await;
+    tokio::spawn(generation_webhooks::notify_events());
+    unsafe { std::env::set_var("WEBHOOK_URL", url) };
+
+    let response = create_test_app()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations")
+                .header("X-User-ID", Uuid::new_v4().to_string())
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"message": "Hi!"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Drain the stream so the generation completes and the bot message is saved
+    axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    let started = next_webhook(&mut webhooks).await;
+    assert_eq!(started["event"], "started");
+
+    let finished = next_webhook(&mut webhooks).await;
+    assert_eq!(finished["event"], "finished");
+    assert_eq!(finished["conversation_id"], started["conversation_id"]);
+    assert_eq!(finished["message_id"], started["message_id"]);
+    assert_eq!(finished["finish_reason"], "stop");
+    assert_eq!(finished["completion_tokens"], CANNED_RESPONSE.len());
+}